@@ -1,21 +1,35 @@
 use std::fs::File;
 use std::io::Write;
 use std::path::Path;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc};
 use std::thread::JoinHandle;
 
-use eframe::egui::{Color32, DragValue, ProgressBar, RichText};
+use eframe::egui::{Button, Color32, DragValue, Label, ProgressBar, RichText, Sense, TextEdit};
 use rfd::FileDialog;
 
-use libattpc_merger::config::Config;
+use super::desktop::{has_desktop_session, open_path};
+use super::log_panel::{render_log_lines, LogBuffer};
+
+use libattpc_merger::config::{
+    Config, ConfigField, ConfigIssue, PostMergeAction, DEFAULT_ONLINE_PATH_TEMPLATE,
+};
 use libattpc_merger::error::ProcessorError;
-use libattpc_merger::process::{create_subsets, process_subset};
-use libattpc_merger::worker_status::WorkerStatus;
+use libattpc_merger::process::{check_disk_space, get_n_workers, process_queue, RunQueue};
+use libattpc_merger::worker_status::{WorkerPhase, WorkerStatus, WorkerStatuses};
+
+/// The log file of the UI
+pub const LOG_FILE_PATH: &str = "./attpc_merger.log";
 
-fn render_error_dialog(show: &mut bool, ctx: &eframe::egui::Context) {
+/// Shown on the buttons which open files when there is no desktop session to open them with
+const NO_DESKTOP_TOOLTIP: &str = "No desktop session was found to open files with";
+
+fn render_error_dialog(show: &mut bool, message: Option<&str>, ctx: &eframe::egui::Context) {
     eframe::egui::Window::new("Error")
         .open(show)
         .show(ctx, |ui| {
+            if let Some(message) = message {
+                ui.label(message);
+            }
             ui.label(
                 "There was an error! Check the log file attpc_merger.log for more information.",
             )
@@ -29,15 +43,20 @@ fn render_error_dialog(show: &mut bool, ctx: &eframe::egui::Context) {
 pub struct MergerApp {
     config: Config,
     workers: Vec<JoinHandle<Result<(), ProcessorError>>>, //processing thread
-    worker_statuses: Vec<WorkerStatus>,
+    worker_statuses: WorkerStatuses,
     show_error_window: bool,
+    config_issues: Vec<ConfigIssue>,
     worker_rx: mpsc::Receiver<WorkerStatus>,
     worker_tx: mpsc::Sender<WorkerStatus>,
+    log_buffer: LogBuffer,
+    error_message: Option<String>, // what went wrong, shown in the error dialog
+    failed_runs: Vec<i32>,         // runs whose worker returned an error
+    has_desktop: bool,             // if files and folders can be opened, detected at startup
 }
 
 impl MergerApp {
     /// Create the application
-    pub fn new(cc: &eframe::CreationContext<'_>) -> Self {
+    pub fn new(cc: &eframe::CreationContext<'_>, log_buffer: LogBuffer) -> Self {
         let mut visuals = eframe::egui::Visuals::dark();
         visuals.override_text_color = Some(Color32::LIGHT_GRAY);
         cc.egui_ctx.set_visuals(visuals);
@@ -46,10 +65,15 @@ impl MergerApp {
         MergerApp {
             config: Config::default(),
             workers: vec![],
-            worker_statuses: vec![],
+            worker_statuses: WorkerStatuses::default(),
             show_error_window: false,
+            config_issues: vec![],
             worker_rx: rx,
             worker_tx: tx,
+            log_buffer,
+            error_message: None,
+            failed_runs: vec![],
+            has_desktop: has_desktop_session(),
         }
     }
 
@@ -58,18 +82,18 @@ impl MergerApp {
         // Safety first
         if self.workers.is_empty() {
             self.worker_statuses.clear();
-            let subsets = create_subsets(&self.config);
-            for (idx, subset) in subsets.into_iter().enumerate() {
-                // Dont make empty workers
-                if subset.is_empty() {
-                    continue;
-                }
+            self.failed_runs.clear();
+            // The workers share the runs, each taking the next one when it is free
+            let queue = Arc::new(RunQueue::new(&self.config));
+            let n_workers = get_n_workers(&self.config);
+            self.worker_statuses = WorkerStatuses::new(n_workers);
+            for idx in 0..n_workers {
                 // Spawn it
                 let conf = self.config.clone();
                 let tx = self.worker_tx.clone();
-                self.worker_statuses.push(WorkerStatus::new(0.0, 0, idx));
+                let queue = queue.clone();
                 self.workers.push(std::thread::spawn(move || {
-                    process_subset(conf, tx, idx, subset)
+                    process_queue(conf, tx, idx, queue)
                 }))
             }
         }
@@ -85,6 +109,9 @@ impl MergerApp {
                         Ok(_) => spdlog::info!("Worker complete"),
                         Err(e) => {
                             self.show_error_window = true;
+                            if let ProcessorError::RunError(run, _) = &e {
+                                self.failed_runs.push(*run);
+                            }
                             spdlog::error!("Processor error: {e}")
                         }
                     },
@@ -97,6 +124,23 @@ impl MergerApp {
         }
     }
 
+    /// Open a file or folder with the system, showing the error dialog if it can't be opened
+    fn open_path(&mut self, path: &Path) {
+        if let Err(e) = open_path(path) {
+            spdlog::error!("{e}");
+            self.error_message = Some(e);
+            self.show_error_window = true;
+        }
+    }
+
+    /// The yml file written next to the output of a run
+    fn get_sidecar_path(&self, run_number: i32) -> Result<std::path::PathBuf, String> {
+        self.config
+            .get_hdf_file_name(run_number)
+            .map(|path| path.with_extension("yml"))
+            .map_err(|e| e.to_string())
+    }
+
     /// Check if there are any workers still doing stuff
     fn are_any_workers_alive(&self) -> bool {
         for worker in self.workers.iter() {
@@ -132,10 +176,7 @@ impl MergerApp {
         // Check messages
         loop {
             match self.worker_rx.try_recv() {
-                Ok(status) => {
-                    let id = status.worker_id;
-                    self.worker_statuses[id] = status;
-                }
+                Ok(status) => self.worker_statuses.update(status),
                 Err(mpsc::TryRecvError::Empty) => break,
                 Err(mpsc::TryRecvError::Disconnected) => {
                     spdlog::error!("Channels became disconnected!");
@@ -145,6 +186,32 @@ impl MergerApp {
         }
     }
 
+    /// Check if a worker is still running but hasn't sent a status within the stall timeout of the Config
+    fn is_worker_stalled(&self, status: &WorkerStatus) -> bool {
+        let timeout = match self.config.get_stall_timeout() {
+            Some(timeout) => timeout,
+            None => return false,
+        };
+        match self.workers.get(status.worker_id) {
+            Some(worker) => !worker.is_finished() && status.is_stalled(timeout),
+            None => false,
+        }
+    }
+
+    /// Check if a config field has an issue
+    fn has_issue(&self, field: ConfigField) -> bool {
+        self.config_issues.iter().any(|issue| issue.field == field)
+    }
+
+    /// Text for a config grid label, highlighted in red if the field has an issue
+    fn config_label(&self, field: ConfigField, text: String) -> RichText {
+        if self.has_issue(field) {
+            RichText::new(text).color(Color32::RED)
+        } else {
+            RichText::new(text)
+        }
+    }
+
     /// Read the Config from a file
     fn read_config(&mut self, path: &Path) {
         match Config::read_config_file(path) {
@@ -157,7 +224,19 @@ impl MergerApp {
 impl eframe::App for MergerApp {
     fn update(&mut self, ctx: &eframe::egui::Context, _frame: &mut eframe::Frame) {
         self.poll_messages();
-        render_error_dialog(&mut self.show_error_window, ctx);
+        render_error_dialog(
+            &mut self.show_error_window,
+            self.error_message.as_deref(),
+            ctx,
+        );
+        if !self.show_error_window {
+            self.error_message = None;
+        }
+        eframe::egui::TopBottomPanel::bottom("log_panel").show(ctx, |ui| {
+            eframe::egui::CollapsingHeader::new("Log")
+                .default_open(false)
+                .show(ui, |ui| render_log_lines(ui, &self.log_buffer));
+        });
         eframe::egui::CentralPanel::default().show(ctx, |ui| {
             //Menus
             ui.menu_button("File", |ui| {
@@ -195,16 +274,42 @@ impl eframe::App for MergerApp {
             eframe::egui::Grid::new("ConfigGrid").show(ui, |ui| {
                 //GRAW directory
                 ui.checkbox(&mut self.config.online, "GRAW files from online source");
+                ui.checkbox(&mut self.config.follow, "Follow files during data taking");
                 ui.end_row();
                 //Online data requires a further path extension based on the experiment
                 if self.config.online {
                     ui.label("Experiment:");
                     ui.text_edit_singleline(&mut self.config.experiment);
                     ui.end_row();
+                    //Where the DAQ exports the data of each CoBo
+                    ui.label(self.config_label(
+                        ConfigField::OnlinePathTemplate,
+                        String::from("Online path template:"),
+                    ));
+                    let mut template = self.config.online_path_template.clone().unwrap_or_default();
+                    ui.add(
+                        TextEdit::singleline(&mut template).hint_text(DEFAULT_ONLINE_PATH_TEMPLATE),
+                    );
+                    self.config.online_path_template = if template.is_empty() {
+                        None
+                    } else {
+                        Some(template)
+                    };
+                    ui.end_row();
+                    //Optionally stream the frames directly from the DAQ
+                    ui.label("Online address (host:port):");
+                    let mut address = self.config.online_address.clone().unwrap_or_default();
+                    ui.text_edit_singleline(&mut address);
+                    self.config.online_address = if address.is_empty() {
+                        None
+                    } else {
+                        Some(address)
+                    };
+                    ui.end_row();
                 } else {
-                    ui.label(format!(
-                        "GRAW directory: {}",
-                        self.config.graw_path.display()
+                    ui.label(self.config_label(
+                        ConfigField::GrawPath,
+                        format!("GRAW directory: {}", self.config.graw_path.display()),
                     ));
                     if ui.button("Open...").clicked() {
                         if let Some(path) = FileDialog::new()
@@ -216,11 +321,15 @@ impl eframe::App for MergerApp {
                             self.config.graw_path = path;
                         }
                     }
+                    ui.checkbox(&mut self.config.merge_get, "Merge GET data");
                     ui.end_row();
                 }
 
                 //EVT directory
-                ui.label(format!("EVT directory: {}", self.config.evt_path.display()));
+                ui.label(self.config_label(
+                    ConfigField::EvtPath,
+                    format!("EVT directory: {}", self.config.evt_path.display()),
+                ));
                 if ui.button("Open...").clicked() {
                     if let Some(path) = FileDialog::new()
                         .set_directory(
@@ -231,12 +340,13 @@ impl eframe::App for MergerApp {
                         self.config.evt_path = path;
                     }
                 }
+                ui.checkbox(&mut self.config.require_evt, "Require EVT data");
                 ui.end_row();
 
                 //HDF directory
-                ui.label(format!(
-                    "HDF5 directory: {}",
-                    self.config.hdf_path.display()
+                ui.label(self.config_label(
+                    ConfigField::HdfPath,
+                    format!("HDF5 directory: {}", self.config.hdf_path.display()),
                 ));
                 if ui.button("Open...").clicked() {
                     if let Some(path) = FileDialog::new()
@@ -250,12 +360,33 @@ impl eframe::App for MergerApp {
                 }
                 ui.end_row();
 
+                //Output suffix, to tag merges with different settings
+                ui.label(
+                    self.config_label(ConfigField::OutputSuffix, String::from("Output suffix:")),
+                );
+                let mut suffix = self.config.output_suffix.clone().unwrap_or_default();
+                ui.add(TextEdit::singleline(&mut suffix).hint_text("None"));
+                self.config.output_suffix = if suffix.is_empty() {
+                    None
+                } else {
+                    Some(suffix)
+                };
+                ui.end_row();
+
                 //Pad map
                 let map_render_text: String = match &self.config.pad_map_path {
                     Some(p) => p.to_string_lossy().to_string(),
                     None => String::from("Default"),
                 };
-                ui.label(format!("Pad map: {}", map_render_text));
+                let mut map_label = self.config_label(
+                    ConfigField::PadMapPath,
+                    format!("Pad map: {}", map_render_text),
+                );
+                // The default map is rarely the map of the experiment, so make it stand out
+                if self.config.pad_map_path.is_none() && !self.has_issue(ConfigField::PadMapPath) {
+                    map_label = map_label.color(Color32::YELLOW);
+                }
+                ui.label(map_label);
                 if ui.button("Open...").clicked() {
                     if let Some(path) = FileDialog::new()
                         .set_directory(
@@ -272,21 +403,127 @@ impl eframe::App for MergerApp {
                 }
                 ui.end_row();
 
-                ui.label("First Run Number");
+                //Pedestals
+                let pedestal_render_text: String = match &self.config.pedestal_path {
+                    Some(p) => p.to_string_lossy().to_string(),
+                    None => String::from("None"),
+                };
+                ui.label(self.config_label(
+                    ConfigField::PedestalPath,
+                    format!("Pedestals: {}", pedestal_render_text),
+                ));
+                if ui.button("Open...").clicked() {
+                    if let Some(path) = FileDialog::new()
+                        .set_directory(
+                            std::env::current_dir().expect("Couldn't access runtime directory"),
+                        )
+                        .add_filter("CSV file", &["csv", "CSV", "txt"])
+                        .pick_file()
+                    {
+                        self.config.pedestal_path = Some(path);
+                    }
+                }
+                if ui.button("None").clicked() {
+                    self.config.pedestal_path = None
+                }
+                ui.end_row();
+
+                //Runs database
+                let run_database_render_text: String = match &self.config.run_database_path {
+                    Some(p) => p.to_string_lossy().to_string(),
+                    None => String::from("None"),
+                };
+                ui.label(self.config_label(
+                    ConfigField::RunDatabasePath,
+                    format!("Runs Database: {}", run_database_render_text),
+                ));
+                if ui.button("Open...").clicked() {
+                    if let Some(path) = FileDialog::new()
+                        .set_directory(
+                            std::env::current_dir().expect("Couldn't access runtime directory"),
+                        )
+                        .add_filter("CSV file", &["csv", "CSV", "txt"])
+                        .pick_file()
+                    {
+                        self.config.run_database_path = Some(path);
+                    }
+                }
+                if ui.button("None").clicked() {
+                    self.config.run_database_path = None
+                }
+                ui.end_row();
+
+                ui.label(
+                    self.config_label(ConfigField::RunRange, String::from("First Run Number")),
+                );
                 ui.add(DragValue::new(&mut self.config.first_run_number).speed(1));
                 ui.end_row();
 
-                ui.label("Last Run Number");
+                ui.label(self.config_label(ConfigField::RunRange, String::from("Last Run Number")));
                 ui.add(DragValue::new(&mut self.config.last_run_number).speed(1));
                 ui.end_row();
 
-                ui.label("Number of Workers");
+                ui.label(
+                    self.config_label(ConfigField::NThreads, String::from("Number of Workers")),
+                );
                 ui.add(
                     DragValue::new(&mut self.config.n_threads)
                         .speed(1)
                         .range(std::ops::RangeInclusive::new(1, 10)),
                 );
                 ui.end_row();
+
+                ui.label(self.config_label(
+                    ConfigField::TimeBuckets,
+                    String::from("Number of Time Buckets"),
+                ));
+                ui.add(
+                    DragValue::new(&mut self.config.time_buckets)
+                        .speed(1)
+                        .range(std::ops::RangeInclusive::new(1, 1024)),
+                );
+                ui.end_row();
+
+                //Post-merge action
+                let action_render_text: String = match &self.config.post_merge_action {
+                    Some(PostMergeAction::Delete) => String::from("Delete source files"),
+                    Some(PostMergeAction::MoveTo(p)) => {
+                        format!("Move source files to {}", p.display())
+                    }
+                    None => String::from("None"),
+                };
+                ui.label(self.config_label(
+                    ConfigField::PostMergeAction,
+                    format!("After merge: {}", action_render_text),
+                ));
+                if ui.button("Move to...").clicked() {
+                    if let Some(path) = FileDialog::new()
+                        .set_directory(
+                            std::env::current_dir().expect("Couldn't access runtime directory"),
+                        )
+                        .pick_folder()
+                    {
+                        self.config.post_merge_action = Some(PostMergeAction::MoveTo(path));
+                    }
+                }
+                if ui.button("Delete").clicked() {
+                    self.config.post_merge_action = Some(PostMergeAction::Delete);
+                }
+                if ui.button("None").clicked() {
+                    self.config.post_merge_action = None;
+                    self.config.confirm_post_merge_action = false;
+                }
+                ui.end_row();
+
+                if self.config.post_merge_action.is_some() {
+                    ui.label("");
+                    ui.checkbox(
+                        &mut self.config.confirm_post_merge_action,
+                        RichText::new("Yes, remove the source files after merging")
+                            .color(Color32::RED),
+                    );
+                    ui.end_row();
+                }
             });
 
             //Controls
@@ -295,12 +532,58 @@ impl eframe::App for MergerApp {
                 .add_enabled(self.workers.is_empty(), eframe::egui::Button::new("Run"))
                 .clicked()
             {
-                spdlog::info!("Starting processor...");
-                self.start_workers();
+                // Don't start if the config has problems; show them instead
+                self.config_issues = self.config.validate();
+                if self.config_issues.is_empty() {
+                    let runs: Vec<i32> =
+                        (self.config.first_run_number..(self.config.last_run_number + 1)).collect();
+                    if let Err(e) = check_disk_space(&self.config, &runs) {
+                        self.config_issues.push(ConfigIssue {
+                            field: ConfigField::HdfPath,
+                            message: e.to_string(),
+                        });
+                    }
+                }
+                if self.config_issues.is_empty() {
+                    if let Some(warning) = self.config.get_default_pad_map_warning() {
+                        spdlog::warn!("{warning}");
+                    }
+                    spdlog::info!("Starting processor...");
+                    self.start_workers();
+                }
             } else if !self.are_any_workers_alive() {
                 self.stop_workers();
             }
 
+            // Shortcuts to the output and the log
+            ui.horizontal(|ui| {
+                if ui
+                    .add_enabled(self.has_desktop, Button::new("Open output folder"))
+                    .on_disabled_hover_text(NO_DESKTOP_TOOLTIP)
+                    .clicked()
+                {
+                    let hdf_path = self.config.hdf_path.clone();
+                    self.open_path(&hdf_path);
+                }
+                if ui
+                    .add_enabled(self.has_desktop, Button::new("Open log"))
+                    .on_disabled_hover_text(NO_DESKTOP_TOOLTIP)
+                    .clicked()
+                {
+                    self.open_path(Path::new(LOG_FILE_PATH));
+                }
+            });
+
+            //Config issues
+            if !self.config_issues.is_empty() {
+                ui.group(|ui| {
+                    ui.label(RichText::new("Configuration Issues").color(Color32::RED));
+                    for issue in self.config_issues.iter() {
+                        ui.label(RichText::new(issue.to_string()).color(Color32::RED));
+                    }
+                });
+            }
+
             //Progress Bars
             ui.separator();
             ui.label(
@@ -309,12 +592,115 @@ impl eframe::App for MergerApp {
                     .size(18.0),
             );
             for status in self.worker_statuses.iter() {
-                ui.add(ProgressBar::new(status.progress).text(format!(
-                    "Worker {} : Run {} - {}%",
-                    status.worker_id,
-                    status.run_number,
-                    (status.progress * 100.0) as i32
-                )));
+                if self.is_worker_stalled(status) {
+                    ui.add(
+                        ProgressBar::new(status.progress)
+                            .fill(Color32::RED)
+                            .text(format!(
+                                "Worker {} : Run {} - {}% - Stalled, no status for {} s",
+                                status.worker_id,
+                                status.run_number,
+                                (status.progress * 100.0) as i32,
+                                status.heartbeat.elapsed().as_secs()
+                            )),
+                    );
+                    continue;
+                }
+                match status.phase {
+                    WorkerPhase::Processing => {
+                        let copy_text = match status.background_copy {
+                            Some(run) => format!(" - Copying run {run}"),
+                            None => String::new(),
+                        };
+                        ui.add(ProgressBar::new(status.progress).text(format!(
+                            "Worker {} : Run {} - {}%{}",
+                            status.worker_id,
+                            status.run_number,
+                            (status.progress * 100.0) as i32,
+                            copy_text
+                        )));
+                    }
+                    WorkerPhase::WaitingForData => {
+                        ui.add(
+                            ProgressBar::new(status.progress)
+                                .fill(Color32::from_rgb(255, 0, 255)) // Magenta
+                                .text(format!(
+                                    "Worker {} : Run {} - {}% - Waiting for data",
+                                    status.worker_id,
+                                    status.run_number,
+                                    (status.progress * 100.0) as i32
+                                )),
+                        );
+                    }
+                    WorkerPhase::Copying => {
+                        ui.add(
+                            ProgressBar::new(status.progress)
+                                .fill(Color32::from_rgb(0, 160, 0)) // Green
+                                .text(format!(
+                                    "Worker {} : Run {} - {}% - Copying",
+                                    status.worker_id,
+                                    status.run_number,
+                                    (status.progress * 100.0) as i32
+                                )),
+                        );
+                    }
+                    WorkerPhase::Uploading => {
+                        ui.add(
+                            ProgressBar::new(status.progress)
+                                .fill(Color32::from_rgb(200, 160, 0)) // Yellow
+                                .text(format!(
+                                    "Worker {} : Run {} - {}% - Uploading",
+                                    status.worker_id,
+                                    status.run_number,
+                                    (status.progress * 100.0) as i32
+                                )),
+                        );
+                    }
+                    WorkerPhase::Checksumming => {
+                        ui.add(
+                            ProgressBar::new(status.progress)
+                                .fill(Color32::from_rgb(60, 120, 255)) // Blue
+                                .text(format!(
+                                    "Worker {} : Run {} - {}% - Checksumming",
+                                    status.worker_id,
+                                    status.run_number,
+                                    (status.progress * 100.0) as i32
+                                )),
+                        );
+                    }
+                }
+            }
+
+            //Failed runs, with a menu to inspect their output
+            if !self.failed_runs.is_empty() {
+                ui.separator();
+                ui.label(RichText::new("Failed Runs").color(Color32::RED).size(18.0));
+                for run in self.failed_runs.clone() {
+                    ui.add(
+                        Label::new(
+                            RichText::new(format!("Run {run} (right click for options)"))
+                                .color(Color32::RED),
+                        )
+                        .sense(Sense::click()),
+                    )
+                    .context_menu(|ui| {
+                        if ui
+                            .add_enabled(self.has_desktop, Button::new("Open its sidecar yml"))
+                            .on_disabled_hover_text(NO_DESKTOP_TOOLTIP)
+                            .clicked()
+                        {
+                            match self.get_sidecar_path(run) {
+                                Ok(path) => self.open_path(&path),
+                                Err(e) => {
+                                    spdlog::error!("{e}");
+                                    self.error_message = Some(e);
+                                    self.show_error_window = true;
+                                }
+                            }
+                            ui.close_menu();
+                        }
+                    });
+                }
             }
 
             ctx.request_repaint_after(std::time::Duration::from_secs(1));
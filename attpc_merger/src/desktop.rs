@@ -0,0 +1,24 @@
+use std::path::Path;
+
+/// Check if there is a desktop session to open files and folders with
+///
+/// On Linux this is an X11 or Wayland display (i.e. not a plain SSH session). Other platforms always have one.
+pub fn has_desktop_session() -> bool {
+    if cfg!(target_os = "linux") {
+        ["DISPLAY", "WAYLAND_DISPLAY"]
+            .iter()
+            .any(|name| std::env::var_os(name).is_some_and(|value| !value.is_empty()))
+    } else {
+        true
+    }
+}
+
+/// Open a file or folder with the default application of the system (i.e. the file browser for a folder)
+///
+/// The path must exist. Returns a message for the error dialog if the path can not be opened.
+pub fn open_path(path: &Path) -> Result<(), String> {
+    if !path.exists() {
+        return Err(format!("{} does not exist!", path.display()));
+    }
+    opener::open(path).map_err(|e| format!("Could not open {}: {}", path.display(), e))
+}
@@ -0,0 +1,113 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use eframe::egui::{Color32, RichText, ScrollArea};
+use spdlog::formatter::Formatter;
+use spdlog::{ErrorHandler, Level, LevelFilter, Record};
+
+/// The number of log lines kept for display in the UI
+pub const LOG_PANEL_LINES: usize = 500;
+
+/// A single log record kept for display in the UI
+#[derive(Debug, Clone)]
+pub struct LogLine {
+    pub level: Level,
+    pub message: String,
+}
+
+/// The buffer of the most recent log lines, shared between the sink and the UI
+pub type LogBuffer = Arc<Mutex<VecDeque<LogLine>>>;
+
+/// A spdlog sink which keeps the most recent log records in memory so that
+/// the UI can display them. Records are stored unformatted; the formatter is ignored.
+pub struct LogPanelSink {
+    buffer: LogBuffer,
+    capacity: usize,
+    level_filter: Mutex<LevelFilter>,
+}
+
+impl LogPanelSink {
+    /// Create a sink keeping at most capacity lines
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buffer: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+            level_filter: Mutex::new(LevelFilter::All),
+        }
+    }
+
+    /// Get a handle to the shared buffer of lines
+    pub fn get_buffer(&self) -> LogBuffer {
+        self.buffer.clone()
+    }
+}
+
+impl spdlog::sink::Sink for LogPanelSink {
+    fn log(&self, record: &Record) -> spdlog::Result<()> {
+        if !self.should_log(record.level()) {
+            return Ok(());
+        }
+        if let Ok(mut lines) = self.buffer.lock() {
+            while lines.len() >= self.capacity {
+                lines.pop_front();
+            }
+            lines.push_back(LogLine {
+                level: record.level(),
+                message: record.payload().to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> spdlog::Result<()> {
+        Ok(())
+    }
+
+    fn level_filter(&self) -> LevelFilter {
+        match self.level_filter.lock() {
+            Ok(filter) => *filter,
+            Err(_) => LevelFilter::All,
+        }
+    }
+
+    fn set_level_filter(&self, level_filter: LevelFilter) {
+        if let Ok(mut filter) = self.level_filter.lock() {
+            *filter = level_filter;
+        }
+    }
+
+    fn set_formatter(&self, _formatter: Box<dyn Formatter>) {}
+
+    fn set_error_handler(&self, _handler: Option<ErrorHandler>) {}
+}
+
+/// The color used to display a line of the given level
+fn level_color(level: Level) -> Color32 {
+    match level {
+        Level::Critical | Level::Error => Color32::RED,
+        Level::Warn => Color32::YELLOW,
+        Level::Info => Color32::LIGHT_GRAY,
+        Level::Debug | Level::Trace => Color32::GRAY,
+    }
+}
+
+/// Render the lines of the buffer, following the end of the log
+pub fn render_log_lines(ui: &mut eframe::egui::Ui, buffer: &LogBuffer) {
+    let lines = match buffer.lock() {
+        Ok(lines) => lines,
+        Err(_) => return,
+    };
+    ScrollArea::vertical()
+        .max_height(150.0)
+        .auto_shrink([false, true])
+        .stick_to_bottom(true)
+        .show(ui, |ui| {
+            for line in lines.iter() {
+                ui.label(
+                    RichText::new(format!("[{}] {}", line.level.as_str(), line.message))
+                        .monospace()
+                        .color(level_color(line.level)),
+                );
+            }
+        });
+}
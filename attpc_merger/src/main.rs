@@ -24,42 +24,75 @@
 //!
 //! - GRAW Files from Online Source Checkbox: If checked, will try to access GRAW files from the AT-TPC DAQ itself. Should typically be unchecked. Only for use when running an experiment.
 //!   - Experiment: Only available when online checkbox is checked. Name of the experiment, matching the AT-TPC DAQ experiment name.
+//!   - Online path template: Only available when online checkbox is checked. Optional directory of the online data of each CoBo, where {cobo} is replaced by the CoBo number and {experiment} by the experiment name. Leave empty for the layout of the original AT-TPC DAQ servers (/Volumes/mm{cobo}/{experiment}).
+//!   - Online address (host:port): Only available when online checkbox is checked. Optional address of the GET DAQ. If given, GRAW frames are streamed directly from the DAQ over TCP rather than read from files. Leave empty to read files.
+//! - Follow Files During Data Taking Checkbox: If checked, the merger waits for the DAQ to write more data instead of stopping at the end of the files. Should typically be unchecked. Only for use when running an experiment.
 //! - GRAW directory: Specifies the full-path to a directory which contains the AT-TPC GETDAQ .graw structure (i.e. contains subdirectories of the run_# format). If online is checked, this field is not available.
 //! - EVT directory: Specifies the full-path to a directory which contains the FRIBDAQ EVT structure (i.e. contains subdirectories of the run# format)
+//!   - Require EVT data: If checked, a run fails if its EVT data is missing or cannot be read. If unchecked, the run is merged without FRIB data and a warning is written to the log. Check this for experiments where the FRIB data is mandatory.
 //! - HDF5 directory: Specifies the full-path to a directory to which merged HDF5 (.h5) files will be written
 //! - Pad map: Specifies the full path to a CSV file which contains the mapping information for AT-TPC pads and electronics
+//! - Pedestals (Optional): Specifies the full path to a CSV file (columns pad,pedestal_mean) which contains the per-pad pedestals to subtract from the traces. If set to None, no subtraction is performed.
 //! - First Run Number: The starting run number (inclusive)
 //! - Last Run Number: The ending run number (inclusive)
+//! - Number of Time Buckets: The number of time buckets in each trace, matching the CoBo configuration (typically 512, but 256 or 1024 are also used). Traces in the output have this many samples. If a frame contains a time bucket beyond this number, the run fails with an error.
+//! - After merge: What to do with the source .graw and .evt files of a run once it has been merged. None (the default) leaves them in place, Delete removes them, and Move to... moves them into the chosen directory, keeping the run directory structure. The action must be confirmed with the red checkbox, is only applied after the merged file has been closed and reopened successfully, and is skipped if there was any error in the run. It is never applied to online data.
 //!
 //! Configurations can be saved using File->Save and loaded using File->Open
+//!
+//! The Open output folder and Open log buttons open the HDF5 directory in the file browser of the system and the log file in the default text editor. If a worker fails, its run is listed under Failed Runs; right click a run to open the yml file written next to its output. On Linux without a desktop session (i.e. over SSH without X forwarding), the buttons are disabled.
+//!
+//! The UI writes its log to attpc_merger.log. The most recent log lines are also shown in the collapsible Log panel at the bottom of the window, with errors in red and warnings in yellow. The lines logged while processing a run are tagged with the run. To write attpc_merger.log as JSON lines instead (one JSON object per record, with timestamp, level, thread, target, run, and message) for automated monitoring, start the UI with `--log-format json`. To also write the JSON lines to attpc_merger.jsonl while keeping the human readable log, start the UI with the `--json-log` flag.
+//!
+//! When Run is clicked, the configuration is checked (paths exist, the run range and number of workers make sense, the pad map and pedestals can be read). If there are any issues, processing is not started; the offending fields are highlighted in red and the issues are listed below the Run button. The CLI performs the same checks after loading the configuration, and exits with an error if there are any issues.
 
 mod app;
-use app::MergerApp;
+mod desktop;
+mod log_panel;
+use app::{MergerApp, LOG_FILE_PATH};
+use libattpc_merger::log_format::{make_formatter, make_json_formatter, LogFormat};
+use log_panel::{LogPanelSink, LOG_PANEL_LINES};
 use std::path::PathBuf;
 use std::sync::Arc;
 
 /// The program entry point
 fn main() {
-    // Setup logging to a file
+    // Setup logging to a file, as text unless asked for JSON with --log-format json
+    let args: Vec<String> = std::env::args().collect();
+    let log_format = args
+        .windows(2)
+        .find(|pair| pair[0] == "--log-format")
+        .and_then(|pair| pair[1].parse::<LogFormat>().ok())
+        .unwrap_or_default();
     let file_sink = Arc::new(
         spdlog::sink::FileSink::builder()
-            .path(PathBuf::from("./attpc_merger.log"))
-            .formatter(Box::new(spdlog::formatter::PatternFormatter::new(
-                spdlog::formatter::pattern!(
-                    "[{date_short} {time_short}] - [thread: {tid}] - [{^{level}}] - {payload}{eol}"
-                ),
-            )))
+            .path(PathBuf::from(LOG_FILE_PATH))
+            .formatter(make_formatter(log_format))
             .truncate(true)
             .build()
             .unwrap(),
     );
-    let logger = Arc::new(
-        spdlog::Logger::builder()
-            .flush_level_filter(spdlog::LevelFilter::All)
-            .sink(file_sink)
-            .build()
-            .unwrap(),
-    );
+    // Keep the most recent lines in memory for the log panel of the UI
+    let panel_sink = Arc::new(LogPanelSink::new(LOG_PANEL_LINES));
+    let log_buffer = panel_sink.get_buffer();
+    let mut logger_builder = spdlog::Logger::builder();
+    logger_builder
+        .flush_level_filter(spdlog::LevelFilter::All)
+        .sink(file_sink)
+        .sink(panel_sink);
+    // Optionally write a machine readable log
+    if args.iter().any(|arg| arg == "--json-log") {
+        let json_sink = Arc::new(
+            spdlog::sink::FileSink::builder()
+                .path(PathBuf::from("./attpc_merger.jsonl"))
+                .formatter(make_json_formatter())
+                .truncate(true)
+                .build()
+                .unwrap(),
+        );
+        logger_builder.sink(json_sink);
+    }
+    let logger = Arc::new(logger_builder.build().unwrap());
     spdlog::set_default_logger(logger);
     spdlog::info!("Starting AT-TPC Merger UI");
 
@@ -73,7 +106,7 @@ fn main() {
     match eframe::run_native(
         "attpc_merger",
         native_options,
-        Box::new(|cc| Ok(Box::new(MergerApp::new(cc)))),
+        Box::new(move |cc| Ok(Box::new(MergerApp::new(cc, log_buffer)))),
     ) {
         Ok(()) => (),
         Err(e) => spdlog::error!("Eframe error: {}", e),
@@ -16,12 +16,102 @@
 //! attpc_merger_cli -p/--path <your_configuration.yaml>
 //! ```
 //!
+//! The level of messages written to the log file can be set with `--log-level` (one of error, warn, info, debug, trace; default is info).
+//! To also print warnings and errors to the terminal as they happen, add the `-v/--verbose` flag
+//!
+//! ```bash
+//! attpc_merger_cli -p/--path <your_configuration.yaml> --log-level debug --verbose
+//! ```
+//!
+//! The lines of the log written while processing a run are tagged with the run. To write the log as JSON lines instead
+//! (one JSON object per record, with timestamp, level, thread, target, run, and message) for automated monitoring, use
+//! `--log-format json`. To keep the human readable log and also write the JSON lines to attpc_merger_cli.jsonl, add the
+//! `--json-log` flag instead
+//!
+//! ```bash
+//! attpc_merger_cli -p/--path <your_configuration.yaml> --log-format json
+//! attpc_merger_cli -p/--path <your_configuration.yaml> --json-log
+//! ```
+//!
+//! When built with the `status-server` feature (`cargo install attpc_merger_cli --features status-server`), the progress
+//! of the merge can be published as JSON over HTTP with `--status-port`, for monitoring a headless merge from another machine.
+//! The same server publishes Prometheus text-format metrics at `/metrics` (runs completed, events merged, GRAW bytes read,
+//! dropped frames, and the run and progress of each worker). The endpoints are read-only and bound to localhost unless
+//! `--status-address` is given.
+//!
+//! ```bash
+//! attpc_merger_cli -p/--path <your_configuration.yaml> --status-port 8080 --status-address 0.0.0.0
+//! curl localhost:8080/status
+//! curl localhost:8080/metrics
+//! ```
+//!
+//! Pressing Ctrl-C stops the merge safely: the workers stop after the event they are on, close their output files so
+//! that they are readable, and start no more runs. The runs which were cut short only have part of their data and must
+//! be merged again. Pressing Ctrl-C a second time exits immediately, which can leave corrupt output files.
+//!
+//! To see where the merge time goes, add the `--profile` flag. At the end of every run, the time spent reading GRAW
+//! frames, building events, writing GET events, reading evt data, and writing evt data is printed to stderr (it is
+//! always written to the log at info level)
+//!
+//! ```bash
+//! attpc_merger_cli -p/--path <your_configuration.yaml> --profile
+//! ```
+//!
+//! To re-merge runs with different settings without overwriting the earlier output, tag the output with
+//! `--output-suffix`. The output files become run_####_<suffix>.h5 (overriding the output_suffix of the configuration)
+//!
+//! ```bash
+//! attpc_merger_cli -p/--path <your_configuration.yaml> --output-suffix new_pedestals
+//! ```
+//!
+//! When built with the `tui` feature (`cargo install attpc_merger_cli --features tui`), `--tui` replaces the progress bars
+//! with a dashboard showing the state of every run, the progress of every worker, and the end of the log, which works
+//! over SSH without X forwarding. Pressing q stops the merge; runs which were being merged are left incomplete. With
+//! `--tui`, `--verbose` and `--profile` print to the dashboard log instead of the terminal.
+//!
+//! ```bash
+//! attpc_merger_cli -p/--path <your_configuration.yaml> --tui
+//! ```
+//!
+//! To check what configuration the merger actually uses, add the `--config-check` flag. The configuration is loaded
+//! (filling in the defaults of missing fields and resolving the paths), printed as YAML along with the pad map which
+//! would be used (the bundled default or a custom map) and any issues, and the CLI exits without merging
+//!
+//! ```bash
+//! attpc_merger_cli -p/--path <your_configuration.yaml> --config-check
+//! ```
+//!
+//! To look at the runs of the configuration without merging them, add the `--peek` flag. For each run the title and
+//! start time of the FRIBDAQ BeginRun item, the CoBos with GRAW data, and the size and first event of each AsAd are
+//! printed. Only the start of the data is read, so this is quick even for large runs
+//!
+//! ```bash
+//! attpc_merger_cli -p/--path <your_configuration.yaml> --peek
+//! ```
+//!
 //! To generate a configuration template file use
 //!
 //! ```bash
 //! attpc_merger_cli -p/--path <your_configuration.yaml> new
 //! ```
 //!
+//! To get the FRIBDAQ scalers of a run without merging it, use the `scalers` subcommand with the evt directory of the run.
+//! The scalers are printed as CSV, one row per scaler read (with the offsets, timestamp, and whether the read is
+//! incremental, then one column per scaler). Only the scaler items are read, so this takes seconds even for large runs
+//!
+//! ```bash
+//! attpc_merger_cli scalers <evt_directory_of_the_run> > scalers.csv
+//! ```
+//!
+//! To check a pad map for problems (duplicate hardware addresses or pads, values out of the range of the hardware, and
+//! lines which can't be parsed) before an experiment, without merging, use
+//!
+//! ```bash
+//! attpc_merger_cli lint-map <your_pad_map.csv>
+//! ```
+//!
+//! Every problem found is printed, and the command exits with a non-zero code if there are any.
+//!
 //! ## Configuration
 //!
 //! The following fields must be specified in the configuration file:
@@ -30,22 +120,107 @@
 //! - evt_path: Specifies the full-path to a directory which contains the FRIBDAQ EVT structure (i.e. contains subdirectories of the run# format)
 //! - hdf_path: Specifies the full-path to a directory to which merged HDF5 (.h5) files will be written
 //! - pad_map_path: Specifies the full path to a CSV file which contains the mapping information for AT-TPC pads and electronics
+//! - pedestal_path: Optional full path to a CSV file (columns pad,pedestal_mean) of per-pad pedestals to subtract from the traces. Use null for no subtraction.
 //! - first_run_number: The starting run number (inclusive)
 //! - last_run_number: The ending run number (inclusive)
 //! - online: Boolean flag indicating if online data sources should be used (overrides some of the path imformation); generally should be false
 //! - experiment: Experiment name as a string. Only used when online is true. Should match the experiment name used by the AT-TPC DAQ.
+//! - online_path_template: Optional directory of the online data of each CoBo, with {cobo} and {experiment} placeholders (i.e. /mnt/daq/mm{cobo}). Optional, defaults to null (/Volumes/mm{cobo}/{experiment}).
+//! - online_address: Optional address (host:port) of the GET DAQ. If online is true and this is set, frames are read directly from the DAQ over TCP instead of from files. Use null to read files.
 //! - n_threads: The number of worker threads to divide the merging amongst.
+//! - frame_provenance: Debug flag. If true, the file, offset, CoBo, and AsAd of every frame is written with each event. Optional, defaults to false.
+//! - follow: If true, the merger assumes files are still being written by the DAQ and waits for new data instead of stopping at the end of a file. Optional, defaults to false.
+//! - follow_poll_interval_ms: In follow mode, the time between checks for new data in milliseconds. Optional, defaults to 1000.
+//! - follow_timeout_s: In follow mode, how long to wait without new data before a run is considered complete in seconds. Optional, defaults to 60.
+//! - write_quality_flags: If true, a dataset of per-trace quality flags (saturated, duplicate buckets, nonzero samples) and of per-AGET multiplicity and hit channels (from the frame headers) are written with each event. Optional, defaults to true.
+//! - require_evt: If true, a run fails if its evt data is missing or cannot be read. If false, the run is merged without FRIB data and a warning is logged. Optional, defaults to false.
+//! - scaler_offset_divisor: Ticks per second of the FRIBDAQ scaler offset clock, used to compute the duration_s and rate_hz attributes of the scalers. Optional, defaults to 1.
+//! - time_buckets: The number of time buckets in each trace, matching the CoBo configuration. Optional, defaults to 512.
+//! - duplicate_bucket_policy: What to do when a time bucket of a pad is written more than once in an event: Overwrite, Sum, or Error. Optional, defaults to Overwrite.
+//! - max_out_of_order: The number of recently built events for which late frames are dropped instead of failing the run. Optional, defaults to 0.
+//! - debug_frame_headers: Debug flag. If true, the header of every GRAW frame which contributed to an event is written with each event. Optional, defaults to false.
+//! - write_cobo_timestamps: Debug flag. If true, the timestamps of every CoBo which contributed to an event are written with each event. Optional, defaults to false.
+//! - min_timestamp, max_timestamp: Only merge the events with GET timestamps in this window (inclusive). FRIBDAQ items are filtered by their body header timestamps. Optional, default to null (no limit).
+//! - post_merge_action: What to do with the source files of a run after it has been merged and verified: null (nothing), Delete, or !MoveTo /path/to/archive. Optional, defaults to null.
+//! - confirm_post_merge_action: Must be true for the post_merge_action to be performed. Optional, defaults to false.
+//! - copy_bandwidth_mbps: Limit in megabits per second on copies of source files made by the MoveTo post_merge_action. Optional, defaults to null (no limit).
+//! - output_format: The format of the merged files. Currently only HDF5. Optional, defaults to HDF5.
+//! - compression_level: gzip level (0-9) of the get_traces datasets. Optional, defaults to null (no compression).
+//! - expected_cobos: List of the CoBos which must have data. Optional, defaults to null (CoBos without a directory are skipped).
+//! - stall_timeout_s: Seconds without a status from a worker after which its progress bar turns red. 0 never flags workers. Optional, defaults to 300.
+//! - clean_partial_outputs: If true, output files left by a merge which crashed are deleted; otherwise they are renamed to .partial. Optional, defaults to false.
+//! - max_events_per_file: Split each run into files of at most this many events, named run_####_part#.h5. Optional, defaults to null (one file per run).
+//! - max_output_gb: Split each run into files of about this many GB, named run_####_part#.h5. Optional, defaults to null (no size limit).
+//! - fix_timestamp_glitches: If true, GET timestamps which jump back by less than timestamp_glitch_threshold ticks are corrected. Optional, defaults to false.
+//! - timestamp_glitch_threshold: Largest backwards jump in ticks treated as a glitch rather than a rollover. Optional, defaults to 100.
+//! - merge_get: If false, only the evt data is merged (no GRAW data is read). Optional, defaults to true.
+//! - timestampother_fallback: If true, events without a frame from CoBo 10 (the FRIBDAQ-synchronized timestamp) get the main GET timestamp as their timestampother instead of 0. Missing timestamps are always reported in the log. Optional, defaults to false.
+//! - fadc_bits: Bit depth of the FRIBDAQ flash ADC (12 for the SIS3300, 14 for the SIS3301); samples are masked to this many bits. Optional, defaults to 12.
+//! - event_naming: How the event groups are numbered: Ordinal (in the order written) or GetEventId (the GET event_id, which the FRIBDAQ physics events follow). Optional, defaults to Ordinal.
+//! - max_evt_item_bytes: Largest plausible FRIBDAQ ring item; larger (or truncated) items are treated as corruption and skipped to the next valid item. Optional, defaults to 16 MiB.
+//! - event_layout: How the events are laid out: Groups (a group per event) or Stacked (all events appended to a few datasets, with an index of the rows of each event). Stacked can not be used with frame_provenance, debug_frame_headers, write_cobo_timestamps, write_readout_info, compute_peaks or write_frib_fragments. Optional, defaults to Groups.
+//! - strict_pad_map: If true, an event with a pad read from more than one channel (a pad map which maps several channels to the same pad) stops the run; otherwise such traces are counted and logged once per run. Optional, defaults to false.
+//! - require_explicit_map: If true, a missing pad_map_path is a configuration issue instead of a warning that the bundled default pad map is used. Optional, defaults to false.
+//! - run_database_path: Path to a runs database CSV. Runs flagged bad in its good column are skipped, its pad_map_path, pedestal_path and time_buckets columns override the configuration per run, and every other column is written as an attribute of the events group. Optional, defaults to null.
+//! - output_suffix: Tag appended to the output file names, which become run_####_<suffix>.h5, so that merges with different settings can share the hdf_path. Only letters, digits, '-', '_' and '.' are allowed. Optional, defaults to null.
+//! - write_readout_info: Diagnostic flag. If true, the readout metadata of the frame headers (readout mode, revision, read offset, status, and which AGETs had hits) of every AsAd which contributed to an event is written with each event. Can not be used with the Stacked event layout. Optional, defaults to false.
+//! - max_events_per_run: Stop merging each run after this many events, for quick test merges. The scalers and run information of the whole run are still merged. Optional, defaults to null (the whole run is merged).
+//! - frib_source_id: For event built FRIBDAQ data, the source id of the fragments holding the AT-TPC physics data. Physics items which were not event built are read as before. Optional, defaults to null (the first fragment of each item).
+//! - write_frib_fragments: If true, the fragments of the other sources of event built physics items are written raw with each physics event. Can not be used with the Stacked event layout. Optional, defaults to false.
+//! - max_frames_per_event: The most frames held in memory for an event. An event reaching it is written as is, and the following frames with the same event id start a new event, so a stuck event id can't use up the memory. Optional, defaults to 10000.
+//! - fail_oversized_events: If true, an event reaching max_frames_per_event is an error for the run instead. Optional, defaults to false.
+//! - compute_peaks: If true, the pad, maximum sample and time bucket of the maximum of every trace are written with each event, as the pad_peaks dataset. Can not be used with the Stacked event layout. Optional, defaults to false.
+//! - prune_empty_datasets: If true, the datasets of an event with no rows are left out, marked by a has_(dataset) attribute of 0 on their group. Optional, defaults to true.
+//! - upload_url: The object store to upload the merged files of each run to, as http(s)://host[:port]/bucket[/prefix]. Credentials are read from AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY. Requires the s3 feature. Optional, defaults to null (no upload).
+//! - upload_retries: The number of times a failed upload of a file is retried, with exponential backoff starting at 1 s. A failed upload does not fail the run. Optional, defaults to 3.
+//! - delete_after_upload: If true, the local merged files of a run are deleted once every one of them is uploaded and verified. Optional, defaults to false.
+//! - fadc_orientation: SampleMajor (one row per sample) or ChannelMajor (one row per channel, like get_traces) for the SIS3300 samples of the 1903 datasets. Optional, defaults to SampleMajor.
+//! - si_merge_by_timestamp: If true, the silicon CoBo (si_cobo) is matched to the TPC events by timestamp instead of by event id. Unmatched silicon events are written to the orphan_silicon group. Optional, defaults to false.
+//! - si_coincidence_window: The largest difference, in clock ticks, between the timestamps of a matched TPC and silicon event. Optional, defaults to 10.
+//! - si_cobo: The CoBo of the silicon detectors, matched by timestamp when si_merge_by_timestamp is set. Optional, defaults to 10.
+//! - si_timestamp_offset: The clock ticks added to the timestamps of the silicon CoBo to put them on the clock of the TPC CoBos. Optional, defaults to 0.
+//! - bad_data_policy: What to do with GET data out of the range of the hardware (AGET id, channel, or time bucket): Skip (leave the datum out and count it) or Fail (fail the run). Optional, defaults to Skip.
+//! - spill_large_events: If true, the frames of an event past spill_threshold_frames are written to a temporary file in the HDF5 directory instead of being held in memory. Optional, defaults to false.
+//! - spill_threshold_frames: The number of frames of an event held in memory before they are spilled. Must be less than max_frames_per_event. Optional, defaults to 1000.
+//! - correct_sca_rotation: If true, the time buckets of each AGET are rotated by the last cell of its SCA in the frame header, so that the oldest sample comes first. The offsets are written to the sca_offsets attribute of get_traces. Needs time_buckets of at least 512. Optional, defaults to false.
+//! - link_get_events: If true, the frib_physics group of each event gets a has_get_event attribute and, if there is a GET event of the same number, a get_traces soft link to its traces. Not supported by the Stacked event_layout. Optional, defaults to false.
+//! - write_checksums: If true, the SHA-256 of each merged file is written to a .sha256 file next to it (in the sha256sum format) once the run is merged. Optional, defaults to false.
+//! - output_size_ratio: Estimated size of the merged output relative to the size of the GRAW files, used to check that there is enough disk space before merging. Optional, defaults to 1.0.
+//! - io_retries: The number of times a file read which fails with a transient error (i.e. on a network mount) is retried, with exponential backoff. Optional, defaults to 3.
 
-use clap::{Arg, Command};
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use clap::{Arg, ArgAction, Command};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
 use std::fs::File;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::sync::{mpsc, Arc};
 
+use spdlog::sink::Sink;
+
 use libattpc_merger::config::Config;
-use libattpc_merger::process::{create_subsets, process_subset};
-use libattpc_merger::worker_status::WorkerStatus;
+use libattpc_merger::evt_stack::EvtStack;
+use libattpc_merger::frib_builder::read_scalers;
+use libattpc_merger::log_format::{
+    make_formatter, make_json_formatter, LogFormat, STAGE_TIMING_MESSAGE,
+};
+use libattpc_merger::merger::Merger;
+use libattpc_merger::pad_map::PadMap;
+use libattpc_merger::process::{check_disk_space, get_n_workers, process_queue, RunQueue};
+use libattpc_merger::worker_status::{WorkerPhase, WorkerStatus, WorkerStatuses};
+
+#[cfg(feature = "status-server")]
+mod status_server;
+#[cfg(feature = "status-server")]
+use status_server::{start_status_server, StatusBoard};
+#[cfg(feature = "status-server")]
+use std::sync::Mutex;
+#[cfg(feature = "tui")]
+mod tui;
+#[cfg(feature = "tui")]
+use tui::{Dashboard, LogTailSink, LOG_TAIL_LINES};
+
+/// How often the progress is updated
+const UPDATE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
 
 fn make_template_config(path: &Path) {
     let config = Config::default();
@@ -55,44 +230,411 @@ fn make_template_config(path: &Path) {
         .expect("Failed to write yaml data to file!");
 }
 
+/// Print a report of the problems of a pad map. Returns the exit code: 0 if the map is good, 1 otherwise
+fn lint_pad_map(path: &Path) -> i32 {
+    println!("Checking pad map {}...", path.display());
+    let code = match PadMap::lint(path) {
+        Ok(problems) if problems.is_empty() => {
+            println!("No problems found.");
+            0
+        }
+        Ok(problems) => {
+            println!("Found {} problem(s) in pad map:", problems.len());
+            for problem in problems.iter() {
+                println!("\t- {problem}");
+            }
+            1
+        }
+        Err(e) => {
+            println!("Could not read the pad map: {e}");
+            1
+        }
+    };
+    println!("-------------------------------------------------------------------------");
+    code
+}
+
+/// Print the scalers of the run in evt_dir to stdout as CSV, one row per scaler read. Returns the exit code
+fn print_scalers_csv(evt_dir: &Path) -> i32 {
+    let scalers = match read_scalers(evt_dir) {
+        Ok(scalers) => scalers,
+        Err(e) => {
+            eprintln!("Could not read the scalers of {}: {e}", evt_dir.display());
+            return 1;
+        }
+    };
+    let n_scalers = scalers
+        .iter()
+        .map(|item| item.data.len())
+        .max()
+        .unwrap_or(0);
+    let mut header = String::from("read,start_offset,stop_offset,timestamp,incremental");
+    for idx in 0..n_scalers {
+        header.push_str(&format!(",scaler_{idx}"));
+    }
+    println!("{header}");
+    for (read, item) in scalers.iter().enumerate() {
+        let mut row = format!(
+            "{read},{},{},{},{}",
+            item.start_offset,
+            item.stop_offset,
+            item.timestamp,
+            item.is_incremental() as u8
+        );
+        for value in item.data.iter() {
+            row.push_str(&format!(",{value}"));
+        }
+        println!("{row}");
+    }
+    0
+}
+
+/// A sink which prints the stage timing of each run to stderr, for --profile
+struct ProfileSink {
+    level_filter: std::sync::Mutex<spdlog::LevelFilter>,
+}
+
+impl ProfileSink {
+    fn new() -> Self {
+        Self {
+            level_filter: std::sync::Mutex::new(spdlog::LevelFilter::All),
+        }
+    }
+}
+
+impl Sink for ProfileSink {
+    fn log(&self, record: &spdlog::Record) -> spdlog::Result<()> {
+        if record.payload().starts_with(STAGE_TIMING_MESSAGE) {
+            eprintln!("{}", record.payload());
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> spdlog::Result<()> {
+        Ok(())
+    }
+
+    fn level_filter(&self) -> spdlog::LevelFilter {
+        match self.level_filter.lock() {
+            Ok(filter) => *filter,
+            Err(_) => spdlog::LevelFilter::All,
+        }
+    }
+
+    fn set_level_filter(&self, level_filter: spdlog::LevelFilter) {
+        if let Ok(mut filter) = self.level_filter.lock() {
+            *filter = level_filter;
+        }
+    }
+
+    fn set_formatter(&self, _formatter: Box<dyn spdlog::formatter::Formatter>) {}
+
+    fn set_error_handler(&self, _handler: Option<spdlog::ErrorHandler>) {}
+}
+
+/// Print the effective configuration as YAML, the pad map which would be used, and any issues with the configuration.
+/// Returns the exit code: 0 if there are no issues, 1 otherwise
+fn print_config_check(config: &Config) -> i32 {
+    match serde_yaml::to_string(config) {
+        Ok(yaml_str) => print!("{yaml_str}"),
+        Err(e) => {
+            println!("Could not write the configuration as YAML: {e}");
+            return 1;
+        }
+    }
+    println!("-------------------------------------------------------------------------");
+    match &config.pad_map_path {
+        Some(path) => println!("Pad map: custom ({})", path.display()),
+        None => println!("Pad map: default (bundled with attpc_merger) -- check that this is the map of the experiment!"),
+    }
+    let issues = config.validate();
+    let code = if issues.is_empty() {
+        println!("No issues found.");
+        0
+    } else {
+        println!("Found {} issue(s):", issues.len());
+        for issue in issues.iter() {
+            println!("\t- {issue}");
+        }
+        1
+    };
+    println!("-------------------------------------------------------------------------");
+    code
+}
+
+/// Print what can be learned about each run of the configuration without merging it: the BeginRun item of the evt data
+/// and the first event of each GRAW source. Returns the exit code: 0 if every run could be read, 1 otherwise
+fn print_run_peeks(config: &Config) -> i32 {
+    let mut code = 0;
+    for run in config.first_run_number..(config.last_run_number + 1) {
+        println!("Run {run}:");
+        if config.merge_get {
+            match Merger::new(config, run).and_then(|mut merger| merger.peek_sources()) {
+                Ok(peeks) => {
+                    let mut cobos: Vec<u8> = peeks.iter().filter_map(|peek| peek.cobo).collect();
+                    cobos.dedup();
+                    println!("\tCoBos: {cobos:?}");
+                    for peek in peeks.iter() {
+                        let source = match (peek.cobo, peek.asad) {
+                            (Some(cobo), Some(asad)) => format!("CoBo {cobo} AsAd {asad}"),
+                            _ => String::from("Online stream"),
+                        };
+                        match &peek.first_event {
+                            Some(meta) => println!(
+                                "\t{source}: {} bytes, first event {} at time {}",
+                                peek.size_bytes, meta.event_id, meta.event_time
+                            ),
+                            None => println!("\t{source}: {} bytes, no frames", peek.size_bytes),
+                        }
+                    }
+                }
+                Err(e) => {
+                    println!("\tCould not read the GET data: {e}");
+                    code = 1;
+                }
+            }
+        }
+        let begin = config
+            .get_evt_directory(run)
+            .map_err(|e| e.to_string())
+            .and_then(|dir| {
+                EvtStack::new(
+                    &dir,
+                    None,
+                    config.get_retry_options(),
+                    config.max_evt_item_bytes,
+                )
+                .and_then(|mut stack| stack.peek_begin_run())
+                .map_err(|e| e.to_string())
+            });
+        match begin {
+            Ok(Some(begin)) => println!(
+                "\tFRIBDAQ run {}, started at {}: {}",
+                begin.run,
+                begin.start,
+                begin.title.trim_end_matches('\0')
+            ),
+            Ok(None) => println!("\tThe evt data has no BeginRun item"),
+            Err(e) if config.require_evt || !config.merge_get => {
+                println!("\tCould not read the evt data: {e}");
+                code = 1;
+            }
+            Err(e) => println!("\tNo evt data: {e}"),
+        }
+    }
+    println!("-------------------------------------------------------------------------");
+    code
+}
+
+/// Convert the --log-level argument to a spdlog Level
+fn parse_log_level(level: &str) -> spdlog::Level {
+    match level {
+        "error" => spdlog::Level::Error,
+        "warn" => spdlog::Level::Warn,
+        "debug" => spdlog::Level::Debug,
+        "trace" => spdlog::Level::Trace,
+        _ => spdlog::Level::Info,
+    }
+}
+
 fn main() {
     // Create a cli
-    let matches = Command::new("attpc_merger_cli")
+    let command = Command::new("attpc_merger_cli")
         .arg_required_else_help(true)
         .subcommand(Command::new("new").about("Make a template configuration yaml file"))
+        .subcommand(
+            Command::new("lint-map")
+                .about("Check a pad map CSV for problems without merging")
+                .arg(Arg::new("map").required(true).help("Path to the pad map")),
+        )
+        .subcommand(
+            Command::new("scalers")
+                .about("Print the FRIBDAQ scalers of a run as CSV without merging")
+                .arg(
+                    Arg::new("evt_dir")
+                        .required(true)
+                        .help("Path to the evt directory of the run"),
+                ),
+        )
         .arg(
             Arg::new("path")
                 .short('p')
                 .long("path")
                 .help("Path to the file"),
         )
-        .get_matches();
+        .arg(
+            Arg::new("log-level")
+                .long("log-level")
+                .value_parser(["error", "warn", "info", "debug", "trace"])
+                .default_value("info")
+                .help("Minimum level of messages written to the log"),
+        )
+        .arg(
+            Arg::new("verbose")
+                .short('v')
+                .long("verbose")
+                .action(ArgAction::SetTrue)
+                .help("Mirror warnings and errors from the log to stderr"),
+        )
+        .arg(
+            Arg::new("config-check")
+                .long("config-check")
+                .action(ArgAction::SetTrue)
+                .help("Print the configuration as the merger sees it (with defaults filled in and paths resolved) and exit"),
+        )
+        .arg(
+            Arg::new("peek")
+                .long("peek")
+                .action(ArgAction::SetTrue)
+                .help("Print the title and start time, CoBos and first events of each run without merging and exit"),
+        )
+        .arg(
+            Arg::new("profile")
+                .long("profile")
+                .action(ArgAction::SetTrue)
+                .help("Print the time spent in each stage of the merge of every run to stderr"),
+        )
+        .arg(
+            Arg::new("output-suffix")
+                .long("output-suffix")
+                .help("Tag the output files as run_####_<suffix>.h5, overriding output_suffix of the configuration"),
+        )
+        .arg(
+            Arg::new("json-log")
+                .long("json-log")
+                .action(ArgAction::SetTrue)
+                .help("Also write the log as JSON lines to attpc_merger_cli.jsonl"),
+        )
+        .arg(
+            Arg::new("log-format")
+                .long("log-format")
+                .value_parser(["text", "json"])
+                .default_value("text")
+                .help("Format of attpc_merger_cli.log: human readable text or JSON lines"),
+        );
+    #[cfg(feature = "status-server")]
+    let command = command
+        .arg(
+            Arg::new("status-port")
+                .long("status-port")
+                .value_parser(clap::value_parser!(u16))
+                .help("Serve the merge progress as JSON at http://<status-address>:<port>/status and as Prometheus metrics at /metrics"),
+        )
+        .arg(
+            Arg::new("status-address")
+                .long("status-address")
+                .default_value("127.0.0.1")
+                .help("Address the status server binds to"),
+        );
+    #[cfg(feature = "tui")]
+    let command = command.arg(Arg::new("tui").long("tui").action(ArgAction::SetTrue).help(
+        "Show an interactive dashboard of the runs, workers, and log instead of progress bars",
+    ));
+    let matches = command.get_matches();
+    // The CSV goes to stdout, so nothing else is printed
+    if let Some(("scalers", scaler_matches)) = matches.subcommand() {
+        let evt_dir = PathBuf::from(
+            scaler_matches
+                .get_one::<String>("evt_dir")
+                .expect("The evt directory is required"),
+        );
+        std::process::exit(print_scalers_csv(&evt_dir));
+    }
+    #[cfg(feature = "tui")]
+    let use_tui = matches.get_flag("tui");
+    #[cfg(not(feature = "tui"))]
+    let use_tui = false;
 
     println!("---------------------------- attpc_merger_cli ---------------------------");
 
     // Setup logging to a file
+    let log_format = matches
+        .get_one::<String>("log-format")
+        .expect("log-format has a default")
+        .parse::<LogFormat>()
+        .expect("log-format is either text or json");
     let file_sink = Arc::new(
         spdlog::sink::FileSink::builder()
             .path(PathBuf::from("./attpc_merger_cli.log"))
-            .formatter(Box::new(spdlog::formatter::PatternFormatter::new(
-                spdlog::formatter::pattern!(
-                    "[{date_short} {time_short}] - [thread: {tid}] - [{^{level}}] - {payload}{eol}"
-                ),
-            )))
+            .formatter(make_formatter(log_format))
             .truncate(true)
             .build()
             .unwrap(),
     );
-    let logger = Arc::new(
-        spdlog::Logger::builder()
-            .flush_level_filter(spdlog::LevelFilter::All)
-            .sink(file_sink)
-            .build()
-            .unwrap(),
+    let log_level = parse_log_level(
+        matches
+            .get_one::<String>("log-level")
+            .expect("log-level has a default"),
     );
+    // The timing of each run is logged at info level, so profiling needs at least that
+    let log_level = match log_level {
+        spdlog::Level::Critical | spdlog::Level::Error | spdlog::Level::Warn
+            if matches.get_flag("profile") =>
+        {
+            spdlog::Level::Info
+        }
+        level => level,
+    };
+    let mut logger_builder = spdlog::Logger::builder();
+    logger_builder
+        .level_filter(spdlog::LevelFilter::MoreSevereEqual(log_level))
+        .flush_level_filter(spdlog::LevelFilter::All)
+        .sink(file_sink);
+    // Optionally mirror problems to the terminal. The dashboard shows them in its log instead
+    if matches.get_flag("verbose") && !use_tui {
+        let console_sink = Arc::new(
+            spdlog::sink::StdStreamSink::builder()
+                .std_stream(spdlog::sink::StdStream::Stderr)
+                .build()
+                .unwrap(),
+        );
+        console_sink.set_level_filter(spdlog::LevelFilter::MoreSevereEqual(spdlog::Level::Warn));
+        logger_builder.sink(console_sink);
+    }
+    // Optionally print the stage timings of each run
+    if matches.get_flag("profile") && !use_tui {
+        logger_builder.sink(Arc::new(ProfileSink::new()));
+    }
+    // Optionally write a machine readable log
+    if matches.get_flag("json-log") {
+        let json_sink = Arc::new(
+            spdlog::sink::FileSink::builder()
+                .path(PathBuf::from("./attpc_merger_cli.jsonl"))
+                .formatter(make_json_formatter())
+                .truncate(true)
+                .build()
+                .unwrap(),
+        );
+        logger_builder.sink(json_sink);
+    }
+    // The dashboard shows the end of the log
+    #[cfg(feature = "tui")]
+    let log_tail = if use_tui {
+        let tail_sink = Arc::new(LogTailSink::new(LOG_TAIL_LINES));
+        let log_tail = tail_sink.get_buffer();
+        logger_builder.sink(tail_sink);
+        Some(log_tail)
+    } else {
+        None
+    };
+    let logger = Arc::new(logger_builder.build().unwrap());
     spdlog::set_default_logger(logger);
 
-    let pb_manager = MultiProgress::new();
+    // The dashboard replaces the progress bars
+    let pb_manager = if use_tui {
+        MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+    } else {
+        MultiProgress::new()
+    };
+
+    if let Some(("lint-map", lint_matches)) = matches.subcommand() {
+        let map_path = PathBuf::from(
+            lint_matches
+                .get_one::<String>("map")
+                .expect("The map is required"),
+        );
+        std::process::exit(lint_pad_map(&map_path));
+    }
 
     // Parse the cli
     let config_path = PathBuf::from(matches.get_one::<String>("path").expect("We require args"));
@@ -111,91 +653,271 @@ fn main() {
 
     // Load our config
     spdlog::info!("Loading config from {}...", config_path.display());
-    let config = match Config::read_config_file(&config_path) {
+    let mut config = match Config::read_config_file(&config_path) {
         Ok(c) => c,
         Err(e) => {
             spdlog::error!("{e}");
             return;
         }
     };
-    if !config.is_n_threads_valid() {
-        spdlog::error!(
-            "n_threads must be > 0 in config file {}",
-            config_path.display()
-        );
+    if let Some(suffix) = matches.get_one::<String>("output-suffix") {
+        config.output_suffix = Some(suffix.clone());
+    }
+    if matches.get_flag("config-check") {
+        std::process::exit(print_config_check(&config));
+    }
+    if matches.get_flag("peek") {
+        std::process::exit(print_run_peeks(&config));
+    }
+    let issues = config.validate();
+    if !issues.is_empty() {
         println!(
-            "n_threads must be > 0 in config file {}",
+            "Found {} issue(s) in config file {}:",
+            issues.len(),
             config_path.display()
         );
+        for issue in issues.iter() {
+            spdlog::error!("Config issue: {issue}");
+            println!("\t- {issue}");
+        }
         println!("-------------------------------------------------------------------------");
-        return;
+        std::process::exit(1);
+    }
+    // All of the workers write to the same place, so check the space for the whole run range
+    let runs: Vec<i32> = (config.first_run_number..(config.last_run_number + 1)).collect();
+    if let Err(e) = check_disk_space(&config, &runs) {
+        spdlog::error!("{e}");
+        println!("{e}");
+        println!("-------------------------------------------------------------------------");
+        std::process::exit(1);
+    }
+    // The first Ctrl-C lets the workers close their files, the second exits right away
+    let stop_flag = config.stop_flag.clone();
+    if let Err(e) = ctrlc::set_handler(move || {
+        if stop_flag.is_stop_requested() {
+            spdlog::error!("Second interrupt recieved, exiting without closing the output!");
+            std::process::exit(130);
+        }
+        spdlog::warn!("Interrupt recieved, stopping after the current event. Press Ctrl-C again to exit immediately.");
+        eprintln!("Stopping after the current event, press Ctrl-C again to exit immediately...");
+        stop_flag.request_stop();
+    }) {
+        spdlog::error!("Could not install the Ctrl-C handler: {e}");
     }
     // Print out a bunch of info from the config as feedback to the user
     println!("Config successfully loaded.");
+    if let Some(warning) = config.get_default_pad_map_warning() {
+        spdlog::warn!("{warning}");
+        println!("WARNING: {warning}");
+    }
     println!("GRAW Path: {}", config.graw_path.to_string_lossy());
     println!("HDF5 Path: {}", config.hdf_path.to_string_lossy());
+    println!("Output Suffix: {:?}", config.output_suffix);
     println!("FRIB EVT Path: {}", config.evt_path.to_string_lossy());
     println!("PadMap Path: {:?}", config.pad_map_path);
+    println!("Pedestal Path: {:?}", config.pedestal_path);
     println!(
         "First Run: {} Last Run: {}",
         config.first_run_number, config.last_run_number
     );
     println!("Experiment Name: {}", config.experiment);
     println!("Is Online: {}", config.online);
+    println!("Online Address: {:?}", config.online_address);
     println!("Number of Worker Threads: {}", config.n_threads);
+    println!("Number of Time Buckets: {}", config.time_buckets);
+    println!("Follow Files: {}", config.follow);
+    println!("Merge GET Data: {}", config.merge_get);
+    println!("Require EVT Data: {}", config.require_evt);
+    println!(
+        "Post-Merge Action: {:?} (confirmed: {})",
+        config.post_merge_action, config.confirm_post_merge_action
+    );
     println!("-------------------------- Progress Per Worker --------------------------");
 
     // Setup the progress bar, statuses, and workers
+    let processing_style =
+        ProgressStyle::with_template("[{msg} - {ellapsed_precise}] {bar:40.cyan/blue} {percent}%")
+            .unwrap();
+    let waiting_style = ProgressStyle::with_template(
+        "[{msg} - {ellapsed_precise}] {bar:40.magenta/blue} {percent}%",
+    )
+    .unwrap();
+    let copying_style =
+        ProgressStyle::with_template("[{msg} - {ellapsed_precise}] {bar:40.green/blue} {percent}%")
+            .unwrap();
+    let uploading_style = ProgressStyle::with_template(
+        "[{msg} - {ellapsed_precise}] {bar:40.yellow/blue} {percent}%",
+    )
+    .unwrap();
+    let checksumming_style = ProgressStyle::with_template(
+        "[{msg} - {ellapsed_precise}] {bar:40.blue.bright/blue} {percent}%",
+    )
+    .unwrap();
+    let stalled_style =
+        ProgressStyle::with_template("[{msg} - {ellapsed_precise}] {bar:40.red/blue} {percent}%")
+            .unwrap();
     let mut progress_bars = vec![];
     let mut handles = vec![];
     let (tx, rx) = mpsc::channel::<WorkerStatus>();
 
-    // Split the runs into subsets for each worker
-    let subsets = create_subsets(&config);
-    spdlog::info!("Subsets: {subsets:?}");
-    let mut error_occured = false;
-    for (id, set) in subsets.into_iter().enumerate() {
-        // Don't make a worker for no work!
-        if set.is_empty() {
-            continue;
+    // The workers share the runs, each taking the next one when it is free
+    let queue = Arc::new(RunQueue::new(&config));
+    let n_workers = get_n_workers(&config);
+    spdlog::info!("Merging {} runs with {} workers", queue.len(), n_workers);
+    let mut statuses = WorkerStatuses::new(n_workers);
+
+    // Optionally publish the progress over HTTP
+    #[cfg(feature = "status-server")]
+    let status_board = Arc::new(Mutex::new(StatusBoard::new(&runs)));
+    #[cfg(feature = "status-server")]
+    if let Some(port) = matches.get_one::<u16>("status-port") {
+        let address = matches
+            .get_one::<String>("status-address")
+            .expect("status-address has a default");
+        if let Err(e) = start_status_server(address, *port, status_board.clone()) {
+            spdlog::error!("Could not start the status server: {e}");
+            println!("Could not start the status server: {e}");
         }
+    }
+    let mut error_occured = false;
+    for id in 0..n_workers {
         // Create all of this worker's info
         let bar = pb_manager.add(
             ProgressBar::new(100)
-                .with_style(
-                    ProgressStyle::with_template(
-                        "[{msg} - {ellapsed_precise}] {bar:40.cyan/blue} {percent}%",
-                    )
-                    .unwrap(),
-                )
+                .with_style(processing_style.clone())
                 .with_message(format!("Worker {id}: Run N/A")),
         );
         // Spawn it
         let conf = config.clone();
         let this_tx = tx.clone();
+        let this_queue = queue.clone();
         progress_bars.push(bar);
         handles.push(std::thread::spawn(move || {
-            process_subset(conf, this_tx, id, set)
+            process_queue(conf, this_tx, id, this_queue)
         }))
     }
 
-    loop {
+    let stall_timeout = config.get_stall_timeout();
+    #[cfg(feature = "tui")]
+    let mut dashboard = log_tail.map(|log_tail| Dashboard::new(&runs, log_tail, stall_timeout));
+    'update: loop {
         // Ugh since we don't have a UI here, I manually sleep for ~ 1 sec before trying to update
-        std::thread::sleep(std::time::Duration::from_secs(1));
-        match rx.try_recv() {
-            Ok(status) => {
-                let bar = &progress_bars[status.worker_id];
-                bar.set_position((status.progress * 100.0) as u64);
-                bar.set_message(format!(
-                    "Worker {}: Run {}",
-                    status.worker_id, status.run_number
-                ));
+        #[cfg(feature = "tui")]
+        if let Some(dashboard) = dashboard.as_mut() {
+            match dashboard.draw_and_wait(UPDATE_INTERVAL) {
+                Ok(false) => (),
+                Ok(true) => {
+                    // There is no way to stop the workers part way through a run, so stop everything
+                    if let Some(dashboard) = dashboard.take() {
+                        dashboard.close();
+                    }
+                    spdlog::warn!("Merge stopped from the dashboard. Runs which were being merged are incomplete and must be merged again.");
+                    println!("Merge stopped. Runs which were being merged are incomplete and must be merged again.");
+                    println!(
+                        "-------------------------------------------------------------------------"
+                    );
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    // Fall back to the log, the merge goes on
+                    if let Some(dashboard) = dashboard.take() {
+                        dashboard.close();
+                    }
+                    spdlog::error!("The dashboard failed: {e}");
+                }
             }
-            Err(mpsc::TryRecvError::Empty) => continue,
-            Err(mpsc::TryRecvError::Disconnected) => {
-                spdlog::error!("All of the communication channels were disconnected!");
-                error_occured = true;
-                break;
+        } else {
+            std::thread::sleep(UPDATE_INTERVAL);
+        }
+        #[cfg(not(feature = "tui"))]
+        std::thread::sleep(UPDATE_INTERVAL);
+        // Handle every status sent since the last update, so that the heartbeats are current
+        loop {
+            match rx.try_recv() {
+                Ok(status) => {
+                    #[cfg(feature = "status-server")]
+                    if let Ok(mut board) = status_board.lock() {
+                        board.update(&status);
+                    }
+                    #[cfg(feature = "tui")]
+                    if let Some(dashboard) = dashboard.as_mut() {
+                        dashboard.update(&status);
+                    }
+                    let Some(bar) = progress_bars.get(status.worker_id) else {
+                        spdlog::warn!("Status from unknown worker {}", status.worker_id);
+                        continue;
+                    };
+                    bar.set_position((status.progress * 100.0) as u64);
+                    match status.phase {
+                        WorkerPhase::Processing => {
+                            bar.set_style(processing_style.clone());
+                            let copy_note = match status.background_copy {
+                                Some(run) => format!(" (copying run {run})"),
+                                None => String::new(),
+                            };
+                            bar.set_message(format!(
+                                "Worker {}: Run {}{}",
+                                status.worker_id, status.run_number, copy_note
+                            ));
+                        }
+                        WorkerPhase::WaitingForData => {
+                            bar.set_style(waiting_style.clone());
+                            bar.set_message(format!(
+                                "Worker {}: Run {} (waiting for data)",
+                                status.worker_id, status.run_number
+                            ));
+                        }
+                        WorkerPhase::Copying => {
+                            bar.set_style(copying_style.clone());
+                            bar.set_message(format!(
+                                "Worker {}: Run {} (copying)",
+                                status.worker_id, status.run_number
+                            ));
+                        }
+                        WorkerPhase::Uploading => {
+                            bar.set_style(uploading_style.clone());
+                            bar.set_message(format!(
+                                "Worker {}: Run {} (uploading)",
+                                status.worker_id, status.run_number
+                            ));
+                        }
+                        WorkerPhase::Checksumming => {
+                            bar.set_style(checksumming_style.clone());
+                            bar.set_message(format!(
+                                "Worker {}: Run {} (checksumming)",
+                                status.worker_id, status.run_number
+                            ));
+                        }
+                    }
+                    statuses.update(status);
+                }
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    spdlog::error!("All of the communication channels were disconnected!");
+                    error_occured = true;
+                    break 'update;
+                }
+            }
+        }
+
+        // Flag the workers which are still running but haven't sent a status in a while
+        if let Some(timeout) = stall_timeout {
+            for status in statuses.iter() {
+                let (Some(handle), Some(bar)) = (
+                    handles.get(status.worker_id),
+                    progress_bars.get(status.worker_id),
+                ) else {
+                    continue;
+                };
+                if !handle.is_finished() && status.is_stalled(timeout) {
+                    bar.set_style(stalled_style.clone());
+                    bar.set_message(format!(
+                        "Worker {}: Run {} (stalled, no status for {} s)",
+                        status.worker_id,
+                        status.run_number,
+                        status.heartbeat.elapsed().as_secs()
+                    ));
+                }
             }
         }
 
@@ -212,6 +934,11 @@ fn main() {
         }
     }
 
+    #[cfg(feature = "tui")]
+    if let Some(dashboard) = dashboard {
+        dashboard.close();
+    }
+
     // Recover all of our workers
     for handle in handles {
         match handle.join() {
@@ -0,0 +1,298 @@
+//! A read-only HTTP endpoint publishing the progress of the merge.
+//!
+//! `GET /status` returns a JSON document with the latest status of each worker and the state of each run:
+//!
+//! ```json
+//! {"workers":[{"worker_id":0,"run":12,"progress":0.45,"phase":"processing","background_copy":11}],"runs":[{"run":12,"state":"processing"},{"run":13,"state":"pending"}]}
+//! ```
+//!
+//! The run state is one of pending, processing, waiting (for the DAQ in follow mode), or complete. The background_copy
+//! of a worker is the run whose source files it is copying to the post-merge destination while it merges, or null.
+//!
+//! `GET /metrics` returns the same information in the Prometheus text format, for scraping alongside the DAQ metrics:
+//!
+//! ```text
+//! attpc_merger_runs_completed_total 3
+//! attpc_merger_events_total 120000
+//! attpc_merger_bytes_read_total 52428800000
+//! attpc_merger_dropped_frames_total 2
+//! attpc_merger_worker_run{worker="0"} 12
+//! attpc_merger_worker_progress{worker="0"} 0.450
+//! ```
+//!
+//! The events, bytes read and dropped frames are summed over all of the runs seen so far.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+use libattpc_merger::worker_status::{RunCounts, WorkerPhase, WorkerStatus};
+
+/// The state of a run, as reported by the status endpoint
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RunState {
+    Pending,
+    Processing,
+    Waiting,
+    Complete,
+}
+
+impl RunState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Processing => "processing",
+            Self::Waiting => "waiting",
+            Self::Complete => "complete",
+        }
+    }
+}
+
+/// The latest status of every worker and run, updated from the WorkerStatus messages
+#[derive(Debug, Default)]
+pub struct StatusBoard {
+    workers: BTreeMap<usize, WorkerStatus>,
+    runs: BTreeMap<i32, RunState>,
+    counts: BTreeMap<i32, RunCounts>,
+}
+
+impl StatusBoard {
+    /// Make a board where all of the given runs are pending
+    pub fn new(runs: &[i32]) -> Self {
+        Self {
+            workers: BTreeMap::new(),
+            runs: runs.iter().map(|run| (*run, RunState::Pending)).collect(),
+            counts: BTreeMap::new(),
+        }
+    }
+
+    /// Record a status message from a worker
+    pub fn update(&mut self, status: &WorkerStatus) {
+        // A worker moving to a new run means it is done with the previous one
+        if let Some(previous) = self.workers.get(&status.worker_id) {
+            if previous.run_number != status.run_number {
+                self.runs.insert(previous.run_number, RunState::Complete);
+            }
+        }
+        let state = if status.progress >= 1.0 {
+            RunState::Complete
+        } else {
+            match status.phase {
+                WorkerPhase::Processing
+                | WorkerPhase::Copying
+                | WorkerPhase::Uploading
+                | WorkerPhase::Checksumming => RunState::Processing,
+                WorkerPhase::WaitingForData => RunState::Waiting,
+            }
+        };
+        self.runs.insert(status.run_number, state);
+        // The counts only grow over a run, and not every status carries them
+        let counts = self.counts.entry(status.run_number).or_default();
+        counts.events = counts.events.max(status.counts.events);
+        counts.bytes_read = counts.bytes_read.max(status.counts.bytes_read);
+        counts.dropped_frames = counts.dropped_frames.max(status.counts.dropped_frames);
+        self.workers.insert(status.worker_id, status.clone());
+    }
+
+    /// Write the board as a JSON document
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\"workers\":[");
+        for (idx, status) in self.workers.values().enumerate() {
+            if idx != 0 {
+                json.push(',');
+            }
+            let phase = match status.phase {
+                WorkerPhase::Processing => "processing",
+                WorkerPhase::WaitingForData => "waiting",
+                WorkerPhase::Copying => "copying",
+                WorkerPhase::Uploading => "uploading",
+                WorkerPhase::Checksumming => "checksumming",
+            };
+            let background_copy = match status.background_copy {
+                Some(run) => run.to_string(),
+                None => String::from("null"),
+            };
+            let _ = write!(
+                json,
+                "{{\"worker_id\":{},\"run\":{},\"progress\":{:.3},\"phase\":\"{}\",\"background_copy\":{}}}",
+                status.worker_id, status.run_number, status.progress, phase, background_copy
+            );
+        }
+        json.push_str("],\"runs\":[");
+        for (idx, (run, state)) in self.runs.iter().enumerate() {
+            if idx != 0 {
+                json.push(',');
+            }
+            let _ = write!(json, "{{\"run\":{},\"state\":\"{}\"}}", run, state.as_str());
+        }
+        json.push_str("]}");
+        json
+    }
+
+    /// Write the board as Prometheus text-format metrics
+    pub fn to_prometheus(&self) -> String {
+        let completed = self
+            .runs
+            .values()
+            .filter(|state| **state == RunState::Complete)
+            .count();
+        let (events, bytes_read, dropped_frames) =
+            self.counts
+                .values()
+                .fold((0, 0, 0), |(events, bytes, dropped), counts| {
+                    (
+                        events + counts.events,
+                        bytes + counts.bytes_read,
+                        dropped + counts.dropped_frames,
+                    )
+                });
+        let mut text = String::new();
+        for (name, kind, help, value) in [
+            (
+                "attpc_merger_runs_completed_total",
+                "counter",
+                "Runs merged",
+                completed as u64,
+            ),
+            (
+                "attpc_merger_events_total",
+                "counter",
+                "GET events merged",
+                events,
+            ),
+            (
+                "attpc_merger_bytes_read_total",
+                "counter",
+                "GRAW bytes read",
+                bytes_read,
+            ),
+            (
+                "attpc_merger_dropped_frames_total",
+                "counter",
+                "Late frames dropped",
+                dropped_frames,
+            ),
+        ] {
+            let _ = writeln!(
+                text,
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}"
+            );
+        }
+        let _ = writeln!(text, "# HELP attpc_merger_worker_run The run each worker is on\n# TYPE attpc_merger_worker_run gauge");
+        for status in self.workers.values() {
+            let _ = writeln!(
+                text,
+                "attpc_merger_worker_run{{worker=\"{}\"}} {}",
+                status.worker_id, status.run_number
+            );
+        }
+        let _ = writeln!(text, "# HELP attpc_merger_worker_progress The progress of each worker through its run\n# TYPE attpc_merger_worker_progress gauge");
+        for status in self.workers.values() {
+            let _ = writeln!(
+                text,
+                "attpc_merger_worker_progress{{worker=\"{}\"}} {:.3}",
+                status.worker_id, status.progress
+            );
+        }
+        text
+    }
+}
+
+/// Serve the board at http://address:port/status (JSON) and http://address:port/metrics (Prometheus) from a
+/// background thread.
+///
+/// Only GET requests of /status and /metrics are answered; anything else is a 404.
+pub fn start_status_server(
+    address: &str,
+    port: u16,
+    board: Arc<Mutex<StatusBoard>>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let server = tiny_http::Server::http((address, port))?;
+    spdlog::info!("Serving merge status at http://{address}:{port}/status");
+    spdlog::info!("Serving merge metrics at http://{address}:{port}/metrics");
+    std::thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let is_get = *request.method() == tiny_http::Method::Get;
+            let response = match request.url() {
+                "/status" if is_get => {
+                    let body = match board.lock() {
+                        Ok(board) => board.to_json(),
+                        Err(_) => String::from("{}"),
+                    };
+                    tiny_http::Response::from_string(body).with_header(
+                        tiny_http::Header::from_bytes("Content-Type", "application/json").unwrap(),
+                    )
+                }
+                "/metrics" if is_get => {
+                    let body = match board.lock() {
+                        Ok(board) => board.to_prometheus(),
+                        Err(_) => String::new(),
+                    };
+                    tiny_http::Response::from_string(body).with_header(
+                        tiny_http::Header::from_bytes("Content-Type", "text/plain; version=0.0.4")
+                            .unwrap(),
+                    )
+                }
+                _ => tiny_http::Response::from_string("Not found").with_status_code(404),
+            };
+            if let Err(e) = request.respond(response) {
+                spdlog::warn!("Failed to respond to a status request: {e}");
+            }
+        }
+    });
+    Ok(())
+}
+
+//Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_board() {
+        let mut board = StatusBoard::new(&[1, 2, 3]);
+        board.update(&WorkerStatus::new(0.5, 1, 0));
+        board.update(&WorkerStatus::waiting(0.25, 3, 1));
+        assert_eq!(
+            board.to_json(),
+            "{\"workers\":[{\"worker_id\":0,\"run\":1,\"progress\":0.500,\"phase\":\"processing\",\"background_copy\":null},{\"worker_id\":1,\"run\":3,\"progress\":0.250,\"phase\":\"waiting\",\"background_copy\":null}],\"runs\":[{\"run\":1,\"state\":\"processing\"},{\"run\":2,\"state\":\"pending\"},{\"run\":3,\"state\":\"waiting\"}]}"
+        );
+
+        board.update(&WorkerStatus::new(0.0, 2, 0));
+        assert!(board
+            .to_json()
+            .contains("{\"run\":1,\"state\":\"complete\"}"));
+        board.update(&WorkerStatus::new(1.0, 2, 0));
+        assert!(board
+            .to_json()
+            .contains("{\"run\":2,\"state\":\"complete\"}"));
+    }
+
+    #[test]
+    fn test_prometheus() {
+        let mut board = StatusBoard::new(&[1, 2]);
+        let counts = RunCounts {
+            events: 10,
+            bytes_read: 2048,
+            dropped_frames: 1,
+        };
+        board.update(&WorkerStatus::new(0.5, 1, 0).with_counts(counts));
+        // Statuses without counts don't reset the run's totals
+        board.update(&WorkerStatus::new(1.0, 1, 0));
+        board.update(&WorkerStatus::new(0.25, 2, 0).with_counts(RunCounts {
+            events: 5,
+            ..Default::default()
+        }));
+        let metrics = board.to_prometheus();
+        for line in [
+            "attpc_merger_runs_completed_total 1",
+            "attpc_merger_events_total 15",
+            "attpc_merger_bytes_read_total 2048",
+            "attpc_merger_dropped_frames_total 1",
+            "attpc_merger_worker_run{worker=\"0\"} 2",
+            "attpc_merger_worker_progress{worker=\"0\"} 0.250",
+            "# TYPE attpc_merger_events_total counter",
+        ] {
+            assert!(metrics.lines().any(|l| l == line), "missing {line}");
+        }
+    }
+}
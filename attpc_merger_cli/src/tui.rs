@@ -0,0 +1,298 @@
+//! A terminal dashboard for merging over SSH, shown with `--tui`.
+//!
+//! The dashboard shows the state of every run, the progress of every worker, and the end of the log. It is fed the
+//! same WorkerStatus messages as the progress bars, and redraws at every update or when the terminal is resized.
+//! Pressing q (or Esc) asks to stop the merge.
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, LineGauge, Paragraph, Row, Table};
+use ratatui::{DefaultTerminal, Frame};
+use spdlog::formatter::Formatter;
+use spdlog::{ErrorHandler, Level, LevelFilter, Record};
+
+use libattpc_merger::worker_status::{WorkerPhase, WorkerStatus};
+
+/// The number of log lines kept for the dashboard
+pub const LOG_TAIL_LINES: usize = 200;
+
+/// The buffer of the most recent log lines, shared between the sink and the dashboard
+pub type LogTail = Arc<Mutex<VecDeque<(Level, String)>>>;
+
+/// A spdlog sink which keeps the most recent log records for the dashboard.
+/// Records are stored unformatted; the formatter is ignored.
+pub struct LogTailSink {
+    buffer: LogTail,
+    capacity: usize,
+    level_filter: Mutex<LevelFilter>,
+}
+
+impl LogTailSink {
+    /// Create a sink keeping at most capacity lines
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buffer: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+            level_filter: Mutex::new(LevelFilter::All),
+        }
+    }
+
+    /// Get a handle to the shared buffer of lines
+    pub fn get_buffer(&self) -> LogTail {
+        self.buffer.clone()
+    }
+}
+
+impl spdlog::sink::Sink for LogTailSink {
+    fn log(&self, record: &Record) -> spdlog::Result<()> {
+        if !self.should_log(record.level()) {
+            return Ok(());
+        }
+        if let Ok(mut lines) = self.buffer.lock() {
+            while lines.len() >= self.capacity {
+                lines.pop_front();
+            }
+            lines.push_back((record.level(), record.payload().to_string()));
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> spdlog::Result<()> {
+        Ok(())
+    }
+
+    fn level_filter(&self) -> LevelFilter {
+        match self.level_filter.lock() {
+            Ok(filter) => *filter,
+            Err(_) => LevelFilter::All,
+        }
+    }
+
+    fn set_level_filter(&self, level_filter: LevelFilter) {
+        if let Ok(mut filter) = self.level_filter.lock() {
+            *filter = level_filter;
+        }
+    }
+
+    fn set_formatter(&self, _formatter: Box<dyn Formatter>) {}
+
+    fn set_error_handler(&self, _handler: Option<ErrorHandler>) {}
+}
+
+/// The state of a run, as shown in the run table
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RunState {
+    Pending,
+    Processing,
+    Waiting,
+    Complete,
+}
+
+impl RunState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Processing => "processing",
+            Self::Waiting => "waiting for data",
+            Self::Complete => "complete",
+        }
+    }
+
+    fn color(&self) -> Color {
+        match self {
+            Self::Pending => Color::Gray,
+            Self::Processing => Color::Cyan,
+            Self::Waiting => Color::Magenta,
+            Self::Complete => Color::Green,
+        }
+    }
+}
+
+/// The color used to display a log line of the given level
+fn level_color(level: Level) -> Color {
+    match level {
+        Level::Critical | Level::Error => Color::Red,
+        Level::Warn => Color::Yellow,
+        Level::Info => Color::White,
+        Level::Debug | Level::Trace => Color::Gray,
+    }
+}
+
+/// The terminal dashboard. The terminal is taken over when the dashboard is made, and given back by close
+pub struct Dashboard {
+    terminal: DefaultTerminal,
+    runs: BTreeMap<i32, RunState>,
+    workers: BTreeMap<usize, WorkerStatus>,
+    log: LogTail,
+    stall_timeout: Option<Duration>,
+}
+
+impl Dashboard {
+    /// Take over the terminal and show the given runs as pending
+    pub fn new(runs: &[i32], log: LogTail, stall_timeout: Option<Duration>) -> Self {
+        Self {
+            terminal: ratatui::init(),
+            runs: runs.iter().map(|run| (*run, RunState::Pending)).collect(),
+            workers: BTreeMap::new(),
+            log,
+            stall_timeout,
+        }
+    }
+
+    /// Record a status message from a worker
+    pub fn update(&mut self, status: &WorkerStatus) {
+        // A worker moving to a new run means it is done with the previous one
+        if let Some(previous) = self.workers.get(&status.worker_id) {
+            if previous.run_number != status.run_number {
+                self.runs.insert(previous.run_number, RunState::Complete);
+            }
+        }
+        let state = if status.progress >= 1.0 {
+            RunState::Complete
+        } else {
+            match status.phase {
+                WorkerPhase::Processing
+                | WorkerPhase::Copying
+                | WorkerPhase::Uploading
+                | WorkerPhase::Checksumming => RunState::Processing,
+                WorkerPhase::WaitingForData => RunState::Waiting,
+            }
+        };
+        self.runs.insert(status.run_number, state);
+        self.workers.insert(status.worker_id, status.clone());
+    }
+
+    /// Redraw the dashboard and handle key presses for the given time.
+    ///
+    /// Returns true if the user asked to stop the merge
+    pub fn draw_and_wait(&mut self, wait: Duration) -> std::io::Result<bool> {
+        let start = Instant::now();
+        loop {
+            self.terminal.draw(|frame| {
+                render(
+                    frame,
+                    &self.runs,
+                    &self.workers,
+                    &self.log,
+                    self.stall_timeout,
+                )
+            })?;
+            let remaining = wait.saturating_sub(start.elapsed());
+            if remaining.is_zero() || !event::poll(remaining)? {
+                return Ok(false);
+            }
+            // Any other event (i.e. a resize) just redraws
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press
+                    && matches!(key.code, KeyCode::Char('q') | KeyCode::Esc)
+                {
+                    return Ok(true);
+                }
+            }
+        }
+    }
+
+    /// Give the terminal back
+    pub fn close(self) {
+        ratatui::restore();
+    }
+}
+
+/// Draw the run table, the worker progress and the log tail
+fn render(
+    frame: &mut Frame,
+    runs: &BTreeMap<i32, RunState>,
+    workers: &BTreeMap<usize, WorkerStatus>,
+    log: &LogTail,
+    stall_timeout: Option<Duration>,
+) {
+    let [runs_area, workers_area, log_area, help_area] = Layout::vertical([
+        Constraint::Min(5),
+        Constraint::Length(workers.len() as u16 + 2),
+        Constraint::Percentage(35),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    let completed = runs
+        .values()
+        .filter(|state| **state == RunState::Complete)
+        .count();
+    let rows = runs.iter().map(|(run, state)| {
+        Row::new(vec![run.to_string(), state.as_str().to_string()])
+            .style(Style::default().fg(state.color()))
+    });
+    let table = Table::new(rows, [Constraint::Length(8), Constraint::Min(10)])
+        .header(Row::new(vec!["Run", "State"]).style(Style::default().fg(Color::Yellow)))
+        .block(Block::bordered().title(format!("Runs ({completed}/{} complete)", runs.len())));
+    frame.render_widget(table, runs_area);
+
+    let workers_block = Block::bordered().title("Workers");
+    let workers_inner = workers_block.inner(workers_area);
+    frame.render_widget(workers_block, workers_area);
+    let worker_areas =
+        Layout::vertical(vec![Constraint::Length(1); workers.len()]).split(workers_inner);
+    for (status, area) in workers.values().zip(worker_areas.iter()) {
+        let is_stalled = stall_timeout.is_some_and(|timeout| status.is_stalled(timeout));
+        let (note, color) = if is_stalled {
+            (
+                format!(
+                    " (stalled, no status for {} s)",
+                    status.heartbeat.elapsed().as_secs()
+                ),
+                Color::Red,
+            )
+        } else {
+            match status.phase {
+                WorkerPhase::Processing => match status.background_copy {
+                    Some(run) => (format!(" (copying run {run})"), Color::Cyan),
+                    None => (String::new(), Color::Cyan),
+                },
+                WorkerPhase::WaitingForData => {
+                    (String::from(" (waiting for data)"), Color::Magenta)
+                }
+                WorkerPhase::Copying => (String::from(" (copying)"), Color::Green),
+                WorkerPhase::Uploading => (String::from(" (uploading)"), Color::Yellow),
+                WorkerPhase::Checksumming => (String::from(" (checksumming)"), Color::Blue),
+            }
+        };
+        let gauge = LineGauge::default()
+            .label(format!(
+                "Worker {}: Run {}{}",
+                status.worker_id, status.run_number, note
+            ))
+            .filled_style(Style::default().fg(color))
+            .ratio(status.progress.clamp(0.0, 1.0) as f64);
+        frame.render_widget(gauge, *area);
+    }
+
+    // Only the lines which fit are shown, the most recent at the bottom
+    let visible = log_area.height.saturating_sub(2) as usize;
+    let lines: Vec<Line> = match log.lock() {
+        Ok(lines) => lines
+            .iter()
+            .skip(lines.len().saturating_sub(visible))
+            .map(|(level, message)| {
+                Line::styled(
+                    format!("[{}] {}", level.as_str(), message),
+                    Style::default().fg(level_color(*level)),
+                )
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::bordered().title("Log")),
+        log_area,
+    );
+
+    frame.render_widget(
+        Line::styled("q: stop the merge", Style::default().fg(Color::Gray)),
+        help_area,
+    );
+}
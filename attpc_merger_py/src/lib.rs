@@ -0,0 +1,159 @@
+//! # attpc_merger_py
+//!
+//! Python bindings for streaming merged AT-TPC GET events, without writing them to HDF5 first.
+//!
+//! Build and install the module with [maturin](https://www.maturin.rs) from this directory:
+//!
+//! ```bash
+//! pip install maturin
+//! maturin develop --release
+//! ```
+//!
+//! Then iterate over the events of a run using the same YAML configuration as the merger:
+//!
+//! ```python
+//! import attpc_merger
+//!
+//! for event in attpc_merger.iter_events("config.yaml", 12):
+//!     print(event["event_id"], event["timestamp"], event["traces"].shape)
+//! ```
+//!
+//! Each event is a dict with the keys:
+//!
+//! - event_id: the GET event id
+//! - timestamp: the GET event timestamp
+//! - timestampother: the secondary GET timestamp
+//! - traces: the NumPy (i16) data matrix of the event, in the same format as the `get_traces` datasets of the merged
+//!   HDF5 files (cobo, asad, aget, channel, pad, then the samples). Pedestals are subtracted if the configuration has a
+//!   pedestal_path.
+//! - flags: the NumPy (u16) quality flags of the event, with rows aligned to traces
+//!
+//! The matrices are moved into NumPy without copying. Only the GET data is read; the FRIBDAQ data is not merged. Events
+//! outside of the time window of the configuration are skipped.
+use std::path::PathBuf;
+use std::time::Duration;
+
+use libattpc_merger::config::Config;
+use libattpc_merger::error::MergerError;
+use libattpc_merger::event::Event;
+use libattpc_merger::event_builder::EventBuilder;
+use libattpc_merger::merger::Merger;
+use libattpc_merger::pad_map::PadMap;
+use libattpc_merger::pedestals::Pedestals;
+use numpy::IntoPyArray;
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+/// Convert an error of the merger into a Python RuntimeError
+fn to_py_err<E: std::fmt::Display>(error: E) -> PyErr {
+    PyRuntimeError::new_err(error.to_string())
+}
+
+/// An iterator over the merged GET events of a run
+///
+/// Made by iter_events. Frames are read and built into events as the iterator is advanced.
+#[pyclass(unsendable)]
+struct EventIter {
+    config: Config,
+    merger: Merger,
+    evb: EventBuilder,
+    pedestals: Option<Pedestals>,
+    is_done: bool,
+}
+
+impl EventIter {
+    fn new(config: Config, run_number: i32) -> PyResult<Self> {
+        let pad_map = PadMap::new(config.pad_map_path.as_deref()).map_err(to_py_err)?;
+        let pedestals = match &config.pedestal_path {
+            Some(path) => Some(Pedestals::new(path).map_err(to_py_err)?),
+            None => None,
+        };
+        let merger = Merger::new(&config, run_number).map_err(to_py_err)?;
+        let evb = EventBuilder::new(
+            pad_map,
+            config.frame_provenance,
+            config.debug_frame_headers,
+            config.time_buckets as usize,
+            config.duplicate_bucket_policy,
+            config.max_out_of_order as usize,
+            config.strict_pad_map,
+        );
+        Ok(Self {
+            config,
+            merger,
+            evb,
+            pedestals,
+            is_done: false,
+        })
+    }
+
+    /// Read frames until the next event is built
+    fn next_event(&mut self, py: Python<'_>) -> PyResult<Option<Event>> {
+        while !self.is_done {
+            let frame = match self.merger.get_next_frame() {
+                Ok(Some(frame)) => frame,
+                //No more data, the final event of the EventBuilder needs a manual flush
+                Ok(None) => {
+                    self.is_done = true;
+                    return self.evb.flush_final_event().map_err(to_py_err);
+                }
+                //In follow mode, the DAQ hasn't written the next data yet
+                Err(MergerError::WaitingForData) => {
+                    let interval = Duration::from_millis(self.config.follow_poll_interval_ms);
+                    py.allow_threads(|| std::thread::sleep(interval));
+                    py.check_signals()?;
+                    continue;
+                }
+                Err(e) => return Err(to_py_err(e)),
+            };
+            if let Some(event) = self.evb.append_frame(frame).map_err(to_py_err)? {
+                return Ok(Some(event));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[pymethods]
+impl EventIter {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__<'py>(&mut self, py: Python<'py>) -> PyResult<Option<Bound<'py, PyDict>>> {
+        loop {
+            let event = match self.next_event(py)? {
+                Some(event) => event,
+                None => return Ok(None),
+            };
+            if !self.config.is_in_time_window(&event.timestamp) {
+                continue;
+            }
+
+            let dict = PyDict::new_bound(py);
+            dict.set_item("event_id", event.event_id)?;
+            dict.set_item("timestamp", event.timestamp)?;
+            dict.set_item("timestampother", event.timestampother)?;
+            let (traces, flags) = event.convert_to_data_matrix(self.pedestals.as_mut());
+            dict.set_item("traces", traces.into_pyarray_bound(py))?;
+            dict.set_item("flags", flags.into_pyarray_bound(py))?;
+            return Ok(Some(dict));
+        }
+    }
+}
+
+/// Iterate over the merged GET events of a run, using the merger configuration at config_path
+#[pyfunction]
+fn iter_events(config_path: PathBuf, run_number: i32) -> PyResult<EventIter> {
+    let config = Config::read_config_file(&config_path).map_err(to_py_err)?;
+    EventIter::new(config, run_number)
+}
+
+#[pymodule]
+#[pyo3(name = "attpc_merger")]
+fn attpc_merger_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(iter_events, m)?)?;
+    m.add_class::<EventIter>()?;
+    Ok(())
+}
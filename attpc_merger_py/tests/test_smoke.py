@@ -0,0 +1,77 @@
+"""Smoke test for the attpc_merger Python module
+
+Writes a small synthetic GET run (the same data as the process tests of libattpc_merger) and iterates over it.
+Run with `maturin develop && pytest` from the attpc_merger_py directory.
+"""
+import struct
+
+import attpc_merger
+
+SIZE_UNIT = 256
+# CoBo 0, AsAd 0, AGET 0, channels 0 and 1 are pads 2817 and 3485 in the default map
+PADS = {0: 2817, 1: 3485}
+
+
+def make_graw_frame(event_id, event_time, cobo_id, asad_id, data):
+    """Bytes of a partial readout GRAW frame, see make_graw_frame in libattpc_merger test_data"""
+    header_bytes = SIZE_UNIT
+    frame_size = -(-(header_bytes + 4 * len(data)) // SIZE_UNIT)
+    frame = bytearray(frame_size * SIZE_UNIT)
+    frame[0] = 8  # meta type
+    frame[1:4] = frame_size.to_bytes(3, "big")
+    frame[5:7] = struct.pack(">H", 1)  # partial readout
+    frame[8:10] = struct.pack(">H", 1)  # header size
+    frame[10:12] = struct.pack(">H", 4)  # item size
+    frame[12:16] = struct.pack(">I", len(data))
+    frame[16:22] = event_time.to_bytes(6, "big")
+    frame[22:26] = struct.pack(">I", event_id)
+    frame[26] = cobo_id
+    frame[27] = asad_id
+    for idx, (aget, channel, bucket, sample) in enumerate(data):
+        raw = (aget << 30) | (channel << 23) | (bucket << 14) | (sample & 0xFFF)
+        start = header_bytes + 4 * idx
+        frame[start : start + 4] = struct.pack(">I", raw)
+    return bytes(frame)
+
+
+def write_fixture_run(directory):
+    graw_dir = directory / "graw" / "run_0001" / "mm0"
+    graw_dir.mkdir(parents=True)
+    frames = b""
+    for event_id in range(2):
+        data = [(0, channel, 10 + event_id, 100 * (event_id + 1) + channel) for channel in PADS]
+        frames += make_graw_frame(event_id, 1000 * (event_id + 1), 0, 0, data)
+    (graw_dir / "CoBo0_AsAd0_0000.graw").write_bytes(frames)
+
+    config_path = directory / "config.yaml"
+    config_path.write_text(
+        f"""graw_path: {directory / "graw"}
+evt_path: {directory / "evt"}
+hdf_path: {directory / "hdf"}
+pad_map_path: null
+pedestal_path: null
+first_run_number: 1
+last_run_number: 1
+online: false
+experiment: ""
+n_threads: 1
+expected_cobos: [0]
+"""
+    )
+    return config_path
+
+
+def test_iter_events(tmp_path):
+    config_path = write_fixture_run(tmp_path)
+    events = list(attpc_merger.iter_events(str(config_path), 1))
+    assert [event["event_id"] for event in events] == [0, 1]
+    assert [event["timestamp"] for event in events] == [1000, 2000]
+    for event_id, event in enumerate(events):
+        traces = event["traces"]
+        assert traces.dtype.name == "int16"
+        assert traces.shape[0] == 2
+        assert event["flags"].shape[0] == 2
+        for row in traces:
+            channel = int(row[3])
+            assert int(row[4]) == PADS[channel]
+            assert int(row[5 + 10 + event_id]) == 100 * (event_id + 1) + channel
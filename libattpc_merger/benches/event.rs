@@ -0,0 +1,66 @@
+//! Benchmark of converting built Events to the data matrices written to HDF5.
+//!
+//! Run with `cargo bench --features testing --bench event` from the libattpc_merger directory.
+//! The events are parameterized by the number of pads hit, each with a full trace. Throughput is reported in pads converted.
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+
+use libattpc_merger::config::DuplicateBucketPolicy;
+use libattpc_merger::constants::{
+    FPN_CHANNELS, NUMBER_OF_AGETS, NUMBER_OF_CHANNELS, NUMBER_OF_TIME_BUCKETS,
+};
+use libattpc_merger::event::Event;
+use libattpc_merger::graw_frame::{GrawData, GrawFrame};
+use libattpc_merger::pad_map::PadMap;
+use libattpc_merger::test_data::make_graw_frame;
+
+const PADS_PER_EVENT: [usize; 2] = [16, 256];
+
+/// A frame of CoBo 0 AsAd 0 with full traces for the given number of pads
+fn make_frame(pads_per_event: usize) -> GrawFrame {
+    let data: Vec<GrawData> = (0..NUMBER_OF_AGETS)
+        .flat_map(|aget| (0..NUMBER_OF_CHANNELS).map(move |channel| (aget, channel)))
+        .filter(|(_, channel)| !FPN_CHANNELS.contains(channel))
+        .take(pads_per_event)
+        .flat_map(|(aget_id, channel)| {
+            (0..NUMBER_OF_TIME_BUCKETS as u16).map(move |time_bucket_id| GrawData {
+                aget_id,
+                channel,
+                time_bucket_id,
+                sample: (time_bucket_id % 100) as i16 + 1,
+            })
+        })
+        .collect();
+    GrawFrame::try_from(make_graw_frame(0, 1000, 0, 0, &data)).unwrap()
+}
+
+fn bench_event(c: &mut Criterion) {
+    let pad_map = PadMap::new(None).unwrap();
+    let mut group = c.benchmark_group("event");
+    for pads_per_event in PADS_PER_EVENT {
+        let frames = vec![make_frame(pads_per_event)];
+        group.throughput(Throughput::Elements(pads_per_event as u64));
+        group.bench_with_input(
+            BenchmarkId::new("convert_to_data_matrix", pads_per_event),
+            &frames,
+            |b, frames| {
+                b.iter_batched(
+                    || {
+                        Event::new(
+                            &pad_map,
+                            frames,
+                            NUMBER_OF_TIME_BUCKETS as usize,
+                            DuplicateBucketPolicy::Overwrite,
+                        )
+                        .unwrap()
+                    },
+                    |event| event.convert_to_data_matrix(None),
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_event);
+criterion_main!(benches);
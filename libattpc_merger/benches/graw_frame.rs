@@ -0,0 +1,64 @@
+//! Benchmark of decoding GrawFrames from their raw bytes.
+//!
+//! Run with `cargo bench --features testing --bench graw_frame` from the libattpc_merger directory.
+//! Frames of both readout modes are decoded, with every channel of an AsAd hit. Throughput is reported in bytes of
+//! frame decoded.
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+
+use libattpc_merger::constants::{NUMBER_OF_AGETS, NUMBER_OF_CHANNELS};
+use libattpc_merger::graw_frame::{GrawData, GrawFrame};
+use libattpc_merger::test_data::{make_full_graw_frame, make_graw_frame};
+
+const TIME_BUCKETS: [u16; 2] = [64, 512];
+
+/// A partial readout frame with every channel hit for the given number of time buckets
+fn make_partial_frame(time_buckets: u16) -> Vec<u8> {
+    let data: Vec<GrawData> = (0..NUMBER_OF_AGETS)
+        .flat_map(|aget_id| {
+            (0..NUMBER_OF_CHANNELS).flat_map(move |channel| {
+                (0..time_buckets).map(move |time_bucket_id| GrawData {
+                    aget_id,
+                    channel,
+                    time_bucket_id,
+                    sample: (time_bucket_id % 100) as i16 + 1,
+                })
+            })
+        })
+        .collect();
+    make_graw_frame(0, 1000, 0, 0, &data)
+}
+
+/// A full readout frame for the given number of time buckets
+fn make_full_frame(time_buckets: u16) -> Vec<u8> {
+    let samples: Vec<(u8, i16)> = (0..(time_buckets as usize * NUMBER_OF_CHANNELS as usize))
+        .flat_map(|idx| (0..NUMBER_OF_AGETS).map(move |aget_id| (aget_id, (idx % 100) as i16 + 1)))
+        .collect();
+    make_full_graw_frame(0, 1000, 0, 0, &samples)
+}
+
+fn bench_graw_frame(c: &mut Criterion) {
+    let mut group = c.benchmark_group("graw_frame");
+    for time_buckets in TIME_BUCKETS {
+        for (mode, buffer) in [
+            ("partial", make_partial_frame(time_buckets)),
+            ("full", make_full_frame(time_buckets)),
+        ] {
+            group.throughput(Throughput::Bytes(buffer.len() as u64));
+            group.bench_with_input(
+                BenchmarkId::new(mode, format!("{time_buckets}_buckets")),
+                &buffer,
+                |b, buffer| {
+                    b.iter_batched(
+                        || buffer.clone(),
+                        |buffer| GrawFrame::try_from(buffer).unwrap(),
+                        BatchSize::LargeInput,
+                    )
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_graw_frame);
+criterion_main!(benches);
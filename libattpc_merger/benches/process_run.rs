@@ -0,0 +1,115 @@
+//! Benchmark of the full merging pipeline (process_run) over synthetic GRAW and EVT data.
+//!
+//! Run with `cargo bench --features testing` from the libattpc_merger directory.
+//! The runs are parameterized by the number of events and the number of pads hit in each event.
+//! Every pad has a full trace, so throughput is reported in bytes of GRAW data merged.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::path::Path;
+use std::sync::mpsc;
+
+use libattpc_merger::config::Config;
+use libattpc_merger::constants::{
+    FPN_CHANNELS, NUMBER_OF_AGETS, NUMBER_OF_CHANNELS, NUMBER_OF_COBOS, NUMBER_OF_TIME_BUCKETS,
+};
+use libattpc_merger::graw_frame::GrawData;
+use libattpc_merger::process::process_run;
+use libattpc_merger::test_data::*;
+use libattpc_merger::worker_status::WorkerStatus;
+
+const RUN_NUMBER: i32 = 1;
+const EVENT_COUNTS: [u32; 2] = [10, 100];
+const PADS_PER_EVENT: [usize; 2] = [16, 256];
+
+/// Write a run to the directory where each event has full traces for the given number of pads.
+///
+/// All of the data is in CoBo 0 AsAd 0, so at most 256 pads (the non-FPN channels of an AsAd) can be used.
+/// Returns the config for the run and the size of the GRAW data in bytes.
+fn write_run(directory: &Path, n_events: u32, pads_per_event: usize) -> (Config, u64) {
+    let config = Config {
+        graw_path: directory.join("graw"),
+        evt_path: directory.join("evt"),
+        hdf_path: directory.join("hdf"),
+        first_run_number: RUN_NUMBER,
+        last_run_number: RUN_NUMBER,
+        ..Default::default()
+    };
+
+    // Every CoBo needs a directory
+    let run_dir = config.graw_path.join(format!("run_{:0>4}", RUN_NUMBER));
+    for cobo in 0..NUMBER_OF_COBOS {
+        std::fs::create_dir_all(run_dir.join(format!("mm{cobo}"))).unwrap();
+    }
+    let channels: Vec<(u8, u8)> = (0..NUMBER_OF_AGETS)
+        .flat_map(|aget| (0..NUMBER_OF_CHANNELS).map(move |channel| (aget, channel)))
+        .filter(|(_, channel)| !FPN_CHANNELS.contains(channel))
+        .take(pads_per_event)
+        .collect();
+    let frames: Vec<Vec<u8>> = (0..n_events)
+        .map(|event_id| {
+            let data: Vec<GrawData> = channels
+                .iter()
+                .flat_map(|(aget_id, channel)| {
+                    (0..NUMBER_OF_TIME_BUCKETS as u16).map(move |time_bucket_id| GrawData {
+                        aget_id: *aget_id,
+                        channel: *channel,
+                        time_bucket_id,
+                        sample: (time_bucket_id % 100) as i16 + 1,
+                    })
+                })
+                .collect();
+            make_graw_frame(event_id, 1000 * (event_id as u64 + 1), 0, 0, &data)
+        })
+        .collect();
+    let graw_size = frames.iter().map(|frame| frame.len() as u64).sum();
+    write_asad_file(&run_dir.join("mm0"), 0, 0, &frames).unwrap();
+
+    let evt_dir = config.evt_path.join(format!("run{}", RUN_NUMBER));
+    std::fs::create_dir_all(&evt_dir).unwrap();
+    let mut rings = vec![make_begin_run_ring(RUN_NUMBER as u32, 0, "benchmark")];
+    for event_id in 0..n_events {
+        rings.push(make_physics_ring(event_id, event_id, &[(1, 2); 16], 1));
+    }
+    rings.push(make_end_run_ring(n_events, n_events));
+    write_evt_file(&evt_dir, RUN_NUMBER, &rings).unwrap();
+
+    std::fs::create_dir_all(&config.hdf_path).unwrap();
+    (config, graw_size)
+}
+
+fn bench_process_run(c: &mut Criterion) {
+    // The merger logs every run, which would swamp the benchmark output
+    spdlog::default_logger()
+        .set_level_filter(spdlog::LevelFilter::MoreSevereEqual(spdlog::Level::Error));
+
+    let mut group = c.benchmark_group("process_run");
+    group.sample_size(10);
+    for n_events in EVENT_COUNTS {
+        for pads_per_event in PADS_PER_EVENT {
+            let directory = std::env::temp_dir().join(format!(
+                "attpc_merger_bench_{}_{}_{}",
+                std::process::id(),
+                n_events,
+                pads_per_event
+            ));
+            let (config, graw_size) = write_run(&directory, n_events, pads_per_event);
+            let (tx, rx) = mpsc::channel::<WorkerStatus>();
+
+            group.throughput(Throughput::Bytes(graw_size));
+            group.bench_with_input(
+                BenchmarkId::new(format!("{n_events}_events"), pads_per_event),
+                &config,
+                |b, config| {
+                    b.iter(|| {
+                        process_run(config, RUN_NUMBER, &tx, &0).unwrap();
+                        rx.try_iter().count() // Drain the status messages
+                    })
+                },
+            );
+            std::fs::remove_dir_all(&directory).unwrap();
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_process_run);
+criterion_main!(benches);
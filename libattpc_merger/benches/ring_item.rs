@@ -0,0 +1,36 @@
+//! Benchmark of converting FRIBDAQ RingItems to PhysicsItems.
+//!
+//! Run with `cargo bench --features testing --bench ring_item` from the libattpc_merger directory.
+//! The physics items are parameterized by the number of SIS3300 samples. Throughput is reported in bytes of ring item converted.
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+
+use libattpc_merger::ring_item::{PhysicsItem, RingItem};
+use libattpc_merger::test_data::make_physics_ring;
+
+const SAMPLE_COUNTS: [usize; 2] = [16, 1024];
+
+fn bench_ring_item(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ring_item");
+    for n_samples in SAMPLE_COUNTS {
+        let samples: Vec<(u16, u16)> = (0..n_samples)
+            .map(|idx| (idx as u16 % 4096, (idx as u16 + 1) % 4096))
+            .collect();
+        let buffer = make_physics_ring(0, 1000, &samples, 1);
+        group.throughput(Throughput::Bytes(buffer.len() as u64));
+        let ring = RingItem::try_from(buffer).unwrap();
+        group.bench_with_input(BenchmarkId::new("physics", n_samples), &ring, |b, ring| {
+            b.iter_batched(
+                || ring.clone(),
+                |mut ring| {
+                    ring.remove_boundaries();
+                    PhysicsItem::try_from(ring).unwrap()
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_ring_item);
+criterion_main!(benches);
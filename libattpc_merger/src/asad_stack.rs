@@ -1,6 +1,10 @@
 use std::collections::VecDeque;
+use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
+use std::time::Instant;
 
+use super::config::{FollowOptions, RetryOptions};
+use super::constants::{EXPECTED_HEADER_SIZE, SIZE_UNIT};
 use super::error::{AsadStackError, GrawFileError};
 use super::graw_file::GrawFile;
 use super::graw_frame::{FrameMetadata, GrawFrame};
@@ -16,6 +20,16 @@ use super::graw_frame::{FrameMetadata, GrawFrame};
 /// ## Why
 /// This is more advantageous than simply opening all files, because we don't want to have to search through all possible files to find the earliest frame
 /// when we dont have to. It can also save some memory/optimization by not having to buffer up all of the files around.
+///
+/// ## Validation
+/// Files too small to hold a frame header (typically left by an interrupted DAQ write) are skipped with a warning
+/// when the stack is created. Files whose size is not a whole number of frame size units are likely truncated,
+/// which is also warned about. Validation is not done in follow mode, as the DAQ may still be writing the files.
+///
+/// ## Follow mode
+/// If the stack is given FollowOptions, the files are assumed to still be in the process of being written by the DAQ.
+/// When the stack runs out of data it checks if the active file has grown or if new files have appeared. If neither has happened
+/// it reports that it is waiting for data. If no new data appears before the timeout, the stack is ended.
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct AsadStack {
@@ -26,27 +40,59 @@ pub struct AsadStack {
     parent_path: PathBuf,
     total_stack_size_bytes: u64,
     is_ended: bool,
+    follow: Option<FollowOptions>,
+    retry: RetryOptions,
+    waiting_since: Option<Instant>,
+    chunk_gaps: Vec<ChunkGap>, // chunks missing from the files found when the stack was made
+}
+
+/// A run of consecutive chunk numbers missing from the .graw files of an AsAd
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChunkGap {
+    pub first_missing: u32,
+    pub last_missing: u32,
+    pub estimated_bytes: u64, // the number of missing chunks times the mean size of the chunks on either side
 }
 
 impl AsadStack {
     /// Create a new AsadStack for a given AsAd-CoBo combo in a given directory
+    ///
+    /// If follow is Some, the stack will wait for files which are still being written. Failed reads are retried
+    /// according to the RetryOptions.
     pub fn new(
         data_path: &Path,
         cobo_number: i32,
         asad_number: i32,
+        follow: Option<FollowOptions>,
+        retry: RetryOptions,
     ) -> Result<Self, AsadStackError> {
-        let (mut file_stack, total_stack_size_bytes) =
+        let (mut file_stack, mut total_stack_size_bytes) =
             Self::get_file_stack(data_path, &cobo_number, &asad_number)?;
+        let file_sizes: Vec<(PathBuf, u64)> = file_stack
+            .iter()
+            .map(|path| {
+                let size = path.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+                (path.clone(), size)
+            })
+            .collect();
+        let chunk_gaps = find_chunk_gaps(&file_sizes);
+        if follow.is_none() {
+            (file_stack, total_stack_size_bytes) = Self::validate_file_stack(file_stack)?;
+        }
         if let Some(path) = file_stack.pop_front() {
             //Activate the first file
             Ok(AsadStack {
-                active_file: GrawFile::new(&path)?,
+                active_file: GrawFile::new(&path, retry)?,
                 file_stack,
                 cobo_number,
                 asad_number,
                 parent_path: data_path.into(),
                 total_stack_size_bytes,
                 is_ended: false,
+                follow,
+                retry,
+                waiting_since: None,
+                chunk_gaps,
             })
         } else {
             Err(AsadStackError::NoMatchingFiles)
@@ -57,6 +103,7 @@ impl AsadStack {
     ///
     /// If there is nothing left to read, the stack attempts to move to the next file.
     /// Returns a `Result<Option<FrameMetadata>>`. If the Option is None, the stack has run out of data.
+    /// In follow mode, `AsadStackError::WaitingForData` is returned if the stack is waiting on the DAQ to write more data.
     ///
     /// # Important
     /// The metadata for the next frame should *always* be queried before attempting to retrieve the next frame.
@@ -68,9 +115,16 @@ impl AsadStack {
                 return Ok(None);
             }
             match self.active_file.get_next_frame_metadata() {
-                Ok(meta) => return Ok(Some(meta)),
+                Ok(meta) => {
+                    self.waiting_since = None;
+                    return Ok(Some(meta));
+                }
                 Err(GrawFileError::EndOfFile) => {
-                    self.move_to_next_file()?;
+                    if self.follow.is_some() && self.file_stack.is_empty() {
+                        self.wait_for_data()?;
+                    } else {
+                        self.move_to_next_file()?;
+                    }
                     continue;
                 }
                 Err(e) => return Err(AsadStackError::FileError(e)),
@@ -113,6 +167,18 @@ impl AsadStack {
         &self.active_file
     }
 
+    /// Get the paths of the active file and the files remaining in the stack
+    pub fn get_file_paths(&self) -> Vec<PathBuf> {
+        let mut paths = vec![self.active_file.get_filename().to_path_buf()];
+        paths.extend(self.file_stack.iter().cloned());
+        paths
+    }
+
+    /// The chunks missing from the files of the stack, as found when the stack was made
+    pub fn get_chunk_gaps(&self) -> &[ChunkGap] {
+        &self.chunk_gaps
+    }
+
     /// Returns true if there is still data to be read from this stack. Returns false if the stack is finished.
     pub fn is_not_ended(&self) -> bool {
         !self.is_ended
@@ -127,12 +193,9 @@ impl AsadStack {
         asad_number: &i32,
     ) -> Result<(VecDeque<PathBuf>, u64), AsadStackError> {
         let mut file_list: Vec<PathBuf> = Vec::new();
-        let start_pattern = format!("CoBo{}_AsAd{}", *cobo_number, *asad_number);
-        let end_pattern = ".graw";
         for item in parent_path.read_dir()? {
             let item_path = item?.path();
-            let item_path_str = item_path.to_str().unwrap();
-            if item_path_str.contains(&start_pattern) && item_path_str.contains(end_pattern) {
+            if is_graw_file_for(&item_path, *cobo_number, *asad_number) {
                 file_list.push(item_path);
             }
         }
@@ -145,19 +208,102 @@ impl AsadStack {
             .iter()
             .fold(0, |sum, path| sum + path.metadata().unwrap().len());
 
-        file_list.sort(); // Can sort standard. The only change should be in the number at the tail.
+        // The chunk numbers grow past the padding of long runs, so they are sorted by value (_2 before _10)
+        file_list.sort_by_cached_key(|path| get_graw_order_key(path));
         let stack = file_list.into();
 
         Ok((stack, total_stack_size_bytes))
     }
 
+    /// Check the sizes of the files in the stack, dropping any which are too small to contain a frame
+    ///
+    /// Returns the remaining files and their total size in bytes.
+    fn validate_file_stack(
+        file_stack: VecDeque<PathBuf>,
+    ) -> Result<(VecDeque<PathBuf>, u64), AsadStackError> {
+        let minimum_size = (EXPECTED_HEADER_SIZE as u32 * SIZE_UNIT) as u64;
+        let mut valid_stack = VecDeque::new();
+        let mut total_stack_size_bytes = 0;
+        for path in file_stack {
+            let size = path.metadata()?.len();
+            if size < minimum_size {
+                spdlog::warn!(
+                    "GRAW file {} is only {} bytes, too small to contain a frame. It will be skipped; it may be left over from an interrupted DAQ write.",
+                    path.display(),
+                    size
+                );
+                continue;
+            }
+            if size % SIZE_UNIT as u64 != 0 {
+                spdlog::warn!(
+                    "GRAW file {} is {} bytes, which is not a multiple of {} bytes. It is likely truncated, and its last frame may fail to parse.",
+                    path.display(),
+                    size,
+                    SIZE_UNIT
+                );
+            }
+            total_stack_size_bytes += size;
+            valid_stack.push_back(path);
+        }
+
+        if valid_stack.is_empty() {
+            return Err(AsadStackError::NoMatchingFiles);
+        }
+        Ok((valid_stack, total_stack_size_bytes))
+    }
+
+    /// Check for new data in follow mode
+    ///
+    /// If the active file has grown or new files have been written, return so that the data can be read.
+    /// Otherwise return a WaitingForData error, or end the stack if we have waited longer than the timeout.
+    fn wait_for_data(&mut self) -> Result<(), AsadStackError> {
+        let options = match self.follow {
+            Some(opt) => opt,
+            None => return Ok(()),
+        };
+
+        if self.active_file.refresh_size()? {
+            return Ok(());
+        }
+
+        // The DAQ may have moved on to a new file
+        if let Ok((all_files, _)) =
+            Self::get_file_stack(&self.parent_path, &self.cobo_number, &self.asad_number)
+        {
+            for path in all_files {
+                if get_graw_order_key(&path) > get_graw_order_key(self.active_file.get_filename())
+                    && !self.file_stack.contains(&path)
+                {
+                    self.total_stack_size_bytes += path.metadata()?.len();
+                    self.file_stack.push_back(path);
+                }
+            }
+        }
+        if !self.file_stack.is_empty() {
+            return self.move_to_next_file();
+        }
+
+        let waiting_since = *self.waiting_since.get_or_insert_with(Instant::now);
+        if waiting_since.elapsed() >= options.timeout {
+            spdlog::info!(
+                "No new data for CoBo {} AsAd {} in {} seconds, assuming the data is complete.",
+                self.cobo_number,
+                self.asad_number,
+                options.timeout.as_secs()
+            );
+            self.is_ended = true;
+            return Ok(());
+        }
+        Err(AsadStackError::WaitingForData)
+    }
+
     /// Move to the next file in the stack
     ///
     /// If there are no more files in the stack, the is_ended flag is set
     fn move_to_next_file(&mut self) -> Result<(), AsadStackError> {
         loop {
             if let Some(next_file_path) = self.file_stack.pop_front() {
-                let next_file = GrawFile::new(&next_file_path)?;
+                let next_file = GrawFile::new(&next_file_path, self.retry)?;
                 if *next_file.is_open() && !(*next_file.is_eof()) {
                     self.active_file = next_file;
                     return Ok(());
@@ -169,3 +315,330 @@ impl AsadStack {
         }
     }
 }
+
+/// Check if a path is a .graw file of the given CoBo and AsAd
+///
+/// Only the file name is matched, so the parent directories may contain anything (spaces, non-UTF8, UNC prefixes, ...)
+pub fn is_graw_file_for(path: &Path, cobo_number: i32, asad_number: i32) -> bool {
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy(),
+        None => return false,
+    };
+    let pattern = format!("CoBo{}_AsAd{}", cobo_number, asad_number);
+    name.contains(&pattern) && path.extension() == Some(OsStr::new("graw"))
+}
+
+/// Get the chunk number of a .graw file (the number after the last underscore, i.e. CoBo0_AsAd0_0012.graw), if the
+/// name has one
+pub fn get_graw_chunk(path: &Path) -> Option<u32> {
+    let stem = path.file_stem()?.to_string_lossy();
+    let (_, chunk) = stem.rsplit_once('_')?;
+    chunk.parse().ok()
+}
+
+/// The key ordering the .graw files of an AsAd: by the name before the chunk number, then by chunk number
+///
+/// The name before the chunk may contain the start time of the DAQ, which then orders the files of a restart.
+fn get_graw_order_key(path: &Path) -> (String, Option<u32>, PathBuf) {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let prefix = match stem.rsplit_once('_') {
+        Some((prefix, _)) => prefix.to_string(),
+        None => stem,
+    };
+    (prefix, get_graw_chunk(path), path.to_path_buf())
+}
+
+/// Find the gaps in the chunk numbers of the .graw files of an AsAd, given with their sizes in stack order
+///
+/// The chunks are numbered from 0 each time the DAQ starts (the name before the chunk number), so each name is checked
+/// on its own. Gaps are logged with an estimate of the missing bytes. Files whose names have no chunk number, and
+/// chunk numbers found in more than one file (i.e. _7 and _0007), are logged but not counted as gaps.
+pub fn find_chunk_gaps(files: &[(PathBuf, u64)]) -> Vec<ChunkGap> {
+    let mut gaps = Vec::new();
+    // The previous numbered file of the same name: (prefix, chunk, size, path)
+    let mut previous: Option<(String, u32, u64, &Path)> = None;
+    for (path, size) in files.iter() {
+        let (prefix, chunk, _) = get_graw_order_key(path);
+        let chunk = match chunk {
+            Some(chunk) => chunk,
+            None => {
+                spdlog::warn!(
+                    "GRAW file {} does not end in a chunk number (CoBo#_AsAd#_..._####.graw), it is read in name order before the numbered files.",
+                    path.display()
+                );
+                continue;
+            }
+        };
+        let (first_missing, neighbor_size) = match &previous {
+            Some((previous_prefix, previous_chunk, previous_size, previous_path))
+                if *previous_prefix == prefix =>
+            {
+                if chunk == *previous_chunk {
+                    spdlog::warn!(
+                        "GRAW files {} and {} have the same chunk number {}! Both are read.",
+                        previous_path.display(),
+                        path.display(),
+                        chunk
+                    );
+                }
+                (previous_chunk + 1, (previous_size + size) / 2)
+            }
+            _ => (0, *size),
+        };
+        if chunk > first_missing {
+            let gap = ChunkGap {
+                first_missing,
+                last_missing: chunk - 1,
+                estimated_bytes: neighbor_size * (chunk - first_missing) as u64,
+            };
+            spdlog::warn!(
+                "GRAW chunks {} to {} are missing before {}, about {} of data!",
+                gap.first_missing,
+                gap.last_missing,
+                path.display(),
+                human_bytes::human_bytes(gap.estimated_bytes as f64)
+            );
+            gaps.push(gap);
+        }
+        previous = Some((prefix, chunk, *size, path.as_path()));
+    }
+    gaps
+}
+
+//Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::time::Duration;
+
+    /// Make the bytes of a partial readout frame with no data items
+    fn make_empty_frame(event_id: u32) -> Vec<u8> {
+        let mut frame = vec![0u8; 256];
+        frame[0] = 8; // meta type
+        frame[3] = 1; // frame size
+        frame[6] = 1; // frame type
+        frame[9] = 1; // header size
+        frame[11] = 4; // item size
+        frame[22..26].copy_from_slice(&event_id.to_be_bytes());
+        frame
+    }
+
+    #[test]
+    fn test_graw_file_names() {
+        let dir = PathBuf::from("/data/e20009 run data/Ünïcode/run_0001");
+        assert!(is_graw_file_for(
+            &dir.join("CoBo1_AsAd2_2023-01-01T00:00:00.000_0000.graw"),
+            1,
+            2
+        ));
+        assert!(!is_graw_file_for(&dir.join("CoBo11_AsAd2_0000.graw"), 1, 2));
+        assert!(!is_graw_file_for(&dir.join("CoBo1_AsAd3_0000.graw"), 1, 2));
+        assert!(!is_graw_file_for(
+            &dir.join("CoBo1_AsAd2_0000.graw.tmp"),
+            1,
+            2
+        ));
+        // The pattern is only matched in the file name, not the directories
+        assert!(!is_graw_file_for(
+            &PathBuf::from("/data/CoBo1_AsAd2.graw/notes.txt"),
+            1,
+            2
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_graw_file_names_non_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let dir = PathBuf::from(OsStr::from_bytes(b"/data/run\xff\xfe"));
+        assert!(is_graw_file_for(&dir.join("CoBo0_AsAd0_0000.graw"), 0, 0));
+    }
+
+    #[test]
+    fn test_skip_empty_files() {
+        let dir =
+            std::env::temp_dir().join(format!("attpc_merger_asad_empty_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("CoBo0_AsAd0_0000.graw"), [0u8; 0]).unwrap();
+        std::fs::write(dir.join("CoBo0_AsAd0_0001.graw"), make_empty_frame(7)).unwrap();
+        std::fs::write(dir.join("CoBo0_AsAd1_0000.graw"), [0u8; 10]).unwrap();
+
+        let mut stack = AsadStack::new(&dir, 0, 0, None, RetryOptions::default()).unwrap();
+        assert_eq!(*stack.get_stack_size_bytes(), 256);
+        assert!(stack.get_next_frame_metadata().unwrap().is_some());
+        assert_eq!(stack.get_next_frame().unwrap().header.event_id, 7);
+        assert!(matches!(
+            AsadStack::new(&dir, 0, 1, None, RetryOptions::default()),
+            Err(AsadStackError::NoMatchingFiles)
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_graw_file_order() {
+        let dir =
+            std::env::temp_dir().join(format!("attpc_merger_asad_order_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // A lexical sort would read chunks 10 and 11 before chunk 2
+        for chunk in 0..12u32 {
+            std::fs::write(
+                dir.join(format!("CoBo0_AsAd0_{:0>1}.graw", chunk)),
+                make_empty_frame(chunk),
+            )
+            .unwrap();
+        }
+        assert_eq!(get_graw_chunk(&dir.join("CoBo0_AsAd0_0011.graw")), Some(11));
+        assert_eq!(get_graw_chunk(&dir.join("CoBo0_AsAd0.graw")), None);
+
+        let mut stack = AsadStack::new(&dir, 0, 0, None, RetryOptions::default()).unwrap();
+        let mut event_ids = Vec::new();
+        while stack.get_next_frame_metadata().unwrap().is_some() {
+            event_ids.push(stack.get_next_frame().unwrap().header.event_id);
+        }
+        assert_eq!(event_ids, (0..12).collect::<Vec<u32>>());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_chunk_gaps() {
+        let find_gaps = |names: &[&str]| {
+            let mut paths: Vec<PathBuf> = names
+                .iter()
+                .map(|name| PathBuf::from("/data/run_0001/mm0").join(name))
+                .collect();
+            paths.sort_by_cached_key(|path| get_graw_order_key(path));
+            let files: Vec<(PathBuf, u64)> = paths.into_iter().map(|path| (path, 100)).collect();
+            find_chunk_gaps(&files)
+        };
+
+        // Chunk 2 is missing
+        let gaps = find_gaps(&[
+            "CoBo0_AsAd0_0000.graw",
+            "CoBo0_AsAd0_0001.graw",
+            "CoBo0_AsAd0_0003.graw",
+        ]);
+        assert_eq!(
+            gaps,
+            vec![ChunkGap {
+                first_missing: 2,
+                last_missing: 2,
+                estimated_bytes: 100
+            }]
+        );
+
+        // Two and four digit numbers are the same sequence
+        let gaps = find_gaps(&[
+            "CoBo0_AsAd0_0.graw",
+            "CoBo0_AsAd0_1.graw",
+            "CoBo0_AsAd0_0002.graw",
+            "CoBo0_AsAd0_10.graw",
+            "CoBo0_AsAd0_0011.graw",
+        ]);
+        assert_eq!(gaps.len(), 1);
+        assert_eq!((gaps[0].first_missing, gaps[0].last_missing), (3, 9));
+        assert_eq!(gaps[0].estimated_bytes, 700);
+
+        // Duplicates and names without a number are not gaps, and each start of the DAQ is numbered from 0
+        let gaps = find_gaps(&[
+            "CoBo0_AsAd0_2023-01-01T00:00:00.000_0000.graw",
+            "CoBo0_AsAd0_2023-01-01T00:00:00.000_0001.graw",
+            "CoBo0_AsAd0_2023-01-01T00:00:00.000_1.graw",
+            "CoBo0_AsAd0_2023-01-01T01:00:00.000_0000.graw",
+            "CoBo0_AsAd0_extra.graw",
+        ]);
+        assert!(gaps.is_empty());
+
+        // A sequence missing its first chunks
+        let gaps = find_gaps(&["CoBo0_AsAd0_0002.graw"]);
+        assert_eq!((gaps[0].first_missing, gaps[0].last_missing), (0, 1));
+    }
+
+    #[test]
+    fn test_follow_growing_file() {
+        let dir =
+            std::env::temp_dir().join(format!("attpc_merger_asad_follow_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("CoBo0_AsAd0_0000.graw");
+        std::fs::write(&path, make_empty_frame(0)).unwrap();
+
+        let options = FollowOptions {
+            poll_interval: Duration::from_millis(10),
+            timeout: Duration::from_millis(500),
+        };
+        let mut stack = AsadStack::new(&dir, 0, 0, Some(options), RetryOptions::default()).unwrap();
+
+        // Pretend to be the DAQ
+        let writer_path = path.clone();
+        let writer = std::thread::spawn(move || {
+            for event_id in 1..3 {
+                std::thread::sleep(Duration::from_millis(50));
+                let mut file = std::fs::OpenOptions::new()
+                    .append(true)
+                    .open(&writer_path)
+                    .unwrap();
+                file.write_all(&make_empty_frame(event_id)).unwrap();
+            }
+        });
+
+        let mut event_ids: Vec<u32> = vec![];
+        let mut waited = false;
+        loop {
+            match stack.get_next_frame_metadata() {
+                Ok(Some(_)) => event_ids.push(stack.get_next_frame().unwrap().header.event_id),
+                Ok(None) => break,
+                Err(AsadStackError::WaitingForData) => {
+                    waited = true;
+                    std::thread::sleep(options.poll_interval);
+                }
+                Err(e) => panic!("Unexpected error: {e}"),
+            }
+        }
+        writer.join().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(waited);
+        assert_eq!(event_ids, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_follow_next_chunk() {
+        let dir = std::env::temp_dir().join(format!(
+            "attpc_merger_asad_follow_chunk_{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("CoBo0_AsAd0_9.graw"), make_empty_frame(0)).unwrap();
+
+        let options = FollowOptions {
+            poll_interval: Duration::from_millis(10),
+            timeout: Duration::from_millis(200),
+        };
+        let mut stack = AsadStack::new(&dir, 0, 0, Some(options), RetryOptions::default()).unwrap();
+        assert!(stack.get_next_frame_metadata().unwrap().is_some());
+        stack.get_next_frame().unwrap();
+        assert!(matches!(
+            stack.get_next_frame_metadata(),
+            Err(AsadStackError::WaitingForData)
+        ));
+
+        // The DAQ moves on to chunk 10, which sorts before 9 by name
+        std::fs::write(dir.join("CoBo0_AsAd0_10.graw"), make_empty_frame(1)).unwrap();
+        let mut event_ids: Vec<u32> = vec![];
+        loop {
+            match stack.get_next_frame_metadata() {
+                Ok(Some(_)) => event_ids.push(stack.get_next_frame().unwrap().header.event_id),
+                Ok(None) => break,
+                Err(AsadStackError::WaitingForData) => std::thread::sleep(options.poll_interval),
+                Err(e) => panic!("Unexpected error: {e}"),
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(event_ids, vec![1]);
+    }
+}
@@ -0,0 +1,85 @@
+use sha2::{Digest, Sha256};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Size of the chunks read while hashing
+const CHECKSUM_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Extension appended to the name of a file for its checksum file
+pub const CHECKSUM_EXTENSION: &str = "sha256";
+
+/// Compute the SHA-256 of a file as lowercase hex, reading it in chunks so that it is never held in memory.
+///
+/// on_progress is called with the number of bytes hashed so far after each chunk, so that a multi-gigabyte file
+/// doesn't look stalled.
+pub fn sha256_file<F: FnMut(u64)>(path: &Path, mut on_progress: F) -> std::io::Result<String> {
+    let mut reader = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; CHECKSUM_CHUNK_SIZE];
+    let mut bytes_hashed: u64 = 0;
+    loop {
+        let n_read = reader.read(&mut buffer)?;
+        if n_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n_read]);
+        bytes_hashed += n_read as u64;
+        on_progress(bytes_hashed);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+/// The path of the checksum file of a file, i.e. run_0001.h5.sha256 for run_0001.h5
+pub fn get_checksum_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".");
+    name.push(CHECKSUM_EXTENSION);
+    PathBuf::from(name)
+}
+
+/// Hash a file and write its checksum file next to it, see sha256_file. Returns the digest.
+///
+/// The checksum file has the format of sha256sum (the digest, two spaces, and the file name), so the file can be
+/// checked with `sha256sum -c` from its directory.
+pub fn write_checksum_file<F: FnMut(u64)>(path: &Path, on_progress: F) -> std::io::Result<String> {
+    let digest = sha256_file(path, on_progress)?;
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let mut checksum_file = std::fs::File::create(get_checksum_path(path))?;
+    writeln!(checksum_file, "{digest}  {file_name}")?;
+    Ok(digest)
+}
+
+//Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_checksum_file() {
+        let dir =
+            std::env::temp_dir().join(format!("attpc_merger_checksum_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("run_0001.h5");
+        std::fs::write(&path, b"abc").unwrap();
+
+        let mut progress = Vec::new();
+        let digest = write_checksum_file(&path, |bytes| progress.push(bytes)).unwrap();
+        assert_eq!(
+            digest,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(progress, vec![3]);
+        let checksum_path = get_checksum_path(&path);
+        assert_eq!(checksum_path, dir.join("run_0001.h5.sha256"));
+        assert_eq!(
+            std::fs::read_to_string(&checksum_path).unwrap(),
+            format!("{digest}  run_0001.h5\n")
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
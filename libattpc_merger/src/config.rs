@@ -1,7 +1,293 @@
 use serde::{Deserialize, Serialize};
-use std::path::{Path, PathBuf};
+use std::fmt::Display;
+use std::path::{Component, Path, PathBuf, Prefix};
+use std::time::Duration;
 
+use super::constants::{
+    COBO_WITH_TIMESTAMP, DEFAULT_MAX_FRAMES_PER_EVENT, DEFAULT_MAX_RING_ITEM_SIZE,
+    DEFAULT_SI_COINCIDENCE_WINDOW, DEFAULT_SPILL_THRESHOLD_FRAMES, FADC_ADC_BITS, MAXIMUM_ADC_BITS,
+    MAXIMUM_NUMBER_OF_TIME_BUCKETS, MINIMUM_RING_ITEM_SIZE, NUMBER_OF_COBOS, NUMBER_OF_SCA_CELLS,
+    NUMBER_OF_TIME_BUCKETS,
+};
 use super::error::ConfigError;
+use super::pad_map::PadMap;
+use super::pedestals::Pedestals;
+use super::run_db::RunDatabase;
+use super::stop_flag::StopFlag;
+
+/// Default time between checks for new data in follow mode
+fn default_follow_poll_interval_ms() -> u64 {
+    1000
+}
+
+/// Default time to wait for new data in follow mode before assuming a run is complete
+fn default_follow_timeout_s() -> u64 {
+    60
+}
+
+/// Quality flags are cheap, so write them by default
+fn default_write_quality_flags() -> bool {
+    true
+}
+
+/// Pure TPC experiments have no auxiliary detector data, so the empty datasets are left out by default
+fn default_prune_empty_datasets() -> bool {
+    true
+}
+
+/// Hashing rereads the whole output, so checksums are only written when asked for, i.e. for the transfer to the archive
+fn default_write_checksums() -> bool {
+    false
+}
+
+/// Most runs have GET data
+fn default_merge_get() -> bool {
+    true
+}
+
+/// The layout of the online data of the original AT-TPC DAQ servers, where each CoBo's data is mounted under /Volumes
+pub const DEFAULT_ONLINE_PATH_TEMPLATE: &str = "/Volumes/mm{cobo}/{experiment}";
+
+/// Bytes in a GB, for max_output_gb
+const BYTES_PER_GB: f64 = 1.0e9;
+
+/// FRIBDAQ scaler offsets are in seconds by default
+fn default_scaler_offset_divisor() -> u32 {
+    1
+}
+
+/// The silicon and TPC CoBos see the same trigger within a few clock ticks
+fn default_si_coincidence_window() -> u64 {
+    DEFAULT_SI_COINCIDENCE_WINDOW
+}
+
+/// The silicon detectors are read out by the CoBo with the FRIBDAQ timestamp
+fn default_si_cobo() -> u8 {
+    COBO_WITH_TIMESTAMP
+}
+
+/// An order of magnitude more frames than a normal event, well under the default max_frames_per_event
+fn default_spill_threshold_frames() -> usize {
+    DEFAULT_SPILL_THRESHOLD_FRAMES
+}
+
+/// The SIS3300 is a 12-bit digitizer
+fn default_fadc_bits() -> u8 {
+    FADC_ADC_BITS
+}
+
+/// FRIBDAQ ring items are far smaller than this, so anything larger is a corrupt size word
+fn default_max_evt_item_bytes() -> u64 {
+    DEFAULT_MAX_RING_ITEM_SIZE
+}
+
+/// Far more frames than the 4 AsAds of each of the CoBos send for an event
+fn default_max_frames_per_event() -> usize {
+    DEFAULT_MAX_FRAMES_PER_EVENT
+}
+
+/// Enough to ride out a brief network outage without holding up the merge for long
+fn default_upload_retries() -> u32 {
+    3
+}
+
+/// Most experiments use the full 512 time buckets
+fn default_time_buckets() -> u16 {
+    NUMBER_OF_TIME_BUCKETS as u16
+}
+
+/// Assume the merged data is about as large as the GRAW data
+fn default_output_size_ratio() -> f64 {
+    1.0
+}
+
+/// Retry reads a few times, to survive hiccups of network mounts
+fn default_io_retries() -> u32 {
+    3
+}
+
+/// Flag a worker as stalled after 5 minutes without a status
+fn default_stall_timeout_s() -> u64 {
+    300
+}
+
+/// The Mutant readout glitch moves timestamps back by a few ticks, far less than this
+fn default_timestamp_glitch_threshold() -> u64 {
+    100
+}
+
+/// Time to wait before the first retry of a failed read. Doubles for each retry.
+const IO_RETRY_INITIAL_BACKOFF_MS: u64 = 100;
+
+/// Time to wait before the first retry of a failed upload. Doubles for each retry.
+const UPLOAD_RETRY_INITIAL_BACKOFF_MS: u64 = 1000;
+
+/// Polling behavior used when following files which are still being written by the DAQ
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FollowOptions {
+    pub poll_interval: Duration,
+    pub timeout: Duration,
+}
+
+/// How reads of the data files (and uploads of the output files) are retried when they fail with a transient error
+///
+/// The default is to not retry at all.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct RetryOptions {
+    pub retries: u32,
+    pub initial_backoff: Duration,
+}
+
+/// What to do with the source (.graw and .evt) files of a run once it has been merged and verified
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum PostMergeAction {
+    Delete,
+    MoveTo(PathBuf),
+}
+
+/// What to do when a time bucket of a trace is written more than once within an event
+///
+/// Overwrite keeps the last sample written, Sum adds the samples (saturating at the limits of i16), and Error
+/// rejects the event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum DuplicateBucketPolicy {
+    #[default]
+    Overwrite,
+    Sum,
+    Error,
+}
+
+/// What to do with the data of a GRAW frame which are out of the range of the hardware (AGET id, channel or time
+/// bucket)
+///
+/// Skip leaves the bad datum out of its frame and counts it, Fail fails the run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum BadDataPolicy {
+    #[default]
+    Skip,
+    Fail,
+}
+
+/// The format of the merged output files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum OutputFormat {
+    #[default]
+    HDF5,
+}
+
+/// How the event groups of the output are numbered
+///
+/// Ordinal numbers the events in the order they are written, starting from 0. GetEventId uses the event_id of each
+/// GET event, and gives the FRIBDAQ physics events the id of the GET event they are matched to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum EventNaming {
+    #[default]
+    Ordinal,
+    GetEventId,
+}
+
+impl EventNaming {
+    /// The name of the naming, as written to the output
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ordinal => "Ordinal",
+            Self::GetEventId => "GetEventId",
+        }
+    }
+}
+
+/// How the events are laid out in the output
+///
+/// Groups writes one group per event (event_0, event_1, ...), the standard AT-TPC format. Stacked appends the events
+/// of a run to a few resizable datasets, with an index table giving the rows of each event, which avoids the HDF5
+/// metadata overhead of a group per event for runs with many events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum EventLayout {
+    #[default]
+    Groups,
+    Stacked,
+}
+
+impl EventLayout {
+    /// The name of the layout, as written to the output
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Groups => "Groups",
+            Self::Stacked => "Stacked",
+        }
+    }
+}
+
+/// How the SIS3300 samples of the FRIBDAQ physics events are laid out in the 1903 datasets
+///
+/// SampleMajor writes one row per sample and one column per channel. ChannelMajor writes one row per channel and one
+/// column per sample, the same orientation as the pad traces of get_traces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum FadcOrientation {
+    #[default]
+    SampleMajor,
+    ChannelMajor,
+}
+
+impl FadcOrientation {
+    /// The name of the orientation, as written to the output
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::SampleMajor => "SampleMajor",
+            Self::ChannelMajor => "ChannelMajor",
+        }
+    }
+}
+
+/// The parts of a Config which can have an issue, used to point the user at the problem
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigField {
+    GrawPath,
+    EvtPath,
+    HdfPath,
+    PadMapPath,
+    PedestalPath,
+    RunRange,
+    NThreads,
+    TimeBuckets,
+    PostMergeAction,
+    TimeWindow,
+    CompressionLevel,
+    ExpectedCobos,
+    ScalerOffsetDivisor,
+    MaxEventsPerFile,
+    MaxOutputGb,
+    OnlinePathTemplate,
+    FadcBits,
+    MaxEvtItemBytes,
+    EventLayout,
+    RunDatabasePath,
+    OutputSuffix,
+    MaxEventsPerRun,
+    MaxFramesPerEvent,
+    UploadUrl,
+    SiMergeByTimestamp,
+    SpillThresholdFrames,
+}
+
+/// A problem found when validating a Config
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigIssue {
+    pub field: ConfigField,
+    pub message: String,
+}
+
+impl ConfigIssue {
+    fn new(field: ConfigField, message: String) -> Self {
+        Self { field, message }
+    }
+}
+
+impl Display for ConfigIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
 
 /// Structure representing the application configuration. Contains pathing and run information
 /// Configs are seralizable and deserializable to YAML using serde and serde_yaml
@@ -11,11 +297,138 @@ pub struct Config {
     pub evt_path: PathBuf,
     pub hdf_path: PathBuf,
     pub pad_map_path: Option<PathBuf>,
+    pub pedestal_path: Option<PathBuf>,
     pub first_run_number: i32,
     pub last_run_number: i32,
     pub online: bool,
     pub experiment: String,
+    #[serde(default)]
+    pub online_address: Option<String>,
     pub n_threads: i32,
+    #[serde(default)]
+    pub frame_provenance: bool,
+    #[serde(default)]
+    pub follow: bool,
+    #[serde(default = "default_follow_poll_interval_ms")]
+    pub follow_poll_interval_ms: u64,
+    #[serde(default = "default_follow_timeout_s")]
+    pub follow_timeout_s: u64,
+    #[serde(default = "default_write_quality_flags")]
+    pub write_quality_flags: bool,
+    #[serde(default)]
+    pub require_evt: bool,
+    #[serde(default = "default_scaler_offset_divisor")]
+    pub scaler_offset_divisor: u32,
+    #[serde(default = "default_time_buckets")]
+    pub time_buckets: u16,
+    #[serde(default)]
+    pub write_cobo_timestamps: bool,
+    #[serde(default)]
+    pub post_merge_action: Option<PostMergeAction>,
+    #[serde(default)]
+    pub confirm_post_merge_action: bool,
+    #[serde(default = "default_io_retries")]
+    pub io_retries: u32,
+    #[serde(default = "default_output_size_ratio")]
+    pub output_size_ratio: f64,
+    #[serde(default)]
+    pub duplicate_bucket_policy: DuplicateBucketPolicy,
+    #[serde(default)]
+    pub copy_bandwidth_mbps: Option<u32>,
+    #[serde(default)]
+    pub output_format: OutputFormat,
+    #[serde(default)]
+    pub max_out_of_order: u32,
+    #[serde(default)]
+    pub min_timestamp: Option<u64>,
+    #[serde(default)]
+    pub max_timestamp: Option<u64>,
+    #[serde(default)]
+    pub debug_frame_headers: bool,
+    #[serde(default)]
+    pub compression_level: Option<u8>,
+    #[serde(default)]
+    pub expected_cobos: Option<Vec<u8>>,
+    #[serde(default = "default_stall_timeout_s")]
+    pub stall_timeout_s: u64,
+    #[serde(default)]
+    pub clean_partial_outputs: bool,
+    #[serde(default)]
+    pub max_events_per_file: Option<u64>,
+    #[serde(default)]
+    pub max_output_gb: Option<f64>,
+    #[serde(default)]
+    pub fix_timestamp_glitches: bool,
+    #[serde(default = "default_timestamp_glitch_threshold")]
+    pub timestamp_glitch_threshold: u64,
+    #[serde(default = "default_merge_get")]
+    pub merge_get: bool,
+    #[serde(default)]
+    pub online_path_template: Option<String>,
+    #[serde(default)]
+    pub timestampother_fallback: bool,
+    #[serde(default = "default_fadc_bits")]
+    pub fadc_bits: u8,
+    #[serde(default)]
+    pub event_naming: EventNaming,
+    #[serde(default = "default_max_evt_item_bytes")]
+    pub max_evt_item_bytes: u64,
+    #[serde(default)]
+    pub event_layout: EventLayout,
+    #[serde(default)]
+    pub strict_pad_map: bool,
+    #[serde(default)]
+    pub require_explicit_map: bool,
+    #[serde(default)]
+    pub run_database_path: Option<PathBuf>,
+    #[serde(default)]
+    pub output_suffix: Option<String>,
+    #[serde(default)]
+    pub write_readout_info: bool,
+    #[serde(default)]
+    pub max_events_per_run: Option<u64>,
+    #[serde(default)]
+    pub frib_source_id: Option<u32>,
+    #[serde(default)]
+    pub write_frib_fragments: bool,
+    #[serde(default = "default_max_frames_per_event")]
+    pub max_frames_per_event: usize,
+    #[serde(default)]
+    pub fail_oversized_events: bool,
+    #[serde(default)]
+    pub compute_peaks: bool,
+    #[serde(default = "default_prune_empty_datasets")]
+    pub prune_empty_datasets: bool,
+    #[serde(default)]
+    pub upload_url: Option<String>,
+    #[serde(default = "default_upload_retries")]
+    pub upload_retries: u32,
+    #[serde(default)]
+    pub delete_after_upload: bool,
+    #[serde(default)]
+    pub fadc_orientation: FadcOrientation,
+    #[serde(default)]
+    pub si_merge_by_timestamp: bool,
+    #[serde(default = "default_si_coincidence_window")]
+    pub si_coincidence_window: u64,
+    #[serde(default = "default_si_cobo")]
+    pub si_cobo: u8,
+    #[serde(default)]
+    pub si_timestamp_offset: i64, // clock ticks added to the silicon CoBo's timestamps to match the TPC CoBos'
+    #[serde(default)]
+    pub bad_data_policy: BadDataPolicy,
+    #[serde(default)]
+    pub spill_large_events: bool,
+    #[serde(default = "default_spill_threshold_frames")]
+    pub spill_threshold_frames: usize,
+    #[serde(default)]
+    pub correct_sca_rotation: bool,
+    #[serde(default)]
+    pub link_get_events: bool,
+    #[serde(default = "default_write_checksums")]
+    pub write_checksums: bool,
+    #[serde(skip)]
+    pub stop_flag: StopFlag, // shared by the clones given to the workers
 }
 
 impl Default for Config {
@@ -26,16 +439,93 @@ impl Default for Config {
             evt_path: PathBuf::from("None"),
             hdf_path: PathBuf::from("None"),
             pad_map_path: None,
+            pedestal_path: None,
             first_run_number: 0,
             last_run_number: 0,
             online: false,
             experiment: String::from(""),
+            online_address: None,
             n_threads: 1,
+            frame_provenance: false,
+            follow: false,
+            follow_poll_interval_ms: default_follow_poll_interval_ms(),
+            follow_timeout_s: default_follow_timeout_s(),
+            write_quality_flags: default_write_quality_flags(),
+            require_evt: false,
+            scaler_offset_divisor: default_scaler_offset_divisor(),
+            time_buckets: default_time_buckets(),
+            write_cobo_timestamps: false,
+            post_merge_action: None,
+            confirm_post_merge_action: false,
+            io_retries: default_io_retries(),
+            output_size_ratio: default_output_size_ratio(),
+            duplicate_bucket_policy: DuplicateBucketPolicy::default(),
+            copy_bandwidth_mbps: None,
+            output_format: OutputFormat::default(),
+            max_out_of_order: 0,
+            min_timestamp: None,
+            max_timestamp: None,
+            debug_frame_headers: false,
+            compression_level: None,
+            expected_cobos: None,
+            stall_timeout_s: default_stall_timeout_s(),
+            clean_partial_outputs: false,
+            max_events_per_file: None,
+            max_output_gb: None,
+            fix_timestamp_glitches: false,
+            timestamp_glitch_threshold: default_timestamp_glitch_threshold(),
+            merge_get: default_merge_get(),
+            online_path_template: None,
+            timestampother_fallback: false,
+            fadc_bits: default_fadc_bits(),
+            event_naming: EventNaming::default(),
+            max_evt_item_bytes: default_max_evt_item_bytes(),
+            event_layout: EventLayout::default(),
+            strict_pad_map: false,
+            require_explicit_map: false,
+            run_database_path: None,
+            output_suffix: None,
+            write_readout_info: false,
+            max_events_per_run: None,
+            frib_source_id: None,
+            write_frib_fragments: false,
+            max_frames_per_event: default_max_frames_per_event(),
+            fail_oversized_events: false,
+            compute_peaks: false,
+            prune_empty_datasets: default_prune_empty_datasets(),
+            upload_url: None,
+            upload_retries: default_upload_retries(),
+            delete_after_upload: false,
+            fadc_orientation: FadcOrientation::default(),
+            si_merge_by_timestamp: false,
+            si_coincidence_window: default_si_coincidence_window(),
+            si_cobo: default_si_cobo(),
+            si_timestamp_offset: 0,
+            bad_data_policy: BadDataPolicy::default(),
+            spill_large_events: false,
+            spill_threshold_frames: default_spill_threshold_frames(),
+            correct_sca_rotation: false,
+            link_get_events: false,
+            write_checksums: default_write_checksums(),
+            stop_flag: StopFlag::default(),
         }
     }
 }
 
 impl Config {
+    /// The maximum size of an output file in bytes, from max_output_gb
+    pub fn get_max_output_bytes(&self) -> Option<u64> {
+        self.max_output_gb
+            .map(|max_output_gb| (max_output_gb * BYTES_PER_GB) as u64)
+    }
+
+    /// Check if count events have reached the max_events_per_run, after which no more GET events and no more
+    /// FRIBDAQ physics events of the run are merged
+    pub fn is_run_event_cap_reached(&self, count: u64) -> bool {
+        self.max_events_per_run
+            .is_some_and(|max_events_per_run| count >= max_events_per_run)
+    }
+
     /// Read the configuration in a YAML file
     /// Returns a Config if successful
     pub fn read_config_file(config_path: &Path) -> Result<Self, ConfigError> {
@@ -45,12 +535,39 @@ impl Config {
 
         let yaml_str = std::fs::read_to_string(config_path)?;
 
-        Ok(serde_yaml::from_str::<Self>(&yaml_str)?)
+        let mut config = serde_yaml::from_str::<Self>(&yaml_str)?;
+        config.normalize_paths();
+        Ok(config)
+    }
+
+    /// Canonicalize the user provided paths which exist, see normalize_path
+    pub fn normalize_paths(&mut self) {
+        self.graw_path = normalize_path(&self.graw_path);
+        self.evt_path = normalize_path(&self.evt_path);
+        self.hdf_path = normalize_path(&self.hdf_path);
+        self.pad_map_path = self.pad_map_path.as_deref().map(normalize_path);
+        self.pedestal_path = self.pedestal_path.as_deref().map(normalize_path);
+        if let Some(PostMergeAction::MoveTo(dir)) = &self.post_merge_action {
+            self.post_merge_action = Some(PostMergeAction::MoveTo(normalize_path(dir)));
+        }
+    }
+
+    /// Check if the source files of each run will be copied after it is merged, i.e. moved to another directory
+    ///
+    /// Only a confirmed MoveTo post-merge action on offline data copies files.
+    pub fn need_copy_files(&self) -> bool {
+        matches!(self.post_merge_action, Some(PostMergeAction::MoveTo(_)))
+            && self.confirm_post_merge_action
+            && !self.online
     }
 
     /// Check if a specific run exists by evaluating the existance of GET DAQ data
-    /// FRIBDAQ data is optional
+    /// FRIBDAQ data is optional, unless GET data is not merged (merge_get is false), in which case the FRIBDAQ data is checked
     pub fn does_run_exist(&self, run_number: i32) -> bool {
+        if !self.merge_get {
+            // Only the evt data is merged
+            return self.get_evt_directory(run_number).is_ok();
+        }
         let run_dir: PathBuf = self.graw_path.join(self.get_run_str(run_number));
         if self.online {
             // Don't check run_dir if online
@@ -70,11 +587,11 @@ impl Config {
         }
     }
 
-    /// Get the path to the online data, assuming the standard AT-TPC Server configuration
+    /// Get the path to the online data of a run, from the online_path_template (or the standard AT-TPC Server configuration)
     pub fn get_online_directory(&self, run_number: i32, cobo: &u8) -> Result<PathBuf, ConfigError> {
-        let mut online_dir: PathBuf = PathBuf::new().join(format!("/Volumes/mm{}", cobo));
-        online_dir = online_dir.join(&self.experiment);
-        online_dir = online_dir.join(self.get_run_str(run_number));
+        let online_dir = self
+            .get_online_cobo_directory(cobo)
+            .join(self.get_run_str(run_number));
         if online_dir.exists() {
             Ok(online_dir)
         } else {
@@ -82,6 +599,45 @@ impl Config {
         }
     }
 
+    /// Get the directory of the online data of a CoBo, which contains the run directories
+    ///
+    /// The {cobo} and {experiment} placeholders of the online_path_template are replaced. If no template is given,
+    /// DEFAULT_ONLINE_PATH_TEMPLATE is used.
+    pub fn get_online_cobo_directory(&self, cobo: &u8) -> PathBuf {
+        let template = self
+            .online_path_template
+            .as_deref()
+            .unwrap_or(DEFAULT_ONLINE_PATH_TEMPLATE);
+        PathBuf::from(
+            template
+                .replace("{cobo}", &cobo.to_string())
+                .replace("{experiment}", &self.experiment),
+        )
+    }
+
+    /// Get the total size in bytes of the GRAW files of a run. CoBos without a directory are skipped.
+    pub fn get_run_size_bytes(&self, run_number: i32) -> u64 {
+        let mut size_bytes = 0;
+        for cobo in 0..NUMBER_OF_COBOS {
+            let run_dir = if self.online {
+                self.get_online_directory(run_number, &cobo)
+            } else {
+                self.get_run_directory(run_number, &cobo)
+            };
+            let entries = match run_dir.map(std::fs::read_dir) {
+                Ok(Ok(entries)) => entries,
+                _ => continue,
+            };
+            size_bytes += entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "graw"))
+                .filter_map(|entry| entry.metadata().ok())
+                .map(|metadata| metadata.len())
+                .sum::<u64>();
+        }
+        size_bytes
+    }
+
     /// Get the path to the FRIBDAQ directory, assuming the standard AT-TPC configuration
     pub fn get_evt_directory(&self, run_number: i32) -> Result<PathBuf, ConfigError> {
         let run_dir: PathBuf = self.evt_path.join(format!("run{}", run_number));
@@ -93,10 +649,15 @@ impl Config {
     }
 
     /// Get the path to the output hdf5 file
+    ///
+    /// With an output_suffix the file is run_####_<suffix>.h5, so that merges with different settings can share the
+    /// HDF5 directory.
     pub fn get_hdf_file_name(&self, run_number: i32) -> Result<PathBuf, ConfigError> {
-        let hdf_file_path: PathBuf = self
-            .hdf_path
-            .join(format!("{}.h5", self.get_run_str(run_number)));
+        let file_name = match &self.output_suffix {
+            Some(suffix) => format!("{}_{}.h5", self.get_run_str(run_number), suffix),
+            None => format!("{}.h5", self.get_run_str(run_number)),
+        };
+        let hdf_file_path: PathBuf = self.hdf_path.join(file_name);
         if self.hdf_path.exists() {
             Ok(hdf_file_path)
         } else {
@@ -112,4 +673,594 @@ impl Config {
     pub fn is_n_threads_valid(&self) -> bool {
         self.n_threads >= 1
     }
+
+    /// Check the Config for problems that would cause processing to fail.
+    ///
+    /// Checks that the paths exist, that the run range and number of threads make sense,
+    /// and that the pad map and pedestals can be read. Returns an empty Vec if no issues were found.
+    pub fn validate(&self) -> Vec<ConfigIssue> {
+        let mut issues = Vec::new();
+        if self.merge_get && !self.online && !self.graw_path.is_dir() {
+            issues.push(ConfigIssue::new(
+                ConfigField::GrawPath,
+                format!("GRAW directory {} does not exist", self.graw_path.display()),
+            ));
+        }
+        if (self.require_evt || !self.merge_get) && !self.evt_path.is_dir() {
+            issues.push(ConfigIssue::new(
+                ConfigField::EvtPath,
+                format!(
+                    "EVT directory {} does not exist, but EVT data is required",
+                    self.evt_path.display()
+                ),
+            ));
+        }
+        if !self.hdf_path.is_dir() {
+            issues.push(ConfigIssue::new(
+                ConfigField::HdfPath,
+                format!("HDF5 directory {} does not exist", self.hdf_path.display()),
+            ));
+        }
+        if let Some(path) = &self.pad_map_path {
+            if let Err(e) = PadMap::new(Some(path.as_path())) {
+                issues.push(ConfigIssue::new(
+                    ConfigField::PadMapPath,
+                    format!("Could not read pad map {}: {}", path.display(), e),
+                ));
+            }
+        }
+        if self.require_explicit_map && self.pad_map_path.is_none() {
+            issues.push(ConfigIssue::new(
+                ConfigField::PadMapPath,
+                String::from("No pad map was given, but require_explicit_map is set; give the pad map of the experiment"),
+            ));
+        }
+        if let Some(path) = &self.pedestal_path {
+            if let Err(e) = Pedestals::new(path) {
+                issues.push(ConfigIssue::new(
+                    ConfigField::PedestalPath,
+                    format!("Could not read pedestals {}: {}", path.display(), e),
+                ));
+            }
+        }
+        if let Some(path) = &self.run_database_path {
+            if let Err(e) = RunDatabase::new(path) {
+                issues.push(ConfigIssue::new(
+                    ConfigField::RunDatabasePath,
+                    format!("Could not read runs database {}: {}", path.display(), e),
+                ));
+            }
+        }
+        if self.first_run_number < 0 {
+            issues.push(ConfigIssue::new(
+                ConfigField::RunRange,
+                format!(
+                    "First run number {} must not be negative",
+                    self.first_run_number
+                ),
+            ));
+        }
+        if self.last_run_number < self.first_run_number {
+            issues.push(ConfigIssue::new(
+                ConfigField::RunRange,
+                format!(
+                    "Last run number {} is before the first run number {}",
+                    self.last_run_number, self.first_run_number
+                ),
+            ));
+        }
+        if !self.is_n_threads_valid() {
+            issues.push(ConfigIssue::new(
+                ConfigField::NThreads,
+                format!("Number of workers {} must be at least 1", self.n_threads),
+            ));
+        }
+        if self.time_buckets == 0 || self.time_buckets as u32 > MAXIMUM_NUMBER_OF_TIME_BUCKETS {
+            issues.push(ConfigIssue::new(
+                ConfigField::TimeBuckets,
+                format!(
+                    "Number of time buckets {} must be between 1 and {}",
+                    self.time_buckets, MAXIMUM_NUMBER_OF_TIME_BUCKETS
+                ),
+            ));
+        }
+        // The rotated time buckets cover the whole SCA, so they can land past a shorter window
+        if self.correct_sca_rotation && (self.time_buckets as u32) < NUMBER_OF_SCA_CELLS {
+            issues.push(ConfigIssue::new(
+                ConfigField::TimeBuckets,
+                format!(
+                    "correct_sca_rotation needs a full readout of the {} SCA cells, but the number of time buckets is {}",
+                    NUMBER_OF_SCA_CELLS, self.time_buckets
+                ),
+            ));
+        }
+        if let Some(PostMergeAction::MoveTo(path)) = &self.post_merge_action {
+            if !path.is_dir() {
+                issues.push(ConfigIssue::new(
+                    ConfigField::PostMergeAction,
+                    format!(
+                        "Directory {} to move the merged source files to does not exist",
+                        path.display()
+                    ),
+                ));
+            }
+        }
+        if let Some(cobos) = &self.expected_cobos {
+            if cobos.is_empty() || cobos.iter().any(|cobo| *cobo >= NUMBER_OF_COBOS) {
+                issues.push(ConfigIssue::new(
+                    ConfigField::ExpectedCobos,
+                    format!(
+                        "Expected CoBos {:?} must be a non-empty list of CoBos between 0 and {}",
+                        cobos,
+                        NUMBER_OF_COBOS - 1
+                    ),
+                ));
+            }
+        }
+        if let Some(level) = self.compression_level {
+            if level > 9 {
+                issues.push(ConfigIssue::new(
+                    ConfigField::CompressionLevel,
+                    format!("Compression level {} must be between 0 and 9", level),
+                ));
+            }
+        }
+        if let (Some(min), Some(max)) = (self.min_timestamp, self.max_timestamp) {
+            if min > max {
+                issues.push(ConfigIssue::new(
+                    ConfigField::TimeWindow,
+                    format!(
+                        "Minimum timestamp {} must be less than or equal to maximum timestamp {}",
+                        min, max
+                    ),
+                ));
+            }
+        }
+        if self.scaler_offset_divisor == 0 {
+            issues.push(ConfigIssue::new(
+                ConfigField::ScalerOffsetDivisor,
+                String::from("Scaler offset divisor must be at least 1 (ticks per second of the FRIBDAQ offset clock)"),
+            ));
+        }
+        if self.max_events_per_file == Some(0) {
+            issues.push(ConfigIssue::new(
+                ConfigField::MaxEventsPerFile,
+                String::from("Maximum events per file must be at least 1"),
+            ));
+        }
+        if self.max_events_per_run == Some(0) {
+            issues.push(ConfigIssue::new(
+                ConfigField::MaxEventsPerRun,
+                String::from("Maximum events per run must be at least 1"),
+            ));
+        }
+        if self.max_frames_per_event == 0 {
+            issues.push(ConfigIssue::new(
+                ConfigField::MaxFramesPerEvent,
+                String::from("Maximum frames per event must be at least 1"),
+            ));
+        }
+        if let Some(url) = &self.upload_url {
+            #[cfg(feature = "s3")]
+            if let Err(e) = super::upload::UploadTarget::new(url) {
+                issues.push(ConfigIssue::new(ConfigField::UploadUrl, e.to_string()));
+            }
+            #[cfg(not(feature = "s3"))]
+            issues.push(ConfigIssue::new(
+                ConfigField::UploadUrl,
+                format!("Can not upload to {url}, the merger was built without the s3 feature"),
+            ));
+        }
+        if self
+            .max_output_gb
+            .is_some_and(|max_output_gb| max_output_gb <= 0.0)
+        {
+            issues.push(ConfigIssue::new(
+                ConfigField::MaxOutputGb,
+                String::from("Maximum output file size must be greater than 0 GB"),
+            ));
+        }
+        if let Some(template) = &self.online_path_template {
+            let unknown = template
+                .replace("{cobo}", "")
+                .replace("{experiment}", "")
+                .contains(['{', '}']);
+            if !template.contains("{cobo}") {
+                issues.push(ConfigIssue::new(
+                    ConfigField::OnlinePathTemplate,
+                    format!(
+                        "Online path template {template} must contain the {{cobo}} placeholder"
+                    ),
+                ));
+            } else if unknown {
+                issues.push(ConfigIssue::new(
+                    ConfigField::OnlinePathTemplate,
+                    format!("Online path template {template} has an unknown placeholder, only {{cobo}} and {{experiment}} are allowed"),
+                ));
+            }
+        }
+        if self.fadc_bits == 0 || self.fadc_bits > MAXIMUM_ADC_BITS {
+            issues.push(ConfigIssue::new(
+                ConfigField::FadcBits,
+                format!(
+                    "FADC bit depth {} must be between 1 and {}",
+                    self.fadc_bits, MAXIMUM_ADC_BITS
+                ),
+            ));
+        }
+        if self.max_evt_item_bytes < MINIMUM_RING_ITEM_SIZE {
+            issues.push(ConfigIssue::new(
+                ConfigField::MaxEvtItemBytes,
+                format!(
+                    "Maximum evt item size {} must be at least the size of an empty ring item ({} bytes)",
+                    self.max_evt_item_bytes, MINIMUM_RING_ITEM_SIZE
+                ),
+            ));
+        }
+        // The suffix is part of a file name, so it can't reach out of the HDF5 directory
+        if let Some(suffix) = &self.output_suffix {
+            let is_valid = !suffix.is_empty()
+                && suffix
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+            if !is_valid {
+                issues.push(ConfigIssue::new(
+                    ConfigField::OutputSuffix,
+                    format!("Output suffix '{suffix}' must be non-empty and contain only letters, digits, '-', '_' and '.'"),
+                ));
+            }
+        }
+        // The per-frame debug datasets, the readout, the CoBo timestamps, the peaks, the fragments and the GET event links
+        // have no stacked equivalent. The stacked FADC samples are rows of a dataset with one column per channel
+        if self.event_layout == EventLayout::Stacked
+            && (self.frame_provenance
+                || self.debug_frame_headers
+                || self.write_cobo_timestamps
+                || self.write_readout_info
+                || self.compute_peaks
+                || self.write_frib_fragments
+                || self.link_get_events
+                || self.fadc_orientation == FadcOrientation::ChannelMajor)
+        {
+            issues.push(ConfigIssue::new(
+                ConfigField::EventLayout,
+                String::from("The stacked event layout does not support frame_provenance, debug_frame_headers, write_cobo_timestamps, write_readout_info, compute_peaks, write_frib_fragments, link_get_events or the ChannelMajor fadc_orientation"),
+            ));
+        }
+        // The frames received from the DAQ over TCP can not be split by CoBo
+        if self.si_merge_by_timestamp && self.online && self.online_address.is_some() {
+            issues.push(ConfigIssue::new(
+                ConfigField::SiMergeByTimestamp,
+                String::from("Merging the silicon CoBo by timestamp needs the GRAW files, it can not be used with an online_address"),
+            ));
+        }
+        if self.si_merge_by_timestamp && self.si_cobo >= NUMBER_OF_COBOS {
+            issues.push(ConfigIssue::new(
+                ConfigField::SiMergeByTimestamp,
+                format!(
+                    "The silicon CoBo {} must be a CoBo between 0 and {}",
+                    self.si_cobo,
+                    NUMBER_OF_COBOS - 1
+                ),
+            ));
+        }
+        if self.spill_large_events
+            && (self.spill_threshold_frames == 0
+                || self.spill_threshold_frames >= self.max_frames_per_event)
+        {
+            issues.push(ConfigIssue::new(
+                ConfigField::SpillThresholdFrames,
+                format!(
+                    "Spill threshold frames must be at least 1 and less than max_frames_per_event ({}), or no event is spilled",
+                    self.max_frames_per_event
+                ),
+            ));
+        }
+        issues
+    }
+
+    /// A warning to show before merging if the bundled default pad map will be used, as the pad map is easily
+    /// forgotten and the default silently mis-maps the data of most experiments. None if a pad map was given
+    pub fn get_default_pad_map_warning(&self) -> Option<String> {
+        match self.pad_map_path {
+            Some(_) => None,
+            None => Some(String::from("No pad map was given, so the default pad map bundled with attpc_merger is used! Unless this is the map of the experiment, every trace will have the wrong pad. Set pad_map_path to the map of the experiment.")),
+        }
+    }
+
+    /// Check if an event with this timestamp should be merged, given the min_timestamp and max_timestamp (both inclusive)
+    pub fn is_in_time_window(&self, timestamp: &u64) -> bool {
+        let is_above_min = match self.min_timestamp {
+            Some(min) => *timestamp >= min,
+            None => true,
+        };
+        let is_below_max = match self.max_timestamp {
+            Some(max) => *timestamp <= max,
+            None => true,
+        };
+        is_above_min && is_below_max
+    }
+
+    /// Returns true if only events within a timestamp window are merged
+    pub fn has_time_window(&self) -> bool {
+        self.min_timestamp.is_some() || self.max_timestamp.is_some()
+    }
+
+    /// Get the retry behavior for reads of the data files
+    pub fn get_retry_options(&self) -> RetryOptions {
+        RetryOptions {
+            retries: self.io_retries,
+            initial_backoff: Duration::from_millis(IO_RETRY_INITIAL_BACKOFF_MS),
+        }
+    }
+
+    /// Get the retry behavior for uploads of the output files
+    pub fn get_upload_retry_options(&self) -> RetryOptions {
+        RetryOptions {
+            retries: self.upload_retries,
+            initial_backoff: Duration::from_millis(UPLOAD_RETRY_INITIAL_BACKOFF_MS),
+        }
+    }
+
+    /// Get the limit on the rate of file copies in bytes per second. Returns None if copies are not limited
+    pub fn get_copy_bytes_per_second(&self) -> Option<u64> {
+        self.copy_bandwidth_mbps
+            .map(|mbps| mbps as u64 * 1_000_000 / 8)
+    }
+
+    /// Get the time without a status after which a worker is flagged as stalled. Returns None if workers are never flagged
+    pub fn get_stall_timeout(&self) -> Option<Duration> {
+        if self.stall_timeout_s == 0 {
+            None
+        } else {
+            Some(Duration::from_secs(self.stall_timeout_s))
+        }
+    }
+
+    /// Get the polling behavior for follow mode. Returns None if follow mode is off
+    pub fn get_follow_options(&self) -> Option<FollowOptions> {
+        if self.follow {
+            Some(FollowOptions {
+                poll_interval: Duration::from_millis(self.follow_poll_interval_ms),
+                timeout: Duration::from_secs(self.follow_timeout_s),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// The longest path Windows accepts without the verbatim (\\?\) prefix
+const MAX_NON_VERBATIM_PATH: usize = 260;
+
+/// Canonicalize a path if it exists, otherwise return it unchanged
+///
+/// On Windows canonicalize returns verbatim paths (\\?\C:\... or \\?\UNC\server\share\...),
+/// which many tools do not understand. These are converted back to the usual form
+/// (C:\... or \\server\share\...) unless the path is too long to be used without the prefix.
+pub fn normalize_path(path: &Path) -> PathBuf {
+    match path.canonicalize() {
+        Ok(canonical) => strip_verbatim_prefix(&canonical),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+/// Remove the verbatim prefix of a disk or UNC path if the path can be used without it
+///
+/// Paths without a verbatim prefix (including all paths on Unix) are returned unchanged.
+pub fn strip_verbatim_prefix(path: &Path) -> PathBuf {
+    let mut components = path.components();
+    let mut stripped = match components.next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::VerbatimDisk(disk) => PathBuf::from(format!("{}:", disk as char)),
+            Prefix::VerbatimUNC(server, share) => {
+                let mut unc = std::ffi::OsString::from(r"\\");
+                unc.push(server);
+                unc.push(r"\");
+                unc.push(share);
+                PathBuf::from(unc)
+            }
+            _ => return path.to_path_buf(),
+        },
+        _ => return path.to_path_buf(),
+    };
+    for component in components {
+        match component {
+            // . and .. are meaningful in a verbatim path, so it can't be converted
+            Component::CurDir | Component::ParentDir => return path.to_path_buf(),
+            _ => stripped.push(component),
+        }
+    }
+    if stripped.as_os_str().len() >= MAX_NON_VERBATIM_PATH {
+        return path.to_path_buf();
+    }
+    stripped
+}
+
+//Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_path() {
+        // Paths which don't exist are left alone
+        let missing = PathBuf::from("/no such dir/Ünïcode données/run_0001");
+        assert_eq!(normalize_path(&missing), missing);
+
+        // Paths which exist are made absolute, keeping spaces and non-ASCII characters
+        let dir = std::env::temp_dir().join(format!("attpc merger Ünïcode {}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let relative = dir.join("..").join(dir.file_name().unwrap());
+        let normalized = normalize_path(&relative);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(normalized.file_name(), dir.file_name());
+        assert!(normalized.is_absolute());
+        assert!(!normalized
+            .components()
+            .any(|c| matches!(c, Component::ParentDir)));
+    }
+
+    #[test]
+    fn test_strip_verbatim_prefix() {
+        let plain = PathBuf::from("/data/e20009 run data/run_0001");
+        assert_eq!(strip_verbatim_prefix(&plain), plain);
+        if cfg!(windows) {
+            assert_eq!(
+                strip_verbatim_prefix(Path::new(r"\\?\C:\data\run_0001")),
+                PathBuf::from(r"C:\data\run_0001")
+            );
+            assert_eq!(
+                strip_verbatim_prefix(Path::new(r"\\?\UNC\attpcstore\data\run_0001")),
+                PathBuf::from(r"\\attpcstore\data\run_0001")
+            );
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let dir = std::env::temp_dir();
+        let config = Config {
+            graw_path: dir.clone(),
+            hdf_path: dir.clone(),
+            first_run_number: 1,
+            last_run_number: 2,
+            ..Default::default()
+        };
+        assert!(config.validate().is_empty());
+        assert!(config.get_default_pad_map_warning().is_some());
+        let strict_config = Config {
+            require_explicit_map: true,
+            ..config.clone()
+        };
+        assert_eq!(
+            strict_config
+                .validate()
+                .iter()
+                .map(|issue| issue.field)
+                .collect::<Vec<_>>(),
+            vec![ConfigField::PadMapPath]
+        );
+        // The rotation needs the whole SCA
+        let rotated_config = Config {
+            correct_sca_rotation: true,
+            ..config.clone()
+        };
+        assert!(rotated_config.validate().is_empty());
+        assert_eq!(
+            Config {
+                time_buckets: 256,
+                ..rotated_config
+            }
+            .validate()
+            .iter()
+            .map(|issue| issue.field)
+            .collect::<Vec<_>>(),
+            vec![ConfigField::TimeBuckets]
+        );
+
+        let config = Config {
+            hdf_path: dir.join("attpc_merger_does_not_exist"),
+            pad_map_path: Some(dir.join("attpc_merger_does_not_exist.csv")),
+            run_database_path: Some(dir.join("attpc_merger_does_not_exist_runs.csv")),
+            first_run_number: 2,
+            last_run_number: 1,
+            n_threads: 0,
+            scaler_offset_divisor: 0,
+            max_events_per_file: Some(0),
+            max_events_per_run: Some(0),
+            max_frames_per_event: 0,
+            upload_url: Some(String::from("localhost:9000/attpc")),
+            max_output_gb: Some(0.0),
+            online_path_template: Some(String::from("/mnt/daq/{run}")),
+            fadc_bits: 17,
+            max_evt_item_bytes: 4,
+            event_layout: EventLayout::Stacked,
+            write_cobo_timestamps: true,
+            online: true,
+            online_address: Some(String::from("127.0.0.1:46005")),
+            si_merge_by_timestamp: true,
+            spill_large_events: true,
+            ..config
+        };
+        let fields: Vec<ConfigField> = config.validate().iter().map(|i| i.field).collect();
+        assert_eq!(
+            fields,
+            vec![
+                ConfigField::HdfPath,
+                ConfigField::PadMapPath,
+                ConfigField::RunDatabasePath,
+                ConfigField::RunRange,
+                ConfigField::NThreads,
+                ConfigField::ScalerOffsetDivisor,
+                ConfigField::MaxEventsPerFile,
+                ConfigField::MaxEventsPerRun,
+                ConfigField::MaxFramesPerEvent,
+                ConfigField::UploadUrl,
+                ConfigField::MaxOutputGb,
+                ConfigField::OnlinePathTemplate,
+                ConfigField::FadcBits,
+                ConfigField::MaxEvtItemBytes,
+                ConfigField::EventLayout,
+                ConfigField::SiMergeByTimestamp,
+                ConfigField::SpillThresholdFrames
+            ]
+        );
+    }
+
+    #[test]
+    fn test_online_path_template() {
+        let mut config = Config {
+            experiment: String::from("e20009"),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.get_online_cobo_directory(&3),
+            PathBuf::from("/Volumes/mm3/e20009")
+        );
+
+        config.online_path_template = Some(String::from("/mnt/daq/mm{cobo}"));
+        assert_eq!(
+            config.get_online_cobo_directory(&3),
+            PathBuf::from("/mnt/daq/mm3")
+        );
+        assert!(config
+            .validate()
+            .iter()
+            .all(|issue| issue.field != ConfigField::OnlinePathTemplate));
+
+        config.online_path_template = Some(String::from("/mnt/daq/{experiment}"));
+        assert!(config
+            .validate()
+            .iter()
+            .any(|issue| issue.field == ConfigField::OnlinePathTemplate));
+    }
+
+    #[test]
+    fn test_output_suffix() {
+        let mut config = Config {
+            hdf_path: std::env::temp_dir(),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.get_hdf_file_name(12).unwrap(),
+            std::env::temp_dir().join("run_0012.h5")
+        );
+
+        config.output_suffix = Some(String::from("new_pedestals"));
+        assert_eq!(
+            config.get_hdf_file_name(12).unwrap(),
+            std::env::temp_dir().join("run_0012_new_pedestals.h5")
+        );
+        assert!(config
+            .validate()
+            .iter()
+            .all(|issue| issue.field != ConfigField::OutputSuffix));
+
+        config.output_suffix = Some(String::from("../elsewhere"));
+        assert!(config
+            .validate()
+            .iter()
+            .any(|issue| issue.field == ConfigField::OutputSuffix));
+    }
 }
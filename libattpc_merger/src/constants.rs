@@ -7,13 +7,46 @@ pub const EXPECTED_FRAME_TYPE_PARTIAL: u16 = 1;
 pub const EXPECTED_FRAME_TYPE_FULL: u16 = 2;
 pub const SIZE_UNIT: u32 = 256;
 pub const SIZE_OF_BITSET: usize = 72;
+pub const MINIMUM_RING_ITEM_SIZE: u64 = 12; // size, type, and body header size words
+pub const DEFAULT_MAX_RING_ITEM_SIZE: u64 = 16 * 1024 * 1024; // default of Config::max_evt_item_bytes
+pub const DEFAULT_MAX_FRAMES_PER_EVENT: usize = 10_000; // default of Config::max_frames_per_event
+pub const DEFAULT_SI_COINCIDENCE_WINDOW: u64 = 10; // clock ticks, default of Config::si_coincidence_window
+pub const DEFAULT_SPILL_THRESHOLD_FRAMES: usize = 1_000; // default of Config::spill_threshold_frames
 
 // Electronics constants
 pub const NUMBER_OF_COBOS: u8 = 11; //total
 pub const COBO_WITH_TIMESTAMP: u8 = 10; // cobo with TS in sync with FRIBDAQ
+pub const TIMESTAMP_CLOCK_HZ: f64 = 100_000_000.0; // GET timestamp clock
 pub const NUMBER_OF_ASADS: u8 = 4; //per cobo
 pub const NUMBER_OF_AGETS: u8 = 4; // per asad
 pub const NUMBER_OF_CHANNELS: u8 = 68;
-pub const NUMBER_OF_TIME_BUCKETS: u32 = 512;
-pub const NUMBER_OF_MATRIX_COLUMNS: usize = NUMBER_OF_TIME_BUCKETS as usize + 5; // cobo, asad, aget, channel, pad, buckets
+pub const NUMBER_OF_TIME_BUCKETS: u32 = 512; // default, configurable
+pub const MAXIMUM_NUMBER_OF_TIME_BUCKETS: u32 = 1024;
+pub const NUMBER_OF_SCA_CELLS: u32 = 512; // cells of the circular switched capacitor array of an AGET
+pub const NUMBER_OF_SCA_OFFSET_COLUMNS: usize = 4; // cobo, asad, aget, offset
+pub const NUMBER_OF_HARDWARE_COLUMNS: usize = 5; // cobo, asad, aget, channel, pad; followed by the buckets
+pub const NUMBER_OF_FLAG_COLUMNS: usize = 3; // saturated, duplicate buckets, nonzero samples
+pub const NUMBER_OF_MULTIPLICITY_COLUMNS: usize = 5; // cobo, asad, aget, multiplicity, hit channels
+pub const NUMBER_OF_READOUT_COLUMNS: usize = 8; // cobo, asad, frames, frame_type, revision, read_offset, status, aget_mask
+pub const NUMBER_OF_QUICKLOOK_COLUMNS: usize = 3; // event, number of pads, total charge
+pub const NUMBER_OF_PAD_PEAK_COLUMNS: usize = 3; // pad, max_sample, max_bucket
+pub const NUMBER_OF_GET_INDEX_COLUMNS: usize = 9; // event, id, timestamps, and the trace and AGET rows of the event
+pub const NUMBER_OF_FRIB_PHYSICS_INDEX_COLUMNS: usize = 7; // event, id, timestamps, coincidence register, and the FADC rows
+pub const NUMBER_OF_FADC_CHANNELS: usize = 8; // SIS3300
+pub const SATURATION_VALUE: i16 = GET_ADC_MASK as i16; // largest sample of the AGET ADC
 pub const FPN_CHANNELS: [u8; 4] = [11, 22, 45, 56]; //From AGET docs
+
+// ADC bit depths of the digitizers. Samples are masked to the bit depth of their digitizer when they are read
+pub const GET_ADC_BITS: u8 = 12; // AGET samples of the pad plane, digitized by the AsAd
+pub const FADC_ADC_BITS: u8 = 12; // SIS3300 samples of the auxiliary detectors, i.e. silicon (default of Config::fadc_bits)
+pub const MAXIMUM_ADC_BITS: u8 = 16; // samples are read from 16 bit words
+pub const GET_ADC_MASK: u16 = adc_mask(GET_ADC_BITS);
+
+/// The mask of the sample bits of an ADC with the given bit depth. Bit depths above MAXIMUM_ADC_BITS keep every bit
+pub const fn adc_mask(bits: u8) -> u16 {
+    if bits >= MAXIMUM_ADC_BITS {
+        u16::MAX
+    } else {
+        ((1u32 << bits) - 1) as u16
+    }
+}
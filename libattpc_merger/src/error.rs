@@ -1,6 +1,6 @@
 use std::error::Error;
 use std::fmt::Display;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use super::constants::*;
 use super::worker_status::WorkerStatus;
@@ -99,18 +99,12 @@ impl Error for GrawFrameError {}
 
 #[derive(Debug)]
 pub enum GrawFileError {
-    BadFrame(GrawFrameError),
+    FrameAt(PathBuf, u64, GrawFrameError), // file, byte offset of the frame, what was wrong
     BadFilePath(PathBuf),
     EndOfFile,
     IOError(std::io::Error),
 }
 
-impl From<GrawFrameError> for GrawFileError {
-    fn from(value: GrawFrameError) -> Self {
-        GrawFileError::BadFrame(value)
-    }
-}
-
 impl From<std::io::Error> for GrawFileError {
     fn from(value: std::io::Error) -> Self {
         GrawFileError::IOError(value)
@@ -120,9 +114,13 @@ impl From<std::io::Error> for GrawFileError {
 impl Display for GrawFileError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            GrawFileError::BadFrame(frame) => {
-                write!(f, "Bad frame found when reading GrawFile! Error: {}", frame)
-            }
+            GrawFileError::FrameAt(path, offset, frame) => write!(
+                f,
+                "Bad frame found when reading GrawFile {} at byte offset {}! Error: {}",
+                path.display(),
+                offset,
+                frame
+            ),
             GrawFileError::BadFilePath(path) => write!(
                 f,
                 "File {} does not exist at GrawFile::new!",
@@ -145,6 +143,8 @@ pub enum EvtItemError {
     IOError(std::io::Error),
     StackOrderError,
     ItemSizeError,
+    SIS3300BufferError(u32, usize),
+    MissingSource(u32),
 }
 
 impl Display for EvtItemError {
@@ -153,6 +153,16 @@ impl Display for EvtItemError {
             Self::IOError(e) => write!(f, "Error parsing buffer into Evt Item: {}", e),
             Self::StackOrderError => write!(f, "In Physics item, module stack was out of order!"),
             Self::ItemSizeError => write!(f, "RingItem buffer has insufficent size!"),
+            Self::SIS3300BufferError(trigger, samples) => write!(
+                f,
+                "SIS3300 group trigger {:#x} is not valid for a buffer of {} samples!",
+                trigger, samples
+            ),
+            Self::MissingSource(source_id) => write!(
+                f,
+                "Event built Physics item has no fragment from source id {}!",
+                source_id
+            ),
         }
     }
 }
@@ -171,18 +181,13 @@ impl Error for EvtItemError {}
 
 #[derive(Debug)]
 pub enum EvtFileError {
-    BadItem(EvtItemError),
+    ItemAt(PathBuf, u64, EvtItemError), // file, byte offset of the item, what was wrong
+    Corrupt(PathBuf, u64, u64),         // file, byte offset of the corrupt item, bytes scanned
     BadFilePath(PathBuf),
     EndOfFile,
     IOError(std::io::Error),
 }
 
-impl From<EvtItemError> for EvtFileError {
-    fn from(value: EvtItemError) -> Self {
-        EvtFileError::BadItem(value)
-    }
-}
-
 impl From<std::io::Error> for EvtFileError {
     fn from(value: std::io::Error) -> Self {
         EvtFileError::IOError(value)
@@ -192,9 +197,20 @@ impl From<std::io::Error> for EvtFileError {
 impl Display for EvtFileError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            EvtFileError::BadItem(frame) => {
-                write!(f, "Bad item found when reading evt File! Error: {}", frame)
-            }
+            EvtFileError::ItemAt(path, offset, item) => write!(
+                f,
+                "Bad item found when reading evt File {} at byte offset {}! Error: {}",
+                path.display(),
+                offset,
+                item
+            ),
+            EvtFileError::Corrupt(path, offset, scanned) => write!(
+                f,
+                "Corrupt item found when reading evt File {} at byte offset {}, and no valid item was found in the next {} bytes!",
+                path.display(),
+                offset,
+                scanned
+            ),
             EvtFileError::BadFilePath(path) => {
                 write!(f, "File {} does not exist at EvtFile::new!", path.display())
             }
@@ -215,6 +231,8 @@ pub enum EvtStackError {
     IOError(std::io::Error),
     NoMatchingFiles,
     FileError(EvtFileError),
+    ItemAt(PathBuf, u64, EvtItemError), // file, byte offset of the item, what was wrong
+    WaitingForData,
 }
 
 impl From<EvtFileError> for EvtStackError {
@@ -234,16 +252,54 @@ impl Display for EvtStackError {
         match self {
             Self::IOError(e) => write!(f, "EvtStack recieved an io error: {}", e),
             Self::FileError(e) => write!(f, "EvtStack recieved an EvtFileError: {}", e),
+            Self::ItemAt(path, offset, e) => write!(
+                f,
+                "EvtStack could not convert the ring item in {} at byte offset {}: {}",
+                path.display(),
+                offset,
+                e
+            ),
             Self::NoMatchingFiles => write!(
                 f,
                 "EvtStack did not find any matching files in the given directory!"
             ),
+            Self::WaitingForData => write!(f, "EvtStack is waiting for more data to be written!"),
         }
     }
 }
 
 impl Error for EvtStackError {}
 
+/*
+    FribBuilder errors
+*/
+
+#[derive(Debug)]
+pub enum FribBuilderError {
+    StackError(EvtStackError),
+    NoBeginRun,
+}
+
+impl From<EvtStackError> for FribBuilderError {
+    fn from(value: EvtStackError) -> Self {
+        Self::StackError(value)
+    }
+}
+
+impl Display for FribBuilderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::StackError(e) => write!(f, "FribBuilder recieved an EvtStackError: {}", e),
+            Self::NoBeginRun => write!(
+                f,
+                "FribBuilder did not find a BeginRun item in the evt data!"
+            ),
+        }
+    }
+}
+
+impl Error for FribBuilderError {}
+
 /*
    AsadStack errors
 */
@@ -253,6 +309,7 @@ pub enum AsadStackError {
     IOError(std::io::Error),
     FileError(GrawFileError),
     NoMatchingFiles,
+    WaitingForData,
 }
 
 impl From<GrawFileError> for AsadStackError {
@@ -273,12 +330,62 @@ impl Display for AsadStackError {
             Self::IOError(e) => write!(f, "AsadStack recieved an io error: {}", e),
             Self::FileError(e) => write!(f, "AsadStack recieved a file error: {}", e),
             Self::NoMatchingFiles => write!(f, "AsadStack couldn't find any matching files!"),
+            Self::WaitingForData => write!(f, "AsadStack is waiting for more data to be written!"),
         }
     }
 }
 
 impl Error for AsadStackError {}
 
+/*
+   GrawSource errors
+*/
+
+#[derive(Debug)]
+pub enum GrawSourceError {
+    IOError(std::io::Error),
+    StackError(AsadStackError),
+    FrameAt(String, u64, GrawFrameError), // address of the DAQ, byte offset in the stream, what was wrong
+    NoFrameAvailable,
+    WaitingForData,
+}
+
+impl From<AsadStackError> for GrawSourceError {
+    fn from(value: AsadStackError) -> Self {
+        match value {
+            AsadStackError::WaitingForData => Self::WaitingForData,
+            e => Self::StackError(e),
+        }
+    }
+}
+
+impl From<std::io::Error> for GrawSourceError {
+    fn from(value: std::io::Error) -> Self {
+        Self::IOError(value)
+    }
+}
+
+impl Display for GrawSourceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IOError(e) => write!(f, "GrawSource recieved an io error: {}", e),
+            Self::StackError(e) => write!(f, "GrawSource recieved a stack error: {}", e),
+            Self::FrameAt(address, offset, e) => write!(
+                f,
+                "GrawSource recieved a bad frame from {} at byte offset {}: {}",
+                address, offset, e
+            ),
+            Self::NoFrameAvailable => write!(
+                f,
+                "GrawSource was asked for a frame before the frame metadata was queried!"
+            ),
+            Self::WaitingForData => write!(f, "GrawSource is waiting for more data!"),
+        }
+    }
+}
+
+impl Error for GrawSourceError {}
+
 /*
    PadMap errors
 */
@@ -286,8 +393,10 @@ impl Error for AsadStackError {}
 #[derive(Debug)]
 pub enum PadMapError {
     IOError(std::io::Error),
-    ParsingError(std::num::ParseIntError),
-    BadFileFormat,
+    BadLine(usize, String), // line number, what was wrong
+    HDF5Error(hdf5::Error),
+    NoEmbeddedMap(PathBuf),
+    BadEmbeddedMap(PathBuf, usize), // path, number of columns
 }
 
 impl From<std::io::Error> for PadMapError {
@@ -296,9 +405,9 @@ impl From<std::io::Error> for PadMapError {
     }
 }
 
-impl From<std::num::ParseIntError> for PadMapError {
-    fn from(value: std::num::ParseIntError) -> Self {
-        PadMapError::ParsingError(value)
+impl From<hdf5::Error> for PadMapError {
+    fn from(value: hdf5::Error) -> Self {
+        PadMapError::HDF5Error(value)
     }
 }
 
@@ -306,14 +415,105 @@ impl Display for PadMapError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             PadMapError::IOError(e) => write!(f, "PadMap recieved an io error: {}", e),
-            PadMapError::ParsingError(e) => write!(f, "PadMap error recieved a parsing error: {}", e),
-            PadMapError::BadFileFormat => write!(f, "PadMap found a bad file format while reading the map file! Expected .csv without whitespaces")
+            PadMapError::BadLine(line, reason) => write!(
+                f,
+                "PadMap found a bad line {} in the map file: {}",
+                line, reason
+            ),
+            PadMapError::HDF5Error(e) => write!(f, "PadMap recieved an HDF5 error: {}", e),
+            PadMapError::NoEmbeddedMap(path) => write!(
+                f,
+                "PadMap could not find an embedded pad map in {}; the file was merged by a version of attpc_merger which did not record the pad map",
+                path.display()
+            ),
+            PadMapError::BadEmbeddedMap(path, columns) => write!(
+                f,
+                "PadMap found an embedded pad map in {} with {} columns, expected 5 (cobo,asad,aget,channel,pad)",
+                path.display(),
+                columns
+            ),
         }
     }
 }
 
 impl Error for PadMapError {}
 
+/*
+   RunDatabase errors
+*/
+
+#[derive(Debug)]
+pub enum RunDatabaseError {
+    IOError(std::io::Error),
+    BadLine(usize, String), // line number, what was wrong
+}
+
+impl From<std::io::Error> for RunDatabaseError {
+    fn from(value: std::io::Error) -> Self {
+        RunDatabaseError::IOError(value)
+    }
+}
+
+impl Display for RunDatabaseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RunDatabaseError::IOError(e) => write!(f, "RunDatabase recieved an io error: {}", e),
+            RunDatabaseError::BadLine(line, reason) => write!(
+                f,
+                "RunDatabase found a bad line {} in the runs database: {}",
+                line, reason
+            ),
+        }
+    }
+}
+
+impl Error for RunDatabaseError {}
+
+/*
+   Pedestal errors
+*/
+
+#[derive(Debug)]
+pub enum PedestalError {
+    IOError(std::io::Error),
+    ParsingError(std::num::ParseIntError),
+    ParsingFloatError(std::num::ParseFloatError),
+    BadFileFormat,
+    ValueOutOfRange(usize, f64), // pad, pedestal mean
+}
+
+impl From<std::io::Error> for PedestalError {
+    fn from(value: std::io::Error) -> Self {
+        PedestalError::IOError(value)
+    }
+}
+
+impl From<std::num::ParseIntError> for PedestalError {
+    fn from(value: std::num::ParseIntError) -> Self {
+        PedestalError::ParsingError(value)
+    }
+}
+
+impl From<std::num::ParseFloatError> for PedestalError {
+    fn from(value: std::num::ParseFloatError) -> Self {
+        PedestalError::ParsingFloatError(value)
+    }
+}
+
+impl Display for PedestalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PedestalError::IOError(e) => write!(f, "Pedestals recieved an io error: {}", e),
+            PedestalError::ParsingError(e) => write!(f, "Pedestals recieved a parsing error: {}", e),
+            PedestalError::ParsingFloatError(e) => write!(f, "Pedestals recieved a parsing error: {}", e),
+            PedestalError::BadFileFormat => write!(f, "Pedestals found a bad file format while reading the pedestal file! Expected .csv with columns pad,pedestal_mean"),
+            PedestalError::ValueOutOfRange(pad, mean) => write!(f, "Pedestals found a pedestal of {} for pad {}, which is out of the range of a sample!", mean, pad),
+        }
+    }
+}
+
+impl Error for PedestalError {}
+
 /*
    Event errors
 */
@@ -322,13 +522,19 @@ impl Error for PadMapError {}
 pub enum EventError {
     InvalidHardware(u8, u8, u8, u8),
     MismatchedEventID(u32, u32),
+    MismatchedTimeBuckets(usize, usize),
+    TimeBucketOutOfRange(u16, usize),
+    DuplicateTimeBucket(u32, usize, u16),
 }
 
 impl Display for EventError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             EventError::InvalidHardware(cb, ad, ag, ch) => write!(f, "Event found hardware which does not correspond to a valid pad! CoBo: {}, AsAd: {}, AGET: {}, Channel: {}", cb, ad, ag, ch),
-            EventError::MismatchedEventID(given, exp) => write!(f, "Event was given a mismatched event id! Given: {}, Expected: {}", given, exp)
+            EventError::MismatchedEventID(given, exp) => write!(f, "Event was given a mismatched event id! Given: {}, Expected: {}", given, exp),
+            EventError::MismatchedTimeBuckets(given, exp) => write!(f, "Event was given an event with a mismatched number of time buckets! Given: {}, Expected: {}", given, exp),
+            EventError::TimeBucketOutOfRange(bucket, n_buckets) => write!(f, "Event found time bucket {} but only {} time buckets are configured! Check that the time_buckets configuration matches the CoBo configuration.", bucket, n_buckets),
+            EventError::DuplicateTimeBucket(event, pad, bucket) => write!(f, "Event {} wrote time bucket {} of pad {} more than once! The duplicate_bucket_policy is Error.", event, bucket, pad),
         }
     }
 }
@@ -342,9 +548,12 @@ impl Error for EventError {}
 #[derive(Debug)]
 pub enum MergerError {
     AsadError(AsadStackError),
+    SourceError(GrawSourceError),
     NoFilesError,
+    WaitingForData,
     IOError(std::io::Error),
     ConfigError(ConfigError),
+    BadData(u8, u8, u32, u64), // CoBo, AsAd, event id, number of bad data in the frame
 }
 
 impl From<AsadStackError> for MergerError {
@@ -353,6 +562,12 @@ impl From<AsadStackError> for MergerError {
     }
 }
 
+impl From<GrawSourceError> for MergerError {
+    fn from(value: GrawSourceError) -> Self {
+        MergerError::SourceError(value)
+    }
+}
+
 impl From<std::io::Error> for MergerError {
     fn from(value: std::io::Error) -> Self {
         MergerError::IOError(value)
@@ -371,13 +586,24 @@ impl Display for MergerError {
             MergerError::AsadError(e) => {
                 write!(f, "A stack error occurred while merging! Error: {}", e)
             }
+            MergerError::SourceError(e) => {
+                write!(f, "A source error occurred while merging! Error: {}", e)
+            }
             MergerError::NoFilesError => {
                 write!(f, "Merger could not find any files with .graw extension!")
             }
+            MergerError::WaitingForData => {
+                write!(f, "The merger is waiting for more data to be written!")
+            }
             MergerError::IOError(e) => write!(f, "The merger recieved an io error: {}", e),
             MergerError::ConfigError(e) => {
                 write!(f, "The merger encountered a config error: {}", e)
             }
+            MergerError::BadData(cobo, asad, event, count) => write!(
+                f,
+                "The frame of event {} from CoBo {} AsAd {} had {} data out of the range of the hardware, and the bad_data_policy is Fail!",
+                event, cobo, asad, count
+            ),
         }
     }
 }
@@ -392,6 +618,8 @@ impl Error for MergerError {}
 pub enum EventBuilderError {
     EventOutOfOrder(u32, u32),
     EventError(EventError),
+    EventTooLarge(u32, usize), // event id, number of frames
+    SpillError(std::io::Error),
 }
 
 impl From<EventError> for EventBuilderError {
@@ -404,19 +632,102 @@ impl Display for EventBuilderError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::EventOutOfOrder(frame, event) => write!(f, "The event builder recieved a frame that is out of order -- frame event id: {} event builder event id: {}", frame, event),
-            Self::EventError(val) => write!(f, "The EventBuilder recieved an event error: {}", val)
+            Self::EventError(val) => write!(f, "The EventBuilder recieved an event error: {}", val),
+            Self::EventTooLarge(event, frames) => write!(f, "The EventBuilder recieved more than {} frames for event {}! The event id may be stuck.", frames, event),
+            Self::SpillError(e) => write!(f, "The EventBuilder could not spill the frames of a large event to disk: {}", e),
         }
     }
 }
 
 impl Error for EventBuilderError {}
 
+/*
+   Format errors
+*/
+
+#[derive(Debug)]
+pub enum FormatError {
+    HDF5Error(hdf5::Error),
+    BadVersion(String),
+}
+
+impl From<hdf5::Error> for FormatError {
+    fn from(value: hdf5::Error) -> Self {
+        Self::HDF5Error(value)
+    }
+}
+
+impl Display for FormatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::HDF5Error(e) => write!(f, "Format recieved an HDF5 error: {}", e),
+            Self::BadVersion(version) => write!(
+                f,
+                "Format could not parse the version {version}, expected name:major.minor"
+            ),
+        }
+    }
+}
+
+impl Error for FormatError {}
+
+/*
+   Upload errors
+*/
+
+#[derive(Debug)]
+pub enum UploadError {
+    IOError(std::io::Error),
+    BadUrl(String),
+    S3Error(String),
+    SizeMismatch(PathBuf, u64, u64),
+    PartsMismatch(PathBuf, String, usize),
+}
+
+impl From<std::io::Error> for UploadError {
+    fn from(value: std::io::Error) -> Self {
+        Self::IOError(value)
+    }
+}
+
+impl Display for UploadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IOError(e) => write!(f, "Upload recieved an io error: {}", e),
+            Self::BadUrl(url) => write!(
+                f,
+                "Upload could not parse the url {url}, expected http(s)://host[:port]/bucket[/prefix]"
+            ),
+            Self::S3Error(e) => write!(f, "Upload recieved an object store error: {}", e),
+            Self::SizeMismatch(path, local, remote) => write!(
+                f,
+                "Upload of {} could not be verified: the file has {} bytes but the object has {} bytes",
+                path.display(),
+                local,
+                remote
+            ),
+            Self::PartsMismatch(path, etag, parts) => write!(
+                f,
+                "Upload of {} could not be verified: the object etag {} does not match the {} parts sent",
+                path.display(),
+                etag,
+                parts
+            ),
+        }
+    }
+}
+
+impl Error for UploadError {}
+
 // HDF5Writer Error
 #[derive(Debug)]
 pub enum HDF5WriterError {
     HDF5Error(hdf5::Error),
     IOError(std::io::Error),
     ParsingError(serde_yaml::Error),
+    IncompatibleVersion(String, String),
+    MixedEventNaming(String, String),
+    MixedEventLayout(String, String),
 }
 
 impl From<std::io::Error> for HDF5WriterError {
@@ -442,6 +753,18 @@ impl Display for HDF5WriterError {
         match self {
             Self::HDF5Error(e) => write!(f, "HDF5Writer recieved an HDF5 error: {}", e),
             Self::IOError(e) => write!(f, "HDF5Writer recieved an IO error: {}", e),
+            Self::IncompatibleVersion(found, expected) => write!(
+                f,
+                "HDF5Writer can not append to a file of version {found}, only to files of version {expected}"
+            ),
+            Self::MixedEventNaming(found, requested) => write!(
+                f,
+                "HDF5Writer can not write {requested} numbered events to a file which already has {found} numbered events"
+            ),
+            Self::MixedEventLayout(found, requested) => write!(
+                f,
+                "HDF5Writer can not write events in the {requested} layout to a file which has events in the {found} layout"
+            ),
             Self::ParsingError(e) => {
                 write!(f, "HDFWriter recieved an error converting to yaml: {e}")
             }
@@ -451,6 +774,31 @@ impl Display for HDF5WriterError {
 
 impl Error for HDF5WriterError {}
 
+/*
+   EventSink errors
+*/
+
+#[derive(Debug)]
+pub enum EventSinkError {
+    HDF5WriterError(HDF5WriterError),
+}
+
+impl From<HDF5WriterError> for EventSinkError {
+    fn from(value: HDF5WriterError) -> Self {
+        Self::HDF5WriterError(value)
+    }
+}
+
+impl Display for EventSinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::HDF5WriterError(e) => write!(f, "EventSink recieved an HDF5Writer error: {}", e),
+        }
+    }
+}
+
+impl Error for EventSinkError {}
+
 /*
    Config errors
 */
@@ -492,11 +840,43 @@ pub enum ProcessorError {
     EVBError(EventBuilderError),
     MergerError(MergerError),
     HDFError(HDF5WriterError),
+    SinkError(EventSinkError),
     ConfigError(ConfigError),
     MapError(PadMapError),
+    PedestalError(PedestalError),
+    RunDatabaseError(RunDatabaseError),
     EvtError(EvtStackError),
     BadRingConversion(EvtItemError),
     SendError(std::sync::mpsc::SendError<WorkerStatus>),
+    PostMergeError(std::io::Error),
+    ChecksumError(std::io::Error),
+    InsufficientDiskSpace(PathBuf, u64, u64),
+    PartialOutputError(PathBuf, std::io::Error),
+    RunError(i32, Box<ProcessorError>), // run number, what went wrong
+    FileError(PathBuf, Box<ProcessorError>), // file being read or written, what went wrong
+    Stopped,                            // a stop was requested before the run was finished
+}
+
+impl ProcessorError {
+    /// Attach the number of the run being processed to the error, so that it shows up in the logs.
+    ///
+    /// Errors which already have a run attached are left as is
+    pub fn with_run(self, run_number: i32) -> Self {
+        match self {
+            Self::RunError(_, _) => self,
+            e => Self::RunError(run_number, Box::new(e)),
+        }
+    }
+
+    /// Attach the path of the file being read or written to the error, so that it shows up in the logs.
+    ///
+    /// Errors which already have a file or a run attached are left as is
+    pub fn with_path(self, path: &Path) -> Self {
+        match self {
+            Self::FileError(_, _) | Self::RunError(_, _) => self,
+            e => Self::FileError(path.to_path_buf(), Box::new(e)),
+        }
+    }
 }
 
 impl From<MergerError> for ProcessorError {
@@ -517,6 +897,12 @@ impl From<HDF5WriterError> for ProcessorError {
     }
 }
 
+impl From<EventSinkError> for ProcessorError {
+    fn from(value: EventSinkError) -> Self {
+        Self::SinkError(value)
+    }
+}
+
 impl From<ConfigError> for ProcessorError {
     fn from(value: ConfigError) -> Self {
         Self::ConfigError(value)
@@ -529,6 +915,18 @@ impl From<PadMapError> for ProcessorError {
     }
 }
 
+impl From<PedestalError> for ProcessorError {
+    fn from(value: PedestalError) -> Self {
+        Self::PedestalError(value)
+    }
+}
+
+impl From<RunDatabaseError> for ProcessorError {
+    fn from(value: RunDatabaseError) -> Self {
+        Self::RunDatabaseError(value)
+    }
+}
+
 impl From<EvtStackError> for ProcessorError {
     fn from(value: EvtStackError) -> Self {
         Self::EvtError(value)
@@ -553,8 +951,13 @@ impl Display for ProcessorError {
             Self::EVBError(e) => write!(f, "Processor failed at Event Builder with error: {}", e),
             Self::MergerError(e) => write!(f, "Processor failed at Merger with error: {}", e),
             Self::HDFError(e) => write!(f, "Processor failed at HDFWriter with error: {}", e),
+            Self::SinkError(e) => write!(f, "Processor failed at output with error: {}", e),
             Self::ConfigError(e) => write!(f, "Processor failed due to Configuration error: {}", e),
             Self::MapError(e) => write!(f, "Processor failed due to PadMap error: {}", e),
+            Self::PedestalError(e) => write!(f, "Processor failed due to Pedestal error: {}", e),
+            Self::RunDatabaseError(e) => {
+                write!(f, "Processor failed due to runs database error: {}", e)
+            }
             Self::EvtError(e) => write!(f, "Processor failed due to evt stack error: {}", e),
             Self::BadRingConversion(e) => {
                 write!(f, "Processor failed due to bad ring item conversion: {}", e)
@@ -562,6 +965,31 @@ impl Display for ProcessorError {
             Self::SendError(e) => {
                 write!(f, "Processor failed to send status: {}", e)
             }
+            Self::PostMergeError(e) => {
+                write!(f, "Processor failed applying the post-merge action: {}", e)
+            }
+            Self::ChecksumError(e) => {
+                write!(f, "Processor failed writing the checksum of the output: {}", e)
+            }
+            Self::InsufficientDiskSpace(path, required, available) => write!(
+                f,
+                "Not enough disk space in {} to merge the runs! About {} is required, but only {} is available.",
+                path.display(),
+                human_bytes::human_bytes(*required as f64),
+                human_bytes::human_bytes(*available as f64)
+            ),
+            Self::PartialOutputError(path, e) => write!(
+                f,
+                "Processor failed to clean up the partial output file {}: {}",
+                path.display(),
+                e
+            ),
+            Self::RunError(run_number, e) => write!(f, "Run {} failed! {}", run_number, e),
+            Self::FileError(path, e) => write!(f, "Error with file {}: {}", path.display(), e),
+            Self::Stopped => write!(
+                f,
+                "Processor was stopped before the run was finished, the output only has part of the run"
+            ),
         }
     }
 }
@@ -1,10 +1,108 @@
+use bitvec::prelude::*;
 use fxhash::FxHashMap;
 use ndarray::{s, Array1, Array2};
+use std::collections::BTreeMap;
 
+use super::config::DuplicateBucketPolicy;
 use super::constants::*;
 use super::error::EventError;
-use super::graw_frame::GrawFrame;
+use super::graw_frame::{FrameProvenance, GrawFrame, GrawFrameHeader};
 use super::pad_map::{HardwareID, PadMap};
+use super::pedestals::Pedestals;
+
+/// A single trace along with a record of which time buckets have been written
+#[derive(Debug, Clone)]
+struct Trace {
+    samples: Array1<i16>,
+    written_buckets: BitVec<u8>,
+    has_duplicate_buckets: bool,
+}
+
+impl Trace {
+    /// Make an empty trace with the given number of time buckets
+    fn new(time_buckets: usize) -> Self {
+        Trace {
+            samples: Array1::<i16>::zeros(time_buckets),
+            written_buckets: bitvec![u8, Lsb0; 0; time_buckets],
+            has_duplicate_buckets: false,
+        }
+    }
+
+    /// Write a sample to a time bucket, noting if the bucket was already written
+    ///
+    /// If the bucket was already written, the policy decides how the samples are combined. Returns false if the
+    /// policy is Error and the bucket was already written, in which case the trace is not modified.
+    fn set_sample(
+        &mut self,
+        time_bucket_id: usize,
+        sample: i16,
+        policy: DuplicateBucketPolicy,
+    ) -> bool {
+        if !self.written_buckets[time_bucket_id] {
+            self.written_buckets.set(time_bucket_id, true);
+            self.samples[time_bucket_id] = sample;
+            return true;
+        }
+
+        self.has_duplicate_buckets = true;
+        match policy {
+            DuplicateBucketPolicy::Overwrite => self.samples[time_bucket_id] = sample,
+            DuplicateBucketPolicy::Sum => {
+                self.samples[time_bucket_id] = self.samples[time_bucket_id].saturating_add(sample)
+            }
+            DuplicateBucketPolicy::Error => return false,
+        }
+        true
+    }
+
+    /// Quality flags for the trace: saturated, duplicate buckets, number of nonzero samples
+    fn get_flags(&self) -> [u16; NUMBER_OF_FLAG_COLUMNS] {
+        let is_saturated = self.samples.iter().any(|s| *s == SATURATION_VALUE);
+        let n_nonzero = self.samples.iter().filter(|s| **s != 0).count();
+        [
+            is_saturated as u16,
+            self.has_duplicate_buckets as u16,
+            n_nonzero as u16,
+        ]
+    }
+}
+
+/// The readout metadata of the frames of an AsAd in an event, from the frame headers
+#[derive(Debug, Clone, Copy, Default)]
+struct AsadReadout {
+    frames: u64,
+    frame_type: u16,  // of the last frame
+    revision: u8,     // of the last frame
+    read_offset: u16, // of the last frame
+    status: u8,       // the status bits set in any of the frames
+    aget_mask: u8,    // bit n is set if any channel of AGET n was set in a hit pattern
+}
+
+impl AsadReadout {
+    /// Add the header of a frame of the AsAd
+    fn add_frame(&mut self, header: &GrawFrameHeader) {
+        self.frames += 1;
+        self.frame_type = header.frame_type;
+        self.revision = header.revision;
+        self.read_offset = header.read_offset;
+        self.status |= header.status;
+        for (aget, pattern) in header.hit_patterns.iter().enumerate() {
+            if pattern.any() {
+                self.aget_mask |= 1 << aget;
+            }
+        }
+    }
+
+    /// Combine with the readout of the same AsAd from another part of the event
+    fn merge(&mut self, other: &AsadReadout) {
+        self.frames += other.frames;
+        self.frame_type = other.frame_type;
+        self.revision = other.revision;
+        self.read_offset = other.read_offset;
+        self.status |= other.status;
+        self.aget_mask |= other.aget_mask;
+    }
+}
 
 /// # Event
 /// An event is a collection of traces which all occured with the same Event ID generated by the AT-TPC DAQ.
@@ -13,21 +111,52 @@ use super::pad_map::{HardwareID, PadMap};
 #[derive(Debug)]
 pub struct Event {
     nframes: i32,
-    traces: FxHashMap<HardwareID, Array1<i16>>, //maps pad id to the trace for that pad
+    time_buckets: usize,
+    duplicate_bucket_policy: DuplicateBucketPolicy,
+    traces: FxHashMap<HardwareID, Trace>, //maps pad id to the trace for that pad
+    pad_sources: FxHashMap<usize, HardwareID>, // pad -> the first hardware whose trace had that pad
+    duplicate_pads: Vec<(HardwareID, HardwareID)>, // (first hardware, other hardware) of pads read from two channels
     pub timestamp: u64,
     pub timestampother: u64,
     pub event_id: u32,
+    pub raw_timestamp: Option<u64>, // the timestamp read from the frames, only set if the timestamp was corrected
+    pub provenance: Vec<FrameProvenance>, // only filled if the EventBuilder records frame provenance
+    pub frame_headers: Vec<GrawFrameHeader>, // only filled if the EventBuilder records frame headers
+    cobo_timestamps: BTreeMap<u8, (u64, u64)>, // cobo -> (event_time of first frame, event_time of last frame)
+    aget_multiplicity: BTreeMap<(u8, u8, u8), (u64, u64)>, // (cobo, asad, aget) -> (multiplicity, hit channels)
+    asad_readout: BTreeMap<(u8, u8), AsadReadout>, // (cobo, asad) -> readout metadata of its frames
+    correct_sca_rotation: bool, // rotate the time buckets of each AGET by the offset of its last cell
+    sca_offsets: BTreeMap<(u8, u8, u8), u16>, // (cobo, asad, aget) -> offset applied, of the last frame
 }
 
 impl Event {
-    /// Make a new event from a list of GrawFrames
-    pub fn new(pad_map: &PadMap, frames: &Vec<GrawFrame>) -> Result<Self, EventError> {
+    /// Make a new event from a list of GrawFrames, with traces of the given number of time buckets
+    ///
+    /// Time buckets written more than once are handled according to the duplicate_bucket_policy.
+    pub fn new(
+        pad_map: &PadMap,
+        frames: &Vec<GrawFrame>,
+        time_buckets: usize,
+        duplicate_bucket_policy: DuplicateBucketPolicy,
+    ) -> Result<Self, EventError> {
         let mut event = Event {
             nframes: 0,
+            time_buckets,
+            duplicate_bucket_policy,
             traces: FxHashMap::default(),
+            pad_sources: FxHashMap::default(),
+            duplicate_pads: Vec::new(),
             timestamp: 0,
             timestampother: 0,
             event_id: 0,
+            raw_timestamp: None,
+            provenance: Vec::new(),
+            frame_headers: Vec::new(),
+            cobo_timestamps: BTreeMap::new(),
+            aget_multiplicity: BTreeMap::new(),
+            asad_readout: BTreeMap::new(),
+            correct_sca_rotation: false,
+            sca_offsets: BTreeMap::new(),
         };
         for frame in frames {
             event.append_frame(pad_map, frame)?;
@@ -36,20 +165,273 @@ impl Event {
         Ok(event)
     }
 
+    /// Rotate the time buckets of the frames appended from now on to undo the rotation of the circular SCA of the AGETs
+    ///
+    /// Each time bucket t of an AGET is moved to (t - offset) mod NUMBER_OF_SCA_CELLS, where offset is given by the last
+    /// cell of the AGET in the frame header (see GrawFrameHeader::get_sca_offset), so that the oldest sample comes first.
+    /// The offsets applied are recorded, see get_sca_offsets. Off by default.
+    pub fn set_sca_rotation_correction(&mut self, correct_sca_rotation: bool) {
+        self.correct_sca_rotation = correct_sca_rotation;
+    }
+
     /// Convert the event traces to a data matrix for writing to disk. Follows format used by AT-TPC analysis
-    pub fn convert_to_data_matrix(self) -> Array2<i16> {
-        let mut data_matrix = Array2::<i16>::zeros([self.traces.len(), NUMBER_OF_MATRIX_COLUMNS]);
-        for (row, (hw_id, trace)) in self.traces.into_iter().enumerate() {
+    ///
+    /// If pedestals are given, the pedestal for each pad is subtracted from every sample of that pad's trace.
+    /// Samples are clamped at i16::MIN.
+    ///
+    /// Also returns a matrix of quality flags with rows aligned to the data matrix. The columns are:
+    /// saturated (any sample at the ADC maximum), duplicate buckets (any time bucket written twice), and the number of nonzero samples.
+    /// Flags are evaluated before pedestal subtraction.
+    ///
+    /// The rows are ordered by pad number (then by hardware address, for a pad read from more than one channel),
+    /// so that merging the same data always writes the same bytes.
+    pub fn convert_to_data_matrix(
+        self,
+        mut pedestals: Option<&mut Pedestals>,
+    ) -> (Array2<i16>, Array2<u16>) {
+        let n_columns = NUMBER_OF_HARDWARE_COLUMNS + self.time_buckets;
+        let mut traces: Vec<(HardwareID, Trace)> = self.traces.into_iter().collect();
+        traces.sort_unstable_by_key(|(hw_id, _)| {
+            (
+                hw_id.pad_id,
+                hw_id.cobo_id,
+                hw_id.asad_id,
+                hw_id.aget_id,
+                hw_id.channel,
+            )
+        });
+        let mut data_matrix = Array2::<i16>::zeros([traces.len(), n_columns]);
+        let mut flag_matrix = Array2::<u16>::zeros([traces.len(), NUMBER_OF_FLAG_COLUMNS]);
+        for (row, (hw_id, trace)) in traces.into_iter().enumerate() {
+            for (col, flag) in trace.get_flags().into_iter().enumerate() {
+                flag_matrix[[row, col]] = flag;
+            }
+            let mut samples = trace.samples;
+            if let Some(peds) = pedestals.as_deref_mut() {
+                let pedestal = peds.get_pedestal(&hw_id.pad_id);
+                samples.mapv_inplace(|sample| sample.saturating_sub(pedestal));
+            }
             data_matrix[[row, 0]] = hw_id.cobo_id as i16;
             data_matrix[[row, 1]] = hw_id.asad_id as i16;
             data_matrix[[row, 2]] = hw_id.aget_id as i16;
             data_matrix[[row, 3]] = hw_id.channel as i16;
             data_matrix[[row, 4]] = hw_id.pad_id as i16;
-            let mut trace_slice = data_matrix.slice_mut(s![row, 5..NUMBER_OF_MATRIX_COLUMNS]);
-            trace.move_into(&mut trace_slice);
+            let mut trace_slice =
+                data_matrix.slice_mut(s![row, NUMBER_OF_HARDWARE_COLUMNS..n_columns]);
+            samples.move_into(&mut trace_slice);
+        }
+
+        (data_matrix, flag_matrix)
+    }
+
+    /// Check if a frame of the CoBo with the timestamp in sync with FRIBDAQ (COBO_WITH_TIMESTAMP) set the timestampother
+    pub fn has_timestampother(&self) -> bool {
+        self.cobo_timestamps.contains_key(&COBO_WITH_TIMESTAMP)
+    }
+
+    /// The event_time of the last frame of the given CoBo, or None if the CoBo contributed no frames to the event
+    pub fn get_cobo_timestamp(&self, cobo: u8) -> Option<u64> {
+        self.cobo_timestamps
+            .get(&cobo)
+            .map(|(_, last_time)| *last_time)
+    }
+
+    /// The timestamps recorded by each CoBo which contributed frames to the event.
+    ///
+    /// One row per CoBo, ordered by CoBo id. The columns are: cobo, event_time of the first frame, event_time of the last frame.
+    /// Useful for debugging clock drift between the CoBos.
+    pub fn get_cobo_timestamps(&self) -> Array2<u64> {
+        let mut matrix = Array2::<u64>::zeros([self.cobo_timestamps.len(), 3]);
+        for (row, (cobo, (first_time, last_time))) in self.cobo_timestamps.iter().enumerate() {
+            matrix[[row, 0]] = *cobo as u64;
+            matrix[[row, 1]] = *first_time;
+            matrix[[row, 2]] = *last_time;
+        }
+        matrix
+    }
+
+    /// The multiplicity and hit pattern of each AGET which contributed frames to the event, summed over its frames.
+    ///
+    /// One row per AGET, ordered by CoBo, AsAd, and AGET. The columns are: cobo, asad, aget, multiplicity (the number of
+    /// channels over threshold), and hit channels (the number of channels set in the hit pattern). Useful for
+    /// cross-checking the zero suppression and finding dead AGETs.
+    pub fn get_aget_multiplicity(&self) -> Array2<u64> {
+        let mut matrix = Array2::<u64>::zeros([self.aget_multiplicity.len(), 5]);
+        for (row, ((cobo, asad, aget), (multiplicity, hits))) in
+            self.aget_multiplicity.iter().enumerate()
+        {
+            matrix[[row, 0]] = *cobo as u64;
+            matrix[[row, 1]] = *asad as u64;
+            matrix[[row, 2]] = *aget as u64;
+            matrix[[row, 3]] = *multiplicity;
+            matrix[[row, 4]] = *hits;
+        }
+        matrix
+    }
+
+    /// The readout metadata of each AsAd which contributed frames to the event, from the frame headers.
+    ///
+    /// One row per AsAd, ordered by CoBo and AsAd. The columns are: cobo, asad, frames, frame_type, revision,
+    /// read_offset, status, and aget_mask. frame_type, revision and read_offset are of the last frame of the AsAd, status
+    /// has the bits set in any of its frames, and bit n of aget_mask is set if AGET n had any channel in a hit pattern.
+    /// Useful for finding AGETs which dropped out of a partial readout.
+    pub fn get_readout_info(&self) -> Array2<u64> {
+        let mut matrix = Array2::<u64>::zeros([self.asad_readout.len(), NUMBER_OF_READOUT_COLUMNS]);
+        for (row, ((cobo, asad), readout)) in self.asad_readout.iter().enumerate() {
+            let values = [
+                *cobo as u64,
+                *asad as u64,
+                readout.frames,
+                readout.frame_type as u64,
+                readout.revision as u64,
+                readout.read_offset as u64,
+                readout.status as u64,
+                readout.aget_mask as u64,
+            ];
+            for (col, value) in values.into_iter().enumerate() {
+                matrix[[row, col]] = value;
+            }
+        }
+        matrix
+    }
+
+    /// The SCA rotation offset applied to each AGET which contributed data to the event, if the rotation is corrected.
+    ///
+    /// One row per AGET, ordered by CoBo, AsAd, and AGET. The columns are: cobo, asad, aget, and the offset (of the last
+    /// frame of the AGET). Empty if set_sca_rotation_correction was not set.
+    pub fn get_sca_offsets(&self) -> Array2<u64> {
+        let mut matrix =
+            Array2::<u64>::zeros([self.sca_offsets.len(), NUMBER_OF_SCA_OFFSET_COLUMNS]);
+        for (row, ((cobo, asad, aget), offset)) in self.sca_offsets.iter().enumerate() {
+            matrix[[row, 0]] = *cobo as u64;
+            matrix[[row, 1]] = *asad as u64;
+            matrix[[row, 2]] = *aget as u64;
+            matrix[[row, 3]] = *offset as u64;
+        }
+        matrix
+    }
+
+    /// The pads which had traces from more than one hardware address in this event, which means the pad map maps
+    /// several channels to the same pad.
+    ///
+    /// Each entry is the hardware of the first trace of the pad and the hardware of a later trace of the same pad.
+    /// The traces are kept apart (each is a row of the data matrix), but share a pad number.
+    pub fn get_duplicate_pads(&self) -> &[(HardwareID, HardwareID)] {
+        &self.duplicate_pads
+    }
+
+    /// Record the hardware of a new trace, noting if its pad already has a trace from other hardware
+    fn add_pad_source(&mut self, hw_id: &HardwareID) {
+        match self.pad_sources.get(&hw_id.pad_id) {
+            Some(first) => self.duplicate_pads.push((first.clone(), hw_id.clone())),
+            None => {
+                self.pad_sources.insert(hw_id.pad_id, hw_id.clone());
+            }
+        }
+    }
+
+    /// Merge a partially built event from another source (i.e. a separate GRAW stream) into this one
+    ///
+    /// The events must have the same event id and number of time buckets. Traces of pads only in the other event are added,
+    /// and the samples of pads in both events are combined according to the duplicate_bucket_policy. If both events have a
+    /// timestamp and they disagree, a warning is logged and the timestamp of this event is kept.
+    pub fn merge(&mut self, other: Event) -> Result<(), EventError> {
+        if other.nframes == 0 {
+            return Ok(());
+        }
+        if self.nframes == 0 {
+            self.event_id = other.event_id;
+        } else if self.event_id != other.event_id {
+            return Err(EventError::MismatchedEventID(other.event_id, self.event_id));
+        }
+        if self.time_buckets != other.time_buckets {
+            return Err(EventError::MismatchedTimeBuckets(
+                other.time_buckets,
+                self.time_buckets,
+            ));
+        }
+
+        let self_has_timestamp = self
+            .cobo_timestamps
+            .keys()
+            .any(|cobo| *cobo != COBO_WITH_TIMESTAMP);
+        let other_has_timestamp = other
+            .cobo_timestamps
+            .keys()
+            .any(|cobo| *cobo != COBO_WITH_TIMESTAMP);
+        if !self_has_timestamp {
+            self.timestamp = other.timestamp;
+        } else if other_has_timestamp && self.timestamp != other.timestamp {
+            spdlog::warn!(
+                "Merging event {} with disagreeing timestamps {} and {}, keeping {}",
+                self.event_id,
+                self.timestamp,
+                other.timestamp,
+                self.timestamp
+            );
         }
+        let self_has_timestampother = self.cobo_timestamps.contains_key(&COBO_WITH_TIMESTAMP);
+        let other_has_timestampother = other.cobo_timestamps.contains_key(&COBO_WITH_TIMESTAMP);
+        if !self_has_timestampother {
+            self.timestampother = other.timestampother;
+        } else if other_has_timestampother && self.timestampother != other.timestampother {
+            spdlog::warn!(
+                "Merging event {} with disagreeing timestampother {} and {}, keeping {}",
+                self.event_id,
+                self.timestampother,
+                other.timestampother,
+                self.timestampother
+            );
+        }
+        for (cobo, (first_time, last_time)) in other.cobo_timestamps {
+            self.cobo_timestamps
+                .entry(cobo)
+                .and_modify(|(_, last)| *last = last_time)
+                .or_insert((first_time, last_time));
+        }
+        for (aget, (multiplicity, hits)) in other.aget_multiplicity {
+            let entry = self.aget_multiplicity.entry(aget).or_default();
+            entry.0 += multiplicity;
+            entry.1 += hits;
+        }
+        for (asad, readout) in other.asad_readout {
+            self.asad_readout.entry(asad).or_default().merge(&readout);
+        }
+        self.sca_offsets.extend(other.sca_offsets);
 
-        data_matrix
+        for (hw_id, other_trace) in other.traces {
+            let trace = match self.traces.get_mut(&hw_id) {
+                Some(trace) => trace,
+                None => {
+                    self.add_pad_source(&hw_id);
+                    self.traces.insert(hw_id, other_trace);
+                    continue;
+                }
+            };
+            for time_bucket_id in other_trace.written_buckets.iter_ones() {
+                if !trace.set_sample(
+                    time_bucket_id,
+                    other_trace.samples[time_bucket_id],
+                    self.duplicate_bucket_policy,
+                ) {
+                    return Err(EventError::DuplicateTimeBucket(
+                        self.event_id,
+                        hw_id.pad_id,
+                        time_bucket_id as u16,
+                    ));
+                }
+            }
+            trace.has_duplicate_buckets |= other_trace.has_duplicate_buckets;
+        }
+
+        if self.raw_timestamp.is_none() {
+            self.raw_timestamp = other.raw_timestamp;
+        }
+        self.provenance.extend(other.provenance);
+        self.frame_headers.extend(other.frame_headers);
+        self.nframes += other.nframes;
+
+        Ok(())
     }
 
     // Formated header array
@@ -64,8 +446,9 @@ impl Event {
 
     /// Add a frame to the event.
     ///
-    /// If the frame does not belong to this event, an error is returned
-    fn append_frame(&mut self, pad_map: &PadMap, frame: &GrawFrame) -> Result<(), EventError> {
+    /// If the frame does not belong to this event, has data beyond the configured time buckets, or writes a time bucket twice
+    /// when the duplicate_bucket_policy is Error, an error is returned
+    pub fn append_frame(&mut self, pad_map: &PadMap, frame: &GrawFrame) -> Result<(), EventError> {
         // Check if this is the first frame or that the event id's match
         if self.nframes == 0 {
             self.event_id = frame.header.event_id;
@@ -83,6 +466,28 @@ impl Event {
             // all other cobos have the same TS from Mutant
             self.timestamp = frame.header.event_time;
         }
+        self.cobo_timestamps
+            .entry(frame.header.cobo_id)
+            .and_modify(|(_, last_time)| *last_time = frame.header.event_time)
+            .or_insert((frame.header.event_time, frame.header.event_time));
+        for (aget, (multiplicity, hits)) in frame
+            .header
+            .multiplicity
+            .iter()
+            .zip(frame.header.get_hit_counts())
+            .enumerate()
+        {
+            let entry = self
+                .aget_multiplicity
+                .entry((frame.header.cobo_id, frame.header.asad_id, aget as u8))
+                .or_default();
+            entry.0 += *multiplicity as u64;
+            entry.1 += hits as u64;
+        }
+        self.asad_readout
+            .entry((frame.header.cobo_id, frame.header.asad_id))
+            .or_default()
+            .add_frame(&frame.header);
 
         let mut hw_id: &HardwareID;
         for datum in frame.data.iter() {
@@ -104,18 +509,63 @@ impl Event {
                 }
             };
 
+            // Check the raw bucket, so that a corrupt bucket can't be rotated into range
+            let n_buckets = if self.correct_sca_rotation {
+                self.time_buckets.min(NUMBER_OF_SCA_CELLS as usize)
+            } else {
+                self.time_buckets
+            };
+            if datum.time_bucket_id as usize >= n_buckets {
+                return Err(EventError::TimeBucketOutOfRange(
+                    datum.time_bucket_id,
+                    n_buckets,
+                ));
+            }
+
+            let time_bucket_id = if self.correct_sca_rotation {
+                let offset = frame.header.get_sca_offset(datum.aget_id);
+                self.sca_offsets.insert(
+                    (frame.header.cobo_id, frame.header.asad_id, datum.aget_id),
+                    offset,
+                );
+                ((datum.time_bucket_id as u32 + NUMBER_OF_SCA_CELLS - offset as u32)
+                    % NUMBER_OF_SCA_CELLS) as u16
+            } else {
+                datum.time_bucket_id
+            };
+            if time_bucket_id as usize >= self.time_buckets {
+                return Err(EventError::TimeBucketOutOfRange(
+                    time_bucket_id,
+                    self.time_buckets,
+                ));
+            }
+
             // Put the data in the appropriate trace
-            match self.traces.get_mut(hw_id) {
-                Some(trace) => {
-                    trace[datum.time_bucket_id as usize] = datum.sample;
-                }
+            let is_written = match self.traces.get_mut(hw_id) {
+                Some(trace) => trace.set_sample(
+                    time_bucket_id as usize,
+                    datum.sample,
+                    self.duplicate_bucket_policy,
+                ),
                 None => {
-                    //First time this pad found during event. Create a new array
-                    let mut trace: Array1<i16> =
-                        Array1::<i16>::zeros(NUMBER_OF_TIME_BUCKETS as usize);
-                    trace[datum.time_bucket_id as usize] = datum.sample;
+                    //First time this pad found during event. Create a new trace
+                    let mut trace = Trace::new(self.time_buckets);
+                    trace.set_sample(
+                        time_bucket_id as usize,
+                        datum.sample,
+                        self.duplicate_bucket_policy,
+                    );
+                    self.add_pad_source(hw_id);
                     self.traces.insert(hw_id.clone(), trace);
+                    true
                 }
+            };
+            if !is_written {
+                return Err(EventError::DuplicateTimeBucket(
+                    self.event_id,
+                    hw_id.pad_id,
+                    time_bucket_id,
+                ));
             }
         }
 
@@ -124,3 +574,376 @@ impl Event {
         Ok(())
     }
 }
+
+//Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graw_frame::GrawData;
+
+    #[test]
+    fn test_quality_flags() {
+        let map = PadMap::new(None).unwrap();
+        // CoBo 7, AsAd 2, AGET 1, channel 10 is pad 9908 in the default map
+        let mut frame = GrawFrame::new();
+        frame.header.cobo_id = 7;
+        frame.header.asad_id = 2;
+        let samples: [(u16, i16); 3] = [(0, SATURATION_VALUE), (1, 10), (1, 12)];
+        for (time_bucket_id, sample) in samples {
+            frame.data.push(GrawData {
+                aget_id: 1,
+                channel: 10,
+                time_bucket_id,
+                sample,
+            });
+        }
+
+        let event = Event::new(
+            &map,
+            &vec![frame],
+            NUMBER_OF_TIME_BUCKETS as usize,
+            DuplicateBucketPolicy::Overwrite,
+        )
+        .unwrap();
+        let (data, flags) = event.convert_to_data_matrix(None);
+        assert_eq!(
+            data.shape(),
+            [
+                1,
+                NUMBER_OF_HARDWARE_COLUMNS + NUMBER_OF_TIME_BUCKETS as usize
+            ]
+        );
+        assert_eq!(data[[0, 4]], 9908);
+        assert_eq!(data[[0, 6]], 12);
+        assert_eq!(flags.row(0).to_vec(), vec![1, 1, 2]);
+    }
+
+    #[test]
+    fn test_row_order() {
+        let map = PadMap::new(None).unwrap();
+        let make_event = |channels: Vec<u8>| {
+            let mut frame = GrawFrame::new();
+            for channel in channels {
+                frame.data.push(GrawData {
+                    aget_id: 0,
+                    channel,
+                    time_bucket_id: 0,
+                    sample: channel as i16,
+                });
+            }
+            Event::new(
+                &map,
+                &vec![frame],
+                NUMBER_OF_TIME_BUCKETS as usize,
+                DuplicateBucketPolicy::Error,
+            )
+            .unwrap()
+        };
+
+        // The rows are ordered by pad, whatever the order the channels were read in
+        let (data, _) = make_event((0..68).collect()).convert_to_data_matrix(None);
+        let (reversed, _) = make_event((0..68).rev().collect()).convert_to_data_matrix(None);
+        assert_eq!(data, reversed);
+        assert_eq!(data.nrows(), 64); // less the FPN channels
+        let pads: Vec<i16> = data.column(4).to_vec();
+        assert!(pads.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn test_merge() {
+        let map = PadMap::new(None).unwrap();
+        let make_frame = |cobo_id: u8, event_time: u64, sample: i16| {
+            let mut frame = GrawFrame::new();
+            frame.header.event_id = 5;
+            frame.header.cobo_id = cobo_id;
+            frame.header.asad_id = 2;
+            frame.header.event_time = event_time;
+            frame.data.push(GrawData {
+                aget_id: 1,
+                channel: 10,
+                time_bucket_id: 3,
+                sample,
+            });
+            frame
+        };
+        let make_event = |frame: GrawFrame| {
+            Event::new(
+                &map,
+                &vec![frame],
+                NUMBER_OF_TIME_BUCKETS as usize,
+                DuplicateBucketPolicy::Error,
+            )
+            .unwrap()
+        };
+
+        // Disjoint pads from two sources
+        let mut event = make_event(make_frame(7, 100, 10));
+        event.merge(make_event(make_frame(6, 100, 20))).unwrap();
+        assert_eq!(event.event_id, 5);
+        assert_eq!(event.timestamp, 100);
+        assert_eq!(event.get_cobo_timestamps().nrows(), 2);
+        let (data, _) = event.convert_to_data_matrix(None);
+        assert_eq!(data.nrows(), 2);
+        let mut samples: Vec<i16> = data
+            .column(NUMBER_OF_HARDWARE_COLUMNS + 3)
+            .iter()
+            .copied()
+            .collect();
+        samples.sort();
+        assert_eq!(samples, vec![10, 20]);
+
+        // The same bucket of the same pad is a duplicate
+        let mut event = make_event(make_frame(7, 100, 10));
+        assert!(matches!(
+            event.merge(make_event(make_frame(7, 100, 20))),
+            Err(EventError::DuplicateTimeBucket(5, _, 3))
+        ));
+
+        // Different event ids can't be merged
+        let mut other_frame = make_frame(6, 100, 20);
+        other_frame.header.event_id = 6;
+        let mut event = make_event(make_frame(7, 100, 10));
+        assert!(matches!(
+            event.merge(make_event(other_frame)),
+            Err(EventError::MismatchedEventID(6, 5))
+        ));
+    }
+
+    #[test]
+    fn test_configured_time_buckets() {
+        let map = PadMap::new(None).unwrap();
+        let make_frame = |time_bucket_id: u16| {
+            let mut frame = GrawFrame::new();
+            frame.header.cobo_id = 7;
+            frame.header.asad_id = 2;
+            frame.data.push(GrawData {
+                aget_id: 1,
+                channel: 10,
+                time_bucket_id,
+                sample: 10,
+            });
+            frame
+        };
+
+        for time_buckets in [256, 1024] {
+            let event = Event::new(
+                &map,
+                &vec![make_frame(time_buckets as u16 - 1)],
+                time_buckets,
+                DuplicateBucketPolicy::Overwrite,
+            )
+            .unwrap();
+            let (data, _) = event.convert_to_data_matrix(None);
+            assert_eq!(data.shape(), [1, NUMBER_OF_HARDWARE_COLUMNS + time_buckets]);
+            assert_eq!(data[[0, NUMBER_OF_HARDWARE_COLUMNS + time_buckets - 1]], 10);
+        }
+
+        assert!(matches!(
+            Event::new(
+                &map,
+                &vec![make_frame(256)],
+                256,
+                DuplicateBucketPolicy::Overwrite
+            ),
+            Err(EventError::TimeBucketOutOfRange(256, 256))
+        ));
+    }
+
+    #[test]
+    fn test_cobo_timestamps() {
+        let map = PadMap::new(None).unwrap();
+        let frames: Vec<GrawFrame> = [(3, 100), (0, 101), (COBO_WITH_TIMESTAMP, 50), (3, 102)]
+            .into_iter()
+            .map(|(cobo_id, event_time)| {
+                let mut frame = GrawFrame::new();
+                frame.header.cobo_id = cobo_id;
+                frame.header.event_time = event_time;
+                frame
+            })
+            .collect();
+
+        let event = Event::new(
+            &map,
+            &frames,
+            NUMBER_OF_TIME_BUCKETS as usize,
+            DuplicateBucketPolicy::Overwrite,
+        )
+        .unwrap();
+        let timestamps = event.get_cobo_timestamps();
+        assert_eq!(timestamps.shape(), [3, 3]);
+        assert_eq!(timestamps.row(0).to_vec(), vec![0, 101, 101]);
+        assert_eq!(timestamps.row(1).to_vec(), vec![3, 100, 102]);
+        assert_eq!(
+            timestamps.row(2).to_vec(),
+            vec![COBO_WITH_TIMESTAMP as u64, 50, 50]
+        );
+        assert_eq!(event.timestamp, 102);
+        assert_eq!(event.timestampother, 50);
+    }
+
+    #[test]
+    fn test_aget_multiplicity() {
+        let map = PadMap::new(None).unwrap();
+        let frames: Vec<GrawFrame> = [
+            (1, 2, [3, 0, 1, 0]),
+            (1, 2, [1, 0, 0, 0]),
+            (0, 0, [0, 0, 0, 4]),
+        ]
+        .into_iter()
+        .map(|(cobo_id, asad_id, multiplicity)| {
+            let mut frame = GrawFrame::new();
+            frame.header.cobo_id = cobo_id;
+            frame.header.asad_id = asad_id;
+            frame.header.multiplicity = multiplicity.to_vec();
+            frame.header.hit_patterns = multiplicity
+                .iter()
+                .map(|mult| {
+                    let mut pattern = bitvec![u8, Lsb0; 0; SIZE_OF_BITSET];
+                    pattern[..*mult as usize].fill(true);
+                    pattern
+                })
+                .collect();
+            frame
+        })
+        .collect();
+
+        let event = Event::new(
+            &map,
+            &frames,
+            NUMBER_OF_TIME_BUCKETS as usize,
+            DuplicateBucketPolicy::Overwrite,
+        )
+        .unwrap();
+        let multiplicity = event.get_aget_multiplicity();
+        assert_eq!(multiplicity.shape(), [8, 5]);
+        assert_eq!(multiplicity.row(3).to_vec(), vec![0, 0, 3, 4, 4]);
+        assert_eq!(multiplicity.row(4).to_vec(), vec![1, 2, 0, 4, 4]);
+        assert_eq!(multiplicity.row(6).to_vec(), vec![1, 2, 2, 1, 1]);
+
+        // One row per AsAd, with the AGETs which had hits
+        let readout = event.get_readout_info();
+        assert_eq!(readout.shape(), [2, NUMBER_OF_READOUT_COLUMNS]);
+        assert_eq!(readout.row(0).to_vec(), vec![0, 0, 1, 0, 0, 0, 0, 0b1000]);
+        assert_eq!(readout.row(1).to_vec(), vec![1, 2, 2, 0, 0, 0, 0, 0b0101]);
+    }
+
+    #[test]
+    fn test_duplicate_bucket_policy() {
+        let map = PadMap::new(None).unwrap();
+        // Two data for the same time bucket of pad 9908
+        let mut frame = GrawFrame::new();
+        frame.header.event_id = 4;
+        frame.header.cobo_id = 7;
+        frame.header.asad_id = 2;
+        for sample in [10, 12] {
+            frame.data.push(GrawData {
+                aget_id: 1,
+                channel: 10,
+                time_bucket_id: 1,
+                sample,
+            });
+        }
+        let frames = vec![frame];
+
+        for (policy, expected) in [
+            (DuplicateBucketPolicy::Overwrite, 12),
+            (DuplicateBucketPolicy::Sum, 22),
+        ] {
+            let event = Event::new(&map, &frames, NUMBER_OF_TIME_BUCKETS as usize, policy).unwrap();
+            let (data, flags) = event.convert_to_data_matrix(None);
+            assert_eq!(data[[0, NUMBER_OF_HARDWARE_COLUMNS + 1]], expected);
+            assert_eq!(flags[[0, 1]], 1);
+        }
+
+        assert!(matches!(
+            Event::new(
+                &map,
+                &frames,
+                NUMBER_OF_TIME_BUCKETS as usize,
+                DuplicateBucketPolicy::Error
+            ),
+            Err(EventError::DuplicateTimeBucket(4, 9908, 1))
+        ));
+    }
+
+    #[test]
+    fn test_sca_rotation() {
+        let map = PadMap::new(None).unwrap();
+        // A ramp which starts at cell 100 of the SCA of AGET 1, which had last cell 99
+        let mut frame = GrawFrame::new();
+        frame.header.cobo_id = 7;
+        frame.header.asad_id = 2;
+        frame.header.last_cells = vec![0, 99, 0, 0];
+        let cells = NUMBER_OF_SCA_CELLS as i16;
+        for time_bucket_id in 0..NUMBER_OF_SCA_CELLS as u16 {
+            frame.data.push(GrawData {
+                aget_id: 1,
+                channel: 10,
+                time_bucket_id,
+                sample: (time_bucket_id as i16 - 100).rem_euclid(cells),
+            });
+        }
+
+        let mut event = Event::new(
+            &map,
+            &Vec::new(),
+            NUMBER_OF_SCA_CELLS as usize,
+            DuplicateBucketPolicy::Error,
+        )
+        .unwrap();
+        event.set_sca_rotation_correction(true);
+        event.append_frame(&map, &frame).unwrap();
+        assert_eq!(event.get_sca_offsets().row(0).to_vec(), vec![7, 2, 1, 100]);
+        let (data, _) = event.convert_to_data_matrix(None);
+        for bucket in 0..NUMBER_OF_SCA_CELLS as usize {
+            assert_eq!(
+                data[[0, NUMBER_OF_HARDWARE_COLUMNS + bucket]],
+                bucket as i16
+            );
+        }
+
+        // Left alone by default
+        let event = Event::new(
+            &map,
+            &vec![frame],
+            NUMBER_OF_SCA_CELLS as usize,
+            DuplicateBucketPolicy::Error,
+        )
+        .unwrap();
+        assert_eq!(event.get_sca_offsets().nrows(), 0);
+        let (data, _) = event.convert_to_data_matrix(None);
+        assert_eq!(data[[0, NUMBER_OF_HARDWARE_COLUMNS + 100]], 0);
+        assert_eq!(data[[0, NUMBER_OF_HARDWARE_COLUMNS]], cells - 100);
+    }
+
+    #[test]
+    fn test_sca_rotation_out_of_range() {
+        let map = PadMap::new(None).unwrap();
+        let mut frame = GrawFrame::new();
+        frame.header.cobo_id = 7;
+        frame.header.asad_id = 2;
+        frame.header.last_cells = vec![0, 99, 0, 0];
+        frame.data.push(GrawData {
+            aget_id: 1,
+            channel: 10,
+            time_bucket_id: NUMBER_OF_SCA_CELLS as u16 + 5,
+            sample: 10,
+        });
+
+        // A corrupt bucket is rejected with or without the correction, and the raw bucket is reported
+        for correct_sca_rotation in [false, true] {
+            let mut event = Event::new(
+                &map,
+                &Vec::new(),
+                NUMBER_OF_SCA_CELLS as usize,
+                DuplicateBucketPolicy::Error,
+            )
+            .unwrap();
+            event.set_sca_rotation_correction(correct_sca_rotation);
+            assert!(matches!(
+                event.append_frame(&map, &frame),
+                Err(EventError::TimeBucketOutOfRange(517, 512))
+            ));
+        }
+    }
+}
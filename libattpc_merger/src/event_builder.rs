@@ -1,5 +1,11 @@
-use super::error::EventBuilderError;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+
+use super::config::DuplicateBucketPolicy;
+use super::constants::DEFAULT_MAX_FRAMES_PER_EVENT;
+use super::error::{EventBuilderError, EventError};
 use super::event::Event;
+use super::frame_spill::FrameSpill;
 use super::graw_frame::GrawFrame;
 use super::pad_map::PadMap;
 
@@ -12,20 +18,100 @@ pub struct EventBuilder {
     current_event_id: Option<u32>,
     pad_map: PadMap,
     frame_stack: Vec<GrawFrame>,
+    frame_provenance: bool,
+    frame_headers: bool,
+    time_buckets: usize,
+    duplicate_bucket_policy: DuplicateBucketPolicy,
+    max_out_of_order: usize,
+    recent_event_ids: VecDeque<u32>, // the ids of the last max_out_of_order events emitted
+    late_frames: u64,                // number of late frames dropped
+    strict_pad_map: bool,            // reject events with a pad read from more than one channel
+    duplicate_pad_traces: u64, // number of traces of a pad which already had a trace from another channel
+    max_frames_per_event: usize, // frames of an event which are built before the event is emitted (or rejected)
+    fail_oversized_events: bool, // reject events with more than max_frames_per_event frames instead of emitting them
+    peak_frames: usize,          // most frames held for an event
+    oversized_events: u64,       // number of events emitted early as they had too many frames
+    spill_directory: Option<PathBuf>, // where the frames of large events are spilled, if they are
+    spill_threshold: usize,      // frames held in memory before they are spilled
+    spill: Option<FrameSpill>,   // the spilled frames of the event being built
+    spilled_events: u64,         // number of events which had frames spilled
+    correct_sca_rotation: bool, // rotate the time buckets of each AGET by the offset of its last cell
 }
 
 impl EventBuilder {
     /// Create a new EventBuilder.
     ///
-    /// Requires a PadMap
-    pub fn new(pad_map: PadMap) -> Self {
+    /// Requires a PadMap. If frame_provenance is true, each Event will record
+    /// the file, offset, CoBo, and AsAd of every frame that contributed to it. If frame_headers is true, each
+    /// Event will also record the header of every frame that contributed to it.
+    /// Each Event will have traces with time_buckets samples, and time buckets written more than
+    /// once are handled according to the duplicate_bucket_policy. Late frames belonging to one of the last
+    /// max_out_of_order events emitted are dropped rather than treated as an error.
+    /// Traces of a pad which already has a trace from another channel (a pad map which maps several channels to the
+    /// same pad) are counted, and logged the first time; if strict_pad_map is true they are an InvalidHardware error.
+    pub fn new(
+        pad_map: PadMap,
+        frame_provenance: bool,
+        frame_headers: bool,
+        time_buckets: usize,
+        duplicate_bucket_policy: DuplicateBucketPolicy,
+        max_out_of_order: usize,
+        strict_pad_map: bool,
+    ) -> Self {
         EventBuilder {
             current_event_id: None,
             pad_map,
             frame_stack: Vec::new(),
+            frame_provenance,
+            frame_headers,
+            time_buckets,
+            duplicate_bucket_policy,
+            max_out_of_order,
+            recent_event_ids: VecDeque::with_capacity(max_out_of_order),
+            late_frames: 0,
+            strict_pad_map,
+            duplicate_pad_traces: 0,
+            max_frames_per_event: DEFAULT_MAX_FRAMES_PER_EVENT,
+            fail_oversized_events: false,
+            peak_frames: 0,
+            oversized_events: 0,
+            spill_directory: None,
+            spill_threshold: usize::MAX,
+            spill: None,
+            spilled_events: 0,
+            correct_sca_rotation: false,
         }
     }
 
+    /// Spill the frames of large events to a temporary file in directory instead of holding them in memory.
+    ///
+    /// Once spill_threshold frames of an event are held, they are written to the spill file, and read back one at a
+    /// time when the event is built, so that no data is lost while the frames held in memory stay bounded. The event
+    /// traces are still built in memory. The frames spilled count towards max_frames_per_event.
+    pub fn set_spill(&mut self, directory: PathBuf, spill_threshold: usize) {
+        self.spill_directory = Some(directory);
+        self.spill_threshold = spill_threshold.max(1);
+    }
+
+    /// Correct the rotation of the circular SCA of the AGETs in the events built, see Event::set_sca_rotation_correction
+    pub fn set_sca_rotation_correction(&mut self, correct_sca_rotation: bool) {
+        self.correct_sca_rotation = correct_sca_rotation;
+    }
+
+    /// Set the maximum number of frames held for an event, so that data with a stuck event id can't use up the memory.
+    ///
+    /// Once an event has max_frames_per_event frames, it is emitted as is, and the following frames with the same
+    /// event id start a new event (with the same id). If fail_oversized_events is true, the next frame is an
+    /// EventTooLarge error instead. The default is DEFAULT_MAX_FRAMES_PER_EVENT frames, emitting the event.
+    pub fn set_max_frames_per_event(
+        &mut self,
+        max_frames_per_event: usize,
+        fail_oversized_events: bool,
+    ) {
+        self.max_frames_per_event = max_frames_per_event.max(1);
+        self.fail_oversized_events = fail_oversized_events;
+    }
+
     /// Add a frame to the event.
     ///
     /// If the frame does not have the same EventID as the event currently being built,
@@ -36,43 +122,450 @@ impl EventBuilder {
     pub fn append_frame(&mut self, frame: GrawFrame) -> Result<Option<Event>, EventBuilderError> {
         if let Some(current_id) = self.current_event_id {
             if frame.header.event_id < current_id {
-                // Some how we recieved a frame from a past event
-                Err(EventBuilderError::EventOutOfOrder(
+                // Some how we recieved a frame from a past event. Tolerate it if the event was recent
+                if !self.recent_event_ids.contains(&frame.header.event_id) {
+                    return Err(EventBuilderError::EventOutOfOrder(
+                        frame.header.event_id,
+                        current_id,
+                    ));
+                }
+                self.late_frames += 1;
+                spdlog::warn!(
+                    "Dropping a late frame for event {} (CoBo {}, AsAd {}) recieved while building event {}",
                     frame.header.event_id,
-                    current_id,
-                ))
+                    frame.header.cobo_id,
+                    frame.header.asad_id,
+                    current_id
+                );
+                Ok(None)
             } else if frame.header.event_id > current_id {
                 // We recieved a frame from the next event; emit the built event and start a new one
-                let event = Event::new(&self.pad_map, &self.frame_stack)?;
+                let event = self.build_event()?;
+                self.remember_event_id(current_id);
                 self.frame_stack.clear();
                 self.current_event_id = Some(frame.header.event_id);
                 self.frame_stack.push(frame);
                 Ok(Some(event))
+            } else if self.get_event_frames() >= self.max_frames_per_event {
+                // We recieved a frame for this event, but it already has too many
+                if self.fail_oversized_events {
+                    return Err(EventBuilderError::EventTooLarge(
+                        current_id,
+                        self.get_event_frames(),
+                    ));
+                }
+                spdlog::warn!(
+                    "Event {} has more than {} frames, emitting it and starting a new event with the same id. The event id may be stuck.",
+                    current_id,
+                    self.max_frames_per_event
+                );
+                let event = self.build_event()?;
+                self.oversized_events += 1;
+                self.frame_stack.clear();
+                self.frame_stack.push(frame);
+                Ok(Some(event))
             } else {
                 // We recieved a frame for this event
                 self.frame_stack.push(frame);
+                self.peak_frames = self.peak_frames.max(self.get_event_frames());
+                if self.frame_stack.len() >= self.spill_threshold {
+                    self.spill_frames()?;
+                }
                 Ok(None)
             }
         } else {
             // This is the first frame ever in history
             self.current_event_id = Some(frame.header.event_id);
             self.frame_stack.push(frame);
+            self.peak_frames = self.peak_frames.max(1);
             Ok(None)
         }
     }
 
+    /// The number of late frames which were dropped
+    pub fn get_late_frames(&self) -> &u64 {
+        &self.late_frames
+    }
+
+    /// The number of traces of a pad which already had a trace from another channel in the same event
+    pub fn get_duplicate_pad_traces(&self) -> &u64 {
+        &self.duplicate_pad_traces
+    }
+
+    /// The most frames held for an event
+    pub fn get_peak_frames(&self) -> &usize {
+        &self.peak_frames
+    }
+
+    /// The number of events which were emitted early, as they reached the maximum number of frames
+    pub fn get_oversized_events(&self) -> &u64 {
+        &self.oversized_events
+    }
+
+    /// The number of events which had frames spilled to disk
+    pub fn get_spilled_events(&self) -> &u64 {
+        &self.spilled_events
+    }
+
+    /// The number of frames of the event being built, in memory and spilled
+    fn get_event_frames(&self) -> usize {
+        self.frame_stack.len() + self.spill.as_ref().map_or(0, |spill| spill.get_n_frames())
+    }
+
+    /// Move the frames held for the event to its spill file, making the file if needed
+    fn spill_frames(&mut self) -> Result<(), EventBuilderError> {
+        if self.spill.is_none() {
+            let directory = match &self.spill_directory {
+                Some(directory) => directory,
+                None => return Ok(()),
+            };
+            let spill = FrameSpill::new(directory).map_err(EventBuilderError::SpillError)?;
+            spdlog::info!(
+                "Event {} has more than {} frames, spilling its frames to {}.",
+                self.current_event_id.unwrap_or(0),
+                self.spill_threshold,
+                spill.get_path().display()
+            );
+            self.spilled_events += 1;
+            self.spill = Some(spill);
+        }
+        if let Some(spill) = self.spill.as_mut() {
+            for frame in self.frame_stack.drain(..) {
+                spill
+                    .write_frame(&frame)
+                    .map_err(EventBuilderError::SpillError)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep the id of an emitted event, forgetting the oldest if more than max_out_of_order are kept
+    fn remember_event_id(&mut self, event_id: u32) {
+        if self.max_out_of_order == 0 {
+            return;
+        }
+        if self.recent_event_ids.len() == self.max_out_of_order {
+            self.recent_event_ids.pop_front();
+        }
+        self.recent_event_ids.push_back(event_id);
+    }
+
     /// Takes any remaining frames and flushes them to an event.
     ///
-    /// Used at the end of processing a run.
+    /// Used at the end of processing a run. The frames of the event may be in memory, spilled, or both.
     /// Returns None if there were no frames left over.
-    pub fn flush_final_event(&mut self) -> Option<Event> {
-        if !self.frame_stack.is_empty() {
-            match Event::new(&self.pad_map, &self.frame_stack) {
-                Ok(event) => Some(event),
-                Err(_) => None,
-            }
+    pub fn flush_final_event(&mut self) -> Result<Option<Event>, EventBuilderError> {
+        if self.get_event_frames() > 0 {
+            let event = self.build_event()?;
+            self.frame_stack.clear();
+            Ok(Some(event))
         } else {
-            None
+            Ok(None)
+        }
+    }
+
+    /// Make an Event from the spilled frames (if any) and the frame stack, attaching the frame provenance and headers if
+    /// requested
+    ///
+    /// Counts the traces of pads read from more than one channel, and rejects the event if strict_pad_map is true
+    fn build_event(&mut self) -> Result<Event, EventBuilderError> {
+        let mut event = Event::new(
+            &self.pad_map,
+            &Vec::new(),
+            self.time_buckets,
+            self.duplicate_bucket_policy,
+        )?;
+        event.set_sca_rotation_correction(self.correct_sca_rotation);
+        if let Some(spill) = self.spill.take() {
+            for frame in spill.into_frames().map_err(EventBuilderError::SpillError)? {
+                let frame = frame.map_err(EventBuilderError::SpillError)?;
+                event.append_frame(&self.pad_map, &frame)?;
+                if self.frame_provenance {
+                    event.provenance.push(frame.provenance);
+                }
+                if self.frame_headers {
+                    event.frame_headers.push(frame.header);
+                }
+            }
+        }
+        for frame in self.frame_stack.iter() {
+            event.append_frame(&self.pad_map, frame)?;
+        }
+        if let Some((first, other)) = event.get_duplicate_pads().first() {
+            if self.strict_pad_map {
+                return Err(EventError::InvalidHardware(
+                    other.cobo_id as u8,
+                    other.asad_id as u8,
+                    other.aget_id as u8,
+                    other.channel as u8,
+                )
+                .into());
+            }
+            if self.duplicate_pad_traces == 0 {
+                spdlog::warn!(
+                    "Pad {} of event {} was read from CoBo {} AsAd {} AGET {} channel {} and from CoBo {} AsAd {} AGET {} channel {}! The pad map maps more than one channel to the pad. Further duplicates are counted but not logged.",
+                    first.pad_id,
+                    event.event_id,
+                    first.cobo_id,
+                    first.asad_id,
+                    first.aget_id,
+                    first.channel,
+                    other.cobo_id,
+                    other.asad_id,
+                    other.aget_id,
+                    other.channel
+                );
+            }
+            self.duplicate_pad_traces += event.get_duplicate_pads().len() as u64;
+        }
+        if self.frame_provenance {
+            event.provenance.extend(
+                self.frame_stack
+                    .iter()
+                    .map(|frame| frame.provenance.clone()),
+            );
+        }
+        if self.frame_headers {
+            event
+                .frame_headers
+                .extend(self.frame_stack.iter().map(|frame| frame.header.clone()));
+        }
+        Ok(event)
+    }
+}
+
+//Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graw_frame::GrawData;
+
+    fn make_frame(event_id: u32) -> GrawFrame {
+        let mut frame = GrawFrame::new();
+        frame.header.event_id = event_id;
+        frame
+    }
+
+    fn make_builder(max_out_of_order: usize) -> EventBuilder {
+        EventBuilder::new(
+            PadMap::new(None).unwrap(),
+            false,
+            false,
+            512,
+            DuplicateBucketPolicy::Overwrite,
+            max_out_of_order,
+            false,
+        )
+    }
+
+    #[test]
+    fn test_out_of_order_frames() {
+        let event_ids = [1, 1, 2, 1, 3];
+
+        // Without a window, the late frame for event 1 is an error
+        let mut evb = make_builder(0);
+        let results: Vec<_> = event_ids
+            .iter()
+            .map(|id| evb.append_frame(make_frame(*id)))
+            .collect();
+        assert!(matches!(
+            results[3],
+            Err(EventBuilderError::EventOutOfOrder(1, 2))
+        ));
+
+        // With a window, it is dropped and counted
+        let mut evb = make_builder(2);
+        let mut emitted = Vec::new();
+        for id in event_ids {
+            if let Some(event) = evb.append_frame(make_frame(id)).unwrap() {
+                emitted.push(event.event_id);
+            }
+        }
+        emitted.push(evb.flush_final_event().unwrap().unwrap().event_id);
+        assert_eq!(emitted, vec![1, 2, 3]);
+        assert_eq!(*evb.get_late_frames(), 1);
+
+        // Frames later than the window are still an error
+        let mut evb = make_builder(1);
+        for id in [1, 2, 3] {
+            evb.append_frame(make_frame(id)).unwrap();
         }
+        assert!(matches!(
+            evb.append_frame(make_frame(1)),
+            Err(EventBuilderError::EventOutOfOrder(1, 3))
+        ));
+    }
+
+    #[test]
+    fn test_max_frames_per_event() {
+        // The event id is stuck at 1
+        let mut evb = make_builder(0);
+        evb.set_max_frames_per_event(1000, false);
+        let mut emitted = Vec::new();
+        for _ in 0..20_000 {
+            if let Some(event) = evb.append_frame(make_frame(1)).unwrap() {
+                emitted.push(event.event_id);
+            }
+        }
+        emitted.push(evb.flush_final_event().unwrap().unwrap().event_id);
+        assert_eq!(emitted, vec![1; 20]);
+        assert_eq!(*evb.get_peak_frames(), 1000);
+        assert_eq!(*evb.get_oversized_events(), 19);
+
+        let mut evb = make_builder(0);
+        evb.set_max_frames_per_event(1000, true);
+        let results: Vec<_> = (0..20_000)
+            .map(|_| evb.append_frame(make_frame(1)))
+            .take_while(|result| result.is_ok())
+            .collect();
+        assert_eq!(results.len(), 1000);
+        assert!(matches!(
+            evb.append_frame(make_frame(1)),
+            Err(EventBuilderError::EventTooLarge(1, 1000))
+        ));
+        assert_eq!(*evb.get_peak_frames(), 1000);
+    }
+
+    #[test]
+    fn test_spill_large_event() {
+        let dir = std::env::temp_dir().join(format!("attpc_merger_spill_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut evb = EventBuilder::new(
+            PadMap::new(None).unwrap(),
+            true,
+            false,
+            512,
+            DuplicateBucketPolicy::Overwrite,
+            0,
+            false,
+        );
+        evb.set_spill(dir.clone(), 4);
+        // Every frame of event 1 has a sample of its own, none of which may be lost
+        for idx in 0..10u16 {
+            let mut frame = make_frame(1);
+            frame.provenance.offset = idx as u64;
+            frame.data.push(GrawData {
+                aget_id: 0,
+                channel: idx as u8,
+                time_bucket_id: idx,
+                sample: idx as i16 + 1,
+            });
+            assert!(evb.append_frame(frame).unwrap().is_none());
+        }
+        // 8 of the frames were spilled, 2 are still in memory
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        let event = evb.append_frame(make_frame(2)).unwrap().unwrap();
+        assert_eq!(*evb.get_spilled_events(), 1);
+        assert_eq!(*evb.get_peak_frames(), 10);
+        // The spill file is removed once the event is built
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        let offsets: Vec<u64> = event.provenance.iter().map(|prov| prov.offset).collect();
+        assert_eq!(offsets, (0..10).collect::<Vec<u64>>());
+        let (data, _) = event.convert_to_data_matrix(None);
+        assert_eq!(data.nrows(), 10);
+        for row in data.rows() {
+            let channel = row[3] as usize;
+            assert_eq!(row[5 + channel], channel as i16 + 1);
+            assert_eq!(row.iter().skip(5).filter(|sample| **sample != 0).count(), 1);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_spill_final_event() {
+        let dir =
+            std::env::temp_dir().join(format!("attpc_merger_spill_final_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut evb = make_builder(0);
+        evb.set_spill(dir.clone(), 4);
+        // The last frame of the run's final event reaches the threshold, so every frame is spilled
+        for idx in 0..8u16 {
+            let mut frame = make_frame(1);
+            frame.data.push(GrawData {
+                aget_id: 0,
+                channel: idx as u8,
+                time_bucket_id: idx,
+                sample: idx as i16 + 1,
+            });
+            assert!(evb.append_frame(frame).unwrap().is_none());
+        }
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        let event = evb.flush_final_event().unwrap().unwrap();
+        assert_eq!(event.event_id, 1);
+        let (data, _) = event.convert_to_data_matrix(None);
+        assert_eq!(data.nrows(), 8);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        // Nothing is left to flush
+        assert!(evb.flush_final_event().unwrap().is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_duplicate_pads() {
+        // Two channels deliberately mapped to the same pad
+        let dir = std::env::temp_dir().join(format!(
+            "attpc_merger_duplicate_pads_{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let map_path = dir.join("map.csv");
+        std::fs::write(
+            &map_path,
+            "cobo,asad,aget,channel,pad\n7,2,1,10,5\n7,2,1,12,5\n",
+        )
+        .unwrap();
+        let make_event_frame = |event_id: u32| {
+            let mut frame = make_frame(event_id);
+            frame.header.cobo_id = 7;
+            frame.header.asad_id = 2;
+            for channel in [10, 12] {
+                frame.data.push(GrawData {
+                    aget_id: 1,
+                    channel,
+                    time_bucket_id: 0,
+                    sample: 10,
+                });
+            }
+            frame
+        };
+
+        let mut evb = EventBuilder::new(
+            PadMap::new(Some(&map_path)).unwrap(),
+            false,
+            false,
+            512,
+            DuplicateBucketPolicy::Overwrite,
+            0,
+            false,
+        );
+        evb.append_frame(make_event_frame(1)).unwrap();
+        let event = evb.append_frame(make_event_frame(2)).unwrap().unwrap();
+        assert_eq!(event.get_duplicate_pads().len(), 1);
+        assert_eq!(*evb.get_duplicate_pad_traces(), 1);
+
+        let mut evb = EventBuilder::new(
+            PadMap::new(Some(&map_path)).unwrap(),
+            false,
+            false,
+            512,
+            DuplicateBucketPolicy::Overwrite,
+            0,
+            true,
+        );
+        evb.append_frame(make_event_frame(1)).unwrap();
+        assert!(matches!(
+            evb.append_frame(make_event_frame(2)),
+            Err(EventBuilderError::EventError(EventError::InvalidHardware(
+                7,
+                2,
+                1,
+                _
+            )))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
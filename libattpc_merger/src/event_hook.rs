@@ -0,0 +1,149 @@
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use super::asad_stack::ChunkGap;
+use super::constants::TIMESTAMP_CLOCK_HZ;
+use super::event::Event;
+use super::graw_frame::BadDataCounts;
+use super::ring_item::PhysicsItem;
+
+/// Summary of a merged run, given to the EventHooks once the run is written
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MergeSummary {
+    pub run_number: i32,
+    pub get_events: u64,              // GET events written
+    pub frib_physics: u64,            // FRIBDAQ physics events written
+    pub frib_scalers: u64,            // FRIBDAQ scaler reads written
+    pub frib_counter: Option<u64>, // physics items counted by FRIBDAQ, from the last Counter item
+    pub frib_other_fragments: u64, // fragments of event built physics items from other sources than frib_source_id
+    pub late_frames: u64,          // frames dropped by the EventBuilder
+    pub timestamp_glitches: u64,   // GET timestamps corrected for small backwards jumps
+    pub timestamp_rollovers: u64,  // GET timestamps which jumped back too far to be corrected
+    pub missing_timestampother: u64, // GET events without a frame from the CoBo with the FRIBDAQ timestamp
+    pub partial_frames: u64,         // GET frames in partial readout (zero suppressed)
+    pub full_frames: u64,            // GET frames in full readout
+    pub duplicate_pad_traces: u64,   // traces of pads already read from another channel
+    pub peak_frames_per_event: u64,  // most frames held by the EventBuilder for an event
+    pub oversized_events: u64, // events emitted early by the EventBuilder as they had too many frames
+    pub spilled_events: u64, // events whose frames were spilled to disk (Config::spill_large_events)
+    pub silicon_matched: u64, // GET events given a silicon event by timestamp (Config::si_merge_by_timestamp)
+    pub silicon_orphans: u64, // silicon events matched to no GET event, written to orphan_silicon
+    pub bad_data: BadDataCounts, // GET data out of the range of the hardware, left out of the events
+    pub timings: StageTimings,   // time spent in each stage of the merge
+    pub uploaded_bytes: u64,     // bytes of output uploaded to the object store (upload_url)
+    pub upload_error: Option<String>, // the last error of an upload which failed after every retry
+    // SHA-256 of each output file, if checksums are written (Config::write_checksums)
+    pub checksums: Vec<(PathBuf, String)>,
+    // corrupt bytes skipped in each evt file which had any
+    pub evt_skipped_bytes: Vec<(PathBuf, u64)>,
+    // runs of chunks missing from the GRAW files of each (CoBo, AsAd) which had any
+    pub graw_gaps: Vec<((u8, u8), ChunkGap)>,
+}
+
+/// The time spent in each stage of merging a run
+///
+/// The GET events are written on their own thread, so get_write overlaps with frame_read and event_build.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StageTimings {
+    pub frame_read: Duration, // reading and decoding GRAW frames (Merger::get_next_frame)
+    pub event_build: Duration, // building events from the frames (EventBuilder::append_frame)
+    pub get_write: Duration,  // writing the GET events (EventSink::write_get_event)
+    pub evt_read: Duration,   // reading and parsing the FRIBDAQ ring items
+    pub evt_write: Duration,  // writing the FRIBDAQ physics events and scalers
+    pub total: Duration,      // the whole run, from opening the data to closing the output
+}
+
+impl Display for StageTimings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "GRAW read: {:.3}s, event build: {:.3}s, GET write: {:.3}s, evt read: {:.3}s, evt write: {:.3}s, total: {:.3}s",
+            self.frame_read.as_secs_f64(),
+            self.event_build.as_secs_f64(),
+            self.get_write.as_secs_f64(),
+            self.evt_read.as_secs_f64(),
+            self.evt_write.as_secs_f64(),
+            self.total.as_secs_f64()
+        )
+    }
+}
+
+/// A hook into the merged data, for custom processing (i.e. online rate monitoring) without forking process_run.
+///
+/// Hooks are given to process_run_with_hooks and see every merged item just before it is written to the output.
+/// All of the methods do nothing by default, so a hook only implements what it needs. Hooks see the GET events
+/// from the writer thread, so they must be Send.
+pub trait EventHook: Send {
+    /// Called for each GET event, with its event number in the output
+    fn on_get_event(&mut self, _event: &Event, _event_counter: u64) {}
+
+    /// Called for each FRIBDAQ physics event, with its event number in the output
+    fn on_frib_physics(&mut self, _physics: &PhysicsItem, _event_counter: u64) {}
+
+    /// Called once the run has been written
+    fn on_close(&mut self, _summary: &MergeSummary) {}
+}
+
+/// A histogram of the GET event rate over a run.
+///
+/// Events are binned by their timestamp relative to the first event of the run.
+#[derive(Debug, Clone)]
+pub struct RateHistogram {
+    bin_width_s: f64,
+    first_timestamp: Option<u64>,
+    counts: Vec<u64>,
+}
+
+impl RateHistogram {
+    /// Make an empty histogram with bins of the given width in seconds
+    pub fn new(bin_width_s: f64) -> Self {
+        Self {
+            bin_width_s,
+            first_timestamp: None,
+            counts: Vec::new(),
+        }
+    }
+
+    /// The number of events in each bin
+    pub fn get_counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    /// The rate of events in each bin in Hz
+    pub fn get_rates_hz(&self) -> Vec<f64> {
+        self.counts
+            .iter()
+            .map(|count| *count as f64 / self.bin_width_s)
+            .collect()
+    }
+
+    /// Clear the histogram, i.e. before the next run
+    pub fn reset(&mut self) {
+        self.first_timestamp = None;
+        self.counts.clear();
+    }
+}
+
+impl EventHook for RateHistogram {
+    fn on_get_event(&mut self, event: &Event, _event_counter: u64) {
+        let first_timestamp = *self.first_timestamp.get_or_insert(event.timestamp);
+        let elapsed_s = event.timestamp.saturating_sub(first_timestamp) as f64 / TIMESTAMP_CLOCK_HZ;
+        let bin = (elapsed_s / self.bin_width_s) as usize;
+        if bin >= self.counts.len() {
+            self.counts.resize(bin + 1, 0);
+        }
+        self.counts[bin] += 1;
+    }
+
+    fn on_close(&mut self, summary: &MergeSummary) {
+        let peak = self.get_rates_hz().into_iter().fold(0.0, f64::max);
+        spdlog::info!(
+            "Run {} GET event rate: {} bins of {} s, peak rate {:.1} Hz",
+            summary.run_number,
+            self.counts.len(),
+            self.bin_width_s,
+            peak
+        );
+    }
+}
@@ -0,0 +1,501 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use super::config::{Config, OutputFormat};
+use super::error::{EventSinkError, HDF5WriterError};
+use super::event::Event;
+use super::hdf_writer::{HDFWriter, HdfLayout, HdfWriterOptions};
+use super::merger::Merger;
+use super::pad_map::PadMap;
+use super::pedestals::Pedestals;
+use super::ring_item::{PhysicsItem, RunInfo, ScalersItem};
+
+/// A destination for merged data.
+///
+/// process_run writes all of the merged data of a run through an EventSink, so that other output formats
+/// only need to implement this trait. The methods mirror those of the HDFWriter.
+/// Sinks must be Send, as the GET events are written from a separate thread.
+pub trait EventSink: Send {
+    /// Write a merged GET event
+    fn write_get_event(&mut self, event: Event, event_counter: &u64) -> Result<(), EventSinkError>;
+
+    /// Write a silicon event which was matched to no GET event (see SiliconMatcher)
+    fn write_orphan_silicon(
+        &mut self,
+        event: Event,
+        orphan_counter: &u64,
+    ) -> Result<(), EventSinkError>;
+
+    /// Write a FRIBDAQ physics event
+    fn write_frib_physics(
+        &mut self,
+        physics: PhysicsItem,
+        event_counter: &u64,
+    ) -> Result<(), EventSinkError>;
+
+    /// Write a FRIBDAQ scaler read
+    fn write_frib_scalers(
+        &mut self,
+        scalers: ScalersItem,
+        counter: &u64,
+    ) -> Result<(), EventSinkError>;
+
+    /// Write the FRIBDAQ run information
+    fn write_frib_runinfo(&mut self, run_info: RunInfo) -> Result<(), EventSinkError>;
+
+    /// Write the list of GRAW files which were merged
+    fn write_fileinfo(&mut self, merger: &Merger) -> Result<(), EventSinkError>;
+
+    /// Write the pad map used to build the events
+    fn write_pad_map(&mut self, pad_map: &PadMap) -> Result<(), EventSinkError>;
+
+    /// Write the attributes of the run from the runs database (see RunRecord)
+    fn write_run_attributes(
+        &mut self,
+        attributes: &BTreeMap<String, String>,
+    ) -> Result<(), EventSinkError>;
+
+    /// The number of the last GET event written
+    fn get_last_get_event(&self) -> u64;
+
+    /// The path of the file an event was written to, or None if the sink writes all events to the path it was made with
+    fn get_event_path(&self, _event_counter: &u64) -> Option<PathBuf> {
+        None
+    }
+
+    /// Finish writing, flushing any buffered data
+    ///
+    /// Returns the paths of the files written, in order
+    fn close(self: Box<Self>) -> Result<Vec<PathBuf>, EventSinkError>;
+}
+
+impl EventSink for HDFWriter {
+    fn write_get_event(&mut self, event: Event, event_counter: &u64) -> Result<(), EventSinkError> {
+        Ok(self.write_event(event, event_counter)?)
+    }
+
+    fn write_orphan_silicon(
+        &mut self,
+        event: Event,
+        orphan_counter: &u64,
+    ) -> Result<(), EventSinkError> {
+        Ok(HDFWriter::write_orphan_silicon(
+            self,
+            event,
+            orphan_counter,
+        )?)
+    }
+
+    fn write_frib_physics(
+        &mut self,
+        physics: PhysicsItem,
+        event_counter: &u64,
+    ) -> Result<(), EventSinkError> {
+        Ok(HDFWriter::write_frib_physics(self, physics, event_counter)?)
+    }
+
+    fn write_frib_scalers(
+        &mut self,
+        scalers: ScalersItem,
+        counter: &u64,
+    ) -> Result<(), EventSinkError> {
+        Ok(HDFWriter::write_frib_scalers(self, scalers, counter)?)
+    }
+
+    fn write_frib_runinfo(&mut self, run_info: RunInfo) -> Result<(), EventSinkError> {
+        Ok(HDFWriter::write_frib_runinfo(self, run_info)?)
+    }
+
+    fn write_fileinfo(&mut self, merger: &Merger) -> Result<(), EventSinkError> {
+        Ok(HDFWriter::write_fileinfo(self, merger)?)
+    }
+
+    fn write_pad_map(&mut self, pad_map: &PadMap) -> Result<(), EventSinkError> {
+        Ok(HDFWriter::write_pad_map(self, pad_map)?)
+    }
+
+    fn write_run_attributes(
+        &mut self,
+        attributes: &BTreeMap<String, String>,
+    ) -> Result<(), EventSinkError> {
+        Ok(HDFWriter::write_run_attributes(self, attributes)?)
+    }
+
+    fn get_last_get_event(&self) -> u64 {
+        *HDFWriter::get_last_get_event(self)
+    }
+
+    fn close(self: Box<Self>) -> Result<Vec<PathBuf>, EventSinkError> {
+        let path = self.get_file_path().to_path_buf();
+        HDFWriter::close(*self)?;
+        Ok(vec![path])
+    }
+}
+
+/// Create an HDFWriter at path, with the options of the Config
+fn make_hdf_writer(
+    config: &Config,
+    path: &Path,
+    pedestals: Option<Pedestals>,
+    layout: &HdfLayout,
+) -> Result<HDFWriter, EventSinkError> {
+    let mut writer = HDFWriter::new(
+        path,
+        pedestals,
+        &HdfWriterOptions::from_config(config, layout),
+    )?;
+    configure_hdf_writer(config, &mut writer)?;
+    Ok(writer)
+}
+
+/// Give a writer the options of the Config which can be changed once the file is open
+fn configure_hdf_writer(config: &Config, writer: &mut HDFWriter) -> Result<(), EventSinkError> {
+    writer.set_compression_level(config.compression_level);
+    writer.set_write_readout_info(config.write_readout_info);
+    writer.set_compute_peaks(config.compute_peaks);
+    writer.set_link_get_events(config.link_get_events);
+    writer.set_prune_empty_datasets(config.prune_empty_datasets)?;
+    writer.set_fadc_orientation(config.fadc_orientation)?;
+    writer.set_event_naming(config.event_naming)?;
+    writer.set_event_layout(config.event_layout)?;
+    Ok(())
+}
+
+/// Get the path of a part of a split run, i.e. run_0001.h5 becomes run_0001_part1.h5 for the first part
+///
+/// The parts are numbered from 1.
+pub fn get_part_path(path: &Path, part: u64) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}_part{}.h5", stem, part))
+}
+
+/// Find the parts of a split run written next to path by a previous merge, in order (see get_part_path)
+pub fn find_part_paths(path: &Path) -> Vec<PathBuf> {
+    find_part_files(path, ".h5")
+}
+
+/// Find the files of the parts of a split run next to path whose names end in suffix instead of .h5 (i.e. the
+/// run_0001_part#.h5.sha256 checksums), in order
+pub fn find_part_files(path: &Path, suffix: &str) -> Vec<PathBuf> {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let prefix = format!("{}_part", stem);
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let entries = match std::fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut parts: Vec<(u64, PathBuf)> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let part = name
+                .strip_prefix(&prefix)?
+                .strip_suffix(suffix)?
+                .parse::<u64>()
+                .ok()?;
+            Some((part, entry.path()))
+        })
+        .collect();
+    parts.sort();
+    parts.into_iter().map(|(_, path)| path).collect()
+}
+
+/// An HDF5 sink which splits a run across several files, of at most max_events_per_file events and/or of about
+/// max_output_bytes each.
+///
+/// Each event keeps its event number from the run. With max_events_per_file, event n is written to part
+/// n / max_events_per_file + 1 (with GetEventId naming, n is the GET event_id, so parts may have fewer events). With
+/// max_output_bytes, a new part is started with the next event once the estimated size of the current part (see
+/// HDFWriter::get_bytes_written) crosses the limit, so a part can be somewhat over the limit.
+/// The FRIBDAQ physics events follow the GET events, and scalers go to the part of the last physics event written. Each
+/// part is a complete file, with its own min/max attributes, the pad map, the CoBos and the run information, and the
+/// part and continues_from attributes linking it to the previous part. The GRAW file list is only written once,
+/// alongside the first part.
+///
+/// Only one part is open at a time: the open part is closed when another is needed, and a part which was closed is
+/// reopened with HDFWriter::open_append (i.e. for the physics events, which are written after all of the GET events).
+struct SplitHDFWriter {
+    config: Config,
+    path: PathBuf,
+    pedestals: Option<Pedestals>,
+    layout: HdfLayout,
+    max_events_per_file: Option<u64>,
+    max_output_bytes: Option<u64>,
+    part_starts: Vec<u64>,               // the first event number of each part
+    n_parts: usize,                      // parts made so far
+    current: Option<(usize, HDFWriter)>, // the open part and its index
+    last_get_event: u64,
+    scalers_part: usize,
+    cobos: Vec<u8>,
+    pad_map: Option<PadMap>,
+    run_info: Option<RunInfo>,
+    run_attributes: BTreeMap<String, String>,
+}
+
+impl SplitHDFWriter {
+    fn new(
+        config: &Config,
+        path: &Path,
+        pedestals: Option<Pedestals>,
+        layout: &HdfLayout,
+        max_events_per_file: Option<u64>,
+        max_output_bytes: Option<u64>,
+    ) -> Self {
+        Self {
+            config: config.clone(),
+            path: path.to_path_buf(),
+            pedestals,
+            layout: layout.clone(),
+            max_events_per_file,
+            max_output_bytes,
+            part_starts: vec![0],
+            n_parts: 0,
+            current: None,
+            last_get_event: 0,
+            scalers_part: 0,
+            cobos: Vec::new(),
+            pad_map: None,
+            run_info: None,
+            run_attributes: BTreeMap::new(),
+        }
+    }
+
+    /// The path of the part with an index, counting from 0
+    fn get_part_path(&self, part: usize) -> PathBuf {
+        get_part_path(&self.path, part as u64 + 1)
+    }
+
+    /// The part an event was (or would be) written to, without starting a new part
+    fn find_part_index(&self, event_counter: &u64) -> usize {
+        self.part_starts
+            .partition_point(|start| start <= event_counter)
+            .saturating_sub(1)
+    }
+
+    /// The part an event belongs in, starting new parts if the event does not fit in the last one
+    fn get_part_index(&mut self, event_counter: &u64) -> Result<usize, EventSinkError> {
+        let part = self.find_part_index(event_counter);
+        if part + 1 < self.part_starts.len() {
+            return Ok(part);
+        }
+        if let Some(max_events_per_file) = self.max_events_per_file {
+            if *event_counter - self.part_starts[part] >= max_events_per_file {
+                // Skipped event numbers leave empty parts, as each part covers max_events_per_file event numbers
+                let mut start = self.part_starts[part];
+                while start + max_events_per_file <= *event_counter {
+                    start += max_events_per_file;
+                    self.part_starts.push(start);
+                }
+                return Ok(self.part_starts.len() - 1);
+            }
+        }
+        if let Some(max_output_bytes) = self.max_output_bytes {
+            if part < self.n_parts {
+                // Events already in the part stay there, i.e. the physics event matching a GET event
+                let writer = self.get_part(part)?;
+                let last_event = (*writer.get_last_get_event()).max(*writer.get_last_frib_event());
+                if *writer.get_bytes_written() >= max_output_bytes && *event_counter > last_event {
+                    self.part_starts.push(*event_counter);
+                    return Ok(self.part_starts.len() - 1);
+                }
+            }
+        }
+        Ok(part)
+    }
+
+    /// Create the next part, giving it the information of the run
+    fn create_part(&mut self) -> Result<HDFWriter, EventSinkError> {
+        let part = self.n_parts;
+        let part_path = self.get_part_path(part);
+        spdlog::info!("Opening output part {}", part_path.display());
+        let writer = make_hdf_writer(
+            &self.config,
+            &part_path,
+            self.pedestals.clone(),
+            &self.layout,
+        )?;
+        let previous_path = part
+            .checked_sub(1)
+            .map(|previous| self.get_part_path(previous));
+        writer.write_part_info(part as u64 + 1, previous_path.as_deref())?;
+        writer.write_cobos(&self.cobos)?;
+        if let Some(pad_map) = &self.pad_map {
+            writer.write_pad_map(pad_map)?;
+        }
+        if let Some(run_info) = &self.run_info {
+            writer.write_frib_runinfo(run_info.clone())?;
+        }
+        writer.write_run_attributes(&self.run_attributes)?;
+        self.n_parts += 1;
+        Ok(writer)
+    }
+
+    /// Get the writer of a part, closing the open part and creating the part (and any parts before it) if needed
+    fn get_part(&mut self, part: usize) -> Result<&mut HDFWriter, EventSinkError> {
+        if self
+            .current
+            .as_ref()
+            .is_some_and(|(index, _)| *index == part)
+        {
+            return Ok(&mut self.current.as_mut().unwrap().1);
+        }
+        if let Some((_, writer)) = self.current.take() {
+            writer.close()?;
+        }
+        let writer = if part < self.n_parts {
+            let part_path = self.get_part_path(part);
+            spdlog::info!("Reopening output part {}", part_path.display());
+            let mut writer = HDFWriter::open_append(
+                &part_path,
+                self.pedestals.clone(),
+                &HdfWriterOptions::from_config(&self.config, &self.layout),
+            )?;
+            configure_hdf_writer(&self.config, &mut writer)?;
+            writer
+        } else {
+            // Parts skipped over are left empty
+            while self.n_parts < part {
+                self.create_part()?.close()?;
+            }
+            self.create_part()?
+        };
+        Ok(&mut self.current.insert((part, writer)).1)
+    }
+
+    /// Write to every part made so far, one at a time
+    fn write_parts(
+        &mut self,
+        write: impl Fn(&HDFWriter) -> Result<(), HDF5WriterError>,
+    ) -> Result<(), EventSinkError> {
+        for part in 0..self.n_parts {
+            write(self.get_part(part)?)?;
+        }
+        Ok(())
+    }
+}
+
+impl EventSink for SplitHDFWriter {
+    fn write_get_event(&mut self, event: Event, event_counter: &u64) -> Result<(), EventSinkError> {
+        let part = self.get_part_index(event_counter)?;
+        let writer = self.get_part(part)?;
+        writer.write_event(event, event_counter)?;
+        let last_get_event = *writer.get_last_get_event();
+        self.last_get_event = self.last_get_event.max(last_get_event);
+        Ok(())
+    }
+
+    fn write_orphan_silicon(
+        &mut self,
+        event: Event,
+        orphan_counter: &u64,
+    ) -> Result<(), EventSinkError> {
+        // Written to the part of the GET events around it
+        let part = self.find_part_index(&self.get_last_get_event());
+        Ok(self
+            .get_part(part)?
+            .write_orphan_silicon(event, orphan_counter)?)
+    }
+
+    fn write_frib_physics(
+        &mut self,
+        physics: PhysicsItem,
+        event_counter: &u64,
+    ) -> Result<(), EventSinkError> {
+        let part = self.get_part_index(event_counter)?;
+        if part > self.scalers_part {
+            // Carry the last cumulative scalers over, so that the rates of the next part are right
+            let previous_scalers = self.get_part(self.scalers_part)?.take_previous_scalers();
+            self.get_part(part)?.set_previous_scalers(previous_scalers);
+            self.scalers_part = part;
+        }
+        Ok(self
+            .get_part(part)?
+            .write_frib_physics(physics, event_counter)?)
+    }
+
+    fn write_frib_scalers(
+        &mut self,
+        scalers: ScalersItem,
+        counter: &u64,
+    ) -> Result<(), EventSinkError> {
+        let part = self.scalers_part;
+        Ok(self.get_part(part)?.write_frib_scalers(scalers, counter)?)
+    }
+
+    fn write_frib_runinfo(&mut self, run_info: RunInfo) -> Result<(), EventSinkError> {
+        self.write_parts(|part| part.write_frib_runinfo(run_info.clone()))?;
+        self.run_info = Some(run_info);
+        Ok(())
+    }
+
+    fn write_fileinfo(&mut self, merger: &Merger) -> Result<(), EventSinkError> {
+        let cobos = merger.get_cobos();
+        self.write_parts(|part| part.write_cobos(&cobos))?;
+        // Parts made from here on are given the CoBos when they are opened
+        self.cobos = cobos;
+        Ok(self.get_part(0)?.write_file_list(merger)?)
+    }
+
+    fn write_pad_map(&mut self, pad_map: &PadMap) -> Result<(), EventSinkError> {
+        self.write_parts(|part| part.write_pad_map(pad_map))?;
+        self.pad_map = Some(pad_map.clone());
+        Ok(())
+    }
+
+    fn write_run_attributes(
+        &mut self,
+        attributes: &BTreeMap<String, String>,
+    ) -> Result<(), EventSinkError> {
+        self.write_parts(|part| part.write_run_attributes(attributes))?;
+        self.run_attributes = attributes.clone();
+        Ok(())
+    }
+
+    fn get_last_get_event(&self) -> u64 {
+        self.last_get_event
+    }
+
+    fn get_event_path(&self, event_counter: &u64) -> Option<PathBuf> {
+        Some(self.get_part_path(self.find_part_index(event_counter)))
+    }
+
+    fn close(self: Box<Self>) -> Result<Vec<PathBuf>, EventSinkError> {
+        let paths = (0..self.n_parts)
+            .map(|part| self.get_part_path(part))
+            .collect();
+        if let Some((_, writer)) = self.current {
+            writer.close()?;
+        }
+        Ok(paths)
+    }
+}
+
+/// Make the sink for the output format of the Config, writing to path
+///
+/// If the Config has a max_events_per_file or a max_output_gb, the run is split into parts next to path (see get_part_path)
+pub fn make_sink(
+    config: &Config,
+    path: &Path,
+    pedestals: Option<Pedestals>,
+    layout: &HdfLayout,
+) -> Result<Box<dyn EventSink>, EventSinkError> {
+    match config.output_format {
+        OutputFormat::HDF5 => {
+            let max_output_bytes = config.get_max_output_bytes();
+            if config.max_events_per_file.is_some() || max_output_bytes.is_some() {
+                return Ok(Box::new(SplitHDFWriter::new(
+                    config,
+                    path,
+                    pedestals,
+                    layout,
+                    config.max_events_per_file,
+                    max_output_bytes,
+                )));
+            }
+            Ok(Box::new(make_hdf_writer(config, path, pedestals, layout)?))
+        }
+    }
+}
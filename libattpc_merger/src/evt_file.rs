@@ -1,18 +1,29 @@
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{Seek, SeekFrom};
 use std::path::{Path, PathBuf};
 
-use byteorder::LittleEndian;
-use byteorder::ReadBytesExt;
-
+use super::config::RetryOptions;
+use super::constants::MINIMUM_RING_ITEM_SIZE;
 use super::error::EvtFileError;
-use super::ring_item::RingItem;
+use super::retry::read_exact_with_retry;
+use super::ring_item::{is_known_ring_type, RingItem, RingType};
+
+/// The furthest a corrupt file is scanned for the next ring item before giving up
+const MAX_RESYNC_SCAN_BYTES: u64 = 64 * 1024 * 1024;
+/// The number of bytes read at a time while scanning
+const RESYNC_CHUNK_SIZE: u64 = 1024 * 1024;
+/// The size and type words at the start of every ring item
+const RING_HEADER_BYTES: u64 = 8;
 
 /// Representation .evt files contain the data recorded by the FRIB DAQ system.
 ///
 /// The data is atomic in RingItems that contain various types of data.
 /// These RingItems can then be cast to functional types which parse the binary buffer
-/// and allow the data to be accessed.
+/// and allow the data to be accessed. Reads which fail with a transient error are retried according to the RetryOptions.
+///
+/// If the size word of an item is corrupt (smaller than an empty item, larger than max_item_size, or past the end of a
+/// file which is not being followed), the file is scanned forward for the next plausible ring item and reading continues
+/// from there. The skipped bytes are counted and logged.
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct EvtFile {
@@ -21,11 +32,24 @@ pub struct EvtFile {
     size_bytes: u64,
     is_eof: bool,
     is_open: bool,
+    retry: RetryOptions,
+    last_item_offset: u64, // byte offset of the last item read
+    max_item_size: u64,    // largest plausible item size
+    is_followed: bool,     // the file may still be written, so items past the end may be incomplete
+    skipped_bytes: u64,    // corrupt bytes skipped while resyncing
 }
 
 impl EvtFile {
     /// Open a evt file in read-only mode.
-    pub fn new(path: &Path) -> Result<Self, EvtFileError> {
+    ///
+    /// Items larger than max_item_size are considered corrupt. If is_followed is true, the file may still be written,
+    /// so an item running past the end of the file is waited on rather than considered corrupt.
+    pub fn new(
+        path: &Path,
+        retry: RetryOptions,
+        max_item_size: u64,
+        is_followed: bool,
+    ) -> Result<Self, EvtFileError> {
         if !path.exists() {
             return Err(EvtFileError::BadFilePath(path.to_path_buf()));
         }
@@ -40,6 +64,11 @@ impl EvtFile {
             size_bytes,
             is_eof: false,
             is_open: true,
+            retry,
+            last_item_offset: 0,
+            max_item_size,
+            is_followed,
+            skipped_bytes: 0,
         })
     }
 
@@ -48,38 +77,203 @@ impl EvtFile {
         self.is_eof
     }
 
+    /// Get the associated path on disk
+    pub fn get_filename(&self) -> &Path {
+        &self.file_path
+    }
+
+    /// Get the byte offset of the last item read from the file
+    pub fn get_last_item_offset(&self) -> u64 {
+        self.last_item_offset
+    }
+
+    /// Get the number of corrupt bytes skipped so far
+    pub fn get_skipped_bytes(&self) -> u64 {
+        self.skipped_bytes
+    }
+
+    /// Re-check the size of the file on disk, for files that are still being written.
+    ///
+    /// Returns true if the file has grown since the last check, in which case the file is no longer at the end.
+    pub fn refresh_size(&mut self) -> Result<bool, EvtFileError> {
+        let new_size = self.file_handle.metadata()?.len();
+        let has_grown = new_size > self.size_bytes;
+        self.size_bytes = new_size;
+        if has_grown {
+            self.is_eof = false;
+        }
+        Ok(has_grown)
+    }
+
     /// Retrieve the next RingItem from the buffer.
     ///
     /// Returns a `Result<RingItem>`. The RingItem can then be cast to
     /// the appropriate usable type.
     pub fn get_next_item(&mut self) -> Result<RingItem, EvtFileError> {
-        //First need to query the size of the next ring item.
-        let current_position: u64 = self.file_handle.stream_position()?;
-        let item_size = match self.file_handle.read_u32::<LittleEndian>() {
-            Ok(val) => val as usize,
-            Err(e) => match e.kind() {
-                std::io::ErrorKind::UnexpectedEof => {
+        self.read_next_item(None)
+    }
+
+    /// Retrieve the next RingItem of a type accepted by is_wanted, skipping the items of the other types.
+    ///
+    /// Only the size and type words of a skipped item are read, so skipping i.e. the physics items of a run is about
+    /// as fast as seeking through the file.
+    pub fn get_next_item_of(
+        &mut self,
+        is_wanted: fn(&RingType) -> bool,
+    ) -> Result<RingItem, EvtFileError> {
+        self.read_next_item(Some(is_wanted))
+    }
+
+    /// Read the next RingItem, skipping the items rejected by the filter if there is one
+    fn read_next_item(
+        &mut self,
+        is_wanted: Option<fn(&RingType) -> bool>,
+    ) -> Result<RingItem, EvtFileError> {
+        loop {
+            //First need to query the size of the next ring item.
+            let current_position: u64 = self.file_handle.stream_position()?;
+            let mut size_word = [0u8; 4];
+            let item_size =
+                match read_exact_with_retry(&mut self.file_handle, &mut size_word, &self.retry) {
+                    Ok(()) => u32::from_le_bytes(size_word) as usize,
+                    Err(e) => match e.kind() {
+                        std::io::ErrorKind::UnexpectedEof => {
+                            self.is_eof = true;
+                            self.file_handle.seek(SeekFrom::Start(current_position))?;
+                            return Err(EvtFileError::EndOfFile);
+                        }
+                        _ => {
+                            return Err(EvtFileError::IOError(e));
+                        }
+                    },
+                };
+
+            if !self.is_plausible_size(current_position, item_size as u64) {
+                // The item found by the scan always has a plausible size, so this only goes one level deep
+                match self.resync(current_position)? {
+                    Some(_) => continue,
+                    None => return Err(EvtFileError::EndOfFile),
+                }
+            }
+
+            if let Some(is_wanted) = is_wanted {
+                let mut type_word = [0u8; 4];
+                if let Err(e) =
+                    read_exact_with_retry(&mut self.file_handle, &mut type_word, &self.retry)
+                {
+                    if e.kind() != std::io::ErrorKind::UnexpectedEof {
+                        return Err(EvtFileError::IOError(e));
+                    }
                     self.is_eof = true;
+                    self.file_handle.seek(SeekFrom::Start(current_position))?;
                     return Err(EvtFileError::EndOfFile);
                 }
-                _ => {
-                    return Err(EvtFileError::IOError(e));
+                // The upper bytes of the type word are always 0, see is_known_ring_type
+                if !is_wanted(&RingType::from(type_word[0])) {
+                    let item_end = current_position + item_size as u64;
+                    if item_end > self.size_bytes {
+                        // A followed file which is still being written, come back once the item is complete
+                        self.is_eof = true;
+                        self.file_handle.seek(SeekFrom::Start(current_position))?;
+                        return Err(EvtFileError::EndOfFile);
+                    }
+                    self.last_item_offset = current_position;
+                    self.file_handle.seek(SeekFrom::Start(item_end))?;
+                    continue;
                 }
-            },
-        };
-
-        self.file_handle.seek(SeekFrom::Start(current_position))?; // Go back to start of item (size is self contained)
-        let mut buffer: Vec<u8> = vec![0; item_size]; // set size of bytes vector
-        match self.file_handle.read_exact(&mut buffer) {
-            // try to read ring item
-            Err(e) => match e.kind() {
-                std::io::ErrorKind::UnexpectedEof => {
-                    self.is_eof = true;
-                    Err(EvtFileError::EndOfFile)
+            }
+
+            self.file_handle.seek(SeekFrom::Start(current_position))?; // Go back to start of item (size is self contained)
+            let mut buffer: Vec<u8> = vec![0; item_size]; // set size of bytes vector
+            return match read_exact_with_retry(&mut self.file_handle, &mut buffer, &self.retry) {
+                // try to read ring item
+                Err(e) => match e.kind() {
+                    std::io::ErrorKind::UnexpectedEof => {
+                        self.is_eof = true;
+                        // Return to the start of the item in case more data is written later
+                        self.file_handle.seek(SeekFrom::Start(current_position))?;
+                        Err(EvtFileError::EndOfFile)
+                    }
+                    _ => Err(EvtFileError::IOError(e)),
+                },
+                Ok(()) => {
+                    self.last_item_offset = current_position;
+                    RingItem::try_from(buffer).map_err(|e| {
+                        EvtFileError::ItemAt(self.file_path.clone(), current_position, e)
+                    })
+                }
+            };
+        }
+    }
+
+    /// Check if an item of the given size could start at position
+    fn is_plausible_size(&self, position: u64, item_size: u64) -> bool {
+        item_size >= MINIMUM_RING_ITEM_SIZE
+            && item_size <= self.max_item_size
+            && (self.is_followed || position + item_size <= self.size_bytes)
+    }
+
+    /// Scan forward from a corrupt item at start for the next offset where the size word is plausible and the type
+    /// word is a known RingType, skipping to it.
+    ///
+    /// Returns the offset of the next item. If the end of the file is reached first, None is returned: a followed file
+    /// is left at start so the scan is repeated once more data is written, otherwise the rest of the file is skipped.
+    /// If nothing is found within MAX_RESYNC_SCAN_BYTES, a Corrupt error is returned.
+    fn resync(&mut self, start: u64) -> Result<Option<u64>, EvtFileError> {
+        let scan_end = self.size_bytes.min(start + MAX_RESYNC_SCAN_BYTES);
+        let mut offset = start + 1;
+        let mut buffer = Vec::new();
+        while offset + RING_HEADER_BYTES <= self.size_bytes && offset < scan_end {
+            // Read a little past the chunk, so that headers across the end of the chunk are checked
+            let chunk_end =
+                (offset + RESYNC_CHUNK_SIZE + RING_HEADER_BYTES - 1).min(self.size_bytes);
+            self.file_handle.seek(SeekFrom::Start(offset))?;
+            buffer.resize((chunk_end - offset) as usize, 0);
+            read_exact_with_retry(&mut self.file_handle, &mut buffer, &self.retry)?;
+            for (idx, header) in buffer.windows(RING_HEADER_BYTES as usize).enumerate() {
+                let position = offset + idx as u64;
+                if position >= scan_end {
+                    break;
                 }
-                _ => Err(EvtFileError::IOError(e)),
-            },
-            Ok(()) => Ok(RingItem::try_from(buffer)?),
+                let item_size = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+                let type_word = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+                if is_known_ring_type(type_word)
+                    && self.is_plausible_size(position, item_size as u64)
+                {
+                    self.skip_to(start, position)?;
+                    return Ok(Some(position));
+                }
+            }
+            offset = chunk_end + 1 - RING_HEADER_BYTES;
         }
+
+        if scan_end < self.size_bytes {
+            return Err(EvtFileError::Corrupt(
+                self.file_path.clone(),
+                start,
+                MAX_RESYNC_SCAN_BYTES,
+            ));
+        }
+        self.is_eof = true;
+        if self.is_followed {
+            self.file_handle.seek(SeekFrom::Start(start))?;
+        } else {
+            self.skip_to(start, self.size_bytes)?;
+        }
+        Ok(None)
+    }
+
+    /// Skip the corrupt bytes from start to position, counting and logging them
+    fn skip_to(&mut self, start: u64, position: u64) -> Result<(), EvtFileError> {
+        let skipped = position - start;
+        spdlog::warn!(
+            "Skipped {} corrupt bytes of evt file {} at byte offset {}.",
+            skipped,
+            self.file_path.display(),
+            start
+        );
+        self.skipped_bytes += skipped;
+        self.file_handle.seek(SeekFrom::Start(position))?;
+        Ok(())
     }
 }
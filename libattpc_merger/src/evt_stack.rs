@@ -1,14 +1,25 @@
-use super::error::{EvtFileError, EvtStackError};
+use super::config::{FollowOptions, RetryOptions};
+use super::error::{EvtFileError, EvtItemError, EvtStackError};
 use super::evt_file::EvtFile;
-use super::ring_item::RingItem;
+use super::ring_item::{BeginRunItem, RingItem, RingType};
 
 use std::collections::VecDeque;
+use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// The most ring items read looking for the BeginRun item when peeking at a run. FRIBDAQ writes it first, after at
+/// most a few items describing the ring format
+const PEEK_MAX_RING_ITEMS: usize = 16;
 
 /// Similar to AsadStack, we have EvtStacks for the FRIBDAQ data.
 ///
 /// FRIBDAQ .evt files are split every 2.0GB for legacy reasons. The stack is the
 /// collection of all files associated with a given run in the FRIBDAQ system.
+///
+/// Like AsadStack, EvtStack can follow files which are still being written when given FollowOptions. A followed
+/// stack ends once the EndRun item is read, as FRIBDAQ writes nothing more for the run, rather than at the timeout.
+/// Corrupt bytes skipped by the files (see EvtFile) are counted per file.
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct EvtStack {
@@ -17,54 +28,152 @@ pub struct EvtStack {
     total_stack_size_bytes: u64,
     is_ended: bool,
     parent_path: PathBuf,
+    follow: Option<FollowOptions>,
+    retry: RetryOptions,
+    waiting_since: Option<Instant>,
+    max_item_size: u64,
+    skipped_bytes: Vec<(PathBuf, u64)>, // corrupt bytes skipped in each finished file, if any
 }
 
 impl EvtStack {
     /// Create a new EvtStack for a given FRIBDAQ run directory
-    pub fn new(path: &Path) -> Result<Self, EvtStackError> {
+    ///
+    /// If follow is Some, the stack will wait for files which are still being written. Failed reads are retried
+    /// according to the RetryOptions. Items larger than max_item_size are considered corrupt.
+    pub fn new(
+        path: &Path,
+        follow: Option<FollowOptions>,
+        retry: RetryOptions,
+        max_item_size: u64,
+    ) -> Result<Self, EvtStackError> {
         let (mut stack, bytes) = Self::get_file_stack(path)?;
         if let Some(file_path) = stack.pop_front() {
             Ok(EvtStack {
                 file_stack: stack,
-                active_file: EvtFile::new(&file_path)?,
+                active_file: EvtFile::new(&file_path, retry, max_item_size, follow.is_some())?,
                 total_stack_size_bytes: bytes,
                 is_ended: false,
                 parent_path: PathBuf::from(path),
+                follow,
+                retry,
+                waiting_since: None,
+                max_item_size,
+                skipped_bytes: Vec::new(),
             })
         } else {
             Err(EvtStackError::NoMatchingFiles)
         }
     }
 
+    /// Attach the file and byte offset of the last ring item read to an error converting it, i.e. to a PhysicsItem
+    pub fn item_error(&self, error: EvtItemError) -> EvtStackError {
+        EvtStackError::ItemAt(
+            self.active_file.get_filename().to_path_buf(),
+            self.active_file.get_last_item_offset(),
+            error,
+        )
+    }
+
+    /// Get the paths of the active file and the files remaining in the stack
+    pub fn get_file_paths(&self) -> Vec<PathBuf> {
+        let mut paths = vec![self.active_file.get_filename().to_path_buf()];
+        paths.extend(self.file_stack.iter().cloned());
+        paths
+    }
+
+    /// Get the number of corrupt bytes skipped in each file so far. Only files with skipped bytes are included
+    pub fn get_skipped_bytes(&self) -> Vec<(PathBuf, u64)> {
+        let mut skipped = self.skipped_bytes.clone();
+        if self.active_file.get_skipped_bytes() > 0 {
+            skipped.push((
+                self.active_file.get_filename().to_path_buf(),
+                self.active_file.get_skipped_bytes(),
+            ));
+        }
+        skipped
+    }
+
     /// Get the next ring item in the file stack
     ///
     /// Returns a `Result<Option<RingItem>>`. The Option is None if the stack has
-    /// no more data.
+    /// no more data. In follow mode, `EvtStackError::WaitingForData` is returned if the
+    /// stack is waiting on the DAQ to write more data, and the stack ends after the EndRun item.
     pub fn get_next_ring_item(&mut self) -> Result<Option<RingItem>, EvtStackError> {
+        self.read_next_ring_item(None)
+    }
+
+    /// Get the next ring item of a type accepted by is_wanted in the file stack, skipping the others without reading
+    /// them (see EvtFile::get_next_item_of)
+    ///
+    /// As for get_next_ring_item, the Option is None if the stack has no more data.
+    pub fn get_next_ring_item_of(
+        &mut self,
+        is_wanted: fn(&RingType) -> bool,
+    ) -> Result<Option<RingItem>, EvtStackError> {
+        self.read_next_ring_item(Some(is_wanted))
+    }
+
+    fn read_next_ring_item(
+        &mut self,
+        is_wanted: Option<fn(&RingType) -> bool>,
+    ) -> Result<Option<RingItem>, EvtStackError> {
         loop {
             if self.is_ended {
                 return Ok(None);
             }
 
-            match self.active_file.get_next_item() {
-                Ok(ring) => return Ok(Some(ring)),
+            let next_item = match is_wanted {
+                Some(is_wanted) => self.active_file.get_next_item_of(is_wanted),
+                None => self.active_file.get_next_item(),
+            };
+            match next_item {
+                Ok(ring) => {
+                    self.waiting_since = None;
+                    // The end of the run, so there is nothing more to wait for
+                    if self.follow.is_some() && matches!(ring.ring_type, RingType::EndRun) {
+                        self.is_ended = true;
+                    }
+                    return Ok(Some(ring));
+                }
                 Err(EvtFileError::EndOfFile) => {
-                    self.move_to_next_file()?;
+                    if self.follow.is_some() && self.file_stack.is_empty() {
+                        self.wait_for_data()?;
+                    } else {
+                        self.move_to_next_file()?;
+                    }
                 }
                 Err(e) => return Err(EvtStackError::FileError(e)),
             };
         }
     }
 
+    /// Read the BeginRun item of the run without reading the rest of the data, i.e. to report the run title and start
+    /// time of a run without merging it
+    ///
+    /// Only the first few ring items are read. Returns None if none of them is the BeginRun item (or the stack has no
+    /// data yet). The items read are consumed, so the stack should not be used to merge the run afterwards.
+    pub fn peek_begin_run(&mut self) -> Result<Option<BeginRunItem>, EvtStackError> {
+        for _ in 0..PEEK_MAX_RING_ITEMS {
+            let ring = match self.get_next_ring_item() {
+                Ok(Some(ring)) => ring,
+                Ok(None) | Err(EvtStackError::WaitingForData) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            if matches!(ring.ring_type, RingType::BeginRun) {
+                return Ok(Some(
+                    BeginRunItem::try_from(ring).map_err(|e| self.item_error(e))?,
+                ));
+            }
+        }
+        Ok(None)
+    }
+
     /// Get all of the associated .evt files and put them in the stack
     fn get_file_stack(parent_path: &Path) -> Result<(VecDeque<PathBuf>, u64), EvtStackError> {
         let mut file_list: Vec<PathBuf> = Vec::new();
-        let start_pattern = "run-";
-        let end_pattern = ".evt";
         for item in parent_path.read_dir()? {
             let item_path = item?.path();
-            let item_path_str = item_path.to_str().unwrap();
-            if item_path_str.contains(start_pattern) && item_path_str.contains(end_pattern) {
+            if is_evt_file(&item_path) {
                 file_list.push(item_path);
             }
         }
@@ -77,18 +186,71 @@ impl EvtStack {
             .iter()
             .fold(0, |sum, path| sum + path.metadata().unwrap().len());
 
-        file_list.sort(); // Can sort standard. The only change should be the number at the tail.
+        // The segment numbers grow past two digits, so they are sorted by value (-2 before -10)
+        file_list.sort_by_cached_key(|path| get_evt_order_key(path));
         let stack = file_list.into();
 
         Ok((stack, total_stack_size_bytes))
     }
 
+    /// Check for new data in follow mode
+    ///
+    /// If the active file has grown or new files have been written, return so that the data can be read.
+    /// Otherwise return a WaitingForData error, or end the stack if we have waited longer than the timeout.
+    fn wait_for_data(&mut self) -> Result<(), EvtStackError> {
+        let options = match self.follow {
+            Some(opt) => opt,
+            None => return Ok(()),
+        };
+
+        if self.active_file.refresh_size()? {
+            return Ok(());
+        }
+
+        // FRIBDAQ may have moved on to a new file
+        if let Ok((all_files, _)) = Self::get_file_stack(&self.parent_path) {
+            for path in all_files {
+                if get_evt_order_key(&path) > get_evt_order_key(self.active_file.get_filename())
+                    && !self.file_stack.contains(&path)
+                {
+                    self.total_stack_size_bytes += path.metadata()?.len();
+                    self.file_stack.push_back(path);
+                }
+            }
+        }
+        if !self.file_stack.is_empty() {
+            return self.move_to_next_file();
+        }
+
+        let waiting_since = *self.waiting_since.get_or_insert_with(Instant::now);
+        if waiting_since.elapsed() >= options.timeout {
+            spdlog::warn!(
+                "No new evt data in {} seconds, assuming the data is complete.",
+                options.timeout.as_secs()
+            );
+            self.is_ended = true;
+            return Ok(());
+        }
+        Err(EvtStackError::WaitingForData)
+    }
+
     ///Move to the next file in the stack
     fn move_to_next_file(&mut self) -> Result<(), EvtStackError> {
         loop {
             if let Some(next_file_path) = self.file_stack.pop_front() {
-                let next_file = EvtFile::new(&next_file_path)?;
+                let next_file = EvtFile::new(
+                    &next_file_path,
+                    self.retry,
+                    self.max_item_size,
+                    self.follow.is_some(),
+                )?;
                 if !next_file.is_eof() {
+                    if self.active_file.get_skipped_bytes() > 0 {
+                        self.skipped_bytes.push((
+                            self.active_file.get_filename().to_path_buf(),
+                            self.active_file.get_skipped_bytes(),
+                        ));
+                    }
                     self.active_file = next_file;
                     return Ok(());
                 }
@@ -99,3 +261,278 @@ impl EvtStack {
         }
     }
 }
+
+/// Check if a path is a FRIBDAQ .evt file (run-####-##.evt)
+///
+/// Only the file name is matched, so the parent directories may contain anything (spaces, non-UTF8, UNC prefixes, ...)
+/// The extension is matched regardless of case (.evt, .EVT), as files copied through some file systems are renamed.
+pub fn is_evt_file(path: &Path) -> bool {
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy(),
+        None => return false,
+    };
+    name.starts_with("run-")
+        && path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case(OsStr::new("evt")))
+}
+
+/// Get the segment number of a FRIBDAQ .evt file (the ## of run-####-##.evt), if the name has one
+pub fn get_evt_segment(path: &Path) -> Option<u32> {
+    let stem = path.file_stem()?.to_string_lossy();
+    let mut parts = stem.rsplitn(3, '-');
+    let segment = parts.next()?;
+    // run-####-## has three parts, run-#### would give the run number
+    match (parts.next(), parts.next()) {
+        (Some(_), Some(_)) => segment.parse().ok(),
+        _ => None,
+    }
+}
+
+/// The key ordering the .evt files of a run: by segment number, then by name
+fn get_evt_order_key(path: &Path) -> (Option<u32>, PathBuf) {
+    (get_evt_segment(path), path.to_path_buf())
+}
+
+//Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::DEFAULT_MAX_RING_ITEM_SIZE;
+    use crate::test_data::{make_begin_run_ring, make_end_run_ring, write_evt_file};
+    use std::io::Write;
+    use std::time::Duration;
+
+    /// Make the bytes of a dummy ring item (no body header)
+    fn make_dummy_ring() -> Vec<u8> {
+        vec![12, 0, 0, 0, 12, 0, 0, 0, 0, 0, 0, 0]
+    }
+
+    #[test]
+    fn test_evt_file_names() {
+        let dir = PathBuf::from("/data/e20009 evt/Ünïcode/run1");
+        assert!(is_evt_file(&dir.join("run-0001-00.evt")));
+        assert!(is_evt_file(&dir.join("run-0001-01.evt")));
+        assert!(!is_evt_file(&dir.join("run-0001-00.evt.bak")));
+        assert!(!is_evt_file(&dir.join("scaler-run-0001-00.evt")));
+        // The pattern is only matched in the file name, not the directories
+        assert!(!is_evt_file(&PathBuf::from("/data/run-0001.evt/notes.txt")));
+        assert!(is_evt_file(&dir.join("run-0001-00.EVT")));
+
+        assert_eq!(get_evt_segment(&dir.join("run-0001-10.evt")), Some(10));
+        assert_eq!(get_evt_segment(&dir.join("run-0001-02.evt")), Some(2));
+        assert_eq!(get_evt_segment(&dir.join("run-0001.evt")), None);
+    }
+
+    #[test]
+    fn test_evt_file_order() {
+        let dir =
+            std::env::temp_dir().join(format!("attpc_merger_evt_order_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // A lexical sort would put -10 and -11 before -2
+        for segment in [11, 2, 0, 10, 1] {
+            std::fs::write(dir.join(format!("run-0001-{:0>2}.evt", segment)), [0u8; 4]).unwrap();
+        }
+        let (stack, total_size) = EvtStack::get_file_stack(&dir).unwrap();
+        let segments: Vec<Option<u32>> = stack.iter().map(|path| get_evt_segment(path)).collect();
+        assert_eq!(
+            segments,
+            vec![Some(0), Some(1), Some(2), Some(10), Some(11)]
+        );
+        assert_eq!(total_size, 20);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_evt_file_names_non_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let dir = PathBuf::from(OsStr::from_bytes(b"/data/run\xff\xfe"));
+        assert!(is_evt_file(&dir.join("run-0001-00.evt")));
+    }
+
+    #[test]
+    fn test_follow_growing_file() {
+        let dir =
+            std::env::temp_dir().join(format!("attpc_merger_evt_follow_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("run-0001-00.evt");
+        std::fs::write(&path, make_dummy_ring()).unwrap();
+
+        let options = FollowOptions {
+            poll_interval: Duration::from_millis(10),
+            timeout: Duration::from_millis(500),
+        };
+        let mut stack = EvtStack::new(
+            &dir,
+            Some(options),
+            RetryOptions::default(),
+            DEFAULT_MAX_RING_ITEM_SIZE,
+        )
+        .unwrap();
+
+        // Pretend to be FRIBDAQ
+        let writer_path = path.clone();
+        let writer = std::thread::spawn(move || {
+            for _ in 0..2 {
+                std::thread::sleep(Duration::from_millis(50));
+                let mut file = std::fs::OpenOptions::new()
+                    .append(true)
+                    .open(&writer_path)
+                    .unwrap();
+                file.write_all(&make_dummy_ring()).unwrap();
+            }
+        });
+
+        let mut n_rings = 0;
+        let mut waited = false;
+        loop {
+            match stack.get_next_ring_item() {
+                Ok(Some(_)) => n_rings += 1,
+                Ok(None) => break,
+                Err(EvtStackError::WaitingForData) => {
+                    waited = true;
+                    std::thread::sleep(options.poll_interval);
+                }
+                Err(e) => panic!("Unexpected error: {e}"),
+            }
+        }
+        writer.join().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(waited);
+        assert_eq!(n_rings, 3);
+    }
+
+    #[test]
+    fn test_peek_begin_run() {
+        let dir =
+            std::env::temp_dir().join(format!("attpc_merger_evt_peek_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        write_evt_file(
+            &dir,
+            1,
+            &[
+                make_dummy_ring(),
+                make_begin_run_ring(7, 1234, "peek"),
+                make_end_run_ring(20, 10),
+            ],
+        )
+        .unwrap();
+        let mut stack = EvtStack::new(
+            &dir,
+            None,
+            RetryOptions::default(),
+            DEFAULT_MAX_RING_ITEM_SIZE,
+        )
+        .unwrap();
+        let begin = stack.peek_begin_run().unwrap().unwrap();
+        assert_eq!(begin.run, 7);
+        assert_eq!(begin.start, 1234);
+        assert_eq!(begin.title, "peek");
+        // The rest of the run is not read
+        assert!(matches!(
+            stack.get_next_ring_item().unwrap().unwrap().ring_type,
+            RingType::EndRun
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_resync_corrupt_item() {
+        let dir =
+            std::env::temp_dir().join(format!("attpc_merger_evt_resync_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // A huge size word, then a zero size word, between two valid rings
+        let mut garbage = vec![0xff; 20];
+        garbage.extend_from_slice(&[0; 17]);
+        let path = write_evt_file(
+            &dir,
+            1,
+            &[
+                make_begin_run_ring(1, 10, "corrupt"),
+                garbage.clone(),
+                make_dummy_ring(),
+                make_end_run_ring(20, 10),
+                // A truncated item at the end of the file
+                vec![0; 5],
+            ],
+        )
+        .unwrap();
+
+        let mut stack = EvtStack::new(
+            &dir,
+            None,
+            RetryOptions::default(),
+            DEFAULT_MAX_RING_ITEM_SIZE,
+        )
+        .unwrap();
+        let mut types = Vec::new();
+        while let Some(ring) = stack.get_next_ring_item().unwrap() {
+            types.push(ring.ring_type);
+        }
+        assert!(matches!(
+            types.as_slice(),
+            [RingType::BeginRun, RingType::Dummy, RingType::EndRun]
+        ));
+        assert_eq!(
+            stack.get_skipped_bytes(),
+            vec![(path, garbage.len() as u64 + 5)]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_follow_next_segment_and_end_run() {
+        let dir = std::env::temp_dir().join(format!(
+            "attpc_merger_evt_follow_end_{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("run-0001-9.evt"), make_dummy_ring()).unwrap();
+
+        // A long timeout, which the EndRun item cuts short
+        let options = FollowOptions {
+            poll_interval: Duration::from_millis(10),
+            timeout: Duration::from_secs(30),
+        };
+        let mut stack = EvtStack::new(
+            &dir,
+            Some(options),
+            RetryOptions::default(),
+            DEFAULT_MAX_RING_ITEM_SIZE,
+        )
+        .unwrap();
+        assert!(stack.get_next_ring_item().unwrap().is_some());
+        assert!(matches!(
+            stack.get_next_ring_item(),
+            Err(EvtStackError::WaitingForData)
+        ));
+
+        // FRIBDAQ moves on to segment 10, which sorts before 9 by name. After the EndRun item, it is done with the run
+        let mut segment = make_dummy_ring();
+        segment.extend(make_end_run_ring(20, 10));
+        std::fs::write(dir.join("run-0001-10.evt"), segment).unwrap();
+        let start = Instant::now();
+        let mut ring_types = Vec::new();
+        loop {
+            match stack.get_next_ring_item() {
+                Ok(Some(ring)) => ring_types.push(ring.ring_type),
+                Ok(None) => break,
+                Err(EvtStackError::WaitingForData) => std::thread::sleep(options.poll_interval),
+                Err(e) => panic!("Unexpected error: {e}"),
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(matches!(
+            ring_types[..],
+            [RingType::Dummy, RingType::EndRun]
+        ));
+        assert!(start.elapsed() < options.timeout);
+    }
+}
@@ -0,0 +1,101 @@
+use std::io::{Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Size of the chunks read and written by a copy
+const COPY_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Copy a file, optionally limiting the rate of the copy.
+///
+/// See `copy_file_with_progress`. Returns the number of bytes copied.
+pub fn copy_file(
+    source: &Path,
+    destination: &Path,
+    bytes_per_second: Option<u64>,
+) -> std::io::Result<u64> {
+    copy_file_with_progress(source, destination, bytes_per_second, |_| {})
+}
+
+/// Copy a file in chunks, reporting the progress after every chunk, optionally limiting the rate of the copy.
+///
+/// on_progress is called with the number of bytes copied so far after each chunk is written, so that a
+/// multi-gigabyte file doesn't look stalled. With a limit (in bytes per second), the copy sleeps after each chunk
+/// as needed to keep the average rate at or below the limit. This keeps large copies from saturating a network
+/// link which is shared with the DAQ. Returns the number of bytes copied.
+pub fn copy_file_with_progress<F: FnMut(u64)>(
+    source: &Path,
+    destination: &Path,
+    bytes_per_second: Option<u64>,
+    mut on_progress: F,
+) -> std::io::Result<u64> {
+    let limit = bytes_per_second.filter(|limit| *limit > 0);
+
+    let mut reader = std::fs::File::open(source)?;
+    let mut writer = std::fs::File::create(destination)?;
+    let mut buffer = vec![0u8; COPY_CHUNK_SIZE];
+    let start = Instant::now();
+    let mut bytes_copied: u64 = 0;
+    loop {
+        let n_read = reader.read(&mut buffer)?;
+        if n_read == 0 {
+            break;
+        }
+        writer.write_all(&buffer[..n_read])?;
+        bytes_copied += n_read as u64;
+        on_progress(bytes_copied);
+
+        if let Some(limit) = limit {
+            let target = Duration::from_secs_f64(bytes_copied as f64 / limit as f64);
+            let elapsed = start.elapsed();
+            if target > elapsed {
+                std::thread::sleep(target - elapsed);
+            }
+        }
+    }
+    writer.flush()?;
+    writer.set_permissions(reader.metadata()?.permissions())?;
+    Ok(bytes_copied)
+}
+
+//Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_copy_file_rate_limit() {
+        let directory =
+            std::env::temp_dir().join(format!("attpc_merger_copy_test_{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let source = directory.join("source.graw");
+        // A bit more than one chunk, so that the copy is split
+        let data: Vec<u8> = (0..(10 * 1024 * 1024))
+            .map(|i: u32| (i % 251) as u8)
+            .collect();
+        std::fs::write(&source, &data).unwrap();
+
+        // 20 MB/s should take about half a second
+        let destination = directory.join("limited.graw");
+        let start = Instant::now();
+        let n_copied = copy_file(&source, &destination, Some(20_000_000)).unwrap();
+        let elapsed = start.elapsed();
+        assert_eq!(n_copied, data.len() as u64);
+        assert_eq!(std::fs::read(&destination).unwrap(), data);
+        assert!(elapsed >= Duration::from_millis(500));
+
+        let destination = directory.join("unlimited.graw");
+        copy_file(&source, &destination, None).unwrap();
+        assert_eq!(std::fs::read(&destination).unwrap(), data);
+
+        // Progress is reported after every chunk, ending at the full size
+        let destination = directory.join("progress.graw");
+        let mut reported = Vec::new();
+        copy_file_with_progress(&source, &destination, None, |n| reported.push(n)).unwrap();
+        assert!(reported.len() >= 2);
+        assert!(reported.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(reported.last(), Some(&(data.len() as u64)));
+        assert_eq!(std::fs::read(&destination).unwrap(), data);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,177 @@
+use hdf5::types::VarLenUnicode;
+use hdf5::File;
+use std::fmt::Display;
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::str::FromStr;
+
+use super::error::FormatError;
+use super::hdf_writer::HdfLayout;
+
+/// This is the version of the output format
+pub const FORMAT_VERSION: &str = "1.6";
+/// The major version of the output format. Files of a different major version can not be read the same way
+pub const FORMAT_MAJOR: u32 = 1;
+/// The minor version of the output format. Minor versions only add to the format
+pub const FORMAT_MINOR: u32 = 6;
+
+/// The version of the output format a file was written with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl Version {
+    pub fn new(major: u32, minor: u32) -> Self {
+        Self { major, minor }
+    }
+
+    /// The version of the output format written by this library
+    pub fn current() -> Self {
+        Self::new(FORMAT_MAJOR, FORMAT_MINOR)
+    }
+}
+
+impl FromStr for Version {
+    type Err = FormatError;
+
+    /// Parse a version of the form major.minor
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (major, minor) = s
+            .trim()
+            .split_once('.')
+            .ok_or_else(|| FormatError::BadVersion(s.to_string()))?;
+        match (major.parse::<u32>(), minor.parse::<u32>()) {
+            (Ok(major), Ok(minor)) => Ok(Self::new(major, minor)),
+            _ => Err(FormatError::BadVersion(s.to_string())),
+        }
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// Parse the version attribute of the events group, which is name:major.minor (i.e. libattpc_merger:1.1)
+pub fn parse_version_attribute(version: &str) -> Result<(String, Version), FormatError> {
+    let (name, number) = version
+        .rsplit_once(':')
+        .ok_or_else(|| FormatError::BadVersion(version.to_string()))?;
+    Ok((name.to_string(), number.parse::<Version>()?))
+}
+
+/// Read the name of the writer and the format version of the merged file at path
+///
+/// The version is read from the `version` attribute of the events group in the default layout. It is written by every
+/// version of the format, unlike the `format_major` and `format_minor` attributes which were added in 1.1.
+pub fn read_format_version(path: &Path) -> Result<(String, Version), FormatError> {
+    let file = File::open(path)?;
+    let version = file
+        .group(&HdfLayout::default().events)?
+        .attr("version")?
+        .read_scalar::<VarLenUnicode>()?;
+    parse_version_attribute(version.as_str())
+}
+
+/// Check if a file of file_version can be read by a reader which supports the supported_range of versions
+///
+/// The versions are ordered by major and then by minor version, so a reader of any 1.x file from 1.1 on supports
+/// `Version::new(1, 1)..Version::new(2, 0)`, and a reader of only the current version supports
+/// `Version::current()..=Version::current()`. Minor versions only add to the format, so a reader should normally not
+/// put an upper bound on the minor version within a major version.
+pub fn is_compatible<R: RangeBounds<Version>>(file_version: &Version, supported_range: R) -> bool {
+    supported_range.contains(file_version)
+}
+
+//Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hdf_writer::{HDFWriter, HdfWriterOptions};
+
+    #[test]
+    fn test_version_parsing() {
+        assert_eq!(format!("{}.{}", FORMAT_MAJOR, FORMAT_MINOR), FORMAT_VERSION);
+        assert_eq!(
+            FORMAT_VERSION.parse::<Version>().unwrap(),
+            Version::current()
+        );
+        let (name, version) = parse_version_attribute("libattpc_merger:1.0").unwrap();
+        assert_eq!(name, "libattpc_merger");
+        assert_eq!(version, Version::new(1, 0));
+        assert!(Version::new(1, 10) > Version::new(1, 9));
+        assert!(parse_version_attribute("libattpc_merger").is_err());
+        assert!(parse_version_attribute("libattpc_merger:1").is_err());
+        assert!(parse_version_attribute("libattpc_merger:1.x").is_err());
+    }
+
+    #[test]
+    fn test_compatibility() {
+        let dir = std::env::temp_dir().join(format!("attpc_merger_format_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // A file of the current version
+        let current_path = dir.join("run_0001.h5");
+        let writer = HDFWriter::new(
+            &current_path,
+            None,
+            &HdfWriterOptions {
+                write_quality_flags: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        writer.close().unwrap();
+        let file = File::open(&current_path).unwrap();
+        let events = file.group("events").unwrap();
+        assert_eq!(
+            events
+                .attr("format_major")
+                .unwrap()
+                .read_scalar::<u32>()
+                .unwrap(),
+            FORMAT_MAJOR
+        );
+        assert_eq!(
+            events
+                .attr("format_minor")
+                .unwrap()
+                .read_scalar::<u32>()
+                .unwrap(),
+            FORMAT_MINOR
+        );
+        drop(file);
+
+        // A file of 1.0, which only had the version string
+        let old_path = dir.join("run_0002.h5");
+        let file = File::create(&old_path).unwrap();
+        file.create_group("events")
+            .unwrap()
+            .new_attr::<VarLenUnicode>()
+            .create("version")
+            .unwrap()
+            .write_scalar(&VarLenUnicode::from_str("libattpc_merger:1.0").unwrap())
+            .unwrap();
+        drop(file);
+
+        let (name, current) = read_format_version(&current_path).unwrap();
+        assert_eq!(name, env!("CARGO_PKG_NAME"));
+        assert_eq!(current, Version::current());
+        let (_, old) = read_format_version(&old_path).unwrap();
+        assert_eq!(old, Version::new(1, 0));
+
+        let any_one = Version::new(1, 0)..Version::new(2, 0);
+        assert!(is_compatible(&old, any_one.clone()));
+        assert!(is_compatible(&current, any_one));
+        let from_current = Version::current()..Version::new(2, 0);
+        assert!(!is_compatible(&old, from_current.clone()));
+        assert!(is_compatible(&current, from_current));
+        assert!(!is_compatible(&Version::new(2, 0), ..Version::new(2, 0)));
+        assert!(is_compatible(&Version::new(2, 0), Version::new(1, 0)..));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,278 @@
+use bitvec::prelude::*;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::graw_frame::{FrameProvenance, GrawData, GrawFrame, GrawFrameHeader};
+
+/// Numbers the spill files of this process, so that every EventBuilder gets its own file
+static SPILL_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Removes the spill file once both the writer and the reader are done with it
+#[derive(Debug)]
+struct SpillPath(PathBuf);
+
+impl Drop for SpillPath {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            spdlog::warn!("Could not remove spill file {}: {e}", self.0.display());
+        }
+    }
+}
+
+/// FrameSpill holds the frames of an event too large to keep in memory in a temporary file, see
+/// EventBuilder::set_spill.
+///
+/// The frames are written in a simple binary layout (not the GRAW format) and read back in the order they were written
+/// with into_frames. The file is removed once the frames are read back or the spill is dropped. The bad data counts of
+/// the frames are not kept, as they are counted by the Merger before the frames reach the EventBuilder.
+#[derive(Debug)]
+pub struct FrameSpill {
+    path: SpillPath,
+    writer: BufWriter<File>,
+    n_frames: usize,
+}
+
+impl FrameSpill {
+    /// Create a new spill file in the given directory
+    pub fn new(directory: &Path) -> Result<Self, std::io::Error> {
+        let path = directory.join(format!(
+            ".attpc_merger_spill_{}_{}.bin",
+            std::process::id(),
+            SPILL_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        Ok(Self {
+            path: SpillPath(path),
+            writer: BufWriter::new(file),
+            n_frames: 0,
+        })
+    }
+
+    /// The path of the spill file
+    pub fn get_path(&self) -> &Path {
+        &self.path.0
+    }
+
+    /// The number of frames in the spill file
+    pub fn get_n_frames(&self) -> usize {
+        self.n_frames
+    }
+
+    /// Add a frame to the spill file
+    pub fn write_frame(&mut self, frame: &GrawFrame) -> Result<(), std::io::Error> {
+        write_header(&mut self.writer, &frame.header)?;
+        let file_path = frame.provenance.file_path.to_string_lossy();
+        self.writer
+            .write_u32::<LittleEndian>(file_path.len() as u32)?;
+        self.writer.write_all(file_path.as_bytes())?;
+        self.writer
+            .write_u64::<LittleEndian>(frame.provenance.offset)?;
+        self.writer.write_u8(frame.provenance.cobo_id)?;
+        self.writer.write_u8(frame.provenance.asad_id)?;
+        self.writer
+            .write_u32::<LittleEndian>(frame.data.len() as u32)?;
+        for datum in frame.data.iter() {
+            self.writer.write_u8(datum.aget_id)?;
+            self.writer.write_u8(datum.channel)?;
+            self.writer
+                .write_u16::<LittleEndian>(datum.time_bucket_id)?;
+            self.writer.write_i16::<LittleEndian>(datum.sample)?;
+        }
+        self.n_frames += 1;
+        Ok(())
+    }
+
+    /// Read the frames back from the spill file, in the order they were written
+    pub fn into_frames(self) -> Result<SpillReader, std::io::Error> {
+        let mut file = self.writer.into_inner().map_err(|e| e.into_error())?;
+        file.seek(SeekFrom::Start(0))?;
+        Ok(SpillReader {
+            _path: self.path,
+            reader: BufReader::new(file),
+            remaining: self.n_frames,
+        })
+    }
+}
+
+/// Iterates over the frames of a FrameSpill, removing the spill file when dropped
+#[derive(Debug)]
+pub struct SpillReader {
+    _path: SpillPath,
+    reader: BufReader<File>,
+    remaining: usize,
+}
+
+impl SpillReader {
+    fn read_frame(&mut self) -> Result<GrawFrame, std::io::Error> {
+        let mut frame = GrawFrame::new();
+        frame.header = read_header(&mut self.reader)?;
+        let path_len = self.reader.read_u32::<LittleEndian>()? as usize;
+        let mut path_bytes = vec![0u8; path_len];
+        self.reader.read_exact(&mut path_bytes)?;
+        frame.provenance = FrameProvenance {
+            file_path: PathBuf::from(String::from_utf8_lossy(&path_bytes).into_owned()),
+            offset: self.reader.read_u64::<LittleEndian>()?,
+            cobo_id: self.reader.read_u8()?,
+            asad_id: self.reader.read_u8()?,
+        };
+        let n_data = self.reader.read_u32::<LittleEndian>()? as usize;
+        frame.data.reserve_exact(n_data);
+        for _ in 0..n_data {
+            frame.data.push(GrawData {
+                aget_id: self.reader.read_u8()?,
+                channel: self.reader.read_u8()?,
+                time_bucket_id: self.reader.read_u16::<LittleEndian>()?,
+                sample: self.reader.read_i16::<LittleEndian>()?,
+            });
+        }
+        Ok(frame)
+    }
+}
+
+impl Iterator for SpillReader {
+    type Item = Result<GrawFrame, std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        Some(self.read_frame())
+    }
+}
+
+fn write_header<W: Write>(writer: &mut W, header: &GrawFrameHeader) -> Result<(), std::io::Error> {
+    writer.write_u8(header.meta_type)?;
+    writer.write_u32::<LittleEndian>(header.frame_size)?;
+    writer.write_u8(header.data_source)?;
+    writer.write_u16::<LittleEndian>(header.frame_type)?;
+    writer.write_u8(header.revision)?;
+    writer.write_u16::<LittleEndian>(header.header_size)?;
+    writer.write_u16::<LittleEndian>(header.item_size)?;
+    writer.write_u32::<LittleEndian>(header.n_items)?;
+    writer.write_u64::<LittleEndian>(header.event_time)?;
+    writer.write_u32::<LittleEndian>(header.event_id)?;
+    writer.write_u8(header.cobo_id)?;
+    writer.write_u8(header.asad_id)?;
+    writer.write_u16::<LittleEndian>(header.read_offset)?;
+    writer.write_u8(header.status)?;
+    writer.write_u8(header.hit_patterns.len() as u8)?;
+    for pattern in header.hit_patterns.iter() {
+        writer.write_u16::<LittleEndian>(pattern.len() as u16)?;
+        let bytes = pattern.as_raw_slice();
+        writer.write_u16::<LittleEndian>(bytes.len() as u16)?;
+        writer.write_all(bytes)?;
+    }
+    writer.write_u8(header.multiplicity.len() as u8)?;
+    for multiplicity in header.multiplicity.iter() {
+        writer.write_u16::<LittleEndian>(*multiplicity)?;
+    }
+    writer.write_u32::<LittleEndian>(header.window_out)?;
+    writer.write_u8(header.last_cells.len() as u8)?;
+    for last_cell in header.last_cells.iter() {
+        writer.write_u16::<LittleEndian>(*last_cell)?;
+    }
+    writer.write_u64::<LittleEndian>(header.total_size_precise)?;
+    Ok(())
+}
+
+fn read_header<R: Read>(reader: &mut R) -> Result<GrawFrameHeader, std::io::Error> {
+    let mut header = GrawFrameHeader {
+        meta_type: reader.read_u8()?,
+        frame_size: reader.read_u32::<LittleEndian>()?,
+        data_source: reader.read_u8()?,
+        frame_type: reader.read_u16::<LittleEndian>()?,
+        revision: reader.read_u8()?,
+        header_size: reader.read_u16::<LittleEndian>()?,
+        item_size: reader.read_u16::<LittleEndian>()?,
+        n_items: reader.read_u32::<LittleEndian>()?,
+        event_time: reader.read_u64::<LittleEndian>()?,
+        event_id: reader.read_u32::<LittleEndian>()?,
+        cobo_id: reader.read_u8()?,
+        asad_id: reader.read_u8()?,
+        read_offset: reader.read_u16::<LittleEndian>()?,
+        status: reader.read_u8()?,
+        ..Default::default()
+    };
+    let n_patterns = reader.read_u8()?;
+    for _ in 0..n_patterns {
+        let n_bits = reader.read_u16::<LittleEndian>()? as usize;
+        let mut bytes = vec![0u8; reader.read_u16::<LittleEndian>()? as usize];
+        reader.read_exact(&mut bytes)?;
+        let mut pattern = BitVec::<u8, Lsb0>::from_vec(bytes);
+        pattern.truncate(n_bits);
+        header.hit_patterns.push(pattern);
+    }
+    let n_multiplicities = reader.read_u8()?;
+    for _ in 0..n_multiplicities {
+        header.multiplicity.push(reader.read_u16::<LittleEndian>()?);
+    }
+    header.window_out = reader.read_u32::<LittleEndian>()?;
+    let n_last_cells = reader.read_u8()?;
+    for _ in 0..n_last_cells {
+        header.last_cells.push(reader.read_u16::<LittleEndian>()?);
+    }
+    header.total_size_precise = reader.read_u64::<LittleEndian>()?;
+    Ok(header)
+}
+
+//Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_data::make_graw_frame;
+
+    #[test]
+    fn test_round_trip() {
+        let data: Vec<GrawData> = (0..10)
+            .map(|idx: u16| GrawData {
+                aget_id: (idx % 4) as u8,
+                channel: idx as u8,
+                time_bucket_id: idx * 3,
+                sample: idx as i16 - 5,
+            })
+            .collect();
+        let mut frame = GrawFrame::try_from(make_graw_frame(4, 5678, 1, 2, &data)).unwrap();
+        frame.provenance = FrameProvenance {
+            file_path: PathBuf::from("/data/run_0001/mm1/CoBo1_AsAd2_0000.graw"),
+            offset: 1024,
+            cobo_id: 1,
+            asad_id: 2,
+        };
+
+        let mut spill = FrameSpill::new(&std::env::temp_dir()).unwrap();
+        let path = spill.get_path().to_path_buf();
+        spill.write_frame(&frame).unwrap();
+        spill.write_frame(&frame).unwrap();
+        assert_eq!(spill.get_n_frames(), 2);
+        let frames: Vec<GrawFrame> = spill
+            .into_frames()
+            .unwrap()
+            .map(|frame| frame.unwrap())
+            .collect();
+        // The file is gone once the frames are read back
+        assert!(!path.exists());
+        assert_eq!(frames.len(), 2);
+        for read in frames.iter() {
+            assert_eq!(read.header.event_id, 4);
+            assert_eq!(read.header.event_time, 5678);
+            assert_eq!(read.header.hit_patterns, frame.header.hit_patterns);
+            assert_eq!(read.header.multiplicity, frame.header.multiplicity);
+            assert_eq!(read.header.last_cells, frame.header.last_cells);
+            assert_eq!(read.provenance, frame.provenance);
+            assert_eq!(read.data.len(), data.len());
+            for (read, expected) in read.data.iter().zip(data.iter()) {
+                assert_eq!(read.channel, expected.channel);
+                assert_eq!(read.time_bucket_id, expected.time_bucket_id);
+                assert_eq!(read.sample, expected.sample);
+            }
+        }
+    }
+}
@@ -0,0 +1,112 @@
+//! Standalone readers of the FRIBDAQ run information and scalers.
+//!
+//! These read the evt files of a run without merging it, i.e. to get the scaler history of a run in seconds. Only the
+//! ring items of the wanted types are read; the physics items, which are most of the data, are skipped by their size
+//! without being copied.
+use std::path::Path;
+
+use super::config::RetryOptions;
+use super::constants::DEFAULT_MAX_RING_ITEM_SIZE;
+use super::error::FribBuilderError;
+use super::evt_stack::EvtStack;
+use super::ring_item::{BeginRunItem, EndRunItem, RingType, RunInfo, ScalersItem};
+
+/// Open the evt files of the run in evt_dir, which are all written already
+fn open_stack(evt_dir: &Path) -> Result<EvtStack, FribBuilderError> {
+    Ok(EvtStack::new(
+        evt_dir,
+        None,
+        RetryOptions::default(),
+        DEFAULT_MAX_RING_ITEM_SIZE,
+    )?)
+}
+
+/// Read every scaler item of the run in evt_dir, in order
+pub fn read_scalers(evt_dir: &Path) -> Result<Vec<ScalersItem>, FribBuilderError> {
+    let mut stack = open_stack(evt_dir)?;
+    let mut scalers = Vec::new();
+    while let Some(ring) =
+        stack.get_next_ring_item_of(|ring_type| matches!(ring_type, RingType::Scalers))?
+    {
+        scalers.push(ScalersItem::try_from(ring).map_err(|e| stack.item_error(e))?);
+    }
+    Ok(scalers)
+}
+
+/// Read the begin and end run items of the run in evt_dir
+///
+/// A run which did not end cleanly has no EndRun item, in which case the end of the RunInfo is left at its default.
+pub fn read_run_info(evt_dir: &Path) -> Result<RunInfo, FribBuilderError> {
+    let mut stack = open_stack(evt_dir)?;
+    let mut run_info = RunInfo::new();
+    let mut has_begin = false;
+    while let Some(ring) = stack.get_next_ring_item_of(|ring_type| {
+        matches!(ring_type, RingType::BeginRun | RingType::EndRun)
+    })? {
+        match ring.ring_type {
+            RingType::BeginRun => {
+                run_info.begin = BeginRunItem::try_from(ring).map_err(|e| stack.item_error(e))?;
+                has_begin = true;
+            }
+            _ => {
+                run_info.end = EndRunItem::try_from(ring).map_err(|e| stack.item_error(e))?;
+                break;
+            }
+        }
+    }
+    if !has_begin {
+        return Err(FribBuilderError::NoBeginRun);
+    }
+    Ok(run_info)
+}
+
+//Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_data::{
+        make_begin_run_ring, make_end_run_ring, make_physics_ring, make_scalers_ring,
+        write_evt_file,
+    };
+
+    #[test]
+    fn test_read_scalers_and_run_info() {
+        let dir =
+            std::env::temp_dir().join(format!("attpc_merger_frib_builder_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        write_evt_file(
+            &dir,
+            1,
+            &[
+                make_begin_run_ring(1, 100, "scalers"),
+                make_physics_ring(0, 10, &[(1, 2); 64], 1),
+                make_scalers_ring(0, 2, 102, &[5, 6]),
+                make_physics_ring(1, 11, &[(3, 4); 64], 1),
+                make_scalers_ring(2, 4, 104, &[7, 8]),
+                make_end_run_ring(105, 5),
+            ],
+        )
+        .unwrap();
+
+        let scalers = read_scalers(&dir).unwrap();
+        assert_eq!(scalers.len(), 2);
+        assert_eq!(scalers[0].data, vec![5, 6]);
+        assert_eq!(scalers[1].timestamp, 104);
+        assert_eq!(scalers[1].data, vec![7, 8]);
+
+        let run_info = read_run_info(&dir).unwrap();
+        assert_eq!(run_info.begin.run, 1);
+        assert_eq!(run_info.begin.title, "scalers");
+        assert_eq!(run_info.end.stop, 105);
+        assert_eq!(run_info.end.time, 5);
+
+        // Without a BeginRun item
+        write_evt_file(&dir, 1, &[make_scalers_ring(0, 2, 102, &[5, 6])]).unwrap();
+        assert!(matches!(
+            read_run_info(&dir),
+            Err(FribBuilderError::NoBeginRun)
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -2,9 +2,11 @@ use std::fs::File;
 use std::io::{Cursor, Read, Seek};
 use std::path::{Path, PathBuf};
 
+use super::config::RetryOptions;
 use super::constants::*;
 use super::error::GrawFileError;
-use super::graw_frame::{FrameMetadata, GrawFrame, GrawFrameHeader};
+use super::graw_frame::{FrameMetadata, FrameProvenance, GrawFrame, GrawFrameHeader};
+use super::retry::read_exact_with_retry;
 
 /// A .graw file is a raw data file produced by the AGET electronics system.
 ///
@@ -14,6 +16,7 @@ use super::graw_frame::{FrameMetadata, GrawFrame, GrawFrameHeader};
 /// The functional purpose of the GrawFile is to provide an interface to the underlying binary data,
 /// by providing methods which query the metadata (event data) of the next GrawFrame
 /// (the functional data unit of a GrawFile) as well as retrieving the next GrawFrame.
+/// Reads which fail with a transient error are retried according to the RetryOptions.
 #[derive(Debug)]
 pub struct GrawFile {
     file_handle: File,
@@ -22,11 +25,12 @@ pub struct GrawFile {
     next_frame_metadata: FrameMetadata, // Store this to reduce read calls
     is_eof: bool,
     is_open: bool,
+    retry: RetryOptions,
 }
 
 impl GrawFile {
     /// Open a graw file in read-only mode.
-    pub fn new(path: &Path) -> Result<Self, GrawFileError> {
+    pub fn new(path: &Path, retry: RetryOptions) -> Result<Self, GrawFileError> {
         if !path.exists() {
             return Err(GrawFileError::BadFilePath(path.to_path_buf()));
         }
@@ -42,12 +46,14 @@ impl GrawFile {
             next_frame_metadata: FrameMetadata::default(),
             is_eof: false,
             is_open: true,
+            retry,
         })
     }
 
     /// Retrieve the next GrawFrame from the file
     pub fn get_next_frame(&mut self) -> Result<GrawFrame, GrawFileError> {
         let next_header = self.get_next_frame_header()?;
+        let offset = self.file_handle.stream_position()?;
         let frame_read_size: usize = (next_header.frame_size * SIZE_UNIT) as usize;
         let mut frame_word: Vec<u8> = vec![0; frame_read_size];
 
@@ -55,15 +61,27 @@ impl GrawFile {
         self.next_frame_metadata = FrameMetadata::default();
 
         //Check to see if we reach end of file... shouldn't happen here tho
-        match self.file_handle.read_exact(&mut frame_word) {
+        match read_exact_with_retry(&mut self.file_handle, &mut frame_word, &self.retry) {
             Err(e) => match e.kind() {
                 std::io::ErrorKind::UnexpectedEof => {
                     self.is_eof = true;
+                    // Return to the start of the frame in case more data is written later
+                    self.file_handle.seek(std::io::SeekFrom::Start(offset))?;
                     Err(GrawFileError::EndOfFile)
                 }
                 _ => Err(GrawFileError::IOError(e)),
             },
-            Ok(()) => Ok(GrawFrame::try_from(frame_word)?),
+            Ok(()) => {
+                let mut frame = GrawFrame::try_from(frame_word)
+                    .map_err(|e| GrawFileError::FrameAt(self.file_path.clone(), offset, e))?;
+                frame.provenance = FrameProvenance {
+                    file_path: self.file_path.clone(),
+                    offset,
+                    cobo_id: frame.header.cobo_id,
+                    asad_id: frame.header.asad_id,
+                };
+                Ok(frame)
+            }
         }
     }
 
@@ -95,29 +113,52 @@ impl GrawFile {
         self.size_bytes
     }
 
+    /// Re-check the size of the file on disk, for files that are still being written.
+    ///
+    /// Returns true if the file has grown since the last check, in which case the file is no longer at the end.
+    pub fn refresh_size(&mut self) -> Result<bool, GrawFileError> {
+        let new_size = self.file_handle.metadata()?.len();
+        let has_grown = new_size > self.size_bytes;
+        self.size_bytes = new_size;
+        if has_grown {
+            self.is_eof = false;
+        }
+        Ok(has_grown)
+    }
+
     /// Peek at the header of the next frame to extract sizing information or metadata
     ///
     /// This resets the file stream to the position at the start of the header, as the read of the frame includes
-    /// reading the header
+    /// reading the header. If the full frame is not yet in the file (i.e. the file is truncated or still being written)
+    /// this is treated as the end of the file.
     fn get_next_frame_header(&mut self) -> Result<GrawFrameHeader, GrawFileError> {
         let read_size: usize = (EXPECTED_HEADER_SIZE as u32 * SIZE_UNIT) as usize;
         let current_position = self.file_handle.stream_position()?;
         let mut header_word: Vec<u8> = vec![0; read_size];
         //Check to see if we reach end of file
-        if let Err(e) = self.file_handle.read_exact(&mut header_word) {
+        if let Err(e) = read_exact_with_retry(&mut self.file_handle, &mut header_word, &self.retry)
+        {
             match e.kind() {
                 std::io::ErrorKind::UnexpectedEof => {
                     self.is_eof = true;
+                    self.file_handle
+                        .seek(std::io::SeekFrom::Start(current_position))?;
                     return Err(GrawFileError::EndOfFile);
                 }
                 _ => return Err(GrawFileError::IOError(e)),
             }
         }
 
-        let header = GrawFrameHeader::read_from_buffer(&mut Cursor::new(header_word))?;
+        let header = GrawFrameHeader::read_from_buffer(&mut Cursor::new(header_word))
+            .map_err(|e| GrawFileError::FrameAt(self.file_path.clone(), current_position, e))?;
         //Return to the start of the header
         self.file_handle
             .seek(std::io::SeekFrom::Start(current_position))?;
+        //Check that the whole frame is available
+        if current_position + (header.frame_size * SIZE_UNIT) as u64 > self.size_bytes {
+            self.is_eof = true;
+            return Err(GrawFileError::EndOfFile);
+        }
         Ok(header)
     }
 }
@@ -1,6 +1,7 @@
 use bitvec::prelude::*;
 use byteorder::{BigEndian, ReadBytesExt};
 use std::io::Cursor;
+use std::path::PathBuf;
 
 use super::constants::*;
 use super::error::{GrawDataError, GrawFrameError};
@@ -23,7 +24,7 @@ impl GrawData {
         if self.channel > NUMBER_OF_CHANNELS {
             return Err(GrawDataError::BadChannel(self.channel));
         }
-        if (self.time_bucket_id as u32) > NUMBER_OF_TIME_BUCKETS {
+        if (self.time_bucket_id as u32) >= MAXIMUM_NUMBER_OF_TIME_BUCKETS {
             return Err(GrawDataError::BadTimeBucket(self.time_bucket_id));
         }
 
@@ -31,15 +32,62 @@ impl GrawData {
     }
 }
 
+/// The data of frames which were out of the range of the hardware, per kind of problem
+///
+/// A bad datum is left out of its frame instead of failing the frame, so that a single noisy channel does not cost the
+/// rest of the data (see Config::bad_data_policy).
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BadDataCounts {
+    pub aget_ids: u64,
+    pub channels: u64,
+    pub time_buckets: u64,
+}
+
+impl BadDataCounts {
+    /// Count a datum rejected by GrawData::check_data
+    pub fn count(&mut self, error: &GrawDataError) {
+        match error {
+            GrawDataError::BadAgetID(_) => self.aget_ids += 1,
+            GrawDataError::BadChannel(_) => self.channels += 1,
+            GrawDataError::BadTimeBucket(_) => self.time_buckets += 1,
+        }
+    }
+
+    /// Add the counts of another frame
+    pub fn add(&mut self, other: &BadDataCounts) {
+        self.aget_ids += other.aget_ids;
+        self.channels += other.channels;
+        self.time_buckets += other.time_buckets;
+    }
+
+    /// The number of bad data of every kind
+    pub fn total(&self) -> u64 {
+        self.aget_ids + self.channels + self.time_buckets
+    }
+}
+
+impl std::fmt::Display for BadDataCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} bad AGET ids, {} bad channels, {} bad time buckets",
+            self.aget_ids, self.channels, self.time_buckets
+        )
+    }
+}
+
 /// Utility to parse the bitset field of the graw header
+///
+/// Each AGET has a 72 bit big-endian word, where bit n is set if channel n was hit
 fn parse_bitsets(cursor: &mut Cursor<Vec<u8>>) -> Result<Vec<BitVec<u8>>, GrawFrameError> {
     let mut sets: Vec<BitVec<u8>> = Vec::with_capacity(4);
     let mut storage_index: usize;
     let mut byte: u8;
     for _ in 0..4 {
         let mut aget_bits = bitvec![u8, Lsb0; 0; SIZE_OF_BITSET];
+        // The first byte holds the highest channels
         for index in (0..9).rev() {
-            storage_index = 8 - index;
+            storage_index = index * 8;
             byte = cursor.read_u8()?;
             aget_bits[storage_index..(storage_index + 8)].store(byte);
         }
@@ -61,6 +109,18 @@ fn parse_multiplicity(cursor: &mut Cursor<Vec<u8>>) -> Result<Vec<u16>, GrawFram
     Ok(mults)
 }
 
+/// Utility to parse the last cell field of the graw header
+///
+/// Each AGET has a 16 bit word, the index of the last cell of its SCA written before the readout
+fn parse_last_cells(cursor: &mut Cursor<Vec<u8>>) -> Result<Vec<u16>, GrawFrameError> {
+    let mut cells: Vec<u16> = Vec::with_capacity(4);
+    for _ in 0..4 {
+        cells.push(cursor.read_u16::<BigEndian>()?);
+    }
+
+    Ok(cells)
+}
+
 /// FrameMetadata provides the GrawFile a way of querying the event (hardware-level)
 /// information without accessing the entire frame
 #[derive(Debug, Clone, Default, PartialEq)]
@@ -79,9 +139,52 @@ impl From<GrawFrameHeader> for FrameMetadata {
     }
 }
 
+/// FrameProvenance records where a GrawFrame was read from.
+///
+/// Used for debugging the event building process
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FrameProvenance {
+    pub file_path: PathBuf,
+    pub offset: u64, // byte offset of the frame in the file
+    pub cobo_id: u8,
+    pub asad_id: u8,
+}
+
 /// GrawFrameHeaders contain the full metadata description of the GrawFrame.
 ///
 /// They are most commonly used to know how large the total frame size is
+/// The number of partial readout (zero suppressed) and full readout frames read from an AsAd
+///
+/// The readout mode is set per CoBo for the whole run, so a source which sends both kinds is a sign of a problem
+/// with the electronics or the DAQ configuration.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FrameTypeCounts {
+    pub partial: u64,
+    pub full: u64,
+}
+
+impl FrameTypeCounts {
+    /// Count a frame of the given frame type. Other types are rejected when the header is read, so they are ignored
+    pub fn count(&mut self, frame_type: u16) {
+        if frame_type == EXPECTED_FRAME_TYPE_PARTIAL {
+            self.partial += 1;
+        } else if frame_type == EXPECTED_FRAME_TYPE_FULL {
+            self.full += 1;
+        }
+    }
+
+    /// Add the counts of another source
+    pub fn add(&mut self, other: &FrameTypeCounts) {
+        self.partial += other.partial;
+        self.full += other.full;
+    }
+
+    /// Check if both partial and full readout frames were read
+    pub fn is_mixed(&self) -> bool {
+        self.partial > 0 && self.full > 0
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct GrawFrameHeader {
     pub meta_type: u8,   //set to 0x6 ?
@@ -98,6 +201,10 @@ pub struct GrawFrameHeader {
     pub asad_id: u8,
     pub read_offset: u16,
     pub status: u8,
+    pub hit_patterns: Vec<BitVec<u8>>, // one per AGET, bit n is set if channel n was hit
+    pub multiplicity: Vec<u16>,        // one per AGET, the number of channels over threshold
+    pub window_out: u32,
+    pub last_cells: Vec<u16>, // one per AGET, the last SCA cell written before the readout
     pub total_size_precise: u64, //Actual size of the header + gap + items
 }
 
@@ -160,10 +267,36 @@ impl GrawFrameHeader {
         header.asad_id = cursor.read_u8()?;
         header.read_offset = cursor.read_u16::<BigEndian>()?;
         header.status = cursor.read_u8()?;
+        header.hit_patterns = parse_bitsets(cursor)?;
+        header.multiplicity = parse_multiplicity(cursor)?;
+        header.window_out = cursor.read_u32::<BigEndian>()?;
+        header.last_cells = parse_last_cells(cursor)?;
         header.total_size_precise = (header.header_size as u32 * SIZE_UNIT
             + header.n_items * header.item_size as u32) as u64;
         Ok(header)
     }
+
+    /// The offset of the time buckets of an AGET from the start of its SCA, given by its last cell
+    ///
+    /// The SCA is circular, so the oldest sample is in the cell after the last cell written. The time bucket t of the
+    /// readout is rotated to (t - offset) mod NUMBER_OF_SCA_CELLS to put the oldest sample first. Returns 0 for an AGET
+    /// without a last cell.
+    pub fn get_sca_offset(&self, aget_id: u8) -> u16 {
+        match self.last_cells.get(aget_id as usize) {
+            Some(last_cell) => ((*last_cell as u32 + 1) % NUMBER_OF_SCA_CELLS) as u16,
+            None => 0,
+        }
+    }
+
+    /// The number of channels hit in the hit pattern of each AGET
+    ///
+    /// For cross-checking the multiplicity, which only counts channels over threshold
+    pub fn get_hit_counts(&self) -> Vec<u16> {
+        self.hit_patterns
+            .iter()
+            .map(|pattern| pattern.count_ones() as u16)
+            .collect()
+    }
 }
 
 /// A GrawFrame is the basic data chunk of the .graw format.
@@ -177,9 +310,9 @@ impl GrawFrameHeader {
 #[derive(Debug, Default)]
 pub struct GrawFrame {
     pub header: GrawFrameHeader,
-    hit_patterns: Vec<BitVec<u8>>,
-    multiplicity: Vec<u16>,
     pub data: Vec<GrawData>,
+    pub provenance: FrameProvenance,
+    pub bad_data: BadDataCounts, // the data left out of the frame as they were out of range
 }
 
 impl TryFrom<Vec<u8>> for GrawFrame {
@@ -193,17 +326,25 @@ impl TryFrom<Vec<u8>> for GrawFrame {
 
         frame.header = GrawFrameHeader::read_from_buffer(&mut cursor)?;
         frame.header.check_header(buffer_length as u32)?;
-        frame.hit_patterns = parse_bitsets(&mut cursor)?;
-        frame.multiplicity = parse_multiplicity(&mut cursor)?;
 
-        cursor.set_position((frame.header.header_size as u32 * SIZE_UNIT) as u64);
+        let buffer = cursor.into_inner();
+        let start_position = (frame.header.header_size as u32 * SIZE_UNIT) as usize;
         let end_position =
-            cursor.position() + (frame.header.n_items * frame.header.item_size as u32) as u64; // Dont read the padding! Use actual size from items
+            start_position + (frame.header.n_items * frame.header.item_size as u32) as usize; // Dont read the padding! Use actual size from items
+        let body = match buffer.get(start_position..end_position) {
+            Some(body) => body,
+            None => {
+                return Err(GrawFrameError::IOError(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "frame body is shorter than the number of items",
+                )))
+            }
+        };
 
         if frame.header.frame_type == EXPECTED_FRAME_TYPE_PARTIAL {
-            frame.extract_partial_data(&mut cursor, end_position)?;
+            frame.extract_partial_data(body)?;
         } else if frame.header.frame_type == EXPECTED_FRAME_TYPE_FULL {
-            frame.extract_full_data(&mut cursor, end_position)?;
+            frame.extract_full_data(body)?;
         }
 
         Ok(frame)
@@ -218,35 +359,29 @@ impl GrawFrame {
 
     /// Extract the data from the frame body if the
     /// DAQ was in Partial-Readout Mode. Parsing done in 32-bit data words
-    fn extract_partial_data(
-        &mut self,
-        cursor: &mut Cursor<Vec<u8>>,
-        end_position: u64,
-    ) -> Result<(), GrawFrameError> {
-        let mut datum: GrawData;
-        let mut raw: u32;
-
-        while cursor.position() < end_position {
-            datum = GrawData::default();
-
-            raw = cursor.read_u32::<BigEndian>()?;
-            datum.aget_id = GrawFrame::extract_aget_id(&raw);
-            datum.channel = GrawFrame::extract_channel(&raw);
-            datum.time_bucket_id = GrawFrame::extract_time_bucket_id(&raw);
-            datum.sample = GrawFrame::extract_sample(&raw);
-
-            match datum.check_data() {
-                Ok(()) => (),
-                Err(e) => {
-                    spdlog::warn!("Error received while parsing frame partial data: {}. This datum will not be recorded.", e);
-                    continue;
-                }
+    ///
+    /// The body must only contain the items, not the padding.
+    fn extract_partial_data(&mut self, body: &[u8]) -> Result<(), GrawFrameError> {
+        self.data.reserve_exact(self.header.n_items as usize);
+        for item in body.chunks_exact(EXPECTED_ITEM_SIZE_PARTIAL as usize) {
+            let raw = u32::from_be_bytes([item[0], item[1], item[2], item[3]]);
+            let datum = GrawData {
+                aget_id: GrawFrame::extract_aget_id(&raw),
+                channel: GrawFrame::extract_channel(&raw),
+                time_bucket_id: GrawFrame::extract_time_bucket_id(&raw),
+                sample: GrawFrame::extract_sample(&raw),
+            };
+
+            if let Err(e) = datum.check_data() {
+                self.bad_data.count(&e);
+                continue;
             }
 
             self.data.push(datum);
         }
+        self.log_bad_data();
 
-        if self.data.len() != (self.header.n_items as usize) {
+        if self.data.len() + self.bad_data.total() as usize != (self.header.n_items as usize) {
             spdlog::warn!(
                 "A frame was read with an incorrect number of items -- Expected: {}, Found: {}",
                 self.header.n_items,
@@ -259,34 +394,51 @@ impl GrawFrame {
 
     /// Extract the data from the frame body if the
     /// DAQ was in Full-Readout Mode. Parsing done in 16-bit data words
-    fn extract_full_data(
-        &mut self,
-        cursor: &mut Cursor<Vec<u8>>,
-        end_position: u64,
-    ) -> Result<(), GrawFrameError> {
-        let mut datum: GrawData;
-        let mut raw: u16;
-        let mut aget_counters: Vec<u64> = vec![0, 0, 0, 0];
-
-        while cursor.position() < end_position {
-            datum = GrawData::default();
-            raw = cursor.read_u16::<BigEndian>()?;
-            datum.aget_id = GrawFrame::extract_aget_id_full(&raw);
-            let aget_index: usize = datum.aget_id as usize;
-            datum.sample = GrawFrame::extract_sample_full(&raw);
-            datum.time_bucket_id = (aget_counters[aget_index] / 68) as u16; //integer division always rounds down
-            datum.channel = (aget_counters[aget_index] % 68) as u8; // % operator in Rust is the remainder
-
-            datum.check_data()?;
+    ///
+    /// The body must only contain the items, not the padding.
+    fn extract_full_data(&mut self, body: &[u8]) -> Result<(), GrawFrameError> {
+        let mut aget_counters: [u64; NUMBER_OF_AGETS as usize] = [0; NUMBER_OF_AGETS as usize];
+
+        self.data.reserve_exact(self.header.n_items as usize);
+        for item in body.chunks_exact(EXPECTED_ITEM_SIZE_FULL as usize) {
+            let raw = u16::from_be_bytes([item[0], item[1]]);
+            let aget_id = GrawFrame::extract_aget_id_full(&raw);
+            let aget_index: usize = aget_id as usize;
+            let datum = GrawData {
+                aget_id,
+                channel: (aget_counters[aget_index] % 68) as u8, // % operator in Rust is the remainder
+                time_bucket_id: (aget_counters[aget_index] / 68) as u16, //integer division always rounds down
+                sample: GrawFrame::extract_sample_full(&raw),
+            };
+
+            // The datum still takes its place in the readout order of its AGET
+            aget_counters[aget_index] += 1;
+            if let Err(e) = datum.check_data() {
+                self.bad_data.count(&e);
+                continue;
+            }
 
             self.data.push(datum);
-
-            aget_counters[aget_index] += 1;
         }
+        self.log_bad_data();
 
         Ok(())
     }
 
+    /// Report the data left out of the frame, once per frame rather than once per datum
+    fn log_bad_data(&self) {
+        if self.bad_data.total() > 0 {
+            spdlog::debug!(
+                "Left out {} of the data of event {} from CoBo {} AsAd {}: {}",
+                self.bad_data.total(),
+                self.header.event_id,
+                self.header.cobo_id,
+                self.header.asad_id,
+                self.bad_data
+            );
+        }
+    }
+
     /// Alias for masking the AGET chip ID
     fn extract_aget_id(raw_item: &u32) -> u8 {
         ((raw_item & 0xC0000000) >> 30) as u8
@@ -304,7 +456,7 @@ impl GrawFrame {
 
     /// Alias for masking the AGET sample value
     fn extract_sample(raw_item: &u32) -> i16 {
-        (raw_item & 0x00000FFF) as i16
+        (raw_item & GET_ADC_MASK as u32) as i16
     }
 
     /// Alias for masking the AGET chip ID in Full-Readout
@@ -314,6 +466,120 @@ impl GrawFrame {
 
     /// Alias for masking the AGET sample value in Full-Readout
     fn extract_sample_full(raw_item: &u16) -> i16 {
-        (raw_item & 0x0FFF) as i16
+        (raw_item & GET_ADC_MASK) as i16
+    }
+}
+
+//Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_data::{make_full_graw_frame, make_graw_frame};
+
+    #[test]
+    fn test_partial_frame() {
+        let data: Vec<GrawData> = (0..100)
+            .map(|idx: u16| GrawData {
+                aget_id: (idx % 4) as u8,
+                channel: (idx % 68) as u8,
+                time_bucket_id: idx * 5,
+                sample: (idx * 40) as i16,
+            })
+            .collect();
+        let frame = GrawFrame::try_from(make_graw_frame(7, 1234, 2, 3, &data)).unwrap();
+        assert_eq!(frame.header.event_id, 7);
+        assert_eq!(frame.header.event_time, 1234);
+        assert_eq!(frame.data.len(), data.len());
+        for (decoded, expected) in frame.data.iter().zip(data.iter()) {
+            assert_eq!(decoded.aget_id, expected.aget_id);
+            assert_eq!(decoded.channel, expected.channel);
+            assert_eq!(decoded.time_bucket_id, expected.time_bucket_id);
+            assert_eq!(decoded.sample, expected.sample);
+        }
+    }
+
+    #[test]
+    fn test_bad_data() {
+        // A noisy channel number is left out, the rest of the frame is kept
+        let data: Vec<GrawData> = [(0, 5), (1, 100), (2, 67), (3, 69)]
+            .iter()
+            .map(|(aget_id, channel)| GrawData {
+                aget_id: *aget_id,
+                channel: *channel,
+                time_bucket_id: 10,
+                sample: 42,
+            })
+            .collect();
+        let frame = GrawFrame::try_from(make_graw_frame(7, 1234, 2, 3, &data)).unwrap();
+        assert_eq!(frame.data.len(), 2);
+        assert_eq!(
+            frame.bad_data,
+            BadDataCounts {
+                channels: 2,
+                ..Default::default()
+            }
+        );
+
+        // In full readout, samples past the last time bucket are left out
+        let samples: Vec<(u8, i16)> =
+            vec![(0, 1); NUMBER_OF_CHANNELS as usize * MAXIMUM_NUMBER_OF_TIME_BUCKETS as usize + 3];
+        let frame = GrawFrame::try_from(make_full_graw_frame(3, 99, 0, 1, &samples)).unwrap();
+        assert_eq!(frame.data.len(), samples.len() - 3);
+        assert_eq!(frame.bad_data.time_buckets, 3);
+        assert_eq!(frame.bad_data.total(), 3);
+    }
+
+    #[test]
+    fn test_full_frame() {
+        // Two time buckets of every channel, with the AGETs interleaved
+        let samples: Vec<(u8, i16)> = (0..(2 * 68))
+            .flat_map(|idx: i16| (0..4).map(move |aget| (aget, idx + 100 * aget as i16)))
+            .collect();
+        let frame = GrawFrame::try_from(make_full_graw_frame(3, 99, 0, 1, &samples)).unwrap();
+        assert_eq!(frame.data.len(), samples.len());
+        for (decoded, (aget_id, sample)) in frame.data.iter().zip(samples.iter()) {
+            let idx = sample - 100 * *aget_id as i16;
+            assert_eq!(decoded.aget_id, *aget_id);
+            assert_eq!(decoded.channel, (idx % 68) as u8);
+            assert_eq!(decoded.time_bucket_id, (idx / 68) as u16);
+            assert_eq!(decoded.sample, *sample);
+        }
+    }
+
+    #[test]
+    fn test_header_hit_patterns() {
+        let mut buffer = make_graw_frame(5, 10, 1, 2, &[]);
+        assert_eq!(buffer.len(), 256);
+        // AGET 0: channels 0 and 10. AGET 3: channel 67. Each pattern is 9 bytes, highest channels first
+        buffer[31 + 8] = 0x01;
+        buffer[31 + 7] = 0x04;
+        buffer[31 + 27] = 0x08;
+        // Multiplicities 2, 0, 0, 1
+        buffer[67..75].copy_from_slice(&[0, 2, 0, 0, 0, 0, 0, 1]);
+        // Window out, then last cells 3, 511, 0, 257
+        buffer[75..79].copy_from_slice(&[0, 0, 0, 9]);
+        buffer[79..87].copy_from_slice(&[0, 3, 1, 255, 0, 0, 1, 1]);
+
+        let header = GrawFrameHeader::read_from_buffer(&mut Cursor::new(buffer.clone())).unwrap();
+        assert_eq!(header.event_id, 5);
+        assert_eq!(header.multiplicity, vec![2, 0, 0, 1]);
+        assert_eq!(header.get_hit_counts(), vec![2, 0, 0, 1]);
+        assert_eq!(header.window_out, 9);
+        assert_eq!(header.last_cells, vec![3, 511, 0, 257]);
+        assert_eq!(header.get_sca_offset(0), 4);
+        assert_eq!(header.get_sca_offset(1), 0);
+        assert_eq!(header.get_sca_offset(3), 258);
+        assert_eq!(
+            header.hit_patterns[0].iter_ones().collect::<Vec<_>>(),
+            vec![0, 10]
+        );
+        assert!(header.hit_patterns[1].not_any());
+        assert_eq!(
+            header.hit_patterns[3].iter_ones().collect::<Vec<_>>(),
+            vec![67]
+        );
+
+        let frame = GrawFrame::try_from(buffer).unwrap();
+        assert_eq!(frame.header.multiplicity, vec![2, 0, 0, 1]);
     }
 }
@@ -0,0 +1,331 @@
+use std::io::{Cursor, ErrorKind, Read};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use super::asad_stack::AsadStack;
+use super::config::FollowOptions;
+use super::constants::*;
+use super::error::GrawSourceError;
+use super::graw_frame::{FrameMetadata, FrameProvenance, GrawFrame, GrawFrameHeader};
+
+const READ_CHUNK_SIZE: usize = 65536; // bytes per socket read
+
+/// GrawSource is anything which can provide the Merger with a time ordered stream of GrawFrames.
+///
+/// The Merger performs a merge-sort over all of the sources, so each source only needs to know its own next frame.
+/// Sources are either the files of an AsAd on disk (AsadStack) or frames streamed directly from the DAQ over a
+/// TCP connection (TcpGrawSource).
+#[derive(Debug)]
+pub enum GrawSource {
+    Files(AsadStack),
+    Tcp(TcpGrawSource),
+}
+
+impl GrawSource {
+    /// Query the source for the next frame's metadata.
+    ///
+    /// Returns a `Result<Option<FrameMetadata>>`. If the Option is None, the source has run out of data.
+    /// `GrawSourceError::WaitingForData` is returned if the source is waiting on the DAQ.
+    pub fn get_next_frame_metadata(&mut self) -> Result<Option<FrameMetadata>, GrawSourceError> {
+        match self {
+            Self::Files(stack) => Ok(stack.get_next_frame_metadata()?),
+            Self::Tcp(stream) => stream.get_next_frame_metadata(),
+        }
+    }
+
+    /// Get the next GrawFrame from the source.
+    ///
+    /// # Important
+    /// The metadata for the next frame should *always* be queried before attempting to retrieve the next frame.
+    pub fn get_next_frame(&mut self) -> Result<GrawFrame, GrawSourceError> {
+        match self {
+            Self::Files(stack) => Ok(stack.get_next_frame()?),
+            Self::Tcp(stream) => stream.get_next_frame(),
+        }
+    }
+
+    /// Returns true if there is still data to be read from this source. Returns false if the source is finished.
+    pub fn is_not_ended(&self) -> bool {
+        match self {
+            Self::Files(stack) => stack.is_not_ended(),
+            Self::Tcp(stream) => stream.is_not_ended(),
+        }
+    }
+
+    /// The total size of the source data in bytes. Streams have no known size and return 0
+    pub fn get_size_bytes(&self) -> u64 {
+        match self {
+            Self::Files(stack) => *stack.get_stack_size_bytes(),
+            Self::Tcp(_) => 0,
+        }
+    }
+}
+
+/// TcpGrawSource reads GrawFrames streamed by the GET DAQ over a TCP connection.
+///
+/// The stream is expected to contain frames back-to-back, exactly as they would be written to a .graw file.
+/// Bytes are buffered until a complete frame has arrived. The source ends when the DAQ closes the connection.
+/// If FollowOptions are given, the source reports that it is waiting for data when nothing arrives within the
+/// poll interval, and ends if nothing arrives within the timeout.
+#[derive(Debug)]
+pub struct TcpGrawSource {
+    stream: TcpStream,
+    address: String,
+    buffer: Vec<u8>,
+    next_frame: Option<GrawFrame>,
+    bytes_received: u64,
+    is_ended: bool,
+    follow: Option<FollowOptions>,
+    waiting_since: Option<Instant>,
+}
+
+impl TcpGrawSource {
+    /// Connect to the DAQ at the given address (host:port)
+    pub fn new(address: &str, follow: Option<FollowOptions>) -> Result<Self, GrawSourceError> {
+        let stream = TcpStream::connect(address)?;
+        if let Some(options) = &follow {
+            stream.set_read_timeout(Some(options.poll_interval))?;
+        }
+        Ok(TcpGrawSource {
+            stream,
+            address: String::from(address),
+            buffer: Vec::new(),
+            next_frame: None,
+            bytes_received: 0,
+            is_ended: false,
+            follow,
+            waiting_since: None,
+        })
+    }
+
+    /// Query the stream for the next frame's metadata.
+    ///
+    /// The full frame is received and stored until it is retrieved with get_next_frame
+    pub fn get_next_frame_metadata(&mut self) -> Result<Option<FrameMetadata>, GrawSourceError> {
+        if let Some(frame) = &self.next_frame {
+            return Ok(Some(FrameMetadata::from(frame.header.clone())));
+        }
+        if self.is_ended {
+            return Ok(None);
+        }
+
+        let header_size = (EXPECTED_HEADER_SIZE as u32 * SIZE_UNIT) as usize;
+        if !self.fill_buffer(header_size)? {
+            return Ok(None);
+        }
+        let header = GrawFrameHeader::read_from_buffer(&mut Cursor::new(
+            self.buffer[..header_size].to_vec(),
+        ))
+        .map_err(|e| GrawSourceError::FrameAt(self.address.clone(), self.bytes_received, e))?;
+        let frame_size = (header.frame_size * SIZE_UNIT) as usize;
+        if !self.fill_buffer(frame_size)? {
+            spdlog::warn!(
+                "Connection to {} closed in the middle of a frame! The partial frame will not be recorded.",
+                self.address
+            );
+            return Ok(None);
+        }
+
+        let frame_word: Vec<u8> = self.buffer.drain(..frame_size).collect();
+        let mut frame = GrawFrame::try_from(frame_word)
+            .map_err(|e| GrawSourceError::FrameAt(self.address.clone(), self.bytes_received, e))?;
+        frame.provenance = FrameProvenance {
+            file_path: PathBuf::from(&self.address),
+            offset: self.bytes_received,
+            cobo_id: frame.header.cobo_id,
+            asad_id: frame.header.asad_id,
+        };
+        self.bytes_received += frame_size as u64;
+
+        let meta = FrameMetadata::from(frame.header.clone());
+        self.next_frame = Some(frame);
+        Ok(Some(meta))
+    }
+
+    /// Get the next GrawFrame from the stream.
+    ///
+    /// # Important
+    /// The metadata for the next frame should *always* be queried before attempting to retrieve the next frame.
+    pub fn get_next_frame(&mut self) -> Result<GrawFrame, GrawSourceError> {
+        match self.next_frame.take() {
+            Some(frame) => Ok(frame),
+            None => Err(GrawSourceError::NoFrameAvailable),
+        }
+    }
+
+    /// Returns true if there is still data to be read from this stream. Returns false if the stream is finished.
+    pub fn is_not_ended(&self) -> bool {
+        !self.is_ended || self.next_frame.is_some()
+    }
+
+    /// Total number of bytes received from the DAQ
+    pub fn get_bytes_received(&self) -> &u64 {
+        &self.bytes_received
+    }
+
+    /// Receive data until the buffer holds at least n_bytes.
+    ///
+    /// Returns false if the connection was closed before enough data arrived.
+    fn fill_buffer(&mut self, n_bytes: usize) -> Result<bool, GrawSourceError> {
+        let mut chunk = vec![0u8; READ_CHUNK_SIZE];
+        while self.buffer.len() < n_bytes {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.is_ended = true;
+                    return Ok(false);
+                }
+                Ok(n_read) => {
+                    self.waiting_since = None;
+                    self.buffer.extend_from_slice(&chunk[..n_read]);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    return self.wait_for_data();
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(GrawSourceError::IOError(e)),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Nothing arrived within the poll interval. Either report that we are waiting or, if we have waited
+    /// longer than the timeout, end the stream.
+    fn wait_for_data(&mut self) -> Result<bool, GrawSourceError> {
+        let timeout = match &self.follow {
+            Some(options) => options.timeout,
+            None => return Err(GrawSourceError::WaitingForData),
+        };
+        let waiting_since = *self.waiting_since.get_or_insert_with(Instant::now);
+        if waiting_since.elapsed() >= timeout {
+            spdlog::info!(
+                "No new data from {} in {} seconds, assuming the data is complete.",
+                self.address,
+                timeout.as_secs()
+            );
+            self.is_ended = true;
+            return Ok(false);
+        }
+        Err(GrawSourceError::WaitingForData)
+    }
+}
+
+//Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graw_frame::GrawData;
+    use crate::test_data::make_graw_frame;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    /// Serve the bytes to one connection on a loopback port, in chunks, and close the connection keep_open later
+    fn serve(chunks: Vec<Vec<u8>>, keep_open: Duration) -> (String, std::thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handle = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            for chunk in chunks {
+                stream.write_all(&chunk).unwrap();
+                stream.flush().unwrap();
+            }
+            std::thread::sleep(keep_open);
+        });
+        (address, handle)
+    }
+
+    #[test]
+    fn test_tcp_frames() {
+        let data = [GrawData {
+            aget_id: 1,
+            channel: 2,
+            time_bucket_id: 3,
+            sample: 40,
+        }];
+        let first = make_graw_frame(0, 100, 4, 1, &data);
+        let second = make_graw_frame(1, 200, 4, 1, &data);
+        // The second frame arrives in two pieces, so it has to be buffered
+        let (head, tail) = second.split_at(10);
+        let (address, handle) = serve(
+            vec![first.clone(), head.to_vec(), tail.to_vec()],
+            Duration::ZERO,
+        );
+
+        let mut source = TcpGrawSource::new(&address, None).unwrap();
+        for (event_id, event_time, offset) in [(0, 100, 0), (1, 200, first.len() as u64)] {
+            let meta = source.get_next_frame_metadata().unwrap().unwrap();
+            assert_eq!(meta.event_id, event_id);
+            assert_eq!(meta.event_time, event_time);
+            let frame = source.get_next_frame().unwrap();
+            assert_eq!(frame.header.cobo_id, 4);
+            assert_eq!(frame.data.len(), 1);
+            assert_eq!(frame.data[0].sample, 40);
+            assert_eq!(frame.provenance.offset, offset);
+            assert_eq!(frame.provenance.file_path, PathBuf::from(&address));
+        }
+        assert!(matches!(
+            source.get_next_frame(),
+            Err(GrawSourceError::NoFrameAvailable)
+        ));
+
+        // The DAQ closed the connection, so the source ends
+        handle.join().unwrap();
+        assert!(source.get_next_frame_metadata().unwrap().is_none());
+        assert!(!source.is_not_ended());
+        assert_eq!(
+            *source.get_bytes_received(),
+            (first.len() + second.len()) as u64
+        );
+    }
+
+    #[test]
+    fn test_tcp_disconnect() {
+        // The connection closes in the middle of the second frame, which is not recorded
+        let first = make_graw_frame(0, 100, 4, 1, &[]);
+        let second = make_graw_frame(1, 200, 4, 1, &[]);
+        let (address, handle) = serve(
+            vec![first.clone(), second[..second.len() - 4].to_vec()],
+            Duration::ZERO,
+        );
+
+        let mut source = TcpGrawSource::new(&address, None).unwrap();
+        assert_eq!(
+            source.get_next_frame_metadata().unwrap().unwrap().event_id,
+            0
+        );
+        source.get_next_frame().unwrap();
+        handle.join().unwrap();
+        assert!(source.get_next_frame_metadata().unwrap().is_none());
+        assert!(!source.is_not_ended());
+        assert_eq!(*source.get_bytes_received(), first.len() as u64);
+    }
+
+    #[test]
+    fn test_tcp_follow_timeout() {
+        // The DAQ keeps the connection open but sends nothing more, so the source waits, then ends at the timeout
+        let first = make_graw_frame(0, 100, 4, 1, &[]);
+        let (address, handle) = serve(vec![first], Duration::from_millis(500));
+
+        let follow = FollowOptions {
+            poll_interval: Duration::from_millis(10),
+            timeout: Duration::from_millis(100),
+        };
+        let mut source = TcpGrawSource::new(&address, Some(follow)).unwrap();
+        assert!(source.get_next_frame_metadata().unwrap().is_some());
+        source.get_next_frame().unwrap();
+        let start = Instant::now();
+        let mut n_waits = 0;
+        loop {
+            match source.get_next_frame_metadata() {
+                Err(GrawSourceError::WaitingForData) => n_waits += 1,
+                Ok(None) => break,
+                other => panic!("Unexpected result {other:?}"),
+            }
+        }
+        assert!(n_waits > 0);
+        assert!(start.elapsed() >= follow.timeout);
+        assert!(!source.is_not_ended());
+        handle.join().unwrap();
+    }
+}
@@ -6,20 +6,527 @@ use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
+use super::config::{Config, EventLayout, EventNaming, FadcOrientation};
+use super::constants::{
+    NUMBER_OF_FADC_CHANNELS, NUMBER_OF_FLAG_COLUMNS, NUMBER_OF_FRIB_PHYSICS_INDEX_COLUMNS,
+    NUMBER_OF_GET_INDEX_COLUMNS, NUMBER_OF_HARDWARE_COLUMNS, NUMBER_OF_MULTIPLICITY_COLUMNS,
+    NUMBER_OF_PAD_PEAK_COLUMNS, NUMBER_OF_QUICKLOOK_COLUMNS, NUMBER_OF_SCA_OFFSET_COLUMNS,
+    NUMBER_OF_TIME_BUCKETS,
+};
 use super::error::HDF5WriterError;
 use super::event::Event;
+use super::format::{FORMAT_MAJOR, FORMAT_MINOR, FORMAT_VERSION};
 use super::merger::Merger;
+use super::pad_map::PadMap;
+use super::pedestals::Pedestals;
 use super::ring_item::{PhysicsItem, RunInfo, ScalersItem};
 
 const EVENTS_NAME: &str = "events";
+const EVENT_PREFIX: &str = "event_";
 const GET_TRACES_NAME: &str = "get_traces";
+const GET_TRACES_FLAGS_NAME: &str = "get_traces_flags";
+const GET_AGET_MULTIPLICITY_NAME: &str = "get_aget_multiplicity";
+const GET_PROVENANCE_NAME: &str = "get_provenance";
+const GET_PROVENANCE_FILES_NAME: &str = "get_provenance_files";
+const GET_COBO_TIMESTAMPS_NAME: &str = "get_cobo_timestamps";
+const GET_FRAME_HEADERS_NAME: &str = "get_frame_headers";
+const GET_READOUT_NAME: &str = "get_readout";
+const GET_INDEX_NAME: &str = "get_index";
+const PAD_PEAKS_NAME: &str = "pad_peaks";
+const QUICKLOOK_NAME: &str = "quicklook";
 const SCALERS_NAME: &str = "scalers";
+const SCALER_RATES_SUFFIX: &str = "_rates";
+const ORPHAN_SILICON_NAME: &str = "orphan_silicon";
 const FRIB_PHYSICS_NAME: &str = "frib_physics";
+const FRIB_COINC_NAME: &str = "977";
+const FRIB_FADC_NAME: &str = "1903";
+const FRIB_FRAGMENTS_NAME: &str = "fragments";
+const FRIB_PHYSICS_INDEX_NAME: &str = "frib_physics_index";
+const CONFIG_NAME: &str = "config";
+const PAD_MAP_NAME: &str = "pad_map";
+
+/// The names of the groups and datasets written by the HDFWriter.
+///
+/// The default layout is the standard AT-TPC format. Groups which have standardized on
+/// different names can give the HDFWriter a custom layout.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HdfLayout {
+    pub events: String,
+    pub event_prefix: String, // prefix of each event group and scaler dataset, followed by the event number
+    pub get_traces: String,
+    pub get_traces_flags: String,
+    pub get_aget_multiplicity: String,
+    pub get_provenance: String,
+    pub get_provenance_files: String,
+    pub get_cobo_timestamps: String,
+    pub get_frame_headers: String,
+    pub get_readout: String,
+    pub get_index: String, // in the events group, stacked layout only
+    pub pad_peaks: String,
+    pub quicklook: String, // in the events group
+    pub scalers: String,
+    pub scaler_rates_suffix: String, // appended to the name of a scaler dataset for its rates since the previous read
+    pub orphan_silicon: String, // silicon events matched to no TPC event (Config::si_merge_by_timestamp)
+    pub frib_physics: String,
+    pub frib_coinc: String,         // V977 data
+    pub frib_fadc: String,          // SIS3300 data
+    pub frib_fragments: String,     // fragments of the other sources of event built data
+    pub frib_physics_index: String, // in the events group, stacked layout only
+    pub config: String,
+    pub pad_map: String, // in the config group
+}
+
+impl Default for HdfLayout {
+    fn default() -> Self {
+        Self {
+            events: String::from(EVENTS_NAME),
+            event_prefix: String::from(EVENT_PREFIX),
+            get_traces: String::from(GET_TRACES_NAME),
+            get_traces_flags: String::from(GET_TRACES_FLAGS_NAME),
+            get_aget_multiplicity: String::from(GET_AGET_MULTIPLICITY_NAME),
+            get_provenance: String::from(GET_PROVENANCE_NAME),
+            get_provenance_files: String::from(GET_PROVENANCE_FILES_NAME),
+            get_cobo_timestamps: String::from(GET_COBO_TIMESTAMPS_NAME),
+            get_frame_headers: String::from(GET_FRAME_HEADERS_NAME),
+            get_readout: String::from(GET_READOUT_NAME),
+            get_index: String::from(GET_INDEX_NAME),
+            pad_peaks: String::from(PAD_PEAKS_NAME),
+            quicklook: String::from(QUICKLOOK_NAME),
+            scalers: String::from(SCALERS_NAME),
+            scaler_rates_suffix: String::from(SCALER_RATES_SUFFIX),
+            orphan_silicon: String::from(ORPHAN_SILICON_NAME),
+            frib_physics: String::from(FRIB_PHYSICS_NAME),
+            frib_coinc: String::from(FRIB_COINC_NAME),
+            frib_fadc: String::from(FRIB_FADC_NAME),
+            frib_fragments: String::from(FRIB_FRAGMENTS_NAME),
+            frib_physics_index: String::from(FRIB_PHYSICS_INDEX_NAME),
+            config: String::from(CONFIG_NAME),
+            pad_map: String::from(PAD_MAP_NAME),
+        }
+    }
+}
+
+impl HdfLayout {
+    /// Name of the group (or scaler dataset) for an event number
+    fn event_name(&self, event_counter: &u64) -> String {
+        format!("{}{}", self.event_prefix, event_counter)
+    }
+}
+
+/// The options of an HDFWriter which are fixed when the file is made
+///
+/// The default options write no quality flags or CoBo timestamps, in the default layout.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HdfWriterOptions {
+    pub write_quality_flags: bool, // write the quality flags of every trace and the multiplicity of every AGET
+    pub write_cobo_timestamps: bool, // write the timestamps of every CoBo in each event
+    pub scaler_offset_divisor: u32, // ticks per second of the FRIBDAQ scaler offset clock
+    pub time_buckets: u16,         // samples per trace
+    pub layout: HdfLayout,
+}
+
+impl Default for HdfWriterOptions {
+    fn default() -> Self {
+        Self {
+            write_quality_flags: false,
+            write_cobo_timestamps: false,
+            scaler_offset_divisor: 1,
+            time_buckets: NUMBER_OF_TIME_BUCKETS as u16,
+            layout: HdfLayout::default(),
+        }
+    }
+}
+
+impl HdfWriterOptions {
+    /// The options given by a Config, writing in the given layout
+    pub fn from_config(config: &Config, layout: &HdfLayout) -> Self {
+        Self {
+            write_quality_flags: config.write_quality_flags,
+            write_cobo_timestamps: config.write_cobo_timestamps,
+            scaler_offset_divisor: config.scaler_offset_divisor,
+            time_buckets: config.time_buckets,
+            layout: layout.clone(),
+        }
+    }
+}
+
+// Column descriptions of the datasets, written as the columns attribute
+const GET_TRACES_COLUMNS: &str = "cobo,asad,aget,channel,pad"; // followed by the samples
+const GET_TRACES_FLAGS_COLUMNS: &str = "saturated,duplicate_buckets,nonzero_samples";
+const GET_AGET_MULTIPLICITY_COLUMNS: &str = "cobo,asad,aget,multiplicity,hit_channels";
+const GET_PROVENANCE_COLUMNS: &str = "offset,cobo,asad";
+const GET_PROVENANCE_FILES_COLUMNS: &str = "file_path";
+const GET_COBO_TIMESTAMPS_COLUMNS: &str = "cobo,first_timestamp,last_timestamp";
+const GET_FRAME_HEADERS_COLUMNS: &str = "frame_size,frame_type,revision,header_size,item_size,n_items,event_time,event_id,cobo,asad,read_offset,status";
+const PAD_PEAKS_COLUMNS: &str = "pad,max_sample,max_bucket";
+const GET_READOUT_COLUMNS: &str =
+    "cobo,asad,frames,frame_type,revision,read_offset,status,aget_mask";
+const GET_INDEX_COLUMNS: &str =
+    "event,id,timestamp,timestamp_other,raw_timestamp,first_row,n_rows,first_aget_row,n_aget_rows";
+const PAD_MAP_COLUMNS: &str = "cobo,asad,aget,channel,pad";
+const QUICKLOOK_COLUMNS: &str = "event,npads,total_charge";
+const SCALERS_COLUMNS: &str = "scaler"; // one value per scaler channel
+const FRIB_COINC_COLUMNS: &str = "coincidence_register";
+const FRIB_FADC_COLUMNS: &str =
+    "channel0,channel1,channel2,channel3,channel4,channel5,channel6,channel7";
+const FRIB_PHYSICS_INDEX_COLUMNS: &str =
+    "event,id,timestamp,body_timestamp,coincidence_register,first_row,n_rows";
+const FRIB_FRAGMENTS_COLUMNS: &str = "byte";
+// Encoding of the FRIB module data
+const FRIB_COINC_ENCODING: &str = "V977 coincidence register, one bit per input";
+const FRIB_FRAGMENTS_ENCODING: &str = "the fragments as written by the FRIBDAQ event builder, one after another. Each is a fragment header (timestamp u64, source id u32, payload size u32, barrier type u32) followed by the ring item of the source, all little endian";
+const FRIB_FADC_ENCODING: &str = "one row per sample and one column per channel. Each group reads out its odd channel before its even channel; the columns are in channel order"; // after the bit depth
+const FRIB_FADC_CHANNEL_MAJOR_COLUMNS: &str = "sample";
+const FRIB_FADC_CHANNEL_MAJOR_ENCODING: &str = "one row per channel and one column per sample. Each group reads out its odd channel before its even channel; the rows are in channel order"; // after the bit depth
+
+/// Describe a dataset with the meaning of its columns and its data type, so that it can be read by generic tools
+fn write_description(
+    dataset: &hdf5::Dataset,
+    columns: &str,
+    dtype: &str,
+) -> Result<(), HDF5WriterError> {
+    dataset
+        .new_attr::<VarLenUnicode>()
+        .create("columns")?
+        .write_scalar(&VarLenUnicode::from_str(columns).unwrap())?;
+    dataset
+        .new_attr::<VarLenUnicode>()
+        .create("dtype")?
+        .write_scalar(&VarLenUnicode::from_str(dtype).unwrap())?;
+    Ok(())
+}
+
+// The quicklook rows are buffered and written a chunk at a time
+const QUICKLOOK_CHUNK_ROWS: usize = 1024;
+// The rows of the stacked index datasets are buffered the same way
+const INDEX_CHUNK_ROWS: usize = 1024;
+// Chunk of the stacked trace datasets, which rows are appended to every event
+const STACKED_CHUNK_ROWS: usize = 256;
+
+/// The size in bytes of a dataset of n_elements elements of type T, before compression
+fn dataset_bytes<T>(n_elements: usize) -> u64 {
+    (n_elements * std::mem::size_of::<T>()) as u64
+}
+
+/// Create an extendible dataset with no rows and the given number of columns, which rows are appended to
+fn create_extendible<T: hdf5::H5Type>(
+    group: &hdf5::Group,
+    name: &str,
+    columns: usize,
+    chunk_rows: usize,
+    compression_level: Option<u8>,
+) -> Result<hdf5::Dataset, HDF5WriterError> {
+    let mut builder = group
+        .new_dataset::<T>()
+        .chunk((chunk_rows, columns))
+        .shape((0.., columns));
+    if let Some(level) = compression_level {
+        builder = builder.deflate(level);
+    }
+    Ok(builder.create(name)?)
+}
+
+/// Append rows to an extendible dataset which has rows_written rows, updating rows_written
+fn append_rows<T: hdf5::H5Type>(
+    dataset: &hdf5::Dataset,
+    rows_written: &mut usize,
+    rows: &Array2<T>,
+) -> Result<(), HDF5WriterError> {
+    if rows.nrows() == 0 {
+        return Ok(());
+    }
+    let total_rows = *rows_written + rows.nrows();
+    dataset.resize((total_rows, rows.ncols()))?;
+    dataset.write_slice(rows, (*rows_written..total_rows, ..))?;
+    *rows_written = total_rows;
+    Ok(())
+}
+
+/// Make a matrix of the buffered rows of a dataset, emptying the buffer
+fn drain_rows<const N: usize>(buffer: &mut Vec<[u64; N]>) -> Array2<u64> {
+    let n_rows = buffer.len();
+    Array2::from_shape_vec((n_rows, N), buffer.drain(..).flatten().collect())
+        .expect("Buffered rows always have the right number of columns")
+}
+
+/// Make the quicklook row of an event from its data matrix: event, number of pads, and total charge.
+///
+/// The total charge is the sum over the traces of the maximum sample over the baseline. The baseline is the
+/// pedestal if pedestals are subtracted, and 0 otherwise.
+fn make_quicklook_row(event_counter: &u64, data_matrix: &Array2<i16>) -> [u64; 3] {
+    let total_charge: u64 = data_matrix
+        .rows()
+        .into_iter()
+        .map(|row| {
+            row.iter()
+                .skip(NUMBER_OF_HARDWARE_COLUMNS)
+                .max()
+                .map_or(0, |max| (*max).max(0) as u64)
+        })
+        .sum();
+    [*event_counter, data_matrix.nrows() as u64, total_charge]
+}
+
+/// Make the pad peaks of an event from its data matrix: the pad, the maximum sample, and the time bucket of the
+/// maximum sample of each trace.
+///
+/// The rows are the rows of the data matrix. The samples are after pedestal subtraction, if pedestals are subtracted,
+/// and the first time bucket of the maximum is taken if it is reached more than once.
+fn make_pad_peaks(data_matrix: &Array2<i16>) -> Array2<i16> {
+    let mut peaks = Array2::<i16>::zeros([data_matrix.nrows(), NUMBER_OF_PAD_PEAK_COLUMNS]);
+    for (row, trace) in data_matrix.rows().into_iter().enumerate() {
+        let (max_bucket, max_sample) = trace
+            .iter()
+            .skip(NUMBER_OF_HARDWARE_COLUMNS)
+            .enumerate()
+            .fold(
+                (0, i16::MIN),
+                |(max_bucket, max_sample), (bucket, sample)| {
+                    if *sample > max_sample {
+                        (bucket, *sample)
+                    } else {
+                        (max_bucket, max_sample)
+                    }
+                },
+            );
+        peaks[[row, 0]] = trace[4]; // pad
+        peaks[[row, 1]] = max_sample;
+        peaks[[row, 2]] = max_bucket as i16;
+    }
+    peaks
+}
+
+/// The datasets of the stacked event layout, in the events group, which the events of a run are appended to
+///
+/// The index datasets have a row per event giving the rows of the event in the other datasets.
+#[derive(Debug)]
+struct StackedEvents {
+    get_index: hdf5::Dataset,
+    get_index_buffer: Vec<[u64; NUMBER_OF_GET_INDEX_COLUMNS]>, // rows not yet written to the index
+    get_index_rows: usize,
+    get_traces: hdf5::Dataset,
+    get_traces_flags: Option<hdf5::Dataset>, // same rows as the traces
+    get_traces_rows: usize,
+    get_aget_multiplicity: Option<hdf5::Dataset>,
+    get_aget_multiplicity_rows: usize,
+    frib_physics_index: hdf5::Dataset,
+    frib_physics_index_buffer: Vec<[u64; NUMBER_OF_FRIB_PHYSICS_INDEX_COLUMNS]>, // rows not yet written to the index
+    frib_physics_index_rows: usize,
+    frib_fadc: hdf5::Dataset,
+    frib_fadc_rows: usize,
+}
+
+impl StackedEvents {
+    /// Create the stacked datasets in the events group. The quality flags datasets are only created if write_quality_flags is true.
+    fn create(
+        events_group: &hdf5::Group,
+        layout: &HdfLayout,
+        time_buckets: u16,
+        write_quality_flags: bool,
+        compression_level: Option<u8>,
+    ) -> Result<Self, HDF5WriterError> {
+        let get_index = create_extendible::<u64>(
+            events_group,
+            &layout.get_index,
+            NUMBER_OF_GET_INDEX_COLUMNS,
+            INDEX_CHUNK_ROWS,
+            None,
+        )?;
+        write_description(&get_index, GET_INDEX_COLUMNS, "uint64")?;
+        let get_traces = create_extendible::<i16>(
+            events_group,
+            &layout.get_traces,
+            NUMBER_OF_HARDWARE_COLUMNS + time_buckets as usize,
+            STACKED_CHUNK_ROWS,
+            compression_level,
+        )?;
+        write_description(
+            &get_traces,
+            &format!("{},samples[{}]", GET_TRACES_COLUMNS, time_buckets),
+            "int16",
+        )?;
+        let (get_traces_flags, get_aget_multiplicity) = if write_quality_flags {
+            let flags = create_extendible::<u16>(
+                events_group,
+                &layout.get_traces_flags,
+                NUMBER_OF_FLAG_COLUMNS,
+                STACKED_CHUNK_ROWS,
+                None,
+            )?;
+            write_description(&flags, GET_TRACES_FLAGS_COLUMNS, "uint16")?;
+            let multiplicity = create_extendible::<u64>(
+                events_group,
+                &layout.get_aget_multiplicity,
+                NUMBER_OF_MULTIPLICITY_COLUMNS,
+                STACKED_CHUNK_ROWS,
+                None,
+            )?;
+            write_description(&multiplicity, GET_AGET_MULTIPLICITY_COLUMNS, "uint64")?;
+            (Some(flags), Some(multiplicity))
+        } else {
+            (None, None)
+        };
+        let frib_physics_index = create_extendible::<u64>(
+            events_group,
+            &layout.frib_physics_index,
+            NUMBER_OF_FRIB_PHYSICS_INDEX_COLUMNS,
+            INDEX_CHUNK_ROWS,
+            None,
+        )?;
+        write_description(&frib_physics_index, FRIB_PHYSICS_INDEX_COLUMNS, "uint64")?;
+        let frib_fadc = create_extendible::<u16>(
+            events_group,
+            &layout.frib_fadc,
+            NUMBER_OF_FADC_CHANNELS,
+            STACKED_CHUNK_ROWS,
+            None,
+        )?;
+        write_description(&frib_fadc, FRIB_FADC_COLUMNS, "uint16")?;
+        Ok(Self {
+            get_index,
+            get_index_buffer: Vec::with_capacity(INDEX_CHUNK_ROWS),
+            get_index_rows: 0,
+            get_traces,
+            get_traces_flags,
+            get_traces_rows: 0,
+            get_aget_multiplicity,
+            get_aget_multiplicity_rows: 0,
+            frib_physics_index,
+            frib_physics_index_buffer: Vec::with_capacity(INDEX_CHUNK_ROWS),
+            frib_physics_index_rows: 0,
+            frib_fadc,
+            frib_fadc_rows: 0,
+        })
+    }
+
+    /// Open the stacked datasets of an existing events group, to append more events to them
+    fn open(events_group: &hdf5::Group, layout: &HdfLayout) -> Result<Self, HDF5WriterError> {
+        let get_index = events_group.dataset(&layout.get_index)?;
+        let get_traces = events_group.dataset(&layout.get_traces)?;
+        let get_aget_multiplicity = events_group.dataset(&layout.get_aget_multiplicity).ok();
+        let frib_physics_index = events_group.dataset(&layout.frib_physics_index)?;
+        let frib_fadc = events_group.dataset(&layout.frib_fadc)?;
+        Ok(Self {
+            get_index_buffer: Vec::with_capacity(INDEX_CHUNK_ROWS),
+            get_index_rows: get_index.shape()[0],
+            get_index,
+            get_traces_flags: events_group.dataset(&layout.get_traces_flags).ok(),
+            get_traces_rows: get_traces.shape()[0],
+            get_traces,
+            get_aget_multiplicity_rows: get_aget_multiplicity
+                .as_ref()
+                .map_or(0, |dset| dset.shape()[0]),
+            get_aget_multiplicity,
+            frib_physics_index_buffer: Vec::with_capacity(INDEX_CHUNK_ROWS),
+            frib_physics_index_rows: frib_physics_index.shape()[0],
+            frib_physics_index,
+            frib_fadc_rows: frib_fadc.shape()[0],
+            frib_fadc,
+        })
+    }
+
+    /// The number of events in the index datasets, written or buffered: GET events and FRIBDAQ physics events
+    fn get_number_of_events(&self) -> (usize, usize) {
+        (
+            self.get_index_rows + self.get_index_buffer.len(),
+            self.frib_physics_index_rows + self.frib_physics_index_buffer.len(),
+        )
+    }
+
+    /// Append a GET event: its index values (event, id, timestamp, timestamp_other, raw_timestamp), its data matrix,
+    /// and if there are quality flags datasets its flags and AGET multiplicity. Returns the number of bytes appended
+    fn append_get_event(
+        &mut self,
+        values: [u64; 5],
+        data_matrix: &Array2<i16>,
+        flag_matrix: &Array2<u16>,
+        aget_multiplicity: &Array2<u64>,
+    ) -> Result<u64, HDF5WriterError> {
+        let mut bytes = dataset_bytes::<i16>(data_matrix.len());
+        let first_row = self.get_traces_rows;
+        if let Some(flags) = &self.get_traces_flags {
+            let mut flag_rows = first_row;
+            append_rows(flags, &mut flag_rows, flag_matrix)?;
+            bytes += dataset_bytes::<u16>(flag_matrix.len());
+        }
+        append_rows(&self.get_traces, &mut self.get_traces_rows, data_matrix)?;
+        let first_aget_row = self.get_aget_multiplicity_rows;
+        if let Some(multiplicity) = &self.get_aget_multiplicity {
+            append_rows(
+                multiplicity,
+                &mut self.get_aget_multiplicity_rows,
+                aget_multiplicity,
+            )?;
+            bytes += dataset_bytes::<u64>(aget_multiplicity.len());
+        }
+        let [event, id, timestamp, timestamp_other, raw_timestamp] = values;
+        self.get_index_buffer.push([
+            event,
+            id,
+            timestamp,
+            timestamp_other,
+            raw_timestamp,
+            first_row as u64,
+            data_matrix.nrows() as u64,
+            first_aget_row as u64,
+            (self.get_aget_multiplicity_rows - first_aget_row) as u64,
+        ]);
+        bytes += dataset_bytes::<u64>(NUMBER_OF_GET_INDEX_COLUMNS);
+        if self.get_index_buffer.len() >= INDEX_CHUNK_ROWS {
+            self.flush()?;
+        }
+        Ok(bytes)
+    }
+
+    /// Append a FRIBDAQ physics event: its index values (event, id, timestamp, body_timestamp, coincidence register)
+    /// and its FADC samples. Returns the number of bytes appended
+    fn append_frib_physics(
+        &mut self,
+        values: [u64; 5],
+        fadc_matrix: &Array2<u16>,
+    ) -> Result<u64, HDF5WriterError> {
+        let first_row = self.frib_fadc_rows;
+        append_rows(&self.frib_fadc, &mut self.frib_fadc_rows, fadc_matrix)?;
+        let [event, id, timestamp, body_timestamp, coinc] = values;
+        self.frib_physics_index_buffer.push([
+            event,
+            id,
+            timestamp,
+            body_timestamp,
+            coinc,
+            first_row as u64,
+            fadc_matrix.nrows() as u64,
+        ]);
+        if self.frib_physics_index_buffer.len() >= INDEX_CHUNK_ROWS {
+            self.flush()?;
+        }
+        Ok(dataset_bytes::<u16>(fadc_matrix.len())
+            + dataset_bytes::<u64>(NUMBER_OF_FRIB_PHYSICS_INDEX_COLUMNS))
+    }
+
+    /// Write the buffered index rows
+    fn flush(&mut self) -> Result<(), HDF5WriterError> {
+        let get_rows = drain_rows(&mut self.get_index_buffer);
+        append_rows(&self.get_index, &mut self.get_index_rows, &get_rows)?;
+        let frib_rows = drain_rows(&mut self.frib_physics_index_buffer);
+        append_rows(
+            &self.frib_physics_index,
+            &mut self.frib_physics_index_rows,
+            &frib_rows,
+        )?;
+        Ok(())
+    }
+}
 
 // All event counters start from 0 by law
 const START_EVENT_NUMBER: u32 = 0;
-/// This is the version of the output format
-const FORMAT_VERSION: &str = "1.0";
+
+/// The last scaler read, which the rates of the next read are taken from
+#[derive(Debug, Clone, Default)]
+pub struct PreviousScalers {
+    pub data: Vec<u32>,         // last cumulative (non-incremental) scaler values
+    pub timestamp: Option<u32>, // timestamp of the last scaler read
+}
 
 /// A simple struct which wraps around the hdf5-rust library.
 ///
@@ -29,28 +536,93 @@ const FORMAT_VERSION: &str = "1.0";
 #[derive(Debug)]
 pub struct HDFWriter {
     file_handle: File, //Idk if this needs to be kept alive, but I think it does
+    file_path: PathBuf,
     parent_file_path: PathBuf,
     events_group: hdf5::Group,
     scalers_group: hdf5::Group,
-    last_get_event: u64,    // GET final event number
-    last_frib_event: u64,   // FRIB final event number
-    last_scaler_event: u64, // FRIB scaler final event number
-    first_timestamp: u64,   // GET info
-    last_timestamp: u64,    // GET info
+    quicklook_dset: hdf5::Dataset,
+    quicklook_buffer: Vec<[u64; 3]>, // rows not yet written to the quicklook dataset
+    quicklook_rows: usize,           // rows written to the quicklook dataset
+    layout: HdfLayout,
+    pedestals: Option<Pedestals>,
+    write_quality_flags: bool,
+    write_cobo_timestamps: bool,
+    write_readout_info: bool,
+    compute_peaks: bool,
+    link_get_events: bool, // link each physics event to the GET event of the same number
+    prune_empty_datasets: bool, // leave out the per-event datasets with no rows
+    fadc_orientation: FadcOrientation, // how the SIS3300 samples are laid out
+    scaler_offset_divisor: u32,
+    compression_level: Option<u8>, // gzip level of the trace datasets
+    previous_scalers: PreviousScalers, // last scaler read
+    first_get_event: Option<u64>,  // GET first event number
+    last_get_event: u64,           // GET final event number
+    last_frib_event: u64,          // FRIB final event number
+    first_scaler_event: Option<u64>, // FRIB scaler first event number
+    last_scaler_event: u64,        // FRIB scaler final event number
+    first_timestamp: u64,          // GET info
+    last_timestamp: u64,           // GET info
+    bytes_written: u64,            // estimate of the size of the datasets written
+    event_naming: EventNaming,     // how the event groups are numbered
+    event_layout: EventLayout,     // how the events are laid out
+    stacked: Option<StackedEvents>, // the datasets of the stacked layout
 }
 // Structure
-// events - min_event, max_event, min_get_ts, max_get_ts, frib_run, frib_start, frib_stop, frib_time, version
+// events - min_event, max_event, min_get_ts, max_get_ts, frib_run, frib_start, frib_stop, frib_time, time_buckets, version, format_major, format_minor, naming, layout, prune_empty_datasets, fadc_orientation, pedestal_file (optional), cobos, part (optional), continues_from (optional), runs database columns (optional)
+// |---- quicklook(dset) - one row per event: event, npads, total_charge
 // |---- event_#
-// |    |---- get_traces(dset) - id, timestamp, timestamp_other
-// |    |---- frib_physics - id, timestamp
+// Every dataset also has columns and dtype attributes, and the frib_physics datasets an encoding attribute
+// With prune_empty_datasets, datasets with no rows (other than get_traces and 907) are left out, and has_<dataset> = 0 is set on the group
+// |    |---- get_traces(dset) - id, timestamp, timestamp_other, raw_timestamp (optional), sca_offsets (optional)
+// |    |---- get_traces_flags(dset) (optional)
+// |    |---- get_aget_multiplicity(dset) (optional)
+// |    |---- get_provenance(dset) (optional)
+// |    |---- get_provenance_files(dset) (optional)
+// |    |---- get_cobo_timestamps(dset) (optional)
+// |    |---- get_frame_headers(dset) (optional)
+// |    |---- get_readout(dset) (optional)
+// |    |---- pad_peaks(dset) (optional) - one row per trace: pad, max_sample, max_bucket
+// |    |---- frib_physics - id, timestamp, body_timestamp (optional), has_get_event (optional)
+// |    |    |---- get_traces(soft link) (optional) - to the get_traces of the event
 // |    |    |---- 907(dset)
 // |    |    |---- 1903(dset)
-// scalers - min_event, max_event
-// |---- event_#(dset) - start_offset, stop_offset, timestamp, incremental
+// |    |    |---- fragments(dset) (optional)
+// scalers - min_event, max_event, offset_divisor
+// |---- event_#(dset) - start_offset, stop_offset, timestamp, incremental, duration_s, rate_hz, body_timestamp (optional)
+// |---- event_#_rates(dset) - interval_s
+// orphan_silicon (optional)
+// |---- event_#(dset) - id, timestamp_other
+// config
+// |---- pad_map(dset) - one row per channel: cobo, asad, aget, channel, pad
+//
+// In the Stacked layout the events group has no event_# groups. The events are appended to datasets of the events group instead
+// events
+// |---- quicklook(dset)
+// |---- get_index(dset) - one row per GET event: event, id, timestamp, timestamp_other, raw_timestamp, first_row, n_rows, first_aget_row, n_aget_rows
+// |---- get_traces(dset) - the traces of every event, first_row to first_row + n_rows of the index
+// |---- get_traces_flags(dset) (optional) - same rows as get_traces
+// |---- get_aget_multiplicity(dset) (optional) - first_aget_row to first_aget_row + n_aget_rows of the index
+// |---- frib_physics_index(dset) - one row per physics event: event, id, timestamp, body_timestamp, coincidence_register, first_row, n_rows
+// |---- 1903(dset) - the FADC samples of every physics event, first_row to first_row + n_rows of the index
 
 impl HDFWriter {
     /// Create the writer, opening a file at path and creating the data groups
-    pub fn new(path: &Path) -> Result<Self, HDF5WriterError> {
+    ///
+    /// If pedestals are given, they will be subtracted from the traces of every event written.
+    /// The options say which optional datasets are written alongside the traces, and in which layout (see HdfWriterOptions).
+    /// The number of time buckets per trace is recorded as an attribute of the events group.
+    pub fn new(
+        path: &Path,
+        pedestals: Option<Pedestals>,
+        options: &HdfWriterOptions,
+    ) -> Result<Self, HDF5WriterError> {
+        let HdfWriterOptions {
+            write_quality_flags,
+            write_cobo_timestamps,
+            scaler_offset_divisor,
+            time_buckets,
+            layout,
+        } = options.clone();
         let file_handle = File::create(path)?;
         let stem = path.parent().unwrap();
         let run_path = path.file_stem().unwrap();
@@ -58,7 +630,7 @@ impl HDFWriter {
 
         let merger_version = format!("{}:{}", env!("CARGO_PKG_NAME"), FORMAT_VERSION);
 
-        let events_group = file_handle.create_group(EVENTS_NAME)?;
+        let events_group = file_handle.create_group(&layout.events)?;
         events_group.new_attr::<u64>().create("min_event")?;
         events_group.new_attr::<u64>().create("max_event")?;
         events_group.new_attr::<u64>().create("min_get_ts")?;
@@ -67,16 +639,71 @@ impl HDFWriter {
         events_group.new_attr::<u32>().create("frib_start")?;
         events_group.new_attr::<u32>().create("frib_stop")?;
         events_group.new_attr::<u32>().create("frib_time")?;
+        events_group
+            .new_attr::<u16>()
+            .create("time_buckets")?
+            .write_scalar(&time_buckets)?;
         events_group
             .new_attr::<hdf5::types::VarLenUnicode>()
             .create("version")?;
         events_group
             .attr("version")?
             .write_scalar(&VarLenUnicode::from_str(&merger_version).unwrap())?;
+        // The version as integers too, so that readers can compare it without parsing the string
+        events_group
+            .new_attr::<u32>()
+            .create("format_major")?
+            .write_scalar(&FORMAT_MAJOR)?;
+        events_group
+            .new_attr::<u32>()
+            .create("format_minor")?
+            .write_scalar(&FORMAT_MINOR)?;
+        // Empty datasets are written until the writer is told to prune them
+        events_group
+            .new_attr::<u8>()
+            .create("prune_empty_datasets")?
+            .write_scalar(&0u8)?;
+        events_group
+            .new_attr::<VarLenUnicode>()
+            .create("fadc_orientation")?
+            .write_scalar(&VarLenUnicode::from_str(FadcOrientation::default().as_str()).unwrap())?;
+        events_group
+            .new_attr::<VarLenUnicode>()
+            .create("naming")?
+            .write_scalar(&VarLenUnicode::from_str(EventNaming::default().as_str()).unwrap())?;
+        events_group
+            .new_attr::<VarLenUnicode>()
+            .create("layout")?
+            .write_scalar(&VarLenUnicode::from_str(EventLayout::default().as_str()).unwrap())?;
+        if let Some(peds) = &pedestals {
+            // Record which pedestals were applied: path:hash
+            let pedestal_info = format!(
+                "{}:{:016x}",
+                peds.get_file_path().display(),
+                peds.get_file_hash()
+            );
+            events_group
+                .new_attr::<hdf5::types::VarLenUnicode>()
+                .create("pedestal_file")?
+                .write_scalar(&VarLenUnicode::from_str(&pedestal_info).unwrap())?;
+        }
+
+        // Extendible, rows are appended as events are written
+        let quicklook_dset = events_group
+            .new_dataset::<u64>()
+            .chunk((QUICKLOOK_CHUNK_ROWS, NUMBER_OF_QUICKLOOK_COLUMNS))
+            .shape((0.., NUMBER_OF_QUICKLOOK_COLUMNS))
+            .create(layout.quicklook.as_str())?;
+        write_description(&quicklook_dset, QUICKLOOK_COLUMNS, "uint64")?;
 
-        let scalers_group = file_handle.create_group(SCALERS_NAME)?;
+        let scalers_group = file_handle.create_group(&layout.scalers)?;
         scalers_group.new_attr::<u32>().create("min_event")?;
         scalers_group.new_attr::<u32>().create("max_event")?;
+        // Ticks per second of the offset clock, needed to interpret start_offset and stop_offset
+        scalers_group
+            .new_attr::<u32>()
+            .create("offset_divisor")?
+            .write_scalar(&scaler_offset_divisor)?;
         scalers_group
             .new_attr::<hdf5::types::VarLenUnicode>()
             .create("version")?;
@@ -86,28 +713,414 @@ impl HDFWriter {
 
         Ok(Self {
             file_handle,
+            file_path: path.to_path_buf(),
             parent_file_path,
             events_group,
             scalers_group,
+            quicklook_dset,
+            quicklook_buffer: Vec::with_capacity(QUICKLOOK_CHUNK_ROWS),
+            quicklook_rows: 0,
+            layout,
+            pedestals,
+            write_quality_flags,
+            write_cobo_timestamps,
+            write_readout_info: false,
+            compute_peaks: false,
+            link_get_events: false,
+            prune_empty_datasets: false,
+            fadc_orientation: FadcOrientation::default(),
+            scaler_offset_divisor,
+            compression_level: None,
+            previous_scalers: PreviousScalers::default(),
+            first_get_event: None,
             last_get_event: 0,
             last_frib_event: 0,
+            first_scaler_event: None,
             last_scaler_event: 0,
             first_timestamp: 0,
             last_timestamp: 0,
+            bytes_written: 0,
+            event_naming: EventNaming::default(),
+            event_layout: EventLayout::default(),
+            stacked: None,
+        })
+    }
+
+    /// Open an existing merged file at path to write more events to it, i.e. for incremental online merging
+    ///
+    /// The first and last events, timestamps, scaler reads and quicklook rows are read back from the file, so that
+    /// closing the writer updates the min/max attributes to cover both the existing and the new data. The next GET event
+    /// to write is get_last_get_event() + 1. The file must have been written by this version of the format (with the
+    /// same layout), otherwise an IncompatibleVersion error is returned. The scaler offset divisor is read from the file.
+    /// The file information (CoBos and file list) and the pad map are already in the file, so they must not be written again.
+    /// The scaler_offset_divisor and time_buckets of the options are not used.
+    pub fn open_append(
+        path: &Path,
+        pedestals: Option<Pedestals>,
+        options: &HdfWriterOptions,
+    ) -> Result<Self, HDF5WriterError> {
+        let HdfWriterOptions {
+            write_quality_flags,
+            write_cobo_timestamps,
+            layout,
+            ..
+        } = options.clone();
+        let file_handle = File::open_rw(path)?;
+        let stem = path.parent().unwrap();
+        let run_path = path.file_stem().unwrap();
+        let parent_file_path = stem.join(format!("{}.yml", run_path.to_string_lossy()));
+
+        let merger_version = format!("{}:{}", env!("CARGO_PKG_NAME"), FORMAT_VERSION);
+        let events_group = file_handle.group(&layout.events)?;
+        let file_version = events_group
+            .attr("version")?
+            .read_scalar::<VarLenUnicode>()?
+            .as_str()
+            .to_string();
+        if file_version != merger_version {
+            return Err(HDF5WriterError::IncompatibleVersion(
+                file_version,
+                merger_version,
+            ));
+        }
+        if let Some(peds) = &pedestals {
+            let pedestal_info = format!(
+                "{}:{:016x}",
+                peds.get_file_path().display(),
+                peds.get_file_hash()
+            );
+            match events_group.attr("pedestal_file") {
+                Ok(attr) => {
+                    let file_pedestals = attr.read_scalar::<VarLenUnicode>()?.as_str().to_string();
+                    if file_pedestals != pedestal_info {
+                        spdlog::warn!("Appending to {} with pedestals {}, but the events already in the file had pedestals {} subtracted!", path.display(), pedestal_info, file_pedestals);
+                    }
+                }
+                Err(_) => spdlog::warn!(
+                    "Appending to {} with pedestals {}, but the events already in the file had no pedestals subtracted!",
+                    path.display(),
+                    pedestal_info
+                ),
+            }
+        }
+
+        // Files written before the naming attribute was added are ordinal
+        let event_naming = match events_group.attr("naming") {
+            Ok(attr) => match attr.read_scalar::<VarLenUnicode>()?.as_str() {
+                "GetEventId" => EventNaming::GetEventId,
+                _ => EventNaming::Ordinal,
+            },
+            Err(_) => EventNaming::Ordinal,
+        };
+
+        // Files written before the layout attribute was added have event groups
+        let event_layout = match events_group.attr("layout") {
+            Ok(attr) => match attr.read_scalar::<VarLenUnicode>()?.as_str() {
+                "Stacked" => EventLayout::Stacked,
+                _ => EventLayout::Groups,
+            },
+            Err(_) => EventLayout::Groups,
+        };
+        let stacked = match event_layout {
+            EventLayout::Stacked => Some(StackedEvents::open(&events_group, &layout)?),
+            EventLayout::Groups => None,
+        };
+
+        // An event only has GET data (or FRIB data) if the dataset (or group) is there
+        let min_event = events_group.attr("min_event")?.read_scalar::<u64>()?;
+        let max_event = events_group.attr("max_event")?.read_scalar::<u64>()?;
+        let event_has = |event: &u64, name: &str| {
+            events_group
+                .group(&layout.event_name(event))
+                .map(|group| group.link_exists(name))
+                .unwrap_or(false)
+        };
+        let (has_get_events, has_frib_events) = match &stacked {
+            Some(stacked) => {
+                let (get_events, frib_events) = stacked.get_number_of_events();
+                (get_events > 0, frib_events > 0)
+            }
+            None => (
+                event_has(&min_event, &layout.get_traces),
+                event_has(&max_event, &layout.frib_physics),
+            ),
+        };
+        let (first_timestamp, last_timestamp) = if has_get_events {
+            (
+                events_group.attr("min_get_ts")?.read_scalar::<u64>()?,
+                events_group.attr("max_get_ts")?.read_scalar::<u64>()?,
+            )
+        } else {
+            (0, 0)
+        };
+
+        let quicklook_dset = events_group.dataset(&layout.quicklook)?;
+        let quicklook_rows = quicklook_dset.shape()[0];
+
+        let scalers_group = file_handle.group(&layout.scalers)?;
+        let scaler_offset_divisor = scalers_group.attr("offset_divisor")?.read_scalar::<u32>()?;
+        let min_scaler_event = scalers_group.attr("min_event")?.read_scalar::<u64>()?;
+        let max_scaler_event = scalers_group.attr("max_event")?.read_scalar::<u64>()?;
+        // Pick the scaler rates up from the last scaler read
+        let (first_scaler_event, previous_scalers) =
+            match scalers_group.dataset(&layout.event_name(&max_scaler_event)) {
+                Ok(last_scalers) => {
+                    let incremental = last_scalers.attr("incremental")?.read_scalar::<u32>()?;
+                    let data = if incremental == 0 {
+                        last_scalers.read_raw::<u32>()?
+                    } else {
+                        Vec::new()
+                    };
+                    let timestamp = last_scalers.attr("timestamp")?.read_scalar::<u32>()?;
+                    (
+                        Some(min_scaler_event),
+                        PreviousScalers {
+                            data,
+                            timestamp: Some(timestamp),
+                        },
+                    )
+                }
+                Err(_) => (None, PreviousScalers::default()),
+            };
+
+        spdlog::info!(
+            "Appending to {}, which has events {} to {}.",
+            path.display(),
+            min_event,
+            max_event
+        );
+
+        Ok(Self {
+            file_handle,
+            file_path: path.to_path_buf(),
+            parent_file_path,
+            events_group,
+            scalers_group,
+            quicklook_dset,
+            quicklook_buffer: Vec::with_capacity(QUICKLOOK_CHUNK_ROWS),
+            quicklook_rows,
+            layout,
+            pedestals,
+            write_quality_flags,
+            write_cobo_timestamps,
+            write_readout_info: false,
+            compute_peaks: false,
+            link_get_events: false,
+            prune_empty_datasets: false,
+            fadc_orientation: FadcOrientation::default(),
+            scaler_offset_divisor,
+            compression_level: None,
+            previous_scalers,
+            first_get_event: if has_get_events {
+                Some(min_event)
+            } else {
+                None
+            },
+            last_get_event: if has_get_events { max_event } else { 0 },
+            last_frib_event: if has_frib_events { max_event } else { 0 },
+            first_scaler_event,
+            last_scaler_event: if first_scaler_event.is_some() {
+                max_scaler_event
+            } else {
+                0
+            },
+            first_timestamp,
+            last_timestamp,
+            bytes_written: std::fs::metadata(path).map(|meta| meta.len()).unwrap_or(0),
+            event_naming,
+            event_layout,
+            stacked,
         })
     }
 
+    /// Compress the trace datasets of the events written from now on with gzip at the given level (0-9).
+    ///
+    /// If the level is None, the traces are not compressed
+    pub fn set_compression_level(&mut self, level: Option<u8>) {
+        self.compression_level = level;
+    }
+
+    /// Write the readout metadata of every AsAd in each event (see Event::get_readout_info) alongside the traces of
+    /// the events written from now on
+    pub fn set_write_readout_info(&mut self, write_readout_info: bool) {
+        self.write_readout_info = write_readout_info;
+    }
+
+    /// Write the peak (maximum sample and its time bucket) of every trace (see make_pad_peaks) alongside the traces of
+    /// the events written from now on
+    pub fn set_compute_peaks(&mut self, compute_peaks: bool) {
+        self.compute_peaks = compute_peaks;
+    }
+
+    /// Link the frib_physics group of each physics event written from now on to the GET event of the same number
+    ///
+    /// The frib_physics group gets a get_traces soft link to the get_traces dataset of its event, and a has_get_event
+    /// attribute of 1. If the event has no GET traces, there is no link and has_get_event is 0. The GET events must be
+    /// written before the physics events.
+    pub fn set_link_get_events(&mut self, link_get_events: bool) {
+        self.link_get_events = link_get_events;
+    }
+
+    /// Leave the per-event datasets with no rows out of the events written from now on, recorded in the
+    /// prune_empty_datasets attribute of the events group
+    ///
+    /// A dataset left out is marked by a has_(dataset name) attribute of 0 on its group, so readers can tell it apart
+    /// from a dataset which was not requested. The get_traces dataset and the coincidence register are always written.
+    pub fn set_prune_empty_datasets(
+        &mut self,
+        prune_empty_datasets: bool,
+    ) -> Result<(), HDF5WriterError> {
+        let value = prune_empty_datasets as u8;
+        match self.events_group.attr("prune_empty_datasets") {
+            Ok(attr) => attr.write_scalar(&value)?,
+            Err(_) => self
+                .events_group
+                .new_attr::<u8>()
+                .create("prune_empty_datasets")?
+                .write_scalar(&value)?,
+        }
+        self.prune_empty_datasets = prune_empty_datasets;
+        Ok(())
+    }
+
+    /// Set how the SIS3300 samples of the physics events written from now on are laid out, recorded in the
+    /// fadc_orientation attribute of the events group
+    ///
+    /// The orientation of each 1903 dataset is also given by its columns and encoding attributes, so files appended to
+    /// with another orientation can still be read.
+    pub fn set_fadc_orientation(
+        &mut self,
+        orientation: FadcOrientation,
+    ) -> Result<(), HDF5WriterError> {
+        let value = VarLenUnicode::from_str(orientation.as_str()).unwrap();
+        match self.events_group.attr("fadc_orientation") {
+            Ok(attr) => attr.write_scalar(&value)?,
+            Err(_) => self
+                .events_group
+                .new_attr::<VarLenUnicode>()
+                .create("fadc_orientation")?
+                .write_scalar(&value)?,
+        }
+        self.fadc_orientation = orientation;
+        Ok(())
+    }
+
+    /// Check if a dataset with the given number of rows is left out of the group, marking it as pruned if so
+    fn prune_if_empty(
+        &self,
+        group: &hdf5::Group,
+        name: &str,
+        rows: usize,
+    ) -> Result<bool, HDF5WriterError> {
+        if !self.prune_empty_datasets || rows > 0 {
+            return Ok(false);
+        }
+        group
+            .new_attr::<u8>()
+            .create(format!("has_{name}").as_str())?
+            .write_scalar(&0u8)?;
+        Ok(true)
+    }
+
+    /// Set how the event groups are numbered, recorded in the naming attribute of the events group
+    ///
+    /// A file can only hold events of one naming, so if the file already has events of a different naming (i.e. when
+    /// appending) a MixedEventNaming error is returned.
+    pub fn set_event_naming(&mut self, naming: EventNaming) -> Result<(), HDF5WriterError> {
+        if naming == self.event_naming {
+            return Ok(());
+        }
+        if self.has_events()? {
+            return Err(HDF5WriterError::MixedEventNaming(
+                self.event_naming.as_str().to_string(),
+                naming.as_str().to_string(),
+            ));
+        }
+        let value = VarLenUnicode::from_str(naming.as_str()).unwrap();
+        match self.events_group.attr("naming") {
+            Ok(attr) => attr.write_scalar(&value)?,
+            Err(_) => self
+                .events_group
+                .new_attr::<VarLenUnicode>()
+                .create("naming")?
+                .write_scalar(&value)?,
+        }
+        self.event_naming = naming;
+        Ok(())
+    }
+
+    /// Set how the events are laid out, recorded in the layout attribute of the events group
+    ///
+    /// For the stacked layout, the datasets the events are appended to are created, compressed with the level of
+    /// set_compression_level. A file can only hold events of one layout, so if the file already has events of a
+    /// different layout (i.e. when appending) a MixedEventLayout error is returned.
+    pub fn set_event_layout(&mut self, event_layout: EventLayout) -> Result<(), HDF5WriterError> {
+        if event_layout == self.event_layout {
+            return Ok(());
+        }
+        if self.has_events()? {
+            return Err(HDF5WriterError::MixedEventLayout(
+                self.event_layout.as_str().to_string(),
+                event_layout.as_str().to_string(),
+            ));
+        }
+        self.stacked = match event_layout {
+            EventLayout::Stacked => {
+                let time_buckets = self
+                    .events_group
+                    .attr("time_buckets")?
+                    .read_scalar::<u16>()?;
+                Some(StackedEvents::create(
+                    &self.events_group,
+                    &self.layout,
+                    time_buckets,
+                    self.write_quality_flags,
+                    self.compression_level,
+                )?)
+            }
+            EventLayout::Groups => None,
+        };
+        let value = VarLenUnicode::from_str(event_layout.as_str()).unwrap();
+        match self.events_group.attr("layout") {
+            Ok(attr) => attr.write_scalar(&value)?,
+            Err(_) => self
+                .events_group
+                .new_attr::<VarLenUnicode>()
+                .create("layout")?
+                .write_scalar(&value)?,
+        }
+        self.event_layout = event_layout;
+        Ok(())
+    }
+
+    /// Check if any events have been written to the file, as event groups or to the stacked datasets
+    fn has_events(&self) -> Result<bool, HDF5WriterError> {
+        if let Some(stacked) = &self.stacked {
+            if stacked.get_number_of_events() != (0, 0) {
+                return Ok(true);
+            }
+        }
+        Ok(self
+            .events_group
+            .member_names()?
+            .iter()
+            .any(|name| name.starts_with(&self.layout.event_prefix)))
+    }
+
     /// Write an event, where the event is converted into a data matrix
+    ///
+    /// If the event recorded the provenance or headers of its frames, these are written as well
     pub fn write_event(
         &mut self,
-        event: Event,
+        mut event: Event,
         event_counter: &u64,
     ) -> Result<(), HDF5WriterError> {
-        if *event_counter == (START_EVENT_NUMBER as u64) {
+        if self.first_get_event.is_none() {
             // Catch first event ts
+            self.first_get_event = Some(*event_counter);
             self.first_timestamp = event.timestamp;
         }
-        if *event_counter > self.last_get_event {
+        if *event_counter >= self.last_get_event {
             self.last_get_event = *event_counter;
             self.last_timestamp = event.timestamp;
         }
@@ -115,16 +1128,43 @@ impl HDFWriter {
         let id = event.event_id;
         let ts = event.timestamp;
         let tso = event.timestampother;
-        let event_name = format!("event_{}", event_counter);
+        let raw_ts = event.raw_timestamp;
+        let provenance = std::mem::take(&mut event.provenance);
+        let frame_headers = std::mem::take(&mut event.frame_headers);
+        let cobo_timestamps = event.get_cobo_timestamps();
+        let aget_multiplicity = event.get_aget_multiplicity();
+        let readout_info = event.get_readout_info();
+        let sca_offsets = event.get_sca_offsets();
+        let (data_matrix, flag_matrix) = event.convert_to_data_matrix(self.pedestals.as_mut());
+        self.quicklook_buffer
+            .push(make_quicklook_row(event_counter, &data_matrix));
+        if self.quicklook_buffer.len() >= QUICKLOOK_CHUNK_ROWS {
+            self.flush_quicklook()?;
+        }
+        // The provenance, frame headers, readout, peaks and CoBo timestamps have no stacked datasets (see Config::validate)
+        if let Some(stacked) = &mut self.stacked {
+            self.bytes_written += stacked.append_get_event(
+                [*event_counter, id as u64, ts, tso, raw_ts.unwrap_or(ts)],
+                &data_matrix,
+                &flag_matrix,
+                &aget_multiplicity,
+            )?;
+            return Ok(());
+        }
 
+        let event_name = self.layout.event_name(event_counter);
         let event_group = match self.events_group.group(&event_name) {
             Ok(group) => group,
             Err(_) => self.events_group.create_group(&event_name)?,
         };
-        let traces_dset = event_group
-            .new_dataset_builder()
-            .with_data(&event.convert_to_data_matrix())
-            .create(GET_TRACES_NAME)?;
+        let mut traces_builder = event_group.new_dataset_builder();
+        if let Some(level) = self.compression_level {
+            traces_builder = traces_builder.deflate(level);
+        }
+        self.bytes_written += dataset_bytes::<i16>(data_matrix.len());
+        let traces_dset = traces_builder
+            .with_data(&data_matrix)
+            .create(self.layout.get_traces.as_str())?;
         traces_dset
             .new_attr::<u32>()
             .create("id")?
@@ -137,12 +1177,172 @@ impl HDFWriter {
             .new_attr::<u64>()
             .create("timestamp_other")?
             .write_scalar(&tso)?;
+        if let Some(raw_ts) = &raw_ts {
+            traces_dset
+                .new_attr::<u64>()
+                .create("raw_timestamp")?
+                .write_scalar(raw_ts)?;
+        }
+        if sca_offsets.nrows() > 0 {
+            // One row per AGET: cobo, asad, aget, offset
+            traces_dset
+                .new_attr::<u64>()
+                .shape((sca_offsets.nrows(), NUMBER_OF_SCA_OFFSET_COLUMNS))
+                .create("sca_offsets")?
+                .write(&sca_offsets)?;
+        }
+        write_description(
+            &traces_dset,
+            &format!(
+                "{},samples[{}]",
+                GET_TRACES_COLUMNS,
+                data_matrix.ncols() - NUMBER_OF_HARDWARE_COLUMNS
+            ),
+            "int16",
+        )?;
+
+        if self.compute_peaks {
+            // One row per trace: pad, max sample, max bucket
+            let pad_peaks = make_pad_peaks(&data_matrix);
+            if !self.prune_if_empty(&event_group, &self.layout.pad_peaks, pad_peaks.nrows())? {
+                self.bytes_written += dataset_bytes::<i16>(pad_peaks.len());
+                let peaks_dset = event_group
+                    .new_dataset_builder()
+                    .with_data(&pad_peaks)
+                    .create(self.layout.pad_peaks.as_str())?;
+                write_description(&peaks_dset, PAD_PEAKS_COLUMNS, "int16")?;
+            }
+        }
+
+        if self.write_quality_flags {
+            // One row per trace: saturated, duplicate buckets, nonzero samples
+            if !self.prune_if_empty(
+                &event_group,
+                &self.layout.get_traces_flags,
+                flag_matrix.nrows(),
+            )? {
+                self.bytes_written += dataset_bytes::<u16>(flag_matrix.len());
+                let flags_dset = event_group
+                    .new_dataset_builder()
+                    .with_data(&flag_matrix)
+                    .create(self.layout.get_traces_flags.as_str())?;
+                write_description(&flags_dset, GET_TRACES_FLAGS_COLUMNS, "uint16")?;
+            }
+
+            // One row per AGET: cobo, asad, aget, multiplicity, hit channels, from the frame headers
+            if !self.prune_if_empty(
+                &event_group,
+                &self.layout.get_aget_multiplicity,
+                aget_multiplicity.nrows(),
+            )? {
+                self.bytes_written += dataset_bytes::<u64>(aget_multiplicity.len());
+                let multiplicity_dset = event_group
+                    .new_dataset_builder()
+                    .with_data(&aget_multiplicity)
+                    .create(self.layout.get_aget_multiplicity.as_str())?;
+                write_description(&multiplicity_dset, GET_AGET_MULTIPLICITY_COLUMNS, "uint64")?;
+            }
+        }
+
+        if self.write_cobo_timestamps {
+            // One row per CoBo: cobo, first frame timestamp, last frame timestamp
+            if !self.prune_if_empty(
+                &event_group,
+                &self.layout.get_cobo_timestamps,
+                cobo_timestamps.nrows(),
+            )? {
+                self.bytes_written += dataset_bytes::<u64>(cobo_timestamps.len());
+                let cobo_timestamps_dset = event_group
+                    .new_dataset_builder()
+                    .with_data(&cobo_timestamps)
+                    .create(self.layout.get_cobo_timestamps.as_str())?;
+                write_description(&cobo_timestamps_dset, GET_COBO_TIMESTAMPS_COLUMNS, "uint64")?;
+            }
+        }
+
+        if !provenance.is_empty() {
+            // One row per frame: offset, cobo, asad. File names are stored in a parallel dataset
+            let mut provenance_matrix = Array2::<u64>::zeros([provenance.len(), 3]);
+            let mut provenance_files: Vec<VarLenUnicode> = Vec::with_capacity(provenance.len());
+            for (row, frame) in provenance.iter().enumerate() {
+                provenance_matrix[[row, 0]] = frame.offset;
+                provenance_matrix[[row, 1]] = frame.cobo_id as u64;
+                provenance_matrix[[row, 2]] = frame.asad_id as u64;
+                provenance_files
+                    .push(VarLenUnicode::from_str(&frame.file_path.to_string_lossy()).unwrap());
+            }
+            self.bytes_written += dataset_bytes::<u64>(provenance_matrix.len());
+            let provenance_dset = event_group
+                .new_dataset_builder()
+                .with_data(&provenance_matrix)
+                .create(self.layout.get_provenance.as_str())?;
+            write_description(&provenance_dset, GET_PROVENANCE_COLUMNS, "uint64")?;
+            let provenance_files_dset = event_group
+                .new_dataset_builder()
+                .with_data(&provenance_files)
+                .create(self.layout.get_provenance_files.as_str())?;
+            write_description(&provenance_files_dset, GET_PROVENANCE_FILES_COLUMNS, "str")?;
+        }
+
+        if !frame_headers.is_empty() {
+            // One row per frame, with the columns of GET_FRAME_HEADERS_COLUMNS
+            let mut header_matrix = Array2::<u64>::zeros([frame_headers.len(), 12]);
+            for (row, header) in frame_headers.iter().enumerate() {
+                let values = [
+                    header.frame_size as u64,
+                    header.frame_type as u64,
+                    header.revision as u64,
+                    header.header_size as u64,
+                    header.item_size as u64,
+                    header.n_items as u64,
+                    header.event_time,
+                    header.event_id as u64,
+                    header.cobo_id as u64,
+                    header.asad_id as u64,
+                    header.read_offset as u64,
+                    header.status as u64,
+                ];
+                for (col, value) in values.into_iter().enumerate() {
+                    header_matrix[[row, col]] = value;
+                }
+            }
+            self.bytes_written += dataset_bytes::<u64>(header_matrix.len());
+            let headers_dset = event_group
+                .new_dataset_builder()
+                .with_data(&header_matrix)
+                .create(self.layout.get_frame_headers.as_str())?;
+            write_description(&headers_dset, GET_FRAME_HEADERS_COLUMNS, "uint64")?;
+        }
+
+        if self.write_readout_info {
+            // One row per AsAd, with the columns of GET_READOUT_COLUMNS
+            if !self.prune_if_empty(&event_group, &self.layout.get_readout, readout_info.nrows())? {
+                self.bytes_written += dataset_bytes::<u64>(readout_info.len());
+                let readout_dset = event_group
+                    .new_dataset_builder()
+                    .with_data(&readout_info)
+                    .create(self.layout.get_readout.as_str())?;
+                write_description(&readout_dset, GET_READOUT_COLUMNS, "uint64")?;
+            }
+        }
 
         Ok(())
     }
 
-    /// Write graw file information in a separate yaml file
+    /// Append the buffered quicklook rows to the quicklook dataset
+    fn flush_quicklook(&mut self) -> Result<(), HDF5WriterError> {
+        let rows = drain_rows(&mut self.quicklook_buffer);
+        append_rows(&self.quicklook_dset, &mut self.quicklook_rows, &rows)
+    }
+
+    /// Write graw file information in a separate yaml file, and the CoBos merged as the cobos attribute of the events group
     pub fn write_fileinfo(&self, merger: &Merger) -> Result<(), HDF5WriterError> {
+        self.write_cobos(&merger.get_cobos())?;
+        self.write_file_list(merger)
+    }
+
+    /// Write graw file information in a separate yaml file
+    pub fn write_file_list(&self, merger: &Merger) -> Result<(), HDF5WriterError> {
         let file_stacks = merger.get_file_stacks();
         let mut file_map = BTreeMap::<String, Vec<String>>::new();
         for stack in file_stacks.iter() {
@@ -162,11 +1362,15 @@ impl HDFWriter {
             let mut size_list = file_list.clone();
             size_list[0] =
                 human_bytes::human_bytes(stack.get_active_file().get_size_bytes() as f64); // Active file is the first one
-            file_list[0] = String::from(stack.get_active_file().get_filename().to_str().unwrap());
+            file_list[0] = stack
+                .get_active_file()
+                .get_filename()
+                .to_string_lossy()
+                .to_string();
             for (row, path) in file_stack.iter().enumerate() {
                 size_list[row + 1] =
                     human_bytes::human_bytes(path.metadata().unwrap().len() as f64);
-                file_list[row + 1] = String::from(path.to_str().unwrap());
+                file_list[row + 1] = path.to_string_lossy().to_string();
             }
             file_map.insert(file_name, file_list);
             file_map.insert(size_name, size_list);
@@ -178,38 +1382,216 @@ impl HDFWriter {
         Ok(())
     }
 
-    /// Write meta information on first and last events, consume the writer
-    pub fn close(self) -> Result<(), HDF5WriterError> {
+    /// Write the CoBos merged as the cobos attribute of the events group
+    pub fn write_cobos(&self, cobos: &[u8]) -> Result<(), HDF5WriterError> {
+        // Record which CoBos were merged, as runs can be taken without some of them
+        if !cobos.is_empty() {
+            self.events_group
+                .new_attr_builder()
+                .with_data(cobos)
+                .create("cobos")?;
+        }
+        Ok(())
+    }
+
+    /// Write the pad map used to build the events to the config group, so that the run can be re-merged with the same map
+    pub fn write_pad_map(&self, pad_map: &PadMap) -> Result<(), HDF5WriterError> {
+        let config_group = self.file_handle.create_group(&self.layout.config)?;
+        let pad_map_dset = config_group
+            .new_dataset_builder()
+            .with_data(&pad_map.to_matrix())
+            .create(self.layout.pad_map.as_str())?;
+        write_description(&pad_map_dset, PAD_MAP_COLUMNS, "uint64")?;
+        Ok(())
+    }
+
+    /// Write the attributes of the run from the runs database (i.e. beam, field, note) as string attributes of the events group
+    ///
+    /// Attributes with the name of an attribute the merger writes are skipped with a warning
+    pub fn write_run_attributes(
+        &self,
+        attributes: &BTreeMap<String, String>,
+    ) -> Result<(), HDF5WriterError> {
+        for (name, value) in attributes.iter() {
+            if self.events_group.attr(name).is_ok() {
+                spdlog::warn!(
+                    "The runs database column {} has the name of an attribute of the events group, skipping it.",
+                    name
+                );
+                continue;
+            }
+            self.events_group
+                .new_attr::<VarLenUnicode>()
+                .create(name.as_str())?
+                .write_scalar(&VarLenUnicode::from_str(value).unwrap())?;
+        }
+        Ok(())
+    }
+
+    /// The path of the file written
+    pub fn get_file_path(&self) -> &Path {
+        &self.file_path
+    }
+
+    /// The number of the last GET event written
+    pub fn get_last_get_event(&self) -> &u64 {
+        &self.last_get_event
+    }
+
+    /// The number of the last FRIBDAQ physics event written
+    pub fn get_last_frib_event(&self) -> &u64 {
+        &self.last_frib_event
+    }
+
+    /// An estimate of the number of bytes written to the file, from the sizes of the datasets (before compression)
+    ///
+    /// Attributes and the HDF5 bookkeeping are not included. For an appended file, the estimate starts at the size of
+    /// the existing file.
+    pub fn get_bytes_written(&self) -> &u64 {
+        &self.bytes_written
+    }
+
+    /// Write the part attribute of the events group, and for parts after the first the continues_from attribute
+    /// (the file name of the previous part), linking the files of a split run
+    pub fn write_part_info(
+        &self,
+        part: u64,
+        continues_from: Option<&Path>,
+    ) -> Result<(), HDF5WriterError> {
+        self.events_group
+            .new_attr::<u64>()
+            .create("part")?
+            .write_scalar(&part)?;
+        if let Some(previous) = continues_from {
+            let file_name = previous.file_name().unwrap_or_default().to_string_lossy();
+            self.events_group
+                .new_attr::<VarLenUnicode>()
+                .create("continues_from")?
+                .write_scalar(&VarLenUnicode::from_str(&file_name).unwrap())?;
+        }
+        Ok(())
+    }
+
+    /// Take the last scaler read, leaving none behind
+    ///
+    /// Used to carry the scaler rates over to the next file when a run is split
+    pub fn take_previous_scalers(&mut self) -> PreviousScalers {
+        std::mem::take(&mut self.previous_scalers)
+    }
+
+    /// Set the last scaler read, which the rates of the next scaler read are taken from
+    pub fn set_previous_scalers(&mut self, previous_scalers: PreviousScalers) {
+        self.previous_scalers = previous_scalers;
+    }
+
+    /// Check if an existing file is a complete merged file: it can be opened, has the max_event attribute, and was
+    /// not marked incomplete (see mark_incomplete).
+    ///
+    /// Files left behind by a merge which crashed are typically empty or truncated, and fail this check.
+    pub fn is_complete(path: &Path, layout: &HdfLayout) -> bool {
+        match File::open(path).and_then(|file| file.group(&layout.events)) {
+            Ok(group) => group.attr("max_event").is_ok() && group.attr("incomplete").is_err(),
+            Err(_) => false,
+        }
+    }
+
+    /// Mark a closed file as incomplete with the incomplete attribute of the events group, e.g. when the merge of
+    /// the run was stopped. The file is still readable, but is_complete is false so it is merged again.
+    pub fn mark_incomplete(path: &Path, layout: &HdfLayout) -> Result<(), HDF5WriterError> {
+        let file = File::open_rw(path)?;
+        file.group(&layout.events)?
+            .new_attr::<bool>()
+            .create("incomplete")?
+            .write_scalar(&true)?;
+        Ok(())
+    }
+
+    /// Check that a closed file can be reopened and that its max_event attribute matches the expected
+    /// last event, which must also exist in the file (as an event group, or in an index of the stacked layout).
+    pub fn verify(
+        path: &Path,
+        layout: &HdfLayout,
+        expected_max_event: &u64,
+    ) -> Result<bool, HDF5WriterError> {
+        let file = File::open(path)?;
+        let events_group = file.group(&layout.events)?;
+        let max_event = events_group.attr("max_event")?.read_scalar::<u64>()?;
+        if max_event != *expected_max_event {
+            return Ok(false);
+        }
+        let is_stacked = events_group
+            .attr("layout")
+            .and_then(|attr| attr.read_scalar::<VarLenUnicode>())
+            .is_ok_and(|value| value.as_str() == EventLayout::Stacked.as_str());
+        if !is_stacked {
+            return Ok(events_group
+                .group(&layout.event_name(expected_max_event))
+                .is_ok());
+        }
+        for index_name in [&layout.get_index, &layout.frib_physics_index] {
+            let index = events_group.dataset(index_name)?.read_2d::<u64>()?;
+            if index
+                .column(0)
+                .iter()
+                .any(|event| event == expected_max_event)
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Write meta information on first and last events and the remaining quicklook rows, consume the writer
+    pub fn close(mut self) -> Result<(), HDF5WriterError> {
+        self.flush_quicklook()?;
+        if let Some(stacked) = &mut self.stacked {
+            stacked.flush()?;
+        }
         self.events_group
             .attr("min_event")?
-            .write_scalar(&(START_EVENT_NUMBER as u64))?;
+            .write_scalar(&self.first_get_event.unwrap_or(START_EVENT_NUMBER as u64))?;
         self.events_group
             .attr("min_get_ts")?
             .write_scalar(&self.first_timestamp)?;
-        // Check if FRIB & GET agree on event numbers
-        if self.last_frib_event != self.last_get_event {
-            spdlog::warn!("FRIB and GET do not agree on the number of events! FRIB saw {} events, while GET saw {} events", self.last_frib_event, self.last_get_event);
-            spdlog::info!(
-                "The max_event attribute of the event group will be set to the last GET event."
-            );
-        }
+        // Without GET events (i.e. an evt only merge), the events are the FRIB events
+        let max_event = if self.first_get_event.is_none() {
+            self.last_frib_event
+        } else {
+            // Check if FRIB & GET agree on event numbers
+            if self.last_frib_event != self.last_get_event {
+                spdlog::warn!("FRIB and GET do not agree on the number of events! FRIB saw {} events, while GET saw {} events", self.last_frib_event, self.last_get_event);
+                spdlog::info!(
+                    "The max_event attribute of the event group will be set to the last GET event."
+                );
+            }
+            self.last_get_event
+        };
         self.events_group
             .attr("max_event")?
-            .write_scalar(&self.last_get_event)?;
+            .write_scalar(&max_event)?;
         self.events_group
             .attr("max_get_ts")?
             .write_scalar(&self.last_timestamp)?;
         self.scalers_group
             .attr("min_event")?
-            .write_scalar(&START_EVENT_NUMBER)?;
+            .write_scalar(&(self.first_scaler_event.unwrap_or(START_EVENT_NUMBER as u64) as u32))?;
         self.scalers_group
             .attr("max_event")?
             .write_scalar(&self.last_scaler_event)?;
         spdlog::info!(
             "{} events written. Run lasted {} seconds.",
             self.last_get_event,
-            (self.last_timestamp - self.first_timestamp) / 100_000_000, // Time Stamp Clock is 100 MHz
+            self.last_timestamp.saturating_sub(self.first_timestamp) / 100_000_000, // Time Stamp Clock is 100 MHz
         );
+        if let Some(peds) = &self.pedestals {
+            if *peds.get_missing_count() > 0 {
+                spdlog::warn!(
+                    "{} pads were missing from the pedestal file, affecting {} traces. These traces had no pedestal subtracted.",
+                    peds.get_number_of_missing_pads(),
+                    peds.get_missing_count()
+                );
+            }
+        }
         Ok(())
     }
 
@@ -230,20 +1612,74 @@ impl HDFWriter {
         Ok(())
     }
 
+    /// Write a silicon event which was matched to no TPC event (see SiliconMatcher) to the orphan_silicon group
+    ///
+    /// The group is made for the first orphan, so files without orphans have no orphan_silicon group. Each orphan is a
+    /// dataset (event_#, numbered from 0 in the order the orphans are written) of its traces, like get_traces, with the
+    /// id and timestamp_other of the silicon event as attributes.
+    pub fn write_orphan_silicon(
+        &mut self,
+        event: Event,
+        orphan_counter: &u64,
+    ) -> Result<(), HDF5WriterError> {
+        let orphan_group = match self.file_handle.group(&self.layout.orphan_silicon) {
+            Ok(group) => group,
+            Err(_) => self.file_handle.create_group(&self.layout.orphan_silicon)?,
+        };
+        let id = event.event_id;
+        let tso = event.timestampother;
+        let (data_matrix, _) = event.convert_to_data_matrix(self.pedestals.as_mut());
+        let mut traces_builder = orphan_group.new_dataset_builder();
+        if let Some(level) = self.compression_level {
+            traces_builder = traces_builder.deflate(level);
+        }
+        self.bytes_written += dataset_bytes::<i16>(data_matrix.len());
+        let traces_dset = traces_builder
+            .with_data(&data_matrix)
+            .create(self.layout.event_name(orphan_counter).as_str())?;
+        traces_dset
+            .new_attr::<u32>()
+            .create("id")?
+            .write_scalar(&id)?;
+        traces_dset
+            .new_attr::<u64>()
+            .create("timestamp_other")?
+            .write_scalar(&tso)?;
+        write_description(
+            &traces_dset,
+            &format!(
+                "{},samples[{}]",
+                GET_TRACES_COLUMNS,
+                data_matrix.ncols() - NUMBER_OF_HARDWARE_COLUMNS
+            ),
+            "int16",
+        )?;
+        Ok(())
+    }
+
     /// Write scaler data from evt file
+    ///
+    /// The length of the scaler time window (duration_s) and the rate of each scaler (rate_hz) are written as attributes.
+    /// The rate of each scaler since the previous read, from the difference of the read timestamps, is written to a
+    /// second dataset (event_#_rates).
     pub fn write_frib_scalers(
         &mut self,
         scalers: ScalersItem,
         counter: &u64,
     ) -> Result<(), HDF5WriterError> {
+        if self.first_scaler_event.is_none() {
+            self.first_scaler_event = Some(*counter);
+        }
         if *counter > self.last_scaler_event {
             self.last_scaler_event = *counter;
         }
+        self.bytes_written += dataset_bytes::<u32>(scalers.data.len());
         let scaler_dset = self
             .scalers_group
             .new_dataset_builder()
             .with_data(&scalers.data)
-            .create(format!("event_{}", counter).as_str())?;
+            .create(self.layout.event_name(counter).as_str())?;
+        write_description(&scaler_dset, SCALERS_COLUMNS, "uint32")?;
 
         scaler_dset
             .new_attr::<u32>()
@@ -261,6 +1697,52 @@ impl HDFWriter {
             .new_attr::<u32>()
             .create("incremental")?
             .write_scalar(&scalers.incremental)?;
+        if let Some(duration_s) = scalers.get_duration_s(self.scaler_offset_divisor) {
+            scaler_dset
+                .new_attr::<f64>()
+                .create("duration_s")?
+                .write_scalar(&duration_s)?;
+        }
+        let rates = scalers.get_rates_hz(self.scaler_offset_divisor, &self.previous_scalers.data);
+        scaler_dset
+            .new_attr_builder()
+            .with_data(&rates)
+            .create("rate_hz")?;
+        if let Some(body_timestamp) = &scalers.body_timestamp {
+            scaler_dset
+                .new_attr::<u64>()
+                .create("body_timestamp")?
+                .write_scalar(body_timestamp)?;
+        }
+        let rates_since = scalers
+            .get_rates_since_hz(&self.previous_scalers.data, self.previous_scalers.timestamp);
+        self.bytes_written += dataset_bytes::<f64>(rates_since.len());
+        let rates_dset = self
+            .scalers_group
+            .new_dataset_builder()
+            .with_data(&rates_since)
+            .create(
+                format!(
+                    "{}{}",
+                    self.layout.event_name(counter),
+                    self.layout.scaler_rates_suffix
+                )
+                .as_str(),
+            )?;
+        write_description(&rates_dset, SCALERS_COLUMNS, "float64")?;
+        let interval_s = match self.previous_scalers.timestamp {
+            Some(previous) => scalers.timestamp.saturating_sub(previous),
+            None => 0,
+        };
+        rates_dset
+            .new_attr::<u32>()
+            .create("interval_s")?
+            .write_scalar(&interval_s)?;
+
+        self.previous_scalers.timestamp = Some(scalers.timestamp);
+        if !scalers.is_incremental() {
+            self.previous_scalers.data = scalers.data;
+        }
         Ok(())
     }
 
@@ -275,12 +1757,27 @@ impl HDFWriter {
             self.last_frib_event = *event_counter;
         }
 
-        let event_name = format!("event_{}", event_counter);
+        if let Some(stacked) = &mut self.stacked {
+            // The body timestamp is 0 for items without a body header
+            self.bytes_written += stacked.append_frib_physics(
+                [
+                    *event_counter,
+                    physics.event as u64,
+                    physics.timestamp as u64,
+                    physics.body_timestamp.unwrap_or(0),
+                    physics.coinc.coinc as u64,
+                ],
+                &physics.fadc.to_matrix(),
+            )?;
+            return Ok(());
+        }
+
+        let event_name = self.layout.event_name(event_counter);
         let event_group = match self.events_group.group(&event_name) {
             Ok(group) => group,
             Err(_) => self.events_group.create_group(&event_name)?,
         };
-        let physics_group = event_group.create_group(FRIB_PHYSICS_NAME)?;
+        let physics_group = event_group.create_group(&self.layout.frib_physics)?;
         physics_group
             .new_attr::<u32>()
             .create("id")?
@@ -289,23 +1786,545 @@ impl HDFWriter {
             .new_attr::<u32>()
             .create("timestamp")?
             .write_scalar(&physics.timestamp)?;
+        if let Some(body_timestamp) = &physics.body_timestamp {
+            physics_group
+                .new_attr::<u64>()
+                .create("body_timestamp")?
+                .write_scalar(body_timestamp)?;
+        }
+        if self.link_get_events {
+            let has_get_event = event_group.link_exists(&self.layout.get_traces);
+            physics_group
+                .new_attr::<u8>()
+                .create("has_get_event")?
+                .write_scalar(&(has_get_event as u8))?;
+            if has_get_event {
+                physics_group.link_soft(
+                    &format!(
+                        "/{}/{}/{}",
+                        self.layout.events, event_name, self.layout.get_traces
+                    ),
+                    &self.layout.get_traces,
+                )?;
+            }
+        }
         // write V977 data
-        physics_group
+        let coinc_dset = physics_group
             .new_dataset_builder()
             .with_data(&[physics.coinc.coinc])
-            .create("977")?;
+            .create(self.layout.frib_coinc.as_str())?;
+        write_description(&coinc_dset, FRIB_COINC_COLUMNS, "uint16")?;
+        coinc_dset
+            .new_attr::<VarLenUnicode>()
+            .create("encoding")?
+            .write_scalar(&VarLenUnicode::from_str(FRIB_COINC_ENCODING).unwrap())?;
         // write SIS3300 data
-        let mut data_matrix =
-            Array2::<u16>::zeros([physics.fadc.samples, physics.fadc.traces.len()]);
-        for i in 0..8 {
-            for j in 0..physics.fadc.samples {
-                data_matrix[[j, i]] = physics.fadc.traces[i][j];
-            }
+        let (data_matrix, columns, encoding) = match self.fadc_orientation {
+            FadcOrientation::SampleMajor => (
+                physics.fadc.to_matrix(),
+                FRIB_FADC_COLUMNS,
+                FRIB_FADC_ENCODING,
+            ),
+            FadcOrientation::ChannelMajor => (
+                physics.fadc.to_channel_matrix(),
+                FRIB_FADC_CHANNEL_MAJOR_COLUMNS,
+                FRIB_FADC_CHANNEL_MAJOR_ENCODING,
+            ),
+        };
+        // The SIS3300 traces, and the single V977 coincidence register
+        self.bytes_written += dataset_bytes::<u16>(data_matrix.len() + 1);
+        // Pruned by the number of samples, whatever the orientation
+        if !self.prune_if_empty(&physics_group, &self.layout.frib_fadc, physics.fadc.samples)? {
+            let fadc_dset = physics_group
+                .new_dataset_builder()
+                .with_data(&data_matrix)
+                .create(self.layout.frib_fadc.as_str())?;
+            write_description(&fadc_dset, columns, "uint16")?;
+            fadc_dset
+                .new_attr::<VarLenUnicode>()
+                .create("encoding")?
+                .write_scalar(
+                    &VarLenUnicode::from_str(&format!(
+                        "SIS3300 {}-bit samples, {}",
+                        physics.fadc.bits, encoding
+                    ))
+                    .unwrap(),
+                )?;
+        }
+        if !physics.fragments.is_empty() {
+            let fragment_bytes: Vec<u8> = physics
+                .fragments
+                .iter()
+                .flat_map(|fragment| fragment.to_bytes())
+                .collect();
+            self.bytes_written += dataset_bytes::<u8>(fragment_bytes.len());
+            let fragments_dset = physics_group
+                .new_dataset_builder()
+                .with_data(&fragment_bytes)
+                .create(self.layout.frib_fragments.as_str())?;
+            write_description(&fragments_dset, FRIB_FRAGMENTS_COLUMNS, "uint8")?;
+            fragments_dset
+                .new_attr::<VarLenUnicode>()
+                .create("encoding")?
+                .write_scalar(&VarLenUnicode::from_str(FRIB_FRAGMENTS_ENCODING).unwrap())?;
         }
-        physics_group
-            .new_dataset_builder()
-            .with_data(&data_matrix)
-            .create("1903")?;
         Ok(())
     }
 }
+
+//Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DuplicateBucketPolicy;
+    use crate::graw_frame::{FrameProvenance, GrawData, GrawFrame};
+
+    #[test]
+    fn test_custom_layout() {
+        let dir = std::env::temp_dir().join(format!("attpc_merger_layout_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("run_0001.h5");
+        let layout = HdfLayout {
+            events: String::from("my_events"),
+            scalers: String::from("my_scalers"),
+            event_prefix: String::from("evt"),
+            ..Default::default()
+        };
+        let mut writer = HDFWriter::new(
+            &path,
+            None,
+            &HdfWriterOptions {
+                write_quality_flags: true,
+                layout,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let scalers = ScalersItem {
+            data: vec![1],
+            ..Default::default()
+        };
+        writer.write_frib_scalers(scalers, &0).unwrap();
+        writer.close().unwrap();
+
+        let file = File::open(&path).unwrap();
+        assert!(file.group("my_events").is_ok());
+        assert!(file.group("events").is_err());
+        assert!(file.group("my_scalers").unwrap().dataset("evt0").is_ok());
+        drop(file);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_pad_peaks() {
+        // cobo, asad, aget, channel, pad, then 4 samples
+        let data_matrix = ndarray::array![
+            [0, 0, 0, 0, 2817, 5, 40, 12, 40],
+            [0, 0, 0, 1, 3485, -3, -1, -7, -2],
+        ];
+        let peaks = make_pad_peaks(&data_matrix);
+        assert_eq!(peaks, ndarray::array![[2817, 40, 1], [3485, -1, 1]]);
+        assert_eq!(make_pad_peaks(&Array2::zeros([0, 9])).nrows(), 0);
+    }
+
+    /// The options of the writers of the tests, which write the quality flags
+    fn quality_flags_options() -> HdfWriterOptions {
+        HdfWriterOptions {
+            write_quality_flags: true,
+            ..Default::default()
+        }
+    }
+
+    /// An event with a single pad (pad 9908 in the default map) at the given timestamp
+    fn make_event(event_id: u32, timestamp: u64) -> Event {
+        let mut frame = GrawFrame::new();
+        frame.header.event_id = event_id;
+        frame.header.event_time = timestamp;
+        frame.header.cobo_id = 7;
+        frame.header.asad_id = 2;
+        frame.data.push(GrawData {
+            aget_id: 1,
+            channel: 10,
+            time_bucket_id: 0,
+            sample: 10,
+        });
+        Event::new(
+            &PadMap::new(None).unwrap(),
+            &vec![frame],
+            512,
+            DuplicateBucketPolicy::Overwrite,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_open_append() {
+        let dir = std::env::temp_dir().join(format!("attpc_merger_append_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("run_0001.h5");
+        let layout = HdfLayout::default();
+        let mut writer = HDFWriter::new(&path, None, &quality_flags_options()).unwrap();
+        for event_id in 0..2 {
+            writer
+                .write_event(
+                    make_event(event_id, 1000 * (event_id as u64 + 1)),
+                    &(event_id as u64),
+                )
+                .unwrap();
+        }
+        writer.close().unwrap();
+
+        let mut writer = HDFWriter::open_append(&path, None, &quality_flags_options()).unwrap();
+        assert_eq!(*writer.get_last_get_event(), 1);
+        let next_event = writer.get_last_get_event() + 1;
+        writer
+            .write_event(make_event(2, 3000), &next_event)
+            .unwrap();
+        writer.close().unwrap();
+
+        assert!(HDFWriter::verify(&path, &layout, &2).unwrap());
+        let file = File::open(&path).unwrap();
+        let events = file.group(&layout.events).unwrap();
+        assert_eq!(
+            events
+                .attr("min_event")
+                .unwrap()
+                .read_scalar::<u64>()
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            events
+                .attr("min_get_ts")
+                .unwrap()
+                .read_scalar::<u64>()
+                .unwrap(),
+            1000
+        );
+        assert_eq!(
+            events
+                .attr("max_get_ts")
+                .unwrap()
+                .read_scalar::<u64>()
+                .unwrap(),
+            3000
+        );
+        assert_eq!(events.dataset(&layout.quicklook).unwrap().shape()[0], 3);
+        drop(file);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_provenance() {
+        let dir =
+            std::env::temp_dir().join(format!("attpc_merger_provenance_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("run_0001.h5");
+        let layout = HdfLayout::default();
+        let mut event = make_event(0, 1000);
+        event.provenance = vec![
+            FrameProvenance {
+                file_path: PathBuf::from("/data/run_0001/mm7/CoBo7_AsAd2_0000.graw"),
+                offset: 0,
+                cobo_id: 7,
+                asad_id: 2,
+            },
+            FrameProvenance {
+                file_path: PathBuf::from("/data/run_0001/mm7/CoBo7_AsAd3_0001.graw"),
+                offset: 1024,
+                cobo_id: 7,
+                asad_id: 3,
+            },
+        ];
+        let mut writer = HDFWriter::new(&path, None, &HdfWriterOptions::default()).unwrap();
+        writer.write_event(event, &0).unwrap();
+        writer.close().unwrap();
+
+        let file = File::open(&path).unwrap();
+        let event_group = file
+            .group(&layout.events)
+            .unwrap()
+            .group(&layout.event_name(&0))
+            .unwrap();
+        let provenance = event_group.dataset(&layout.get_provenance).unwrap();
+        assert_eq!(
+            provenance.read_2d::<u64>().unwrap(),
+            ndarray::array![[0, 7, 2], [1024, 7, 3]]
+        );
+        let files = event_group.dataset(&layout.get_provenance_files).unwrap();
+        let files: Vec<String> = files
+            .read_1d::<VarLenUnicode>()
+            .unwrap()
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                "/data/run_0001/mm7/CoBo7_AsAd2_0000.graw",
+                "/data/run_0001/mm7/CoBo7_AsAd3_0001.graw"
+            ]
+        );
+        let read_description = |dset: &hdf5::Dataset, attr: &str| {
+            dset.attr(attr)
+                .unwrap()
+                .read_scalar::<VarLenUnicode>()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(
+            read_description(&provenance, "columns"),
+            GET_PROVENANCE_COLUMNS
+        );
+        assert_eq!(read_description(&provenance, "dtype"), "uint64");
+        assert_eq!(
+            read_description(
+                &event_group.dataset(&layout.get_provenance_files).unwrap(),
+                "columns"
+            ),
+            GET_PROVENANCE_FILES_COLUMNS
+        );
+        drop(file);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_event_naming() {
+        let dir = std::env::temp_dir().join(format!("attpc_merger_naming_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("run_0001.h5");
+        let mut writer = HDFWriter::new(&path, None, &quality_flags_options()).unwrap();
+        writer.set_event_naming(EventNaming::GetEventId).unwrap();
+        writer.write_event(make_event(7, 1000), &7).unwrap();
+        writer.close().unwrap();
+
+        // Appending with the same naming is fine, but the file can not be given another naming
+        let mut writer = HDFWriter::open_append(&path, None, &quality_flags_options()).unwrap();
+        writer.set_event_naming(EventNaming::GetEventId).unwrap();
+        assert!(matches!(
+            writer.set_event_naming(EventNaming::Ordinal),
+            Err(HDF5WriterError::MixedEventNaming(_, _))
+        ));
+        writer.close().unwrap();
+
+        let file = File::open(&path).unwrap();
+        let events = file.group("events").unwrap();
+        let naming = events.attr("naming").unwrap();
+        assert_eq!(
+            naming.read_scalar::<VarLenUnicode>().unwrap().as_str(),
+            "GetEventId"
+        );
+        assert_eq!(
+            events
+                .attr("min_event")
+                .unwrap()
+                .read_scalar::<u64>()
+                .unwrap(),
+            7
+        );
+        drop(file);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_prune_empty_datasets() {
+        let dir = std::env::temp_dir().join(format!("attpc_merger_prune_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("run_0001.h5");
+        let mut writer = HDFWriter::new(&path, None, &quality_flags_options()).unwrap();
+        writer.set_compute_peaks(true);
+        writer.set_prune_empty_datasets(true).unwrap();
+        // An event whose only frame has no data has no traces
+        let mut empty_frame = GrawFrame::new();
+        empty_frame.header.event_id = 0;
+        let empty_event = Event::new(
+            &PadMap::new(None).unwrap(),
+            &vec![empty_frame],
+            512,
+            DuplicateBucketPolicy::Overwrite,
+        )
+        .unwrap();
+        writer.write_event(empty_event, &0).unwrap();
+        writer.write_event(make_event(1, 1000), &1).unwrap();
+        writer.close().unwrap();
+
+        let file = File::open(&path).unwrap();
+        let events = file.group("events").unwrap();
+        let read_flag = |group: &hdf5::Group, name: &str| {
+            group.attr(name).unwrap().read_scalar::<u8>().unwrap()
+        };
+        assert_eq!(read_flag(&events, "prune_empty_datasets"), 1);
+        let empty = events.group("event_0").unwrap();
+        assert_eq!(empty.dataset("get_traces").unwrap().shape()[0], 0);
+        assert!(empty.dataset("pad_peaks").is_err());
+        assert!(empty.dataset("get_traces_flags").is_err());
+        assert_eq!(read_flag(&empty, "has_pad_peaks"), 0);
+        assert_eq!(read_flag(&empty, "has_get_traces_flags"), 0);
+        let full = events.group("event_1").unwrap();
+        assert!(full.dataset("pad_peaks").is_ok());
+        assert!(full.dataset("get_traces_flags").is_ok());
+        assert!(full.attr("has_pad_peaks").is_err());
+        drop(file);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_fadc_orientation() {
+        let dir = std::env::temp_dir().join(format!(
+            "attpc_merger_fadc_orientation_{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("run_0001.h5");
+        let mut writer = HDFWriter::new(&path, None, &quality_flags_options()).unwrap();
+        writer
+            .set_fadc_orientation(FadcOrientation::ChannelMajor)
+            .unwrap();
+        let mut physics = PhysicsItem::new();
+        physics.fadc.samples = 3;
+        physics.fadc.traces = (0..8).map(|channel| vec![channel, 10, 20]).collect();
+        writer.write_frib_physics(physics, &0).unwrap();
+        writer.close().unwrap();
+
+        let file = File::open(&path).unwrap();
+        let events = file.group("events").unwrap();
+        let orientation = events
+            .attr("fadc_orientation")
+            .unwrap()
+            .read_scalar::<VarLenUnicode>()
+            .unwrap();
+        assert_eq!(orientation.as_str(), "ChannelMajor");
+        let fadc = events
+            .group("event_0/frib_physics")
+            .unwrap()
+            .dataset("1903")
+            .unwrap();
+        let samples = fadc.read_2d::<u16>().unwrap();
+        // One row per channel, like get_traces
+        assert_eq!(samples.shape(), [8, 3]);
+        assert_eq!(samples[[5, 0]], 5);
+        assert_eq!(samples[[5, 2]], 20);
+        let columns = fadc
+            .attr("columns")
+            .unwrap()
+            .read_scalar::<VarLenUnicode>()
+            .unwrap();
+        assert_eq!(columns.as_str(), "sample");
+        drop(file);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_link_get_events() {
+        let dir = std::env::temp_dir().join(format!(
+            "attpc_merger_link_get_events_{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("run_0001.h5");
+        let mut writer = HDFWriter::new(&path, None, &quality_flags_options()).unwrap();
+        writer.set_link_get_events(true);
+        writer.write_event(make_event(1, 100), &0).unwrap();
+        // Event 1 has no GET event
+        for event_counter in 0..2 {
+            writer
+                .write_frib_physics(PhysicsItem::new(), &event_counter)
+                .unwrap();
+        }
+        writer.close().unwrap();
+
+        let file = File::open(&path).unwrap();
+        let events = file.group("events").unwrap();
+        let linked = events.group("event_0/frib_physics").unwrap();
+        assert_eq!(
+            linked
+                .attr("has_get_event")
+                .unwrap()
+                .read_scalar::<u8>()
+                .unwrap(),
+            1
+        );
+        let traces = linked.dataset("get_traces").unwrap();
+        assert_eq!(traces.attr("id").unwrap().read_scalar::<u32>().unwrap(), 1);
+        let unlinked = events.group("event_1/frib_physics").unwrap();
+        assert_eq!(
+            unlinked
+                .attr("has_get_event")
+                .unwrap()
+                .read_scalar::<u8>()
+                .unwrap(),
+            0
+        );
+        assert!(!unlinked.link_exists("get_traces"));
+        drop(file);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_stacked_layout() {
+        let dir = std::env::temp_dir().join(format!("attpc_merger_stacked_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("run_0001.h5");
+        let layout = HdfLayout::default();
+        let mut writer = HDFWriter::new(&path, None, &quality_flags_options()).unwrap();
+        writer.set_event_layout(EventLayout::Stacked).unwrap();
+        for event_id in 0..2 {
+            writer
+                .write_event(
+                    make_event(event_id, 1000 * (event_id as u64 + 1)),
+                    &(event_id as u64),
+                )
+                .unwrap();
+        }
+        let mut physics = PhysicsItem::new();
+        physics.event = 1;
+        physics.fadc.samples = 3;
+        physics.fadc.traces = vec![vec![5; 3]; 8];
+        writer.write_frib_physics(physics, &1).unwrap();
+        writer.close().unwrap();
+
+        // Appending continues the stacked datasets, and the file can not be given another layout
+        let mut writer = HDFWriter::open_append(&path, None, &quality_flags_options()).unwrap();
+        assert_eq!(*writer.get_last_get_event(), 1);
+        assert!(matches!(
+            writer.set_event_layout(EventLayout::Groups),
+            Err(HDF5WriterError::MixedEventLayout(_, _))
+        ));
+        writer.write_event(make_event(2, 3000), &2).unwrap();
+        writer.close().unwrap();
+
+        assert!(HDFWriter::verify(&path, &layout, &2).unwrap());
+        let file = File::open(&path).unwrap();
+        let events = file.group(&layout.events).unwrap();
+        assert!(events.group(&layout.event_name(&0)).is_err());
+        let index = events
+            .dataset(&layout.get_index)
+            .unwrap()
+            .read_2d::<u64>()
+            .unwrap();
+        assert_eq!(index.nrows(), 3);
+        // event, id, timestamp, ..., first_row, n_rows: one pad per event
+        assert_eq!(index[[2, 0]], 2);
+        assert_eq!(index[[2, 2]], 3000);
+        assert_eq!(index[[2, 5]], 2);
+        assert_eq!(index[[2, 6]], 1);
+        let traces = events.dataset(&layout.get_traces).unwrap();
+        assert_eq!(traces.shape(), vec![3, NUMBER_OF_HARDWARE_COLUMNS + 512]);
+        assert_eq!(
+            events.dataset(&layout.get_traces_flags).unwrap().shape()[0],
+            3
+        );
+        let physics_index = events
+            .dataset(&layout.frib_physics_index)
+            .unwrap()
+            .read_2d::<u64>()
+            .unwrap();
+        assert_eq!(physics_index.row(0).to_vec(), vec![1, 1, 0, 0, 0, 0, 3]);
+        assert_eq!(
+            events.dataset(&layout.frib_fadc).unwrap().shape(),
+            vec![3, NUMBER_OF_FADC_CHANNELS]
+        );
+        drop(file);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -48,15 +48,23 @@
 //!
 //! - GRAW Files from Online Source Checkbox: If checked, will try to access GRAW files from the AT-TPC DAQ itself. Should typically be unchecked. Only for use when running an experiment.
 //!   - Experiment: Only available when online checkbox is checked. Name of the experiment, matching the AT-TPC DAQ experiment name.
+//!   - Online address (host:port): Only available when online checkbox is checked. Optional address of the GET DAQ. If given, GRAW frames are streamed directly from the DAQ over TCP rather than read from files. Leave empty to read files.
+//! - Follow Files During Data Taking Checkbox: If checked, the merger waits for the DAQ to write more data instead of stopping at the end of the files. Should typically be unchecked. Only for use when running an experiment.
 //! - GRAW directory: Specifies the full-path to a directory which contains the AT-TPC GETDAQ .graw structure (i.e. contains subdirectories of the run_# format). If online is checked, this field is not available.
 //! - EVT directory: Specifies the full-path to a directory which contains the FRIBDAQ EVT structure (i.e. contains subdirectories of the run# format)
+//!   - Require EVT data: If checked, a run fails if its EVT data is missing or cannot be read. If unchecked, the run is merged without FRIB data and a warning is written to the log. Check this for experiments where the FRIB data is mandatory.
 //! - HDF5 directory: Specifies the full-path to a directory to which merged HDF5 (.h5) files will be written
 //! - Pad map: Specifies the full path to a CSV file which contains the mapping information for AT-TPC pads and electronics
+//! - Pedestals (Optional): Specifies the full path to a CSV file (columns pad,pedestal_mean) which contains the per-pad pedestals to subtract from the traces. If set to None, no subtraction is performed.
 //! - First Run Number: The starting run number (inclusive)
 //! - Last Run Number: The ending run number (inclusive)
+//! - Number of Time Buckets: The number of time buckets in each trace, matching the CoBo configuration (typically 512, but 256 or 1024 are also used). Traces in the output have this many samples. If a frame contains a time bucket beyond this number, the run fails with an error.
+//! - After merge: What to do with the source .graw and .evt files of a run once it has been merged. None (the default) leaves them in place, Delete removes them, and Move to... moves them into the chosen directory, keeping the run directory structure. The action must be confirmed with the red checkbox, is only applied after the merged file has been closed and reopened successfully, and is skipped if there was any error in the run. It is never applied to online data.
 //!
 //! Configurations can be saved using File->Save and loaded using File->Open
 //!
+//! When Run is clicked, the configuration is checked (paths exist, the run range and number of workers make sense, the pad map and pedestals can be read). If there are any issues, processing is not started; the offending fields are highlighted in red and the issues are listed below the Run button. The CLI performs the same checks after loading the configuration, and exits with an error if there are any issues.
+//!
 //! A configuration file saved using the UI is compatible with the CLI and vice-versa.
 //!
 //! ## Output
@@ -69,28 +77,96 @@
 //!
 //! ```text
 //! run_0001.h5
-//! |---- events - min_event, max_event, min_get_ts, max_get_ts, frib_run, frib_start, frib_stop, frib_time, version
+//! |---- events - min_event, max_event, min_get_ts, max_get_ts, frib_run, frib_start, frib_stop, frib_time, time_buckets, version, format_major, format_minor, naming, layout, prune_empty_datasets, fadc_orientation, pedestal_file (optional), cobos, part (optional), continues_from (optional), runs database columns (optional)
+//! |    |---- quicklook(dset) - one row per event: event, npads, total_charge
 //! |    |---- event_#
-//! |    |    |---- get_traces(dset) - id, timestamp, timestamp_other
-//! |    |    |---- frib_physics - id, timestamp
+//! |    |    |---- get_traces(dset) - id, timestamp, timestamp_other, raw_timestamp (optional), sca_offsets (optional)
+//! |    |    |---- get_traces_flags(dset) (optional)
+//! |    |    |---- get_aget_multiplicity(dset) (optional)
+//! |    |    |---- get_provenance(dset) (optional)
+//! |    |    |---- get_provenance_files(dset) (optional)
+//! |    |    |---- get_cobo_timestamps(dset) (optional)
+//! |    |    |---- get_frame_headers(dset) (optional)
+//! |    |    |---- get_readout(dset) (optional)
+//! |    |    |---- pad_peaks(dset) (optional) - one row per trace: pad, max_sample, max_bucket
+//! |    |    |---- frib_physics - id, timestamp, body_timestamp (optional), has_get_event (optional)
+//! |    |    |    |---- get_traces(soft link) (optional) - to the get_traces of the event
 //! |    |    |    |---- 907(dset)
 //! |    |    |    |---- 1903(dset)
-//! |    scalers - min_event, max_event
-//! |    |---- event_#(dset) - start_offset, stop_offset, timestamp, incremental
+//! |    |    |    |---- fragments(dset) (optional)
+//! |    scalers - min_event, max_event, offset_divisor
+//! |    |---- event_#(dset) - start_offset, stop_offset, timestamp, incremental, duration_s, rate_hz, body_timestamp (optional)
+//! |    |---- event_#_rates(dset) - interval_s
+//! |---- orphan_silicon (optional)
+//! |    |---- event_#(dset) - id, timestamp_other
+//! |---- config
+//! |    |---- pad_map(dset) - one row per channel: cobo, asad, aget, channel, pad
+//! ```
+//!
+//! The `body_timestamp` attributes are only written when FRIBDAQ recorded the items with body headers (event builder timestamps enabled). They contain the 64-bit timestamp of the body header.
+//!
+//! From format 1.2 on, the datasets of an event with no rows (i.e. the `1903` dataset of a physics event without SIS3300 data, or the flags and peaks of an event without traces) are left out when `prune_empty_datasets` is set, which is recorded in the `prune_empty_datasets` attribute of the events group. Each dataset left out is marked by a `has_<dataset>` attribute of 0 on its group (i.e. `has_1903`), so readers can tell an empty dataset apart from one which was not requested. The `get_traces` and `907` datasets are always written.
+//!
+//! From format 1.3 on, the orientation of the `1903` datasets of the events is recorded in the `fadc_orientation` attribute of the events group: `SampleMajor` (the default) for one row per sample and one column per channel, or `ChannelMajor` for one row per channel and one column per sample, like `get_traces`. The `columns` and `encoding` attributes of each `1903` dataset describe its orientation as well.
+//!
+//! From format 1.4 on, the silicon events which matched no TPC event when `si_merge_by_timestamp` is set are written to the `orphan_silicon` group, one `event_#` dataset of traces per event (numbered in the order they were let go), with the `id` and `timestamp_other` of the silicon event as attributes. The group is only made when there is an orphan.
+//!
+//! From format 1.5 on, the SCA rotation offsets applied to the traces of an event when `correct_sca_rotation` is set are written to the `sca_offsets` attribute of `get_traces`, one row per AGET: cobo, asad, aget, and the offset. The time bucket t as read out was moved to (t - offset) mod 512. The attribute is not written when the rotation is not corrected, or with the stacked event layout.
+//!
+//! From format 1.6 on, the `frib_physics` group of each physics event can be linked to the GET event of the same number when `link_get_events` is set. The group then has a `has_get_event` attribute, which is 1 if the event has GET traces and 0 if it does not, and a `get_traces` soft link to the `get_traces` dataset of its event when it is 1. Without `link_get_events`, the pairing is only given by the event number.
+//!
+//! The `quicklook` dataset is a cheap summary of the event activity, for histogramming without reading the traces. It has one row per event with the event number, the number of pads hit, and the total charge (the sum over the pads of the maximum sample of the trace, after pedestal subtraction).
+//!
+//! The `config` group records the pad map used to build the events, with one row per channel. `PadMap::from_hdf5` reads it back, so that a run can be re-merged or cross-checked with exactly the same mapping as an earlier file.
+//!
+//! The `event_layout` field `Stacked` replaces the `event_#` groups with datasets of the events group which every event is appended to, and records `Stacked` in the `layout` attribute of the events group:
+//!
+//! ```text
+//! run_0001.h5
+//! |---- events - ..., layout
+//! |    |---- quicklook(dset) - one row per event: event, npads, total_charge
+//! |    |---- get_index(dset) - one row per GET event: event, id, timestamp, timestamp_other, raw_timestamp, first_row, n_rows, first_aget_row, n_aget_rows
+//! |    |---- get_traces(dset) - the traces of every event
+//! |    |---- get_traces_flags(dset) (optional) - same rows as get_traces
+//! |    |---- get_aget_multiplicity(dset) (optional)
+//! |    |---- frib_physics_index(dset) - one row per physics event: event, id, timestamp, body_timestamp, coincidence_register, first_row, n_rows
+//! |    |---- 1903(dset) - the FADC samples of every physics event
 //! ```
+//!
+//! The traces of an event are rows `first_row` to `first_row + n_rows` of `get_traces` (and `get_traces_flags`), and its AGET multiplicities rows `first_aget_row` to `first_aget_row + n_aget_rows` of `get_aget_multiplicity`. The FADC samples of a physics event are rows `first_row` to `first_row + n_rows` of `1903`. The `raw_timestamp` is the `timestamp` when the timestamp was not corrected, and the `body_timestamp` is 0 for items without a body header.
+//!
+//! Every dataset has a `columns` attribute listing the meaning of its columns (for example `cobo,asad,aget,channel,pad,samples[512]` for `get_traces`) and a `dtype` attribute giving its data type (for example `int16`). The datasets of `frib_physics` also have an `encoding` attribute describing how the module data is laid out.
 pub mod asad_stack;
+pub mod checksum;
 pub mod config;
 pub mod constants;
 pub mod error;
 pub mod event;
 pub mod event_builder;
+pub mod event_hook;
+pub mod event_sink;
 pub mod evt_file;
 pub mod evt_stack;
+pub mod file_copy;
+pub mod format;
+pub mod frame_spill;
+pub mod frib_builder;
 pub mod graw_file;
 pub mod graw_frame;
+pub mod graw_source;
 pub mod hdf_writer;
+pub mod log_format;
 pub mod merger;
 pub mod pad_map;
+pub mod pedestals;
 pub mod process;
+pub mod retry;
 pub mod ring_item;
+pub mod run_db;
+pub mod silicon_matcher;
+pub mod stop_flag;
+#[cfg(any(test, feature = "testing"))]
+pub mod test_data;
+#[cfg(feature = "s3")]
+pub mod upload;
 pub mod worker_status;
@@ -0,0 +1,201 @@
+//! Machine readable log output for the merger applications.
+//!
+//! The default logs of attpc_merger are written for humans. For automated monitoring, the applications can also
+//! write a JSON-lines log, where each record is one JSON object:
+//!
+//! ```json
+//! {"timestamp":"2024-06-01T12:00:00.000+00:00","level":"info","thread":1234,"target":"libattpc_merger::process","run":12,"message":"Processing run 12..."}
+//! ```
+//!
+//! The target is the module which logged the record (empty if spdlog was built without source locations). The run
+//! is the run being processed by the thread which logged the record, or null if the thread is not processing a run.
+//! The human readable format (see make_text_formatter) tags the records of a run with the run as well.
+use std::cell::Cell;
+use std::fmt::Write;
+use std::str::FromStr;
+
+use spdlog::formatter::{pattern, Formatter, Pattern, PatternContext, PatternFormatter};
+use spdlog::{Record, StringBuf};
+
+thread_local! {
+    static CURRENT_RUN: Cell<Option<i32>> = const { Cell::new(None) };
+}
+
+/// The start of the log message giving the time spent in each stage of merging a run, so that applications can pick it out
+pub const STAGE_TIMING_MESSAGE: &str = "Stage timing of run";
+
+/// Set the run being processed by this thread, which is added to its log records
+pub fn set_log_run(run: Option<i32>) {
+    CURRENT_RUN.with(|current| current.set(run));
+}
+
+/// Write the string to dest with the JSON string escapes applied
+fn escape_json(value: &str, dest: &mut StringBuf) -> std::fmt::Result {
+    for c in value.chars() {
+        match c {
+            '"' => dest.write_str("\\\"")?,
+            '\\' => dest.write_str("\\\\")?,
+            '\n' => dest.write_str("\\n")?,
+            '\r' => dest.write_str("\\r")?,
+            '\t' => dest.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(dest, "\\u{:04x}", c as u32)?,
+            c => dest.write_char(c)?,
+        }
+    }
+    Ok(())
+}
+
+/// The message of the record, escaped to be a JSON string
+#[derive(Debug, Clone, Default)]
+struct JsonPayload;
+
+impl Pattern for JsonPayload {
+    fn format(
+        &self,
+        record: &Record,
+        dest: &mut StringBuf,
+        _ctx: &mut PatternContext,
+    ) -> spdlog::Result<()> {
+        escape_json(record.payload(), dest).map_err(spdlog::Error::FormatRecord)
+    }
+}
+
+/// The run being processed by the thread, or null
+#[derive(Debug, Clone, Default)]
+struct RunNumber;
+
+impl Pattern for RunNumber {
+    fn format(
+        &self,
+        _record: &Record,
+        dest: &mut StringBuf,
+        _ctx: &mut PatternContext,
+    ) -> spdlog::Result<()> {
+        match CURRENT_RUN.with(|current| current.get()) {
+            Some(run) => write!(dest, "{run}"),
+            None => dest.write_str("null"),
+        }
+        .map_err(spdlog::Error::FormatRecord)
+    }
+}
+
+/// The run being processed by the thread as a tag of a human readable record, or nothing
+#[derive(Debug, Clone, Default)]
+struct RunTag;
+
+impl Pattern for RunTag {
+    fn format(
+        &self,
+        _record: &Record,
+        dest: &mut StringBuf,
+        _ctx: &mut PatternContext,
+    ) -> spdlog::Result<()> {
+        match CURRENT_RUN.with(|current| current.get()) {
+            Some(run) => write!(dest, " - [run: {run}]"),
+            None => Ok(()),
+        }
+        .map_err(spdlog::Error::FormatRecord)
+    }
+}
+
+/// The format of a log file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Text, // for humans, see make_text_formatter
+    Json, // JSON lines, see make_json_formatter
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("Unknown log format {s}, expected text or json")),
+        }
+    }
+}
+
+/// Make a formatter which writes each record as a line of JSON
+pub fn make_json_formatter() -> Box<dyn Formatter> {
+    Box::new(PatternFormatter::new(pattern!(
+        "{{\"timestamp\":\"{date}T{time}.{millisecond}{tz_offset}\",\"level\":\"{level}\",\"thread\":{tid},\"target\":\"{module_path}\",\"run\":{$run},\"message\":\"{$json_payload}\"}}{eol}",
+        {$run} => RunNumber::default,
+        {$json_payload} => JsonPayload::default,
+    )))
+}
+
+/// Make a formatter which writes each record as a human readable line, tagged with the run of the thread if any
+pub fn make_text_formatter() -> Box<dyn Formatter> {
+    Box::new(PatternFormatter::new(pattern!(
+        "[{date_short} {time_short}] - [thread: {tid}]{$run_tag} - [{^{level}}] - {payload}{eol}",
+        {$run_tag} => RunTag::default,
+    )))
+}
+
+/// Make the formatter of a log file of the given format
+pub fn make_formatter(format: LogFormat) -> Box<dyn Formatter> {
+    match format {
+        LogFormat::Text => make_text_formatter(),
+        LogFormat::Json => make_json_formatter(),
+    }
+}
+
+//Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_json() {
+        let mut dest = StringBuf::new();
+        escape_json("Error: \"bad\"\\\nSkipping\t\u{1}", &mut dest).unwrap();
+        assert_eq!(dest, "Error: \\\"bad\\\"\\\\\\nSkipping\\t\\u0001");
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let dir =
+            std::env::temp_dir().join(format!("attpc_merger_json_log_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("merger.jsonl");
+        let sink = std::sync::Arc::new(
+            spdlog::sink::FileSink::builder()
+                .path(&path)
+                .formatter(make_formatter("json".parse().unwrap()))
+                .truncate(true)
+                .build()
+                .unwrap(),
+        );
+        let logger = spdlog::Logger::builder()
+            .flush_level_filter(spdlog::LevelFilter::All)
+            .sink(sink)
+            .build()
+            .unwrap();
+        spdlog::info!(logger: logger, "Starting");
+        set_log_run(Some(12));
+        spdlog::warn!(logger: logger, "Run \"12\" has\na gap");
+        set_log_run(None);
+        logger.flush();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["level"], "info");
+        assert!(lines[0]["run"].is_null());
+        assert!(lines[0]["thread"].is_u64());
+        assert!(lines[0]["target"].is_string());
+        assert_eq!(lines[0]["message"], "Starting");
+        assert_eq!(lines[1]["level"], "warn");
+        assert_eq!(lines[1]["run"], 12);
+        assert_eq!(lines[1]["message"], "Run \"12\" has\na gap");
+        assert_eq!("TEXT".parse::<LogFormat>().unwrap(), LogFormat::Text);
+        assert!("yaml".parse::<LogFormat>().is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
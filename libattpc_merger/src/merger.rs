@@ -1,44 +1,112 @@
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 
 use super::constants::{NUMBER_OF_ASADS, NUMBER_OF_COBOS};
-use super::error::AsadStackError;
+use super::error::{AsadStackError, GrawSourceError};
 
-use super::asad_stack::AsadStack;
-use super::config::Config;
+use super::asad_stack::{AsadStack, ChunkGap};
+use super::config::{BadDataPolicy, Config};
 use super::error::MergerError;
-use super::graw_frame::GrawFrame;
+use super::graw_frame::{BadDataCounts, FrameMetadata, FrameTypeCounts, GrawFrame};
+use super::graw_source::{GrawSource, TcpGrawSource};
+
+/// What is known about a source of the Merger before reading it, see Merger::peek_sources
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourcePeek {
+    pub cobo: Option<u8>, // None for frames received over TCP
+    pub asad: Option<u8>,
+    pub size_bytes: u64,
+    pub first_event: Option<FrameMetadata>, // the event id and time of the first frame, if the source has data yet
+}
 
 /// The object which merges all of the data from individual .graw files into a single data stream.
 ///
-/// Merger essentially performs a merge-sort operation on the data files, taking all of the separate
+/// Merger essentially performs a merge-sort operation on the data sources, taking all of the separate
 /// data from the .graw files and zipping them into a single data stream which is sorted in time.
 /// Currently uses EventID to decide the time of a frame, not the timestamp.
+///
+/// The frames of one CoBo can be merged as a separate stream (see set_split_cobo), for a CoBo on its own trigger
+/// whose event ids drift from those of the others.
+///
+/// If the config is online and an online address is given, the frames are instead received from the DAQ
+/// over a TCP connection.
 #[derive(Debug)]
 pub struct Merger {
-    file_stacks: Vec<AsadStack>,
+    sources: Vec<GrawSource>,
     total_data_size_bytes: u64,
+    frame_type_counts: BTreeMap<(u8, u8), FrameTypeCounts>, // frames read from each (CoBo, AsAd)
+    chunk_gaps: Vec<((u8, u8), ChunkGap)>, // chunks missing from the files of each (CoBo, AsAd)
+    split_cobo: Option<u8>,                // the CoBo merged as a separate stream
+    split_time_offset: i64, // added to the event times of the split CoBo to match the others
+    bad_data: BadDataCounts, // data left out of the frames read so far
+    bad_data_policy: BadDataPolicy,
 }
 
 impl Merger {
     /// Create a new merger. Requires the path to the graw data files
     pub fn new(config: &Config, run_number: i32) -> Result<Self, MergerError> {
         let mut merger = Merger {
-            file_stacks: Vec::new(),
+            sources: Vec::new(),
             total_data_size_bytes: 0,
+            frame_type_counts: BTreeMap::new(),
+            chunk_gaps: Vec::new(),
+            split_cobo: None,
+            split_time_offset: 0,
+            bad_data: BadDataCounts::default(),
+            bad_data_policy: config.bad_data_policy,
         };
 
+        //Online data streamed straight from the DAQ
+        if let (true, Some(address)) = (config.online, &config.online_address) {
+            spdlog::info!("Connecting to the online GRAW source at {}...", address);
+            merger.sources.push(GrawSource::Tcp(TcpGrawSource::new(
+                address,
+                config.get_follow_options(),
+            )?));
+            return Ok(merger);
+        }
+
         //For every asad in every cobo, attempt to make a stack
+        //If the expected CoBos are given they must all be present, otherwise CoBos without a directory are skipped
+        let cobos: Vec<u8> = match &config.expected_cobos {
+            Some(cobos) => cobos.clone(),
+            None => (0..NUMBER_OF_COBOS).collect(),
+        };
         let mut graw_dir: PathBuf;
-        for cobo in 0..NUMBER_OF_COBOS {
-            if config.online {
-                graw_dir = config.get_online_directory(run_number, &cobo)?;
+        for cobo in cobos {
+            let dir = if config.online {
+                config.get_online_directory(run_number, &cobo)
             } else {
-                graw_dir = config.get_run_directory(run_number, &cobo)?;
-            }
+                config.get_run_directory(run_number, &cobo)
+            };
+            graw_dir = match dir {
+                Ok(dir) if config.online => {
+                    spdlog::info!("Reading online data of CoBo {cobo} from {}", dir.display());
+                    dir
+                }
+                Ok(dir) => dir,
+                Err(e) if config.expected_cobos.is_some() => return Err(e.into()),
+                Err(e) => {
+                    spdlog::warn!("Skipping CoBo {cobo}: {e}");
+                    continue;
+                }
+            };
             for asad in 0..NUMBER_OF_ASADS {
-                match AsadStack::new(&graw_dir, cobo as i32, asad as i32) {
+                match AsadStack::new(
+                    &graw_dir,
+                    cobo as i32,
+                    asad as i32,
+                    config.get_follow_options(),
+                    config.get_retry_options(),
+                ) {
                     Ok(stack) => {
-                        merger.file_stacks.push(stack);
+                        merger.chunk_gaps.extend(
+                            stack
+                                .get_chunk_gaps()
+                                .iter()
+                                .map(|gap| ((cobo, asad), gap.clone())),
+                        );
+                        merger.sources.push(GrawSource::Files(stack));
                     }
                     Err(AsadStackError::NoMatchingFiles) => {
                         continue;
@@ -51,58 +119,350 @@ impl Merger {
         }
 
         //Oops no files
-        if merger.file_stacks.is_empty() {
+        if merger.sources.is_empty() {
             return Err(MergerError::NoFilesError);
         }
 
         merger.total_data_size_bytes = merger
-            .file_stacks
+            .sources
             .iter()
-            .fold(0, |sum, stack| sum + stack.get_stack_size_bytes());
+            .fold(0, |sum, source| sum + source.get_size_bytes());
         Ok(merger)
     }
 
-    /// Asks the stacks for the next frame.
+    /// Merge the frames of the given CoBo as a separate stream from the frames of the other CoBos, or all of the
+    /// frames as one stream if None
     ///
-    /// Which ever stack has the earliest event, returns its frame.
+    /// Each stream is merged by event id, and the next frame is taken from the stream whose next frame has the earlier
+    /// event time, so that the events of both streams are built at about the same time even if their event ids drift
+    /// apart. The split CoBo can run on its own clock, so time_offset (in clock ticks) is added to its event times
+    /// before they are compared. Frames received over TCP can not be told apart by source, and are all in the stream of
+    /// the other CoBos.
+    pub fn set_split_cobo(&mut self, cobo: Option<u8>, time_offset: i64) {
+        self.split_cobo = cobo;
+        self.split_time_offset = time_offset;
+    }
+
+    /// Check if a source is in the stream of the split CoBo
+    fn is_split_source(&self, source: &GrawSource) -> bool {
+        match (self.split_cobo, source) {
+            (Some(cobo), GrawSource::Files(stack)) => *stack.get_cobo_number() == cobo as i32,
+            _ => false,
+        }
+    }
+
+    /// Asks the sources for the next frame.
+    ///
+    /// Which ever source has the earliest event, returns its frame. With a split CoBo, the earliest event of each
+    /// stream is found, and the one with the earlier event time is returned.
     /// Returns `Result<Option<GrawFrame>>`. If the Option is None, that
-    /// means that there is no more data to be read from the stacks
+    /// means that there is no more data to be read from the sources. In follow mode,
+    /// `MergerError::WaitingForData` is returned if any source is waiting on the DAQ, as
+    /// we cannot know which frame is earliest until that source has data.
     pub fn get_next_frame(&mut self) -> Result<Option<GrawFrame>, MergerError> {
-        let mut earliest_event_index: Option<(usize, u32)> = Option::None;
-        for (idx, stack) in self.file_stacks.iter_mut().enumerate() {
-            if let Some(meta) = stack.get_next_frame_metadata()? {
-                match earliest_event_index {
-                    None => {
-                        earliest_event_index = Some((idx, meta.event_id));
-                    }
-                    Some((_index, event_id)) => {
-                        if meta.event_id < event_id {
-                            earliest_event_index = Some((idx, meta.event_id));
-                        }
-                    }
+        // (source index, event id, event time) of the earliest event of the other CoBos, and of the split CoBo
+        let mut earliest: [Option<(usize, u32, u64)>; 2] = [None, None];
+        let mut is_waiting = false;
+        let is_split: Vec<bool> = self
+            .sources
+            .iter()
+            .map(|source| self.is_split_source(source))
+            .collect();
+        for (idx, source) in self.sources.iter_mut().enumerate() {
+            let meta = match source.get_next_frame_metadata() {
+                Ok(Some(meta)) => meta,
+                Ok(None) => continue,
+                Err(GrawSourceError::WaitingForData) => {
+                    is_waiting = true;
+                    continue;
                 }
+                Err(e) => return Err(MergerError::SourceError(e)),
+            };
+            let stream = &mut earliest[is_split[idx] as usize];
+            match stream {
+                Some((_index, event_id, _time)) if meta.event_id >= *event_id => (),
+                _ => *stream = Some((idx, meta.event_id, meta.event_time)),
             }
         }
+        let earliest_event_index = match earliest {
+            [Some(main), Some(split)]
+                if split.2.saturating_add_signed(self.split_time_offset) < main.2 =>
+            {
+                Some(split)
+            }
+            [Some(main), _] => Some(main),
+            [None, split] => split,
+        };
 
-        if earliest_event_index.is_none() {
-            //None of the remaining stacks had data for us. We've read everything.
+        if is_waiting {
+            self.sources.retain(|source| source.is_not_ended());
+            Err(MergerError::WaitingForData)
+        } else if earliest_event_index.is_none() {
+            //None of the remaining sources had data for us. We've read everything.
             Ok(None)
         } else {
             //This MUST happen before the retain call. The indexes will be modified.
-            let frame = self.file_stacks[earliest_event_index.unwrap().0].get_next_frame()?;
-            //Only keep stacks which still have data to be read
-            self.file_stacks.retain(|stack| stack.is_not_ended());
+            let frame = self.sources[earliest_event_index.unwrap().0].get_next_frame()?;
+            self.frame_type_counts
+                .entry((frame.header.cobo_id, frame.header.asad_id))
+                .or_default()
+                .count(frame.header.frame_type);
+            //Only keep sources which still have data to be read
+            self.sources.retain(|source| source.is_not_ended());
+            if frame.bad_data.total() > 0 {
+                self.bad_data.add(&frame.bad_data);
+                if self.bad_data_policy == BadDataPolicy::Fail {
+                    return Err(MergerError::BadData(
+                        frame.header.cobo_id,
+                        frame.header.asad_id,
+                        frame.header.event_id,
+                        frame.bad_data.total(),
+                    ));
+                }
+            }
             Ok(Some(frame))
         }
     }
 
-    /// Total size of the run in bytes
+    /// Describe each source without merging it: its CoBo and AsAd, size, and the event of its first frame
+    ///
+    /// Only the header of the next frame of each source is read, and the frame is not consumed, so the merger can
+    /// still be used to merge the run afterwards. Sources waiting on the DAQ have no first event yet.
+    pub fn peek_sources(&mut self) -> Result<Vec<SourcePeek>, MergerError> {
+        let mut peeks = Vec::with_capacity(self.sources.len());
+        for source in self.sources.iter_mut() {
+            let first_event = match source.get_next_frame_metadata() {
+                Ok(meta) => meta,
+                Err(GrawSourceError::WaitingForData) => None,
+                Err(e) => return Err(MergerError::SourceError(e)),
+            };
+            let (cobo, asad) = match source {
+                GrawSource::Files(stack) => (
+                    Some(*stack.get_cobo_number() as u8),
+                    Some(*stack.get_asad_number() as u8),
+                ),
+                GrawSource::Tcp(_) => (None, None),
+            };
+            peeks.push(SourcePeek {
+                cobo,
+                asad,
+                size_bytes: source.get_size_bytes(),
+                first_event,
+            });
+        }
+        Ok(peeks)
+    }
+
+    /// Total size of the run in bytes. Streamed data has no known size and does not contribute
     pub fn get_total_data_size(&self) -> &u64 {
         &self.total_data_size_bytes
     }
 
-    /// Get an immutable reference to the underlying file stacks
-    pub fn get_file_stacks(&self) -> &Vec<AsadStack> {
-        &self.file_stacks
+    /// The number of partial and full readout frames read so far from each (CoBo, AsAd), taken from the frame headers
+    pub fn get_frame_type_counts(&self) -> &BTreeMap<(u8, u8), FrameTypeCounts> {
+        &self.frame_type_counts
+    }
+
+    /// The data out of the range of the hardware left out of the frames read so far, per kind of problem
+    pub fn get_bad_data(&self) -> &BadDataCounts {
+        &self.bad_data
+    }
+
+    /// The chunks missing from the GRAW files of each (CoBo, AsAd), found when the merger was made
+    ///
+    /// Kept by the merger, as the stacks are dropped once they are read.
+    pub fn get_chunk_gaps(&self) -> &[((u8, u8), ChunkGap)] {
+        &self.chunk_gaps
+    }
+
+    /// Get immutable references to the underlying file stacks
+    pub fn get_file_stacks(&self) -> Vec<&AsadStack> {
+        self.sources
+            .iter()
+            .filter_map(|source| match source {
+                GrawSource::Files(stack) => Some(stack),
+                GrawSource::Tcp(_) => None,
+            })
+            .collect()
+    }
+
+    /// Get the CoBos which have data being merged, in ascending order
+    pub fn get_cobos(&self) -> Vec<u8> {
+        let mut cobos: Vec<u8> = self
+            .get_file_stacks()
+            .iter()
+            .map(|stack| *stack.get_cobo_number() as u8)
+            .collect();
+        cobos.sort();
+        cobos.dedup();
+        cobos
+    }
+
+    /// Get the paths of all of the files which have not yet been completely read
+    pub fn get_file_paths(&self) -> Vec<PathBuf> {
+        self.get_file_stacks()
+            .iter()
+            .flat_map(|stack| stack.get_file_paths())
+            .collect()
+    }
+
+    /// Get an immutable reference to the underlying sources
+    pub fn get_sources(&self) -> &Vec<GrawSource> {
+        &self.sources
+    }
+}
+
+//Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graw_frame::GrawData;
+    use crate::test_data::{make_full_graw_frame, make_graw_frame, write_asad_file};
+
+    #[test]
+    fn test_missing_cobos() {
+        let dir =
+            std::env::temp_dir().join(format!("attpc_merger_missing_cobos_{}", std::process::id()));
+        // Only CoBos 0 and 2 took data
+        let run_dir = dir.join("run_0001");
+        for cobo in [0, 2] {
+            let cobo_dir = run_dir.join(format!("mm{cobo}"));
+            std::fs::create_dir_all(&cobo_dir).unwrap();
+            write_asad_file(&cobo_dir, cobo, 0, &[make_graw_frame(0, 1, cobo, 0, &[])]).unwrap();
+        }
+        let mut config = Config {
+            graw_path: dir.clone(),
+            ..Default::default()
+        };
+
+        let mut merger = Merger::new(&config, 1).unwrap();
+        assert_eq!(merger.get_cobos(), vec![0, 2]);
+        let peeks = merger.peek_sources().unwrap();
+        let cobos: Vec<Option<u8>> = peeks.iter().map(|peek| peek.cobo).collect();
+        assert_eq!(cobos, vec![Some(0), Some(2)]);
+        assert_eq!(
+            peeks[0].first_event,
+            Some(FrameMetadata {
+                event_id: 0,
+                event_time: 1
+            })
+        );
+        // Peeking does not consume the frames
+        assert_eq!(merger.get_next_frame().unwrap().unwrap().header.event_id, 0);
+
+        config.expected_cobos = Some(vec![2]);
+        let merger = Merger::new(&config, 1).unwrap();
+        assert_eq!(merger.get_cobos(), vec![2]);
+
+        config.expected_cobos = Some(vec![0, 1]);
+        assert!(matches!(
+            Merger::new(&config, 1),
+            Err(MergerError::ConfigError(_))
+        ));
+
+        // No CoBo directories at all
+        config.expected_cobos = None;
+        assert!(matches!(
+            Merger::new(&config, 2),
+            Err(MergerError::NoFilesError)
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_frame_type_counts() {
+        let dir =
+            std::env::temp_dir().join(format!("attpc_merger_frame_types_{}", std::process::id()));
+        // AsAd 0 switches to full readout part way through the run, AsAd 1 stays in partial readout
+        let cobo_dir = dir.join("run_0001").join("mm0");
+        std::fs::create_dir_all(&cobo_dir).unwrap();
+        write_asad_file(
+            &cobo_dir,
+            0,
+            0,
+            &[
+                make_graw_frame(0, 1, 0, 0, &[]),
+                make_full_graw_frame(1, 2, 0, 0, &[]),
+            ],
+        )
+        .unwrap();
+        write_asad_file(&cobo_dir, 0, 1, &[make_graw_frame(0, 1, 0, 1, &[])]).unwrap();
+        let config = Config {
+            graw_path: dir.clone(),
+            ..Default::default()
+        };
+
+        let mut merger = Merger::new(&config, 1).unwrap();
+        while merger.get_next_frame().unwrap().is_some() {}
+        let counts = merger.get_frame_type_counts();
+        assert_eq!(
+            counts[&(0, 0)],
+            FrameTypeCounts {
+                partial: 1,
+                full: 1
+            }
+        );
+        assert!(counts[&(0, 0)].is_mixed());
+        assert_eq!(
+            counts[&(0, 1)],
+            FrameTypeCounts {
+                partial: 1,
+                full: 0
+            }
+        );
+        assert!(!counts[&(0, 1)].is_mixed());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_bad_data_policy() {
+        let dir =
+            std::env::temp_dir().join(format!("attpc_merger_bad_data_{}", std::process::id()));
+        let cobo_dir = dir.join("run_0001").join("mm0");
+        std::fs::create_dir_all(&cobo_dir).unwrap();
+        let noisy = GrawData {
+            aget_id: 1,
+            channel: 90,
+            time_bucket_id: 3,
+            sample: 10,
+        };
+        let good = GrawData {
+            channel: 4,
+            ..noisy.clone()
+        };
+        write_asad_file(
+            &cobo_dir,
+            0,
+            0,
+            &[
+                make_graw_frame(0, 1, 0, 0, &[good.clone()]),
+                make_graw_frame(1, 2, 0, 0, &[noisy, good]),
+            ],
+        )
+        .unwrap();
+        let mut config = Config {
+            graw_path: dir.clone(),
+            ..Default::default()
+        };
+
+        let mut merger = Merger::new(&config, 1).unwrap();
+        let mut n_data = 0;
+        while let Some(frame) = merger.get_next_frame().unwrap() {
+            n_data += frame.data.len();
+        }
+        assert_eq!(n_data, 2);
+        assert_eq!(merger.get_bad_data().channels, 1);
+
+        config.bad_data_policy = BadDataPolicy::Fail;
+        let mut merger = Merger::new(&config, 1).unwrap();
+        assert!(merger.get_next_frame().unwrap().is_some());
+        assert!(matches!(
+            merger.get_next_frame(),
+            Err(MergerError::BadData(0, 0, 1, 1))
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -1,11 +1,16 @@
 use std::fs::File;
 use std::hash::Hash;
 use std::io::Read;
+use std::num::ParseIntError;
 use std::path::Path;
+use std::str::FromStr;
 
 use fxhash::FxHashMap;
+use ndarray::Array2;
 
+use super::constants::{NUMBER_OF_AGETS, NUMBER_OF_ASADS, NUMBER_OF_CHANNELS, NUMBER_OF_COBOS};
 use super::error::PadMapError;
+use super::hdf_writer::HdfLayout;
 
 const ENTRIES_PER_LINE: usize = 5; //Number of elements in a single row in the CSV file
 
@@ -45,8 +50,16 @@ impl HardwareID {
 }
 
 impl Hash for HardwareID {
+    /// Hash the full hardware address along with the pad number, consistent with Eq.
+    ///
+    /// The pad number alone is not guaranteed to be unique (i.e. a map may assign the same pad number
+    /// to channels which are not connected to the pad plane), so it cannot be the only input.
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.pad_id.hash(state) //Just use the pad number as it is unqiue by definition
+        self.cobo_id.hash(state);
+        self.asad_id.hash(state);
+        self.aget_id.hash(state);
+        self.channel.hash(state);
+        self.pad_id.hash(state);
     }
 }
 
@@ -58,10 +71,106 @@ fn generate_uuid(cobo_id: &u8, asad_id: &u8, aget_id: &u8, channel_id: &u8) -> u
         + (*cobo_id as u64) * 1_000_000
 }
 
+/// Parse a single element of the map file, reporting the line on failure
+fn parse_entry<T: FromStr<Err = ParseIntError>>(
+    entry: &str,
+    line_number: usize,
+) -> Result<T, PadMapError> {
+    entry.parse().map_err(|e| {
+        PadMapError::BadLine(
+            line_number,
+            format!("could not parse '{}' as an integer: {}", entry, e),
+        )
+    })
+}
+
+/// Read the contents of a map file. If the path is None, the default map is used
+fn read_map_contents(path: Option<&Path>) -> Result<String, PadMapError> {
+    let mut contents = String::new();
+    if let Some(p) = path {
+        let mut file = File::open(p)?;
+        file.read_to_string(&mut contents)?;
+    } else {
+        contents = load_default_map();
+    }
+    Ok(contents)
+}
+
+/// The data lines of a map file with their line numbers, skipping blank lines, comments and the header
+fn map_lines(contents: &str) -> impl Iterator<Item = (usize, &str)> {
+    contents
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| (idx + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .skip(1) // Skip the header
+}
+
+/// Check that a hardware identifier is within the range of the hardware
+fn check_range(value: u8, limit: u8, name: &str, line_number: usize) -> Result<u8, PadMapError> {
+    if value < limit {
+        Ok(value)
+    } else {
+        Err(PadMapError::BadLine(
+            line_number,
+            format!(
+                "{} {} is out of range, must be less than {}",
+                name, value, limit
+            ),
+        ))
+    }
+}
+
+/// Parse a data line of the map file into the cobo, asad, aget, channel, and pad
+fn parse_line(line: &str, line_number: usize) -> Result<(u8, u8, u8, u8, u64), PadMapError> {
+    let mut entries: Vec<&str> = line.split(',').map(|entry| entry.trim()).collect();
+    while entries.last().is_some_and(|entry| entry.is_empty()) {
+        entries.pop(); // Trailing commas
+    }
+    if entries.len() != ENTRIES_PER_LINE {
+        return Err(PadMapError::BadLine(
+            line_number,
+            format!(
+                "expected {} columns (cobo,asad,aget,channel,pad), found {}",
+                ENTRIES_PER_LINE,
+                entries.len()
+            ),
+        ));
+    }
+
+    let cb_id = check_range(
+        parse_entry(entries[0], line_number)?,
+        NUMBER_OF_COBOS,
+        "cobo",
+        line_number,
+    )?;
+    let ad_id = check_range(
+        parse_entry(entries[1], line_number)?,
+        NUMBER_OF_ASADS,
+        "asad",
+        line_number,
+    )?;
+    let ag_id = check_range(
+        parse_entry(entries[2], line_number)?,
+        NUMBER_OF_AGETS,
+        "aget",
+        line_number,
+    )?;
+    let ch_id = check_range(
+        parse_entry(entries[3], line_number)?,
+        NUMBER_OF_CHANNELS,
+        "channel",
+        line_number,
+    )?;
+    let pd_id = parse_entry(entries[4], line_number)?;
+    Ok((cb_id, ad_id, ag_id, ch_id, pd_id))
+}
+
 /// PadMap contains the mapping of the individual hardware identifiers (CoBo ID, AsAd ID, AGET ID, AGET channel) to AT-TPC pad number.
 ///
 /// This can change from experiment to experiment, so PadMap reads in a CSV file where each row contains 5 elements. The first four are the
-/// hardware identifiers (in the order listed previously) and the fifth is the pad number.
+/// hardware identifiers (in the order listed previously) and the fifth is the pad number. The first row is a header. Whitespace around
+/// the elements and trailing commas are ignored, as are blank lines and lines starting with `#`.
 #[derive(Debug, Clone, Default)]
 pub struct PadMap {
     map: FxHashMap<u64, HardwareID>,
@@ -70,47 +179,74 @@ pub struct PadMap {
 impl PadMap {
     /// Create a new PadMap
     /// If the path is None, we load the default that is bundled with the merger
+    ///
+    /// Fails on the first bad line, including values out of the range of the hardware and hardware addresses
+    /// which are mapped more than once. Use lint to find all of the problems of a map.
     pub fn new(path: Option<&Path>) -> Result<Self, PadMapError> {
-        let mut contents = String::new();
-        if let Some(p) = path {
-            let mut file = File::open(p)?;
-            file.read_to_string(&mut contents)?;
-        } else {
-            contents = load_default_map();
-        }
+        let contents = read_map_contents(path)?;
 
-        let mut cb_id: u8;
-        let mut ad_id: u8;
-        let mut ag_id: u8;
-        let mut ch_id: u8;
-        let mut pd_id: u64;
         let mut uuid: u64;
         let mut hw_id: HardwareID;
 
         let mut pm = PadMap::default();
 
-        let mut lines = contents.lines();
-        lines.next(); // Skip the header
-        for line in lines {
-            let entries: Vec<&str> = line.split_terminator(",").collect();
-            if entries.len() < ENTRIES_PER_LINE {
-                return Err(PadMapError::BadFileFormat);
-            }
-
-            cb_id = entries[0].parse()?;
-            ad_id = entries[1].parse()?;
-            ag_id = entries[2].parse()?;
-            ch_id = entries[3].parse()?;
-            pd_id = entries[4].parse()?;
+        for (line_number, line) in map_lines(&contents) {
+            let (cb_id, ad_id, ag_id, ch_id, pd_id) = parse_line(line, line_number)?;
 
             uuid = generate_uuid(&cb_id, &ad_id, &ag_id, &ch_id);
             hw_id = HardwareID::new(&cb_id, &ad_id, &ag_id, &ch_id, &pd_id);
-            pm.map.insert(uuid, hw_id);
+            if pm.map.insert(uuid, hw_id).is_some() {
+                return Err(PadMapError::BadLine(
+                    line_number,
+                    format!(
+                        "cobo {} asad {} aget {} channel {} is mapped more than once",
+                        cb_id, ad_id, ag_id, ch_id
+                    ),
+                ));
+            }
         }
 
         Ok(pm)
     }
 
+    /// Check a map file for problems without stopping at the first one, i.e. to validate a map before an experiment.
+    ///
+    /// Finds lines with the wrong number of columns or values which are not integers, values out of the range of the
+    /// hardware, hardware addresses mapped more than once, and pads mapped to more than one hardware address.
+    /// Returns every problem found as a BadLine error; an empty list means the map is good.
+    pub fn lint(path: &Path) -> Result<Vec<PadMapError>, PadMapError> {
+        let contents = read_map_contents(Some(path))?;
+        let mut problems = Vec::new();
+        let mut hardware_lines = FxHashMap::<u64, usize>::default();
+        let mut pad_lines = FxHashMap::<u64, usize>::default();
+        for (line_number, line) in map_lines(&contents) {
+            let (cb_id, ad_id, ag_id, ch_id, pd_id) = match parse_line(line, line_number) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    problems.push(e);
+                    continue;
+                }
+            };
+            let uuid = generate_uuid(&cb_id, &ad_id, &ag_id, &ch_id);
+            if let Some(first_line) = hardware_lines.insert(uuid, line_number) {
+                problems.push(PadMapError::BadLine(
+                    line_number,
+                    format!(
+                        "cobo {} asad {} aget {} channel {} was already mapped on line {}",
+                        cb_id, ad_id, ag_id, ch_id, first_line
+                    ),
+                ));
+            }
+            if let Some(first_line) = pad_lines.insert(pd_id, line_number) {
+                problems.push(PadMapError::BadLine(
+                    line_number,
+                    format!("pad {} was already mapped on line {}", pd_id, first_line),
+                ));
+            }
+        }
+        Ok(problems)
+    }
+
     /// Get the full HardwareID for a given set of hardware identifiers.
     ///
     /// If returns None the identifiers given do not exist in the map
@@ -124,6 +260,57 @@ impl PadMap {
         let uuid = generate_uuid(cobo_id, asad_id, aget_id, channel_id);
         self.map.get(&uuid)
     }
+
+    /// Read the pad map embedded in a merged HDF5 file, so that a run can be re-merged with exactly the same mapping.
+    ///
+    /// Files merged before the pad map was recorded return a NoEmbeddedMap error.
+    pub fn from_hdf5(path: &Path) -> Result<Self, PadMapError> {
+        let layout = HdfLayout::default();
+        let file = hdf5::File::open(path)?;
+        let dataset = match file
+            .group(&layout.config)
+            .and_then(|group| group.dataset(&layout.pad_map))
+        {
+            Ok(dataset) => dataset,
+            Err(_) => return Err(PadMapError::NoEmbeddedMap(path.to_path_buf())),
+        };
+        let matrix = dataset.read_2d::<u64>()?;
+        if matrix.ncols() != ENTRIES_PER_LINE {
+            return Err(PadMapError::BadEmbeddedMap(
+                path.to_path_buf(),
+                matrix.ncols(),
+            ));
+        }
+
+        let mut pm = PadMap::default();
+        for row in matrix.rows() {
+            let (cb_id, ad_id, ag_id, ch_id) =
+                (row[0] as u8, row[1] as u8, row[2] as u8, row[3] as u8);
+            pm.map.insert(
+                generate_uuid(&cb_id, &ad_id, &ag_id, &ch_id),
+                HardwareID::new(&cb_id, &ad_id, &ag_id, &ch_id, &row[4]),
+            );
+        }
+        Ok(pm)
+    }
+
+    /// Convert the map to a matrix with one row per channel, ordered by hardware address.
+    ///
+    /// The columns are: cobo, asad, aget, channel, pad
+    pub fn to_matrix(&self) -> Array2<u64> {
+        let mut uuids: Vec<&u64> = self.map.keys().collect();
+        uuids.sort();
+        let mut matrix = Array2::<u64>::zeros([uuids.len(), ENTRIES_PER_LINE]);
+        for (row, uuid) in uuids.into_iter().enumerate() {
+            let hw_id = &self.map[uuid];
+            matrix[[row, 0]] = hw_id.cobo_id as u64;
+            matrix[[row, 1]] = hw_id.asad_id as u64;
+            matrix[[row, 2]] = hw_id.aget_id as u64;
+            matrix[[row, 3]] = hw_id.channel as u64;
+            matrix[[row, 4]] = hw_id.pad_id as u64;
+        }
+        matrix
+    }
 }
 
 //Unit tests
@@ -151,4 +338,130 @@ mod tests {
         };
         assert_eq!(expected_id, *given_id);
     }
+
+    #[test]
+    fn test_map_file_formats() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+        for name in ["pad_map.csv", "pad_map_whitespace.csv"] {
+            let map = PadMap::new(Some(&fixtures.join(name))).unwrap();
+            assert_eq!(map.map.len(), 3);
+            assert_eq!(map.get_hardware_id(&0, &0, &0, &1).unwrap().pad_id, 3485);
+            assert_eq!(map.get_hardware_id(&7, &2, &1, &10).unwrap().pad_id, 9908);
+        }
+
+        match PadMap::new(Some(&fixtures.join("pad_map_bad.csv"))) {
+            Err(PadMapError::BadLine(line, _)) => assert_eq!(line, 4),
+            _ => panic!("The bad map should fail on line 4"),
+        }
+    }
+
+    #[test]
+    fn test_lint() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+        assert!(PadMap::lint(&fixtures.join("pad_map.csv"))
+            .unwrap()
+            .is_empty());
+
+        let lint_path = fixtures.join("pad_map_lint.csv");
+        let lines: Vec<usize> = PadMap::lint(&lint_path)
+            .unwrap()
+            .iter()
+            .map(|problem| match problem {
+                PadMapError::BadLine(line, _) => *line,
+                _ => panic!("Lint problems are bad lines"),
+            })
+            .collect();
+        // Duplicate hardware, duplicate pad, cobo out of range, channel out of range, wrong column count, not an integer
+        assert_eq!(lines, vec![4, 5, 6, 7, 8, 9]);
+        match PadMap::new(Some(&lint_path)) {
+            Err(PadMapError::BadLine(line, _)) => assert_eq!(line, 4),
+            _ => panic!("The map should fail on the duplicate hardware of line 4"),
+        }
+    }
+
+    #[test]
+    fn test_default_map_unique_hardware() {
+        let map = PadMap::new(None).unwrap();
+        let mut hashes = fxhash::FxHashSet::default();
+        for hw_id in map.map.values() {
+            assert!(hashes.insert(fxhash::hash64(hw_id)));
+        }
+        assert_eq!(hashes.len(), map.map.len());
+    }
+
+    /// The hash of the HardwareID of each hardware address of the map
+    fn get_hashes(map: &PadMap) -> std::collections::BTreeMap<u64, u64> {
+        map.map
+            .iter()
+            .map(|(uuid, hw_id)| (*uuid, fxhash::hash64(hw_id)))
+            .collect()
+    }
+
+    #[test]
+    fn test_hash_stable() {
+        // Equal HardwareIDs hash the same, whether made by hand or loaded from the same map twice
+        let hw_id = HardwareID::new(&7, &2, &1, &10, &9908);
+        assert_eq!(
+            fxhash::hash64(&hw_id),
+            fxhash::hash64(&HardwareID::new(&7, &2, &1, &10, &9908))
+        );
+        assert_ne!(
+            fxhash::hash64(&hw_id),
+            fxhash::hash64(&HardwareID::new(&7, &2, &1, &12, &9908))
+        );
+        let map = PadMap::new(None).unwrap();
+        assert_eq!(get_hashes(&map), get_hashes(&PadMap::new(None).unwrap()));
+
+        // Every channel of the map gets its own trace in an event
+        let mut frame = crate::graw_frame::GrawFrame::new();
+        frame.header.cobo_id = 7;
+        frame.header.asad_id = 2;
+        for channel in [10, 12] {
+            frame.data.push(crate::graw_frame::GrawData {
+                aget_id: 1,
+                channel,
+                time_bucket_id: 0,
+                sample: 10,
+            });
+        }
+        let event = crate::event::Event::new(
+            &map,
+            &vec![frame],
+            512,
+            crate::config::DuplicateBucketPolicy::Error,
+        )
+        .unwrap();
+        assert_eq!(event.convert_to_data_matrix(None).0.nrows(), 2);
+    }
+
+    #[test]
+    fn test_hdf5_round_trip() {
+        let dir =
+            std::env::temp_dir().join(format!("attpc_merger_pad_map_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let map = PadMap::new(None).unwrap();
+
+        let path = dir.join("run_0001.h5");
+        let writer = crate::hdf_writer::HDFWriter::new(&path, None, &Default::default()).unwrap();
+        writer.write_pad_map(&map).unwrap();
+        writer.close().unwrap();
+        let read_map = PadMap::from_hdf5(&path).unwrap();
+        assert_eq!(read_map.to_matrix(), map.to_matrix());
+        // The map read back keys the traces the same way
+        assert_eq!(get_hashes(&read_map), get_hashes(&map));
+        assert_eq!(
+            read_map.get_hardware_id(&7, &2, &1, &10).unwrap().pad_id,
+            9908
+        );
+
+        // Files merged without the map can't be used
+        let path = dir.join("run_0002.h5");
+        let writer = crate::hdf_writer::HDFWriter::new(&path, None, &Default::default()).unwrap();
+        writer.close().unwrap();
+        assert!(matches!(
+            PadMap::from_hdf5(&path),
+            Err(PadMapError::NoEmbeddedMap(_))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
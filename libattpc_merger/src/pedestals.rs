@@ -0,0 +1,218 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use fxhash::{FxHashMap, FxHashSet};
+
+use super::error::PedestalError;
+
+const ENTRIES_PER_LINE: usize = 2; //Number of elements in a single row in the CSV file
+
+/// Pedestals contains the per-pad baseline (pedestal) values measured in dedicated pedestal runs.
+///
+/// Pedestals reads in a CSV file where each row contains 2 elements: the pad number and the mean pedestal
+/// value for that pad. The mean is rounded to the nearest integer sample value, and must fit in a sample. Blank lines
+/// are skipped. Pads which are not found in the file are given a pedestal of 0, and are recorded so that they can be
+/// reported once the run is complete.
+#[derive(Debug, Clone, Default)]
+pub struct Pedestals {
+    map: FxHashMap<usize, i16>,
+    file_path: PathBuf,
+    file_hash: u64,
+    missing_pads: FxHashSet<usize>,
+    missing_count: u64,
+}
+
+impl Pedestals {
+    /// Create a new Pedestals from the CSV file at path
+    pub fn new(path: &Path) -> Result<Self, PedestalError> {
+        let mut contents = String::new();
+        let mut file = File::open(path)?;
+        file.read_to_string(&mut contents)?;
+
+        let mut pedestals = Pedestals {
+            file_path: path.to_path_buf(),
+            file_hash: fxhash::hash64(contents.as_bytes()),
+            ..Default::default()
+        };
+
+        let mut pad_id: usize;
+        let mut mean: f64;
+
+        let mut lines = contents.lines().filter(|line| !line.trim().is_empty());
+        lines.next(); // Skip the header
+        for line in lines {
+            let entries: Vec<&str> = line.split_terminator(",").collect();
+            if entries.len() < ENTRIES_PER_LINE {
+                return Err(PedestalError::BadFileFormat);
+            }
+
+            pad_id = entries[0].trim().parse()?;
+            mean = entries[1].trim().parse()?;
+
+            let rounded = mean.round();
+            if !(i16::MIN as f64..=i16::MAX as f64).contains(&rounded) {
+                return Err(PedestalError::ValueOutOfRange(pad_id, mean));
+            }
+
+            pedestals.map.insert(pad_id, rounded as i16);
+        }
+
+        spdlog::info!(
+            "Loaded pedestals for {} pads from {}",
+            pedestals.map.len(),
+            path.display()
+        );
+
+        Ok(pedestals)
+    }
+
+    /// Get the pedestal for a given pad.
+    ///
+    /// If the pad is not in the pedestal file, 0 is returned and the pad is recorded as missing.
+    /// The first time a pad is found to be missing a warning is logged.
+    pub fn get_pedestal(&mut self, pad_id: &usize) -> i16 {
+        match self.map.get(pad_id) {
+            Some(value) => *value,
+            None => {
+                self.missing_count += 1;
+                if self.missing_pads.insert(*pad_id) {
+                    spdlog::warn!(
+                        "Pad {} does not have a pedestal in {}! Using a pedestal of 0.",
+                        pad_id,
+                        self.file_path.display()
+                    );
+                }
+                0
+            }
+        }
+    }
+
+    /// Get the path to the pedestal file
+    pub fn get_file_path(&self) -> &Path {
+        &self.file_path
+    }
+
+    /// Get the hash of the pedestal file contents
+    pub fn get_file_hash(&self) -> &u64 {
+        &self.file_hash
+    }
+
+    /// Get the number of unique pads which were missing a pedestal
+    pub fn get_number_of_missing_pads(&self) -> usize {
+        self.missing_pads.len()
+    }
+
+    /// Get the total number of traces which were missing a pedestal
+    pub fn get_missing_count(&self) -> &u64 {
+        &self.missing_count
+    }
+}
+
+//Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DuplicateBucketPolicy;
+    use crate::event::Event;
+    use crate::graw_frame::{GrawData, GrawFrame};
+    use crate::pad_map::PadMap;
+
+    /// Write contents to a pedestal file in a directory of its own, named for the test
+    fn write_pedestals(name: &str, contents: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "attpc_merger_pedestals_{}_{}",
+            name,
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("pedestals.csv");
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn remove_pedestals(path: &Path) {
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_parse() {
+        // The header is skipped even though it is first, and blank lines (trailing too) are skipped
+        let path = write_pedestals(
+            "parse",
+            "pad,pedestal_mean\n0, 10.4\n\n1,10.5\n 2 ,-3.6\n3,7\n\n",
+        );
+        let mut pedestals = Pedestals::new(&path).unwrap();
+        assert_eq!(pedestals.map.len(), 4);
+        assert_eq!(pedestals.get_pedestal(&0), 10);
+        assert_eq!(pedestals.get_pedestal(&1), 11);
+        assert_eq!(pedestals.get_pedestal(&2), -4);
+        assert_eq!(pedestals.get_pedestal(&3), 7);
+        assert_eq!(*pedestals.get_missing_count(), 0);
+        remove_pedestals(&path);
+    }
+
+    #[test]
+    fn test_bad_files() {
+        let path = write_pedestals("bad", "pad,pedestal_mean\n0,10\n1\n");
+        assert!(matches!(
+            Pedestals::new(&path),
+            Err(PedestalError::BadFileFormat)
+        ));
+
+        std::fs::write(&path, "pad,pedestal_mean\n0,40000.0\n").unwrap();
+        assert!(matches!(
+            Pedestals::new(&path),
+            Err(PedestalError::ValueOutOfRange(0, _))
+        ));
+        std::fs::write(&path, "pad,pedestal_mean\n0,-32768.4\n1,NaN\n").unwrap();
+        assert!(matches!(
+            Pedestals::new(&path),
+            Err(PedestalError::ValueOutOfRange(1, _))
+        ));
+        remove_pedestals(&path);
+    }
+
+    #[test]
+    fn test_missing_pads() {
+        let path = write_pedestals("missing", "pad,pedestal_mean\n0,10\n");
+        let mut pedestals = Pedestals::new(&path).unwrap();
+        assert_eq!(pedestals.get_pedestal(&5), 0);
+        assert_eq!(pedestals.get_pedestal(&5), 0);
+        assert_eq!(pedestals.get_pedestal(&6), 0);
+        assert_eq!(pedestals.get_pedestal(&0), 10);
+        // Every trace is counted, every pad only once
+        assert_eq!(*pedestals.get_missing_count(), 3);
+        assert_eq!(pedestals.get_number_of_missing_pads(), 2);
+        remove_pedestals(&path);
+    }
+
+    #[test]
+    fn test_subtraction() {
+        // CoBo 7, AsAd 2, AGET 1, channel 10 is pad 9908 in the default map
+        let path = write_pedestals("subtraction", "pad,pedestal_mean\n9908,100\n");
+        let mut pedestals = Pedestals::new(&path).unwrap();
+        let map = PadMap::new(None).unwrap();
+        let mut frame = GrawFrame::new();
+        frame.header.cobo_id = 7;
+        frame.header.asad_id = 2;
+        for (time_bucket_id, sample) in [(0, 150), (1, i16::MIN + 50)] {
+            frame.data.push(GrawData {
+                aget_id: 1,
+                channel: 10,
+                time_bucket_id,
+                sample,
+            });
+        }
+        let event = Event::new(&map, &vec![frame], 512, DuplicateBucketPolicy::Overwrite).unwrap();
+        let (data, _) = event.convert_to_data_matrix(Some(&mut pedestals));
+        assert_eq!(data[[0, 4]], 9908);
+        assert_eq!(data[[0, 5]], 50);
+        // Clamped rather than wrapped around
+        assert_eq!(data[[0, 6]], i16::MIN);
+        // Unwritten time buckets are 0, so they also have the pedestal subtracted
+        assert_eq!(data[[0, 7]], -100);
+        assert_eq!(*pedestals.get_missing_count(), 0);
+        remove_pedestals(&path);
+    }
+}
@@ -1,50 +1,485 @@
-use std::path::PathBuf;
-use std::sync::mpsc::Sender;
+use std::collections::{BTreeMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{Scope, ScopedJoinHandle};
+use std::time::{Duration, Instant};
 
-use super::ring_item::{BeginRunItem, EndRunItem, PhysicsItem, RingType, RunInfo, ScalersItem};
+use super::ring_item::{
+    select_fragment, BeginRunItem, CounterItem, EndRunItem, PhysicsItem, RingType, RunInfo,
+    ScalersItem,
+};
 
-use super::config::Config;
-use super::constants::SIZE_UNIT;
-use super::error::ProcessorError;
+use super::checksum::{get_checksum_path, write_checksum_file, CHECKSUM_EXTENSION};
+use super::config::{Config, EventNaming, OutputFormat, PostMergeAction};
+use super::constants::{COBO_WITH_TIMESTAMP, SIZE_UNIT};
+use super::error::{EventBuilderError, EventSinkError, EvtStackError, MergerError, ProcessorError};
+use super::event::Event;
 use super::event_builder::EventBuilder;
+use super::event_hook::{EventHook, MergeSummary, StageTimings};
+use super::event_sink::{find_part_files, find_part_paths};
+use super::event_sink::{make_sink, EventSink};
 use super::evt_stack::EvtStack;
-use super::hdf_writer::HDFWriter;
+use super::file_copy::copy_file_with_progress;
+use super::graw_frame::FrameTypeCounts;
+use super::hdf_writer::{HDFWriter, HdfLayout};
+use super::log_format::{set_log_run, STAGE_TIMING_MESSAGE};
 use super::merger::Merger;
 use super::pad_map::PadMap;
-use super::worker_status::WorkerStatus;
+use super::pedestals::Pedestals;
+#[cfg(feature = "s3")]
+use super::retry::get_backoff;
+use super::run_db::{RunDatabase, RunRecord};
+use super::silicon_matcher::{MatchedEvent, SiliconMatcher};
+#[cfg(feature = "s3")]
+use super::upload::{upload_file, UploadTarget};
+use super::worker_status::{RunCounts, WorkerStatus};
+
+/// The longest time a working worker goes without sending a status, so that the UI can tell it is alive
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The number of built events which can wait to be written before the GET data loop blocks
+const WRITE_QUEUE_SIZE: usize = 64;
+
+/// Corrects the GET timestamps which jump backwards by a few ticks, a known glitch of the Mutant readout
+///
+/// A timestamp smaller than the previous one by less than the threshold is replaced by the previous timestamp + 1,
+/// keeping the timestamps monotonic. The original is kept as the raw_timestamp of the event. Larger jumps are
+/// genuine rollovers of the clock, and are only logged.
+///
+/// Also counts the events which never got a timestampother, because the CoBo with the FRIBDAQ timestamp had no frame.
+#[derive(Debug, Default)]
+struct TimestampFixer {
+    threshold: u64,
+    previous: Option<u64>,
+    glitches: u64,
+    rollovers: u64,
+    missing_timestampother: u64,
+}
+
+impl TimestampFixer {
+    fn new(threshold: u64) -> Self {
+        Self {
+            threshold,
+            ..Default::default()
+        }
+    }
+
+    /// Correct the timestamp of the event if it glitched
+    fn fix(&mut self, event: &mut Event) {
+        if let Some(previous) = self.previous {
+            if event.timestamp < previous {
+                let jump = previous - event.timestamp;
+                if jump < self.threshold {
+                    event.raw_timestamp = Some(event.timestamp);
+                    event.timestamp = previous + 1;
+                    self.glitches += 1;
+                } else {
+                    spdlog::warn!(
+                        "GET timestamp of event {} jumped back by {} ticks, treating it as a rollover.",
+                        event.event_id,
+                        jump
+                    );
+                    self.rollovers += 1;
+                }
+            }
+        }
+        self.previous = Some(event.timestamp);
+    }
+
+    /// Count the event if it has no timestampother, falling back to its (main) timestamp if requested
+    fn check_timestampother(&mut self, event: &mut Event, fallback: bool) {
+        if event.has_timestampother() {
+            return;
+        }
+        self.missing_timestampother += 1;
+        if fallback {
+            event.timestampother = event.timestamp;
+        }
+    }
+}
+
+/// An event queued to be written
+enum QueuedEvent {
+    Get(Event, u64),      // a GET event and its event number
+    OrphanSilicon(Event), // a silicon event matched to no GET event, numbered by the writer
+}
+
+/// Queue an event to be written, if it is within the time window of the Config
+///
+/// If the Config fixes timestamp glitches, the timestamp is corrected first. Events without a timestampother are counted,
+/// and given the timestamp instead if the Config asks for the fallback.
+/// Events outside the time window, or past the max_events_per_run, are skipped, keeping the event numbering contiguous. The event is queued with its
+/// event number, which is the event counter or the GET event_id depending on the event_naming of the Config.
+/// Returns false if the writer has stopped, which only happens if it failed.
+fn queue_event(
+    config: &Config,
+    mut event: Event,
+    events: &SyncSender<QueuedEvent>,
+    event_counter: &mut u64,
+    fixer: &mut TimestampFixer,
+) -> bool {
+    if config.fix_timestamp_glitches {
+        fixer.fix(&mut event);
+    }
+    fixer.check_timestampother(&mut event, config.timestampother_fallback);
+    if !config.is_in_time_window(&event.timestamp)
+        || config.is_run_event_cap_reached(*event_counter)
+    {
+        return true;
+    }
+    let event_number = match config.event_naming {
+        EventNaming::Ordinal => *event_counter,
+        EventNaming::GetEventId => event.event_id as u64,
+    };
+    if events.send(QueuedEvent::Get(event, event_number)).is_err() {
+        return false;
+    }
+    *event_counter += 1;
+    true
+}
 
 /// The final event of the EventBuilder will need a manual flush
 fn flush_final_event(
-    mut evb: EventBuilder,
-    mut writer: HDFWriter,
-    event_counter: &u64,
-) -> Result<(), ProcessorError> {
-    if let Some(event) = evb.flush_final_event() {
-        writer.write_event(event, event_counter)?;
+    config: &Config,
+    evb: &mut EventBuilder,
+    events: &SyncSender<QueuedEvent>,
+    event_counter: &mut u64,
+    fixer: &mut TimestampFixer,
+) -> Result<(), EventBuilderError> {
+    if let Some(event) = evb.flush_final_event()? {
+        queue_event(config, event, events, event_counter, fixer);
     } else {
         spdlog::warn!("Last event was not flushed successfully!")
     }
-    writer.close()?;
     Ok(())
 }
 
+/// Queue the events the SiliconMatcher is done with: the GET events as by queue_event, and the orphan silicon events
+///
+/// Returns false if the writer has stopped, which only happens if it failed.
+fn queue_matched_events(
+    config: &Config,
+    matcher: &mut SiliconMatcher,
+    events: &SyncSender<QueuedEvent>,
+    event_counter: &mut u64,
+    fixer: &mut TimestampFixer,
+) -> bool {
+    for matched in matcher.take_ready() {
+        let is_queued = match matched {
+            MatchedEvent::Tpc(event) => queue_event(config, event, events, event_counter, fixer),
+            MatchedEvent::Orphan(event) => events.send(QueuedEvent::OrphanSilicon(event)).is_ok(),
+        };
+        if !is_queued {
+            return false;
+        }
+    }
+    true
+}
+
+/// Write the GET events recieved from the queue, in the order they were queued, showing each to the hooks first
+///
+/// This runs on its own thread, so that converting (and compressing) the events for writing overlaps with reading the
+/// frames and building the events. HDF5 only allows one thread in the library at a time, so there is one writer.
+/// Orphan silicon events are written too, numbered in the order they are recieved, but are not shown to the hooks.
+/// Returns the event numbers of the GET events written, in order, and the time spent writing.
+fn write_get_events(
+    writer: &mut dyn EventSink,
+    hooks: &mut [&mut dyn EventHook],
+    events: Receiver<QueuedEvent>,
+) -> Result<(Vec<u64>, Duration), EventSinkError> {
+    let mut written = Vec::new();
+    let mut orphans: u64 = 0;
+    let mut write_time = Duration::ZERO;
+    for queued in events {
+        match queued {
+            QueuedEvent::Get(event, event_number) => {
+                for hook in hooks.iter_mut() {
+                    hook.on_get_event(&event, event_number);
+                }
+                let start = Instant::now();
+                writer.write_get_event(event, &event_number)?;
+                write_time += start.elapsed();
+                written.push(event_number);
+            }
+            QueuedEvent::OrphanSilicon(event) => {
+                let start = Instant::now();
+                writer.write_orphan_silicon(event, &orphans)?;
+                write_time += start.elapsed();
+                orphans += 1;
+            }
+        }
+    }
+    Ok((written, write_time))
+}
+
+/// Read the GET data of the run, building events and queueing them to be written
+///
+/// The queue is closed when this returns, which stops the writer once it has written the queued events.
+/// If a SiliconMatcher is given, the frames of the silicon CoBo (Config::si_cobo) are built into events by the
+/// matcher, and the events built by evb go through the matcher to be given their silicon event.
+/// The time spent reading frames and building events is added to the timings.
+/// Returns the TimestampFixer, which counted the timestamp glitches and rollovers.
+#[allow(clippy::too_many_arguments)]
+fn process_get_data(
+    config: &Config,
+    merger: &mut Merger,
+    evb: &mut EventBuilder,
+    mut matcher: Option<&mut SiliconMatcher>,
+    events: SyncSender<QueuedEvent>,
+    timings: &mut StageTimings,
+    run_number: i32,
+    tx: &Sender<WorkerStatus>,
+    worker_id: &usize,
+) -> Result<TimestampFixer, ProcessorError> {
+    let total_data_size = merger.get_total_data_size();
+    let flush_frac: f32 = 0.01;
+    let mut count = 0;
+    let mut progress: f32 = 0.0;
+    let flush_val = (*total_data_size as f64 * flush_frac as f64) as u64;
+
+    let mut event_counter = 0;
+    let mut bytes_read = 0;
+    let mut fixer = TimestampFixer::new(config.timestamp_glitch_threshold);
+    let mut is_waiting = false;
+    let mut last_status = Instant::now();
+    loop {
+        // The event being built is dropped, the events already built are still written
+        if config.stop_flag.is_stop_requested() {
+            spdlog::warn!(
+                "Stop requested, the rest of the GET data of run {} is not merged.",
+                run_number
+            );
+            break;
+        }
+        let counts = RunCounts {
+            events: event_counter,
+            bytes_read,
+            dropped_frames: *evb.get_late_frames(),
+        };
+        let read_start = Instant::now();
+        let next_frame = merger.get_next_frame();
+        timings.frame_read += read_start.elapsed();
+        let frame = match next_frame {
+            //Merger found a frame
+            Ok(Some(frame)) => frame,
+            //If the merger returns none, there is no more data to be read
+            Ok(None) => {
+                match matcher.as_deref_mut() {
+                    Some(matcher) => {
+                        if let Some(event) = evb.flush_final_event()? {
+                            matcher.push_tpc(event)?;
+                        }
+                        matcher.finish()?;
+                        queue_matched_events(
+                            config,
+                            matcher,
+                            &events,
+                            &mut event_counter,
+                            &mut fixer,
+                        );
+                    }
+                    None => {
+                        flush_final_event(config, evb, &events, &mut event_counter, &mut fixer)?
+                    }
+                }
+                break;
+            }
+            //In follow mode, the DAQ hasn't written the next data yet
+            Err(MergerError::WaitingForData) => {
+                is_waiting = true;
+                wait_for_data(
+                    config,
+                    WorkerStatus::waiting(progress, run_number, *worker_id).with_counts(counts),
+                    tx,
+                )?;
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if is_waiting {
+            is_waiting = false;
+            tx.send(WorkerStatus::new(progress, run_number, *worker_id).with_counts(counts))?;
+            last_status = Instant::now();
+        }
+
+        //bleh
+        count += (frame.header.frame_size * SIZE_UNIT) as u64;
+        bytes_read += (frame.header.frame_size * SIZE_UNIT) as u64;
+        if flush_val != 0 && count > flush_val {
+            count = 0;
+            progress += flush_frac;
+            tx.send(WorkerStatus::new(progress, run_number, *worker_id).with_counts(counts))?;
+            last_status = Instant::now();
+        } else if last_status.elapsed() >= HEARTBEAT_INTERVAL {
+            // Heartbeat, so a slow run isn't mistaken for a stalled worker
+            tx.send(WorkerStatus::new(progress, run_number, *worker_id).with_counts(counts))?;
+            last_status = Instant::now();
+        }
+
+        let build_start = Instant::now();
+        let built_event = match matcher.as_deref_mut() {
+            Some(matcher) if frame.header.cobo_id == config.si_cobo => {
+                matcher.append_silicon_frame(frame)?;
+                None
+            }
+            _ => evb.append_frame(frame)?,
+        };
+        timings.event_build += build_start.elapsed();
+        let is_writing = match matcher.as_deref_mut() {
+            Some(matcher) => {
+                if let Some(event) = built_event {
+                    matcher.push_tpc(event)?;
+                }
+                queue_matched_events(config, matcher, &events, &mut event_counter, &mut fixer)
+            }
+            None => match built_event {
+                Some(event) => queue_event(config, event, &events, &mut event_counter, &mut fixer),
+                None => true,
+            },
+        };
+        if !is_writing {
+            // The writer failed, its error is reported by process_run
+            break;
+        }
+        // The event being built is past the cap, so it is dropped rather than flushed
+        if config.is_run_event_cap_reached(event_counter) {
+            spdlog::info!(
+                "Reached the maximum of {} events for run {}, the rest of the GET data is not merged.",
+                event_counter,
+                run_number
+            );
+            break;
+        }
+    }
+    Ok(fixer)
+}
+
+/// In follow mode, tell the UI we are waiting on the DAQ and sleep until it is time to check for new data
+fn wait_for_data(
+    config: &Config,
+    status: WorkerStatus,
+    tx: &Sender<WorkerStatus>,
+) -> Result<(), ProcessorError> {
+    tx.send(status)?;
+    std::thread::sleep(Duration::from_millis(config.follow_poll_interval_ms));
+    Ok(())
+}
+
+/// Check if a FRIBDAQ item should be merged given the time window of the Config
+///
+/// Items are filtered by their body header timestamp. Items without one cannot be filtered, so they are kept
+/// (with a single warning).
+fn is_frib_item_in_window(
+    config: &Config,
+    body_timestamp: Option<u64>,
+    warned_no_timestamp: &mut bool,
+) -> bool {
+    if !config.has_time_window() {
+        return true;
+    }
+    match body_timestamp {
+        Some(timestamp) => config.is_in_time_window(&timestamp),
+        None => {
+            if !*warned_no_timestamp {
+                spdlog::warn!("FRIBDAQ items have no body header timestamp, so they can not be filtered by the time window. All FRIBDAQ items will be merged.");
+                *warned_no_timestamp = true;
+            }
+            true
+        }
+    }
+}
+
+/// The event number of the nth FRIBDAQ physics event written
+///
+/// Physics events are matched to the GET events in the order they were written, so with GetEventId naming the nth
+/// physics event is given the event number of the nth GET event. Physics events beyond the GET events continue
+/// counting from the last GET event.
+fn get_physics_event_number(config: &Config, get_event_numbers: &[u64], n: u64) -> u64 {
+    match config.event_naming {
+        EventNaming::Ordinal => n,
+        EventNaming::GetEventId => match get_event_numbers.get(n as usize) {
+            Some(number) => *number,
+            None => match get_event_numbers.last() {
+                Some(last) => last + 1 + n - get_event_numbers.len() as u64,
+                None => n,
+            },
+        },
+    }
+}
+
 /// Process the evt data for this run
-fn process_evt_data(evt_path: PathBuf, writer: &mut HDFWriter) -> Result<(), ProcessorError> {
-    let mut evt_stack = EvtStack::new(&evt_path)?; // open evt file
+///
+/// Each physics event is shown to the hooks before it is written. The number of physics events and scalers written
+/// are recorded in the summary, along with the count of the last FRIBDAQ Counter item. If the Counter disagrees with
+/// the number of physics items read, FRIBDAQ events were dropped and a warning is logged. Corrupt bytes skipped in the
+/// evt files are recorded in the summary.
+/// The physics events are numbered to match the GET events written, given by get_event_numbers (see get_physics_event_number).
+/// Returns the paths of the evt files which were read
+#[allow(clippy::too_many_arguments)]
+fn process_evt_data(
+    evt_path: PathBuf,
+    get_event_numbers: &[u64],
+    writer: &mut dyn EventSink,
+    hooks: &mut [&mut dyn EventHook],
+    summary: &mut MergeSummary,
+    config: &Config,
+    run_number: i32,
+    tx: &Sender<WorkerStatus>,
+    worker_id: &usize,
+) -> Result<Vec<PathBuf>, ProcessorError> {
+    let mut evt_stack = EvtStack::new(
+        &evt_path,
+        config.get_follow_options(),
+        config.get_retry_options(),
+        config.max_evt_item_bytes,
+    )?; // open evt file
+    let evt_files = evt_stack.get_file_paths();
     let mut run_info = RunInfo::new();
     let mut scaler_counter: u64 = 0;
     let mut event_counter: u64 = 0;
-    while let Some(mut ring) = evt_stack.get_next_ring_item()? {
+    let mut physics_read: u64 = 0;
+    let mut warned_no_timestamp = false;
+    let mut last_status = Instant::now();
+    loop {
+        if config.stop_flag.is_stop_requested() {
+            spdlog::warn!(
+                "Stop requested, the rest of the evt data of run {} is not merged.",
+                run_number
+            );
+            break;
+        }
+        let read_start = Instant::now();
+        let next_ring = evt_stack.get_next_ring_item();
+        summary.timings.evt_read += read_start.elapsed();
+        let ring = match next_ring {
+            Ok(Some(ring)) => ring,
+            Ok(None) => break,
+            Err(EvtStackError::WaitingForData) => {
+                wait_for_data(
+                    config,
+                    WorkerStatus::waiting(1.0, run_number, *worker_id),
+                    tx,
+                )?;
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if last_status.elapsed() >= HEARTBEAT_INTERVAL {
+            tx.send(WorkerStatus::new(1.0, run_number, *worker_id))?;
+            last_status = Instant::now();
+        }
         match ring.ring_type {
             // process each ring depending on its type
             RingType::BeginRun => {
                 // Begin run
-                run_info.begin = BeginRunItem::try_from(ring)?;
+                run_info.begin =
+                    BeginRunItem::try_from(ring).map_err(|e| evt_stack.item_error(e))?;
                 spdlog::info!("Detected begin run -- {}", run_info.print_begin());
             }
             RingType::EndRun => {
                 // End run
-                run_info.end = EndRunItem::try_from(ring)?;
+                run_info.end = EndRunItem::try_from(ring).map_err(|e| evt_stack.item_error(e))?;
                 spdlog::info!("Detected end run -- {}", run_info.print_end());
                 writer.write_frib_runinfo(run_info)?;
                 break;
@@ -52,150 +487,2467 @@ fn process_evt_data(evt_path: PathBuf, writer: &mut HDFWriter) -> Result<(), Pro
             RingType::Dummy => (),
             RingType::Scalers => {
                 // Scalers
-                writer.write_frib_scalers(ScalersItem::try_from(ring)?, &scaler_counter)?;
+                let scalers = ScalersItem::try_from(ring).map_err(|e| evt_stack.item_error(e))?;
+                if !is_frib_item_in_window(config, scalers.body_timestamp, &mut warned_no_timestamp)
+                {
+                    continue;
+                }
+                let write_start = Instant::now();
+                writer.write_frib_scalers(scalers, &scaler_counter)?;
+                summary.timings.evt_write += write_start.elapsed();
                 scaler_counter += 1;
+                summary.frib_scalers = scaler_counter;
             }
             RingType::Physics => {
                 // Physics data
+                let parse_start = Instant::now();
+                // An event built item has a fragment per source, only the configured source has the AT-TPC stack
+                let (mut ring, other_fragments) = match ring.get_fragments() {
+                    Some(fragments) => select_fragment(fragments, config.frib_source_id)
+                        .map_err(|e| evt_stack.item_error(e))?,
+                    None => (ring, Vec::new()),
+                };
+                summary.frib_other_fragments += other_fragments.len() as u64;
                 ring.remove_boundaries(); // physics event often cross VMUSB buffer boundary
-                writer.write_frib_physics(PhysicsItem::try_from(ring)?, &event_counter)?;
+                let mut physics = PhysicsItem::from_ring(ring, config.fadc_bits)
+                    .map_err(|e| evt_stack.item_error(e))?;
+                if config.write_frib_fragments {
+                    physics.fragments = other_fragments;
+                }
+                summary.timings.evt_read += parse_start.elapsed();
+                physics_read += 1;
+                if !is_frib_item_in_window(config, physics.body_timestamp, &mut warned_no_timestamp)
+                {
+                    continue;
+                }
+                // Keep reading past the cap, so that the scalers and the run information are still merged
+                if config.is_run_event_cap_reached(event_counter) {
+                    continue;
+                }
+                let event_number =
+                    get_physics_event_number(config, get_event_numbers, event_counter);
+                for hook in hooks.iter_mut() {
+                    hook.on_frib_physics(&physics, event_number);
+                }
+                let write_start = Instant::now();
+                writer.write_frib_physics(physics, &event_number)?;
+                summary.timings.evt_write += write_start.elapsed();
                 event_counter += 1;
+                summary.frib_physics = event_counter;
+            }
+            RingType::Counter => {
+                // Counters written by old versions of FRIBDAQ could be malformed, so these aren't fatal
+                match CounterItem::try_from(ring) {
+                    Ok(counter) => summary.frib_counter = Some(counter.count),
+                    Err(e) => spdlog::warn!(
+                        "Could not read a FRIBDAQ counter item: {}",
+                        evt_stack.item_error(e)
+                    ),
+                }
             }
-            RingType::Counter => (), // Unused, old that could cause many errors
             _ => spdlog::error!("Unrecognized ring type: {}", ring.bytes[4]),
         }
     }
-    Ok(())
+    if let Some(count) = summary.frib_counter {
+        if count != physics_read {
+            spdlog::warn!(
+                "FRIBDAQ counted {} physics events, but {} were found in the evt data! FRIBDAQ events may have been dropped.",
+                count,
+                physics_read
+            );
+        }
+    }
+    if summary.frib_other_fragments > 0 {
+        spdlog::info!(
+            "Found {} fragments from other sources in the event built physics items.",
+            summary.frib_other_fragments
+        );
+    }
+    summary.evt_skipped_bytes = evt_stack.get_skipped_bytes();
+    let total_skipped: u64 = summary
+        .evt_skipped_bytes
+        .iter()
+        .map(|(_, bytes)| bytes)
+        .sum();
+    if total_skipped > 0 {
+        spdlog::warn!(
+            "Skipped {} corrupt bytes in {} of the evt files. The FRIBDAQ items in them were lost.",
+            total_skipped,
+            summary.evt_skipped_bytes.len()
+        );
+    }
+    Ok(evt_files)
 }
 
-/// The main loop of attpc_merger.
-///
-/// This takes in a config (and progress monitor) and preforms the merging logic on the recieved data.
-pub fn process_run(
+/// Check that the closed output file is complete, as required before touching the source files
+fn verify_output(
     config: &Config,
-    run_number: i32,
-    tx: &Sender<WorkerStatus>,
-    worker_id: &usize,
-) -> Result<(), ProcessorError> {
-    let hdf_path = config.get_hdf_file_name(run_number)?;
-    let pad_map = PadMap::new(config.pad_map_path.as_deref())?;
-
-    //Initialize the merger, event builder, and hdf writer
-    let mut merger = Merger::new(config, run_number)?;
-    spdlog::info!(
-        "Total run size: {}",
-        human_bytes::human_bytes(*merger.get_total_data_size() as f64)
-    );
-    let mut evb = EventBuilder::new(pad_map);
-    let mut writer = HDFWriter::new(&hdf_path)?;
+    path: &Path,
+    layout: &HdfLayout,
+    expected_max_event: &u64,
+) -> Result<bool, ProcessorError> {
+    match config.output_format {
+        OutputFormat::HDF5 => Ok(HDFWriter::verify(path, layout, expected_max_event)?),
+    }
+}
 
-    let total_data_size = merger.get_total_data_size();
-    let flush_frac: f32 = 0.01;
-    let mut count = 0;
-    let mut progress: f32 = 0.0;
-    let flush_val = (*total_data_size as f64 * flush_frac as f64) as u64;
+/// Check if an existing output file is complete, i.e. not left behind by a merge which crashed
+fn is_output_complete(config: &Config, path: &Path, layout: &HdfLayout) -> bool {
+    match config.output_format {
+        OutputFormat::HDF5 => HDFWriter::is_complete(path, layout),
+    }
+}
 
-    // Handle evt data if present
-    match config.get_evt_directory(run_number) {
-        Ok(evt_path) => {
-            spdlog::info!("Now processing evt data...");
-            match process_evt_data(evt_path, &mut writer) {
-                Ok(_) => spdlog::info!("Done with evt data."),
-                Err(e) => {
-                    spdlog::warn!("Error while processing evt data: {e}\nSkipping evt processing.")
-                }
-            }
-        }
-        Err(e) => {
-            spdlog::warn!("Could not access evt directory: {e}");
-            spdlog::warn!("Skipping processing evt data...");
-        }
+/// Mark a closed output file as incomplete, so that is_output_complete is false for it
+fn mark_output_incomplete(
+    config: &Config,
+    path: &Path,
+    layout: &HdfLayout,
+) -> Result<(), ProcessorError> {
+    match config.output_format {
+        OutputFormat::HDF5 => Ok(HDFWriter::mark_incomplete(path, layout)?),
     }
+}
 
-    //Handle the get data
-    spdlog::info!("Processing get data...");
-    writer.write_fileinfo(&merger).unwrap();
-    let mut event_counter = 0;
-    loop {
-        if let Some(frame) = merger.get_next_frame()? {
-            //Merger found a frame
-            //bleh
-            count += (frame.header.frame_size * SIZE_UNIT) as u64;
-            if count > flush_val {
-                count = 0;
-                progress += flush_frac;
-                tx.send(WorkerStatus::new(progress, run_number, *worker_id))?;
-            }
+/// The sidecar files written next to an output file, which belong with it
+fn get_sidecar_paths(path: &Path) -> [PathBuf; 2] {
+    [path.with_extension("yml"), get_checksum_path(path)]
+}
 
-            if let Some(event) = evb.append_frame(frame)? {
-                writer.write_event(event, &event_counter)?;
-                event_counter += 1;
-            } else {
-                continue;
+/// Add a suffix to the name of a path, i.e. run_0001.h5 becomes run_0001.h5.partial
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Clean up the output files of a previous merge of the run which did not complete: the merged file, or the parts
+/// of a split run (see event_sink::get_part_path), along with their sidecars
+///
+/// If clean_partial_outputs is set the files are deleted, otherwise they are renamed to <file>.partial so that they
+/// can be inspected. Complete files are left alone.
+fn clean_partial_output(
+    config: &Config,
+    path: &Path,
+    layout: &HdfLayout,
+) -> Result<(), ProcessorError> {
+    for output in std::iter::once(path.to_path_buf()).chain(find_part_paths(path)) {
+        if !output.exists() || is_output_complete(config, &output, layout) {
+            continue;
+        }
+        let sidecars = get_sidecar_paths(&output);
+        let files =
+            std::iter::once(&output).chain(sidecars.iter().filter(|sidecar| sidecar.exists()));
+        if config.clean_partial_outputs {
+            spdlog::warn!(
+                "Found a partial output file {} from a previous merge which did not complete. Deleting it.",
+                output.display()
+            );
+            for file in files {
+                std::fs::remove_file(file)
+                    .map_err(|e| ProcessorError::PartialOutputError(file.clone(), e))?;
             }
         } else {
-            //If the merger returns none, there is no more data to be read
-            flush_final_event(evb, writer, &event_counter)?;
-            break;
+            spdlog::warn!(
+                "Found a partial output file {} from a previous merge which did not complete. It was moved to {}.",
+                output.display(),
+                with_suffix(&output, ".partial").display()
+            );
+            for file in files {
+                std::fs::rename(file, with_suffix(file, ".partial"))
+                    .map_err(|e| ProcessorError::PartialOutputError(file.clone(), e))?;
+            }
         }
     }
+    Ok(())
+}
 
-    tx.send(WorkerStatus::new(1.0, run_number, *worker_id))?;
-    spdlog::info!("Done with get data.");
+/// Delete the parts of a split run left by a previous merge of the run, along with their sidecars
+///
+/// The new merge may write fewer parts, so the old parts would otherwise be taken for parts of the new output.
+fn remove_old_parts(path: &Path) -> Result<(), ProcessorError> {
+    for part in find_part_paths(path) {
+        let sidecars = get_sidecar_paths(&part);
+        for file in std::iter::once(&part).chain(sidecars.iter().filter(|sidecar| sidecar.exists()))
+        {
+            std::fs::remove_file(file)
+                .map_err(|e| ProcessorError::PartialOutputError(file.clone(), e))?;
+        }
+        spdlog::info!(
+            "Deleted {}, a part of a previous merge of the run.",
+            part.display()
+        );
+    }
+    Ok(())
+}
 
+/// Move a source file to the destination directory, keeping its path relative to the GRAW or evt directory
+///
+/// on_progress is called with the number of bytes copied so far if the file has to be copied.
+fn move_source_file(
+    config: &Config,
+    path: &Path,
+    destination: &Path,
+    on_progress: &mut dyn FnMut(u64),
+) -> std::io::Result<()> {
+    let relative_path = match path
+        .strip_prefix(&config.graw_path)
+        .or_else(|_| path.strip_prefix(&config.evt_path))
+    {
+        Ok(relative) => relative,
+        Err(_) => Path::new(path.file_name().unwrap_or(path.as_os_str())),
+    };
+    let new_path = destination.join(relative_path);
+    if let Some(parent) = new_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // Renaming fails across file systems, so fall back to copying (rate limited if requested)
+    if std::fs::rename(path, &new_path).is_err() {
+        copy_file_with_progress(
+            path,
+            &new_path,
+            config.get_copy_bytes_per_second(),
+            on_progress,
+        )?;
+        std::fs::remove_file(path)?;
+    }
+    spdlog::info!("Moved {} to {}", path.display(), new_path.display());
     Ok(())
 }
 
-/// The function to be called by a separate thread (typically the UI).
-/// This particular flavor is unused by the default tools (attpc_merger and attpc_merger_cli)
-/// but could be useful to someone else
-/// Allows multiple runs to be processed
-pub fn process(
-    config: Config,
-    tx: Sender<WorkerStatus>,
-    worker_id: usize,
+/// Delete or move the source files of a merged run
+///
+/// Never touches the online DAQ volumes, and requires the action to be confirmed in the Config.
+/// While moving, the fraction of the total bytes moved is sent as a copying status.
+fn apply_post_merge_action(
+    config: &Config,
+    files: &[PathBuf],
+    tx: &Sender<WorkerStatus>,
+    run_number: i32,
+    worker_id: &usize,
 ) -> Result<(), ProcessorError> {
-    for run in config.first_run_number..(config.last_run_number + 1) {
-        tx.send(WorkerStatus::new(0.0, run, worker_id))?;
-        if config.does_run_exist(run) {
-            spdlog::info!("Processing run {}...", run);
-            process_run(&config, run, &tx, &worker_id)?;
-            spdlog::info!("Finished processing run {}.", run);
-        } else {
-            spdlog::info!("Run {} does not exist, skipping...", run);
+    let action = match &config.post_merge_action {
+        Some(action) => action,
+        None => return Ok(()),
+    };
+    if config.online {
+        spdlog::warn!("Post-merge actions are not allowed on online data, skipping.");
+        return Ok(());
+    }
+    if !config.confirm_post_merge_action {
+        spdlog::warn!(
+            "A post-merge action was requested, but confirm_post_merge_action is false, skipping."
+        );
+        return Ok(());
+    }
+    let total_bytes: u64 = files
+        .iter()
+        .filter_map(|path| std::fs::metadata(path).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    let mut bytes_moved: u64 = 0;
+    for path in files.iter() {
+        match action {
+            PostMergeAction::Delete => {
+                std::fs::remove_file(path)
+                    .map_err(|e| ProcessorError::PostMergeError(e).with_path(path))?;
+                spdlog::info!("Deleted {}", path.display());
+            }
+            PostMergeAction::MoveTo(destination) => {
+                let file_bytes = std::fs::metadata(path)
+                    .map(|metadata| metadata.len())
+                    .unwrap_or(0);
+                // A failed send only means nobody is watching, the move should still finish
+                let send_progress = |bytes_done: u64| {
+                    let progress = bytes_done as f32 / total_bytes.max(1) as f32;
+                    let _ = tx.send(WorkerStatus::copying(progress, run_number, *worker_id));
+                };
+                let previous_bytes = bytes_moved;
+                move_source_file(config, path, destination, &mut |bytes_copied| {
+                    send_progress(previous_bytes + bytes_copied)
+                })
+                .map_err(|e| ProcessorError::PostMergeError(e).with_path(path))?;
+                bytes_moved += file_bytes;
+                send_progress(bytes_moved);
+            }
         }
     }
     Ok(())
 }
 
-/// Process a subset of runs
-pub fn process_subset(
-    config: Config,
-    tx: Sender<WorkerStatus>,
-    worker_id: usize,
-    subset: Vec<i32>,
+/// The files written for a run: the merged file or its parts, each followed by its yml and checksum sidecars if there
+/// are any
+#[cfg(feature = "s3")]
+fn get_output_files(outputs: &[PathBuf]) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for path in outputs.iter() {
+        let sidecars = [path.with_extension("yml"), get_checksum_path(path)];
+        files.push(path.clone());
+        files.extend(sidecars.into_iter().filter(|sidecar| sidecar.exists()));
+    }
+    files
+}
+
+/// Write the SHA-256 checksum file of each output file of a run (see checksum::write_checksum_file), keeping the
+/// digests in the summary
+///
+/// The fraction of the total bytes hashed is sent as a checksumming status, as hashing a large run takes a while.
+fn write_output_checksums(
+    outputs: &[PathBuf],
+    summary: &mut MergeSummary,
+    tx: &Sender<WorkerStatus>,
+    worker_id: &usize,
 ) -> Result<(), ProcessorError> {
-    for run in subset {
-        tx.send(WorkerStatus::new(0.0, run, worker_id))?;
-        if config.does_run_exist(run) {
-            spdlog::info!("Processing run {}...", run);
-            process_run(&config, run, &tx, &worker_id)?;
-            spdlog::info!("Finished processing run {}.", run);
-        } else {
-            spdlog::info!("Run {} does not exist, skipping...", run);
-        }
+    let total_bytes: u64 = outputs
+        .iter()
+        .filter_map(|path| std::fs::metadata(path).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    let run_number = summary.run_number;
+    let mut previous_bytes: u64 = 0;
+    for path in outputs.iter() {
+        // A failed send only means nobody is watching, the checksum should still be written
+        let digest = write_checksum_file(path, |bytes_hashed| {
+            let progress = (previous_bytes + bytes_hashed) as f32 / total_bytes.max(1) as f32;
+            let _ = tx.send(WorkerStatus::checksumming(progress, run_number, *worker_id));
+        })
+        .map_err(|e| ProcessorError::ChecksumError(e).with_path(path))?;
+        previous_bytes += std::fs::metadata(path).map(|meta| meta.len()).unwrap_or(0);
+        spdlog::info!("Run {} SHA-256 {}  {}", run_number, digest, path.display());
+        summary.checksums.push((path.clone(), digest));
     }
     Ok(())
 }
 
-/// Divide a run range in to a set of subranges (per thread/worker)
-pub fn create_subsets(config: &Config) -> Vec<Vec<i32>> {
-    let mut subsets: Vec<Vec<i32>> = vec![Vec::new(); config.n_threads as usize];
-    let n_subsets = subsets.len();
-
-    for (idx, run) in (config.first_run_number..(config.last_run_number + 1)).enumerate() {
-        subsets[idx % n_subsets].push(run)
+/// Upload the output files of a run to the upload_url of the Config, retrying each file up to upload_retries times
+/// with exponential backoff
+///
+/// A failed upload does not fail the run: the error is logged and kept in the summary. The fraction of the total
+/// bytes uploaded is sent as an uploading status. Returns the files which were uploaded and verified.
+#[cfg(feature = "s3")]
+fn upload_output(
+    config: &Config,
+    files: &[PathBuf],
+    summary: &mut MergeSummary,
+    tx: &Sender<WorkerStatus>,
+    worker_id: &usize,
+) -> Vec<PathBuf> {
+    let mut uploaded = Vec::new();
+    let url = match &config.upload_url {
+        Some(url) => url,
+        None => return uploaded,
+    };
+    let target = match UploadTarget::new(url) {
+        Ok(target) => target,
+        Err(e) => {
+            spdlog::error!("Could not upload run {}: {e}", summary.run_number);
+            summary.upload_error = Some(e.to_string());
+            return uploaded;
+        }
+    };
+    let total_bytes: u64 = files
+        .iter()
+        .filter_map(|path| std::fs::metadata(path).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    let run_number = summary.run_number;
+    // A failed send only means nobody is watching, the upload should still finish
+    let send_progress = |bytes_done: u64| {
+        let progress = bytes_done as f32 / total_bytes.max(1) as f32;
+        let _ = tx.send(WorkerStatus::uploading(progress, run_number, *worker_id));
+    };
+    let retry_options = config.get_upload_retry_options();
+    for path in files.iter() {
+        let mut attempt: u32 = 0;
+        loop {
+            let previous_bytes = summary.uploaded_bytes;
+            match upload_file(&target, path, &mut |bytes_sent| {
+                send_progress(previous_bytes + bytes_sent)
+            }) {
+                Ok(bytes) => {
+                    spdlog::info!("Uploaded {} to {}", path.display(), url);
+                    summary.uploaded_bytes += bytes;
+                    uploaded.push(path.clone());
+                    break;
+                }
+                Err(e) if attempt < retry_options.retries => {
+                    attempt += 1;
+                    let backoff = get_backoff(&retry_options, attempt);
+                    spdlog::warn!(
+                        "Upload of {} failed: {e}. Retrying in {} ms (attempt {} of {})",
+                        path.display(),
+                        backoff.as_millis(),
+                        attempt,
+                        retry_options.retries
+                    );
+                    std::thread::sleep(backoff);
+                }
+                Err(e) => {
+                    spdlog::error!(
+                        "Upload of {} failed after {} retries: {e}",
+                        path.display(),
+                        retry_options.retries
+                    );
+                    summary.upload_error = Some(e.to_string());
+                    break;
+                }
+            }
+        }
+        send_progress(summary.uploaded_bytes);
     }
+    uploaded
+}
 
-    subsets
+/// The main loop of attpc_merger.
+///
+/// This takes in a config (and progress monitor) and preforms the merging logic on the recieved data.
+///
+/// The GET data is processed before the evt data. In follow mode this means the bulk of the merging happens
+/// while the run is still being taken, as the evt data is only complete once FRIBDAQ writes the EndRun item.
+///
+/// If a post-merge action is configured, it is applied to the source files only after the merged file
+/// has been closed and verified, and only if there were no errors.
+pub fn process_run(
+    config: &Config,
+    run_number: i32,
+    tx: &Sender<WorkerStatus>,
+    worker_id: &usize,
+) -> Result<(), ProcessorError> {
+    process_run_with_hooks(config, run_number, tx, worker_id, &mut [])
+}
+
+/// Log the number of partial and full readout frames read, returning the totals of the run
+///
+/// The readout mode is set per CoBo, so an AsAd sending both kinds of frames, or a run where the CoBos disagree,
+/// is warned about.
+fn log_frame_types(counts: &BTreeMap<(u8, u8), FrameTypeCounts>) -> FrameTypeCounts {
+    let mut total = FrameTypeCounts::default();
+    for ((cobo, asad), asad_counts) in counts.iter() {
+        if asad_counts.is_mixed() {
+            spdlog::warn!(
+                "CoBo {} AsAd {} sent both partial ({}) and full ({}) readout frames! Check the readout mode of CoBo {}.",
+                cobo,
+                asad,
+                asad_counts.partial,
+                asad_counts.full,
+                cobo
+            );
+        }
+        total.add(asad_counts);
+    }
+    spdlog::info!(
+        "Read {} partial readout and {} full readout GET frames.",
+        total.partial,
+        total.full
+    );
+    if total.is_mixed() && !counts.values().any(|asad_counts| asad_counts.is_mixed()) {
+        let full_asads: Vec<String> = counts
+            .iter()
+            .filter(|(_, asad_counts)| asad_counts.full > 0)
+            .map(|((cobo, asad), _)| format!("CoBo {cobo} AsAd {asad}"))
+            .collect();
+        spdlog::warn!(
+            "The run mixes partial and full readout frames; only {} sent full readout frames. Check the readout mode of the CoBos.",
+            full_asads.join(", ")
+        );
+    }
+    total
+}
+
+/// Merge the GET data of the run, writing the events to the writer
+///
+/// The events are built on this thread and written on another. The number of events written, the late frames,
+/// and the corrected timestamps are recorded in the summary. Returns the event numbers of the events written, in order.
+#[allow(clippy::too_many_arguments)]
+fn merge_get_data(
+    config: &Config,
+    merger: &mut Merger,
+    pad_map: PadMap,
+    writer: &mut dyn EventSink,
+    hooks: &mut [&mut dyn EventHook],
+    summary: &mut MergeSummary,
+    tx: &Sender<WorkerStatus>,
+    worker_id: &usize,
+) -> Result<Vec<u64>, ProcessorError> {
+    let run_number = summary.run_number;
+    spdlog::info!("Processing get data...");
+    writer.write_fileinfo(merger).unwrap();
+    writer.write_pad_map(&pad_map)?;
+    let make_event_builder = |pad_map: PadMap| {
+        let mut evb = EventBuilder::new(
+            pad_map,
+            config.frame_provenance,
+            config.debug_frame_headers,
+            config.time_buckets as usize,
+            config.duplicate_bucket_policy,
+            config.max_out_of_order as usize,
+            config.strict_pad_map,
+        );
+        evb.set_max_frames_per_event(config.max_frames_per_event, config.fail_oversized_events);
+        evb.set_sca_rotation_correction(config.correct_sca_rotation);
+        if config.spill_large_events {
+            evb.set_spill(config.hdf_path.clone(), config.spill_threshold_frames);
+        }
+        evb
+    };
+    // The silicon CoBo is merged as its own stream and matched to the TPC events by timestamp
+    let mut matcher = if config.si_merge_by_timestamp {
+        merger.set_split_cobo(Some(config.si_cobo), config.si_timestamp_offset);
+        Some(SiliconMatcher::new(
+            make_event_builder(pad_map.clone()),
+            config.si_cobo,
+            config.si_coincidence_window,
+            config.si_timestamp_offset,
+        ))
+    } else {
+        None
+    };
+    let mut evb = make_event_builder(pad_map);
+    let (event_tx, event_rx) = std::sync::mpsc::sync_channel::<QueuedEvent>(WRITE_QUEUE_SIZE);
+    let (read_result, write_result) = std::thread::scope(|scope| {
+        let write_handle = scope.spawn(move || {
+            set_log_run(Some(run_number));
+            write_get_events(writer, hooks, event_rx)
+        });
+        let read_result = process_get_data(
+            config,
+            merger,
+            &mut evb,
+            matcher.as_mut(),
+            event_tx,
+            &mut summary.timings,
+            run_number,
+            tx,
+            worker_id,
+        );
+        let write_result = write_handle
+            .join()
+            .expect("The GET event writer thread panicked!");
+        (read_result, write_result)
+    });
+    let (get_event_numbers, write_time) = write_result?;
+    summary.get_events = get_event_numbers.len() as u64;
+    summary.timings.get_write = write_time;
+    let fixer = read_result?;
+    summary.late_frames = *evb.get_late_frames();
+    summary.spilled_events = *evb.get_spilled_events();
+    if let Some(matcher) = &matcher {
+        summary.late_frames += *matcher.get_event_builder().get_late_frames();
+        summary.spilled_events += *matcher.get_event_builder().get_spilled_events();
+        summary.silicon_matched = *matcher.get_matched();
+        summary.silicon_orphans = *matcher.get_orphans();
+        spdlog::info!(
+            "Matched {} silicon events to GET events by timestamp, {} silicon events had no match.",
+            matcher.get_matched(),
+            matcher.get_orphans()
+        );
+    }
+    summary.duplicate_pad_traces = *evb.get_duplicate_pad_traces();
+    summary.peak_frames_per_event = *evb.get_peak_frames() as u64;
+    summary.oversized_events = *evb.get_oversized_events();
+    summary.timestamp_glitches = fixer.glitches;
+    summary.timestamp_rollovers = fixer.rollovers;
+    summary.missing_timestampother = fixer.missing_timestampother;
+    let frame_types = log_frame_types(merger.get_frame_type_counts());
+    summary.partial_frames = frame_types.partial;
+    summary.full_frames = frame_types.full;
+    summary.graw_gaps = merger.get_chunk_gaps().to_vec();
+    summary.bad_data = *merger.get_bad_data();
+    spdlog::info!("Done with get data.");
+    if summary.bad_data.total() > 0 {
+        spdlog::warn!(
+            "Left out {} GET data out of the range of the hardware ({}). Check the electronics for a noisy channel!",
+            summary.bad_data.total(),
+            summary.bad_data
+        );
+    }
+    if fixer.glitches > 0 {
+        spdlog::info!("Corrected {} glitched GET timestamps.", fixer.glitches);
+    }
+    if fixer.missing_timestampother > 0 {
+        let replacement = if config.timestampother_fallback {
+            "it was replaced by the GET timestamp"
+        } else {
+            "it was left at 0"
+        };
+        spdlog::warn!(
+            "{} of the GET events had no frame from CoBo {}, so they have no timestampother (synchronized with FRIBDAQ); {}. Check the electronics of CoBo {}!",
+            fixer.missing_timestampother,
+            COBO_WITH_TIMESTAMP,
+            replacement,
+            COBO_WITH_TIMESTAMP
+        );
+    }
+    if *evb.get_late_frames() > 0 {
+        spdlog::warn!(
+            "Dropped {} late frames which arrived after their event was built.",
+            evb.get_late_frames()
+        );
+    }
+    spdlog::info!(
+        "At most {} frames were held for an event.",
+        evb.get_peak_frames()
+    );
+    if summary.spilled_events > 0 {
+        spdlog::info!(
+            "Spilled the frames of {} events with more than {} frames to disk.",
+            summary.spilled_events,
+            config.spill_threshold_frames
+        );
+    }
+    if *evb.get_oversized_events() > 0 {
+        spdlog::warn!(
+            "{} events reached the maximum of {} frames and were emitted early. Check the event ids of the GET data!",
+            evb.get_oversized_events(),
+            config.max_frames_per_event
+        );
+    }
+    if *evb.get_duplicate_pad_traces() > 0 {
+        spdlog::warn!(
+            "{} traces were of a pad which already had a trace from another channel. Check the pad map with attpc_merger_cli lint-map!",
+            evb.get_duplicate_pad_traces()
+        );
+    }
+    Ok(get_event_numbers)
+}
+
+/// process_run, additionally showing the merged data to the given hooks.
+///
+/// The hooks see every GET event and FRIBDAQ physics event before it is written, and the summary of the run
+/// once the output is closed. See EventHook. Errors are returned as a RunError, carrying the run number.
+pub fn process_run_with_hooks(
+    config: &Config,
+    run_number: i32,
+    tx: &Sender<WorkerStatus>,
+    worker_id: &usize,
+    hooks: &mut [&mut dyn EventHook],
+) -> Result<(), ProcessorError> {
+    merge_run(config, run_number, tx, worker_id, hooks, false)
+        .map(|_| ())
+        .map_err(|e| e.with_run(run_number))
+}
+
+/// Look up a run in the runs database of the Config. Returns None if there is no database or the run is not in it
+///
+/// The database is read again for every run, so that it can be edited during a long merge
+fn get_run_record(config: &Config, run_number: i32) -> Result<Option<RunRecord>, ProcessorError> {
+    match &config.run_database_path {
+        Some(path) => Ok(RunDatabase::new(path)
+            .map_err(|e| ProcessorError::from(e).with_path(path))?
+            .get_record(&run_number)
+            .cloned()),
+        None => Ok(None),
+    }
+}
+
+/// The body of process_run_with_hooks, whose errors are given the run number
+///
+/// If defer_post_merge is true, the post-merge action is not applied. Instead the source files it should be applied
+/// to are returned, which is empty if there is no action or the run did not pass the checks for it.
+fn merge_run(
+    config: &Config,
+    run_number: i32,
+    tx: &Sender<WorkerStatus>,
+    worker_id: &usize,
+    hooks: &mut [&mut dyn EventHook],
+    defer_post_merge: bool,
+) -> Result<Vec<PathBuf>, ProcessorError> {
+    let run_start = Instant::now();
+    // The runs database can flag the run as bad, or override the Config for it
+    let record = get_run_record(config, run_number)?;
+    if record.as_ref().is_some_and(|record| !record.is_good) {
+        spdlog::info!(
+            "Run {} is flagged bad in the runs database, skipping...",
+            run_number
+        );
+        tx.send(WorkerStatus::new(1.0, run_number, *worker_id))?;
+        return Ok(Vec::new());
+    }
+    let run_config = record.as_ref().map(|record| record.apply(config));
+    let config = run_config.as_ref().unwrap_or(config);
+    let hdf_path = config.get_hdf_file_name(run_number)?;
+    // Without GET data, the evt data is all there is
+    let require_evt = config.require_evt || !config.merge_get;
+    // Check for the evt data up front, so that runs missing required FRIB data fail before any merging
+    let evt_dir = match config.get_evt_directory(run_number) {
+        Err(e) if require_evt => {
+            spdlog::error!("Could not access evt directory: {e}, but evt data is required!");
+            return Err(e.into());
+        }
+        dir => dir,
+    };
+    let pad_map = PadMap::new(config.pad_map_path.as_deref()).map_err(|e| {
+        let e = ProcessorError::from(e);
+        match &config.pad_map_path {
+            Some(path) => e.with_path(path),
+            None => e,
+        }
+    })?;
+    let pedestals = match &config.pedestal_path {
+        Some(path) => {
+            Some(Pedestals::new(path).map_err(|e| ProcessorError::from(e).with_path(path))?)
+        }
+        None => None,
+    };
+
+    //Initialize the merger and output sink. Without GET data, there is nothing to merge
+    let mut merger = if config.merge_get {
+        let merger = Merger::new(config, run_number)?;
+        spdlog::info!(
+            "Total run size: {}",
+            human_bytes::human_bytes(*merger.get_total_data_size() as f64)
+        );
+        Some(merger)
+    } else {
+        None
+    };
+    let layout = HdfLayout::default();
+    clean_partial_output(config, &hdf_path, &layout)?;
+    remove_old_parts(&hdf_path)?;
+    // The checksums of a previous merge of the run would not match the new output, even those of parts which are gone
+    let stale_checksums = std::iter::once(get_checksum_path(&hdf_path)).chain(find_part_files(
+        &hdf_path,
+        &format!(".h5.{}", CHECKSUM_EXTENSION),
+    ));
+    for stale_checksum in stale_checksums.filter(|path| path.exists()) {
+        std::fs::remove_file(&stale_checksum)
+            .map_err(|e| ProcessorError::ChecksumError(e).with_path(&stale_checksum))?;
+    }
+    // Errors of the output don't say which file they are from
+    let output_context = |e: ProcessorError| match e {
+        ProcessorError::HDFError(_) | ProcessorError::SinkError(_) => e.with_path(&hdf_path),
+        e => e,
+    };
+    let mut writer =
+        make_sink(config, &hdf_path, pedestals, &layout).map_err(|e| output_context(e.into()))?;
+    if let Some(record) = &record {
+        writer
+            .write_run_attributes(&record.attributes)
+            .map_err(|e| output_context(e.into()))?;
+    }
+
+    //Handle the get data
+    let mut summary = MergeSummary {
+        run_number,
+        ..Default::default()
+    };
+    let mut source_files = Vec::new();
+    let mut get_event_numbers = Vec::new();
+    match merger.as_mut() {
+        Some(merger) => {
+            source_files = merger.get_file_paths();
+            get_event_numbers = merge_get_data(
+                config,
+                merger,
+                pad_map,
+                writer.as_mut(),
+                hooks,
+                &mut summary,
+                tx,
+                worker_id,
+            )
+            .map_err(output_context)?;
+        }
+        None => spdlog::info!("GET data is not merged, only processing evt data..."),
+    }
+
+    // Handle evt data if present. If evt data is required, any failure is an error for the run
+    let mut evt_error = false;
+    match evt_dir {
+        Ok(evt_path) => {
+            spdlog::info!("Now processing evt data...");
+            match process_evt_data(
+                evt_path,
+                &get_event_numbers,
+                writer.as_mut(),
+                hooks,
+                &mut summary,
+                config,
+                run_number,
+                tx,
+                worker_id,
+            ) {
+                Ok(evt_files) => {
+                    source_files.extend(evt_files);
+                    spdlog::info!("Done with evt data.")
+                }
+                Err(e) if require_evt => {
+                    let e = output_context(e);
+                    spdlog::error!(
+                        "Error while processing evt data: {e}, but evt data is required!"
+                    );
+                    return Err(e);
+                }
+                Err(e) => {
+                    evt_error = true;
+                    spdlog::warn!("Error while processing evt data: {e}\nSkipping evt processing.")
+                }
+            }
+        }
+        Err(e) => {
+            spdlog::warn!("Could not access evt directory: {e}");
+            spdlog::warn!("Skipping processing evt data...");
+        }
+    }
+
+    // Without GET data, the events are the FRIBDAQ physics events
+    let last_event = if config.merge_get {
+        writer.get_last_get_event()
+    } else {
+        summary.frib_physics.saturating_sub(1)
+    };
+    // When the run is split, the last event is in the last part
+    let last_path = writer
+        .get_event_path(&last_event)
+        .unwrap_or_else(|| hdf_path.clone());
+    let outputs = writer.close().map_err(|e| output_context(e.into()))?;
+    // The output is readable, but the run was cut short, so it is marked incomplete and merged again by the next
+    // merge of the run (see clean_partial_output)
+    if config.stop_flag.is_stop_requested() {
+        for output in outputs.iter() {
+            mark_output_incomplete(config, output, &layout).map_err(|e| e.with_path(output))?;
+        }
+        spdlog::warn!(
+            "Run {} was stopped, {} only has part of the run and was marked incomplete.",
+            run_number,
+            hdf_path.display()
+        );
+        return Err(ProcessorError::Stopped);
+    }
+    summary.timings.total = run_start.elapsed();
+    spdlog::info!(
+        "{} {} -- {}",
+        STAGE_TIMING_MESSAGE,
+        run_number,
+        summary.timings
+    );
+    if config.write_checksums {
+        write_output_checksums(&outputs, &mut summary, tx, worker_id)?;
+    }
+    // Upload before the hooks, so they see the outcome in the summary
+    #[cfg(feature = "s3")]
+    let uploaded_files = upload_output(
+        config,
+        &get_output_files(&outputs),
+        &mut summary,
+        tx,
+        worker_id,
+    );
+    #[cfg(not(feature = "s3"))]
+    let uploaded_files: Vec<PathBuf> = match &config.upload_url {
+        Some(url) => {
+            spdlog::warn!("Can not upload to {url}, the merger was built without the s3 feature");
+            Vec::new()
+        }
+        None => Vec::new(),
+    };
+    for hook in hooks.iter_mut() {
+        hook.on_close(&summary);
+    }
+
+    // Only touch the source files if everything went well
+    let mut deferred_files = Vec::new();
+    if config.post_merge_action.is_some() {
+        if evt_error {
+            spdlog::warn!(
+                "There was an error processing the evt data, skipping the post-merge action."
+            );
+        } else if !verify_output(config, &last_path, &layout, &last_event)
+            .map_err(|e| e.with_path(&last_path))?
+        {
+            spdlog::error!(
+                "Merged file {} failed verification, skipping the post-merge action.",
+                last_path.display()
+            );
+        } else if defer_post_merge {
+            deferred_files = source_files;
+        } else {
+            apply_post_merge_action(config, &source_files, tx, run_number, worker_id)?;
+        }
+    }
+    // The local copy is only removed once it is verified, and the object store has a verified copy of every file
+    if config.delete_after_upload && !uploaded_files.is_empty() {
+        if summary.upload_error.is_some() {
+            spdlog::warn!(
+                "Not every file of run {run_number} was uploaded, keeping the local copies."
+            );
+        } else {
+            for path in uploaded_files.iter() {
+                std::fs::remove_file(path)
+                    .map_err(|e| ProcessorError::PostMergeError(e).with_path(path))?;
+                spdlog::info!("Deleted the uploaded file {}", path.display());
+            }
+        }
+    }
+    let counts = RunCounts {
+        events: summary.get_events,
+        bytes_read: merger
+            .as_ref()
+            .map(|merger| *merger.get_total_data_size())
+            .unwrap_or(0),
+        dropped_frames: summary.late_frames,
+    };
+    tx.send(WorkerStatus::new(1.0, run_number, *worker_id).with_counts(counts))?;
+
+    Ok(deferred_files)
+}
+
+/// The function to be called by a separate thread (typically the UI).
+/// This particular flavor is unused by the default tools (attpc_merger and attpc_merger_cli)
+/// but could be useful to someone else
+/// Allows multiple runs to be processed
+pub fn process(
+    config: Config,
+    tx: Sender<WorkerStatus>,
+    worker_id: usize,
+) -> Result<(), ProcessorError> {
+    let runs: Vec<i32> = (config.first_run_number..(config.last_run_number + 1)).collect();
+    check_disk_space(&config, &runs)?;
+    for run in runs {
+        if config.stop_flag.is_stop_requested() {
+            break;
+        }
+        tx.send(WorkerStatus::new(0.0, run, worker_id))?;
+        set_log_run(Some(run));
+        if config.does_run_exist(run) {
+            spdlog::info!("Processing run {}...", run);
+            process_run(&config, run, &tx, &worker_id)?;
+            spdlog::info!("Finished processing run {}.", run);
+        } else {
+            spdlog::info!("Run {} does not exist, skipping...", run);
+        }
+    }
+    set_log_run(None);
+    Ok(())
+}
+
+/// The runs left to merge, shared by the workers
+///
+/// Each worker takes the next run once it finishes its current one, so a worker merging a large run does not hold up
+/// the runs behind it while the other workers are idle.
+#[derive(Debug, Default)]
+pub struct RunQueue {
+    runs: Mutex<VecDeque<i32>>,
+}
+
+impl RunQueue {
+    /// Make a queue of the run range of the Config, in order
+    pub fn new(config: &Config) -> Self {
+        Self {
+            runs: Mutex::new((config.first_run_number..(config.last_run_number + 1)).collect()),
+        }
+    }
+
+    /// Take the next run to merge. Returns None once every run has been taken
+    pub fn next_run(&self) -> Option<i32> {
+        self.runs
+            .lock()
+            .expect("The run queue was poisoned!")
+            .pop_front()
+    }
+
+    /// The number of runs not yet taken
+    pub fn len(&self) -> usize {
+        self.runs.lock().expect("The run queue was poisoned!").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Make a queue of the given runs, in order
+impl From<Vec<i32>> for RunQueue {
+    fn from(runs: Vec<i32>) -> Self {
+        Self {
+            runs: Mutex::new(runs.into()),
+        }
+    }
+}
+
+/// The number of workers to start: n_threads, but no more than there are runs to merge
+pub fn get_n_workers(config: &Config) -> usize {
+    let n_runs = (config.last_run_number - config.first_run_number + 1).max(0) as usize;
+    (config.n_threads as usize).min(n_runs)
+}
+
+/// Process runs from the queue until it is empty
+///
+/// The disk space for the whole run range should be checked before the workers are started (see check_disk_space).
+pub fn process_queue(
+    config: Config,
+    tx: Sender<WorkerStatus>,
+    worker_id: usize,
+    queue: Arc<RunQueue>,
+) -> Result<(), ProcessorError> {
+    if config.need_copy_files() {
+        return process_queue_copy_ahead(&config, tx, worker_id, &queue);
+    }
+    while let Some(run) = queue.next_run() {
+        if config.stop_flag.is_stop_requested() {
+            break;
+        }
+        tx.send(WorkerStatus::new(0.0, run, worker_id))?;
+        set_log_run(Some(run));
+        if config.does_run_exist(run) {
+            spdlog::info!("Processing run {}...", run);
+            process_run(&config, run, &tx, &worker_id)?;
+            spdlog::info!("Finished processing run {}.", run);
+        } else {
+            spdlog::info!("Run {} does not exist, skipping...", run);
+        }
+    }
+    set_log_run(None);
+    Ok(())
+}
+
+/// Process a subset of runs
+///
+/// Kept for existing callers; the runs are merged as by process_queue, with a queue of their own.
+#[deprecated(note = "use process_queue with a RunQueue shared by the workers")]
+pub fn process_subset(
+    config: Config,
+    tx: Sender<WorkerStatus>,
+    worker_id: usize,
+    subset: Vec<i32>,
+) -> Result<(), ProcessorError> {
+    check_disk_space(&config, &subset)?;
+    process_queue(config, tx, worker_id, Arc::new(RunQueue::from(subset)))
+}
+
+/// Divide a run range in to a set of subranges (per thread/worker)
+///
+/// Kept for existing callers of process_subset; the runs are dealt out round-robin, so a worker with large runs
+/// holds up the merge.
+#[deprecated(note = "use a RunQueue shared by get_n_workers workers")]
+pub fn create_subsets(config: &Config) -> Vec<Vec<i32>> {
+    let mut subsets: Vec<Vec<i32>> = vec![Vec::new(); config.n_threads as usize];
+    let n_subsets = subsets.len();
+
+    for (idx, run) in (config.first_run_number..(config.last_run_number + 1)).enumerate() {
+        subsets[idx % n_subsets].push(run)
+    }
+
+    subsets
+}
+
+/// process_queue, copying the source files of each run to the post-merge destination while the next run is merged
+///
+/// The copy is network bound and the merge CPU bound, so overlapping them keeps both busy. At most one copy runs in
+/// the background, and it is finished before the copy of the next run starts. While a copy runs, the statuses of the
+/// merge are tagged with the run being copied. A failed copy only fails its own run: it is logged, the remaining runs
+/// are still merged, and the first failure is returned at the end.
+fn process_queue_copy_ahead(
+    config: &Config,
+    tx: Sender<WorkerStatus>,
+    worker_id: usize,
+    queue: &RunQueue,
+) -> Result<(), ProcessorError> {
+    let copying: Mutex<Option<i32>> = Mutex::new(None);
+    let (merge_tx, merge_rx) = std::sync::mpsc::channel::<WorkerStatus>();
+    let result = std::thread::scope(|scope| {
+        let copying = &copying;
+        scope.spawn(move || {
+            for status in merge_rx {
+                let background_copy = *copying.lock().expect("The copy state was poisoned!");
+                if tx
+                    .send(status.with_background_copy(background_copy))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+        merge_and_copy_ahead(scope, config, merge_tx, worker_id, queue, copying)
+    });
+    set_log_run(None);
+    result
+}
+
+/// The loop of process_queue_copy_ahead, run within the scope of the copy threads
+///
+/// Owns the sender of the merge statuses, so that the status thread ends with the loop.
+fn merge_and_copy_ahead<'scope, 'env>(
+    scope: &'scope Scope<'scope, 'env>,
+    config: &'env Config,
+    tx: Sender<WorkerStatus>,
+    worker_id: usize,
+    queue: &RunQueue,
+    copying: &'env Mutex<Option<i32>>,
+) -> Result<(), ProcessorError> {
+    let mut copy: Option<ScopedJoinHandle<'scope, Result<(), ProcessorError>>> = None;
+    let mut copy_error: Option<ProcessorError> = None;
+    let mut finish_copy = |copy: Option<ScopedJoinHandle<'scope, Result<(), ProcessorError>>>| {
+        if let Some(handle) = copy {
+            let result = handle.join().expect("The copy thread panicked!");
+            *copying.lock().expect("The copy state was poisoned!") = None;
+            if let Err(e) = result {
+                spdlog::error!("Failed to copy the source files: {e}");
+                copy_error.get_or_insert(e);
+            }
+        }
+    };
+    while let Some(run) = queue.next_run() {
+        if config.stop_flag.is_stop_requested() {
+            break;
+        }
+        tx.send(WorkerStatus::new(0.0, run, worker_id))?;
+        set_log_run(Some(run));
+        if !config.does_run_exist(run) {
+            spdlog::info!("Run {} does not exist, skipping...", run);
+            continue;
+        }
+        spdlog::info!("Processing run {}...", run);
+        let merged = merge_run(config, run, &tx, &worker_id, &mut [], true);
+        finish_copy(copy.take());
+        let files = merged.map_err(|e| e.with_run(run))?;
+        spdlog::info!("Finished processing run {}.", run);
+        if files.is_empty() {
+            continue;
+        }
+        *copying.lock().expect("The copy state was poisoned!") = Some(run);
+        copy = Some(scope.spawn(move || {
+            set_log_run(Some(run));
+            // The progress of the copy is not sent, the statuses of the merge are tagged with the run instead
+            let (copy_tx, _) = std::sync::mpsc::channel::<WorkerStatus>();
+            apply_post_merge_action(config, &files, &copy_tx, run, &worker_id)
+                .map_err(|e| e.with_run(run))
+        }));
+    }
+    finish_copy(copy.take());
+    match copy_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Check that there is enough space in the HDF5 directory for the merged output of the runs.
+///
+/// The output size is estimated as the size of the GRAW files times the output_size_ratio of the Config.
+/// If the available space cannot be queried, the check is skipped with a warning.
+pub fn check_disk_space(config: &Config, runs: &[i32]) -> Result<(), ProcessorError> {
+    let graw_size: u64 = runs.iter().map(|run| config.get_run_size_bytes(*run)).sum();
+    let required = (graw_size as f64 * config.output_size_ratio) as u64;
+    let available = match fs2::available_space(&config.hdf_path) {
+        Ok(space) => space,
+        Err(e) => {
+            spdlog::warn!(
+                "Could not check the available space in {}: {e}. Skipping the disk space check.",
+                config.hdf_path.display()
+            );
+            return Ok(());
+        }
+    };
+    spdlog::info!(
+        "Estimated output size: {}, available space: {}",
+        human_bytes::human_bytes(required as f64),
+        human_bytes::human_bytes(available as f64)
+    );
+    if required > available {
+        return Err(ProcessorError::InsufficientDiskSpace(
+            config.hdf_path.clone(),
+            required,
+            available,
+        ));
+    }
+    Ok(())
+}
+
+//Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{
+        NUMBER_OF_COBOS, NUMBER_OF_FLAG_COLUMNS, NUMBER_OF_HARDWARE_COLUMNS,
+        NUMBER_OF_QUICKLOOK_COLUMNS, NUMBER_OF_TIME_BUCKETS,
+    };
+    use crate::event_sink::get_part_path;
+    use crate::graw_frame::GrawData;
+    use crate::stop_flag::StopFlag;
+    use crate::test_data::*;
+    use std::sync::mpsc;
+
+    /// Write a two event run (number 1) of GET data to the directory and make a config for it
+    fn write_synthetic_get_run(dir: &std::path::Path) -> Config {
+        write_synthetic_get_run_events(dir, 2)
+    }
+
+    /// Write a run (number 1) of n_events GET events to the directory and make a config for it
+    ///
+    /// Event n has timestamp 1000 * (n + 1)
+    fn write_synthetic_get_run_events(dir: &std::path::Path, n_events: u32) -> Config {
+        let config = Config {
+            graw_path: dir.join("graw"),
+            evt_path: dir.join("evt"),
+            hdf_path: dir.join("hdf"),
+            first_run_number: 1,
+            last_run_number: 1,
+            ..Default::default()
+        };
+
+        // Every CoBo needs a directory, but only CoBo 0 AsAd 0 has data
+        for cobo in 0..NUMBER_OF_COBOS {
+            std::fs::create_dir_all(config.graw_path.join("run_0001").join(format!("mm{cobo}")))
+                .unwrap();
+        }
+        // CoBo 0, AsAd 0, AGET 0, channels 0 and 1 are pads 2817 and 3485 in the default map
+        let frames: Vec<Vec<u8>> = (0..n_events)
+            .map(|event_id| {
+                let data: Vec<GrawData> = (0..2)
+                    .map(|channel| GrawData {
+                        aget_id: 0,
+                        channel,
+                        time_bucket_id: 10 + event_id as u16,
+                        sample: 100 * (event_id as i16 + 1) + channel as i16,
+                    })
+                    .collect();
+                make_graw_frame(event_id, 1000 * (event_id as u64 + 1), 0, 0, &data)
+            })
+            .collect();
+        write_asad_file(
+            &config.graw_path.join("run_0001").join("mm0"),
+            0,
+            0,
+            &frames,
+        )
+        .unwrap();
+        std::fs::create_dir_all(&config.hdf_path).unwrap();
+        config
+    }
+
+    /// Write the evt data matching write_synthetic_get_run
+    fn write_synthetic_evt_run(config: &Config) {
+        let evt_dir = config.evt_path.join("run1");
+        std::fs::create_dir_all(&evt_dir).unwrap();
+        write_evt_file(
+            &evt_dir,
+            1,
+            &[
+                make_begin_run_ring(1, 10, "synthetic"),
+                make_scalers_ring(0, 2, 11, &[5, 6, 7]),
+                make_physics_ring(0, 12, &[(1, 2), (3, 4)], 0x1),
+                make_physics_ring(1, 13, &[(5, 6), (7, 8)], 0x2),
+                make_end_run_ring(20, 10),
+            ],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_process_synthetic_run() {
+        let dir =
+            std::env::temp_dir().join(format!("attpc_merger_pipeline_{}", std::process::id()));
+        let run_number = 1;
+        let config = write_synthetic_get_run(&dir);
+        write_synthetic_evt_run(&config);
+
+        let (tx, rx) = mpsc::channel::<WorkerStatus>();
+        process_run(&config, run_number, &tx, &0).unwrap();
+        assert_eq!(rx.try_iter().last().unwrap().progress, 1.0);
+
+        let file = hdf5::File::open(config.get_hdf_file_name(run_number).unwrap()).unwrap();
+        let events = file.group("events").unwrap();
+        let read_u64 = |name: &str| events.attr(name).unwrap().read_scalar::<u64>().unwrap();
+        let read_u32 = |name: &str| events.attr(name).unwrap().read_scalar::<u32>().unwrap();
+        assert_eq!(read_u64("min_event"), 0);
+        assert_eq!(read_u64("max_event"), 1);
+        assert_eq!(read_u64("min_get_ts"), 1000);
+        assert_eq!(read_u64("max_get_ts"), 2000);
+        assert_eq!(read_u32("frib_run"), 1);
+        assert_eq!(read_u32("frib_start"), 10);
+        assert_eq!(read_u32("frib_stop"), 20);
+        assert_eq!(read_u32("frib_time"), 10);
+        assert_eq!(
+            events.attr("cobos").unwrap().read_raw::<u8>().unwrap(),
+            vec![0]
+        );
+        assert_eq!(
+            events
+                .attr("time_buckets")
+                .unwrap()
+                .read_scalar::<u16>()
+                .unwrap(),
+            NUMBER_OF_TIME_BUCKETS as u16
+        );
+
+        for event_id in 0..2 {
+            let event = events.group(&format!("event_{event_id}")).unwrap();
+            let traces_dset = event.dataset("get_traces").unwrap();
+            assert_eq!(
+                traces_dset
+                    .attr("id")
+                    .unwrap()
+                    .read_scalar::<u32>()
+                    .unwrap(),
+                event_id
+            );
+            let read_str = |name: &str| {
+                traces_dset
+                    .attr(name)
+                    .unwrap()
+                    .read_scalar::<hdf5::types::VarLenUnicode>()
+                    .unwrap()
+                    .to_string()
+            };
+            assert_eq!(
+                read_str("columns"),
+                "cobo,asad,aget,channel,pad,samples[512]"
+            );
+            assert_eq!(read_str("dtype"), "int16");
+            let traces = traces_dset.read_2d::<i16>().unwrap();
+            assert_eq!(
+                traces.shape(),
+                [
+                    2,
+                    NUMBER_OF_HARDWARE_COLUMNS + NUMBER_OF_TIME_BUCKETS as usize
+                ]
+            );
+            for row in traces.rows() {
+                let channel = row[3];
+                let expected_pad = if channel == 0 { 2817 } else { 3485 };
+                assert_eq!(row[4], expected_pad);
+                let bucket = 5 + 10 + event_id as usize;
+                assert_eq!(row[bucket], 100 * (event_id as i16 + 1) + channel);
+                assert_eq!(row.iter().skip(5).filter(|s| **s != 0).count(), 1);
+            }
+            let flags = event
+                .dataset("get_traces_flags")
+                .unwrap()
+                .read_2d::<u16>()
+                .unwrap();
+            assert_eq!(flags.shape(), [2, NUMBER_OF_FLAG_COLUMNS]);
+
+            let physics = event.group("frib_physics").unwrap();
+            assert_eq!(
+                physics.attr("id").unwrap().read_scalar::<u32>().unwrap(),
+                event_id
+            );
+            let coinc = physics.dataset("977").unwrap().read_raw::<u16>().unwrap();
+            assert_eq!(coinc, vec![event_id as u16 + 1]);
+            let fadc = physics.dataset("1903").unwrap().read_2d::<u16>().unwrap();
+            assert_eq!(fadc.shape(), [2, 8]);
+            assert_eq!(fadc[[0, 1]], (4 * event_id + 1) as u16);
+            assert_eq!(fadc[[0, 0]], (4 * event_id + 2) as u16);
+        }
+
+        // One row per event: event, npads, total_charge
+        let quicklook = events
+            .dataset("quicklook")
+            .unwrap()
+            .read_2d::<u64>()
+            .unwrap();
+        assert_eq!(quicklook.shape(), [2, NUMBER_OF_QUICKLOOK_COLUMNS]);
+        assert_eq!(quicklook.row(0).to_vec(), vec![0, 2, 201]);
+        assert_eq!(quicklook.row(1).to_vec(), vec![1, 2, 401]);
+
+        let scalers = file.group("scalers").unwrap();
+        assert_eq!(
+            scalers
+                .attr("offset_divisor")
+                .unwrap()
+                .read_scalar::<u32>()
+                .unwrap(),
+            config.scaler_offset_divisor
+        );
+        let scaler_dset = scalers.dataset("event_0").unwrap();
+        assert_eq!(scaler_dset.read_raw::<u32>().unwrap(), vec![5, 6, 7]);
+        let duration = scaler_dset.attr("duration_s").unwrap();
+        assert_eq!(duration.read_scalar::<f64>().unwrap(), 2.0);
+        let rates = scaler_dset.attr("rate_hz").unwrap();
+        assert_eq!(rates.read_raw::<f64>().unwrap(), vec![2.5, 3.0, 3.5]);
+
+        drop(file);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Keeps the summary of the run, to check what the hooks are given
+    #[derive(Default)]
+    struct SummaryHook {
+        physics_events: Vec<u64>,
+        summary: Option<MergeSummary>,
+    }
+
+    impl EventHook for SummaryHook {
+        fn on_frib_physics(&mut self, _physics: &PhysicsItem, event_counter: u64) {
+            self.physics_events.push(event_counter);
+        }
+
+        fn on_close(&mut self, summary: &MergeSummary) {
+            self.summary = Some(summary.clone());
+        }
+    }
+
+    #[test]
+    fn test_reproducible_output() {
+        let dir =
+            std::env::temp_dir().join(format!("attpc_merger_reproducible_{}", std::process::id()));
+        let config = write_synthetic_get_run(&dir);
+        let (tx, _rx) = mpsc::channel::<WorkerStatus>();
+
+        // Merging the same data twice writes the same traces, row for row
+        let mut merges = Vec::new();
+        for _ in 0..2 {
+            process_run(&config, 1, &tx, &0).unwrap();
+            let file = hdf5::File::open(config.get_hdf_file_name(1).unwrap()).unwrap();
+            let traces: Vec<Vec<i16>> = (0..2)
+                .map(|event_id| {
+                    file.dataset(&format!("events/event_{event_id}/get_traces"))
+                        .unwrap()
+                        .read_raw::<i16>()
+                        .unwrap()
+                })
+                .collect();
+            merges.push(traces);
+        }
+        assert_eq!(merges[0], merges[1]);
+        // The pad column (4) is in order, 2817 before 3485
+        let pads: Vec<i16> = merges[0][0]
+            .chunks(NUMBER_OF_HARDWARE_COLUMNS + NUMBER_OF_TIME_BUCKETS as usize)
+            .map(|row| row[4])
+            .collect();
+        assert_eq!(pads, vec![2817, 3485]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_hooks() {
+        let dir = std::env::temp_dir().join(format!("attpc_merger_hooks_{}", std::process::id()));
+        let config = Config {
+            write_checksums: true,
+            ..write_synthetic_get_run(&dir)
+        };
+        write_synthetic_evt_run(&config);
+
+        // The events are 1000 ticks (10 us) apart
+        let mut rates = crate::event_hook::RateHistogram::new(1.0e-5);
+        let mut summary = SummaryHook::default();
+        let (tx, _rx) = mpsc::channel::<WorkerStatus>();
+        process_run_with_hooks(&config, 1, &tx, &0, &mut [&mut rates, &mut summary]).unwrap();
+
+        assert_eq!(rates.get_counts(), &[1, 1]);
+        assert!(rates
+            .get_rates_hz()
+            .iter()
+            .all(|rate| (rate - 1.0e5).abs() < 1.0e-6));
+        assert_eq!(summary.physics_events, vec![0, 1]);
+        let summary = summary.summary.unwrap();
+        assert!(summary.timings.total >= summary.timings.frame_read);
+        // The checksum of the output is written next to it
+        let hdf_path = config.get_hdf_file_name(1).unwrap();
+        let digest = crate::checksum::sha256_file(&hdf_path, |_| ()).unwrap();
+        assert_eq!(summary.checksums, vec![(hdf_path.clone(), digest.clone())]);
+        assert_eq!(
+            std::fs::read_to_string(get_checksum_path(&hdf_path)).unwrap(),
+            format!("{digest}  run_0001.h5\n")
+        );
+        let checksums = summary.checksums.clone();
+        // The timings differ from run to run
+        assert_eq!(
+            MergeSummary {
+                timings: StageTimings::default(),
+                ..summary
+            },
+            MergeSummary {
+                run_number: 1,
+                get_events: 2,
+                frib_physics: 2,
+                frib_scalers: 1,
+                // Only CoBo 0 has data
+                missing_timestampother: 2,
+                partial_frames: 2,
+                checksums,
+                ..Default::default()
+            }
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_frib_counter() {
+        let dir = std::env::temp_dir().join(format!("attpc_merger_counter_{}", std::process::id()));
+        let config = write_synthetic_get_run(&dir);
+        let evt_dir = config.evt_path.join("run1");
+        std::fs::create_dir_all(&evt_dir).unwrap();
+        // FRIBDAQ claims a third physics event, which is missing
+        write_evt_file(
+            &evt_dir,
+            1,
+            &[
+                make_begin_run_ring(1, 10, "synthetic"),
+                make_physics_ring(0, 12, &[(1, 2)], 0x1),
+                make_counter_ring(1, 12, 1),
+                make_physics_ring(1, 13, &[(5, 6)], 0x2),
+                make_counter_ring(2, 13, 3),
+                make_end_run_ring(20, 10),
+            ],
+        )
+        .unwrap();
+
+        let mut summary = SummaryHook::default();
+        let (tx, _rx) = mpsc::channel::<WorkerStatus>();
+        process_run_with_hooks(&config, 1, &tx, &0, &mut [&mut summary]).unwrap();
+        let summary = summary.summary.unwrap();
+        assert_eq!(summary.frib_physics, 2);
+        assert_eq!(summary.frib_counter, Some(3));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_built_physics() {
+        let dir = std::env::temp_dir().join(format!("attpc_merger_built_{}", std::process::id()));
+        let config = Config {
+            frib_source_id: Some(1),
+            write_frib_fragments: true,
+            ..write_synthetic_get_run(&dir)
+        };
+        let evt_dir = config.evt_path.join("run1");
+        std::fs::create_dir_all(&evt_dir).unwrap();
+        // The AT-TPC stack is source 1, with another source (4) built into the same events
+        let other = |event: u32| make_counter_ring(event, 12, event as u64);
+        write_evt_file(
+            &evt_dir,
+            1,
+            &[
+                make_begin_run_ring(1, 10, "synthetic"),
+                make_built_physics_ring(
+                    1000,
+                    &[(4, other(0)), (1, make_physics_ring(0, 12, &[(1, 2)], 0x1))],
+                ),
+                make_built_physics_ring(
+                    2000,
+                    &[(1, make_physics_ring(1, 13, &[(5, 6)], 0x2)), (4, other(1))],
+                ),
+                make_end_run_ring(20, 10),
+            ],
+        )
+        .unwrap();
+
+        let mut summary = SummaryHook::default();
+        let (tx, _rx) = mpsc::channel::<WorkerStatus>();
+        process_run_with_hooks(&config, 1, &tx, &0, &mut [&mut summary]).unwrap();
+        let summary = summary.summary.unwrap();
+        assert_eq!(summary.frib_physics, 2);
+        assert_eq!(summary.frib_other_fragments, 2);
+
+        let file = hdf5::File::open(config.get_hdf_file_name(1).unwrap()).unwrap();
+        let physics = file
+            .group("events")
+            .unwrap()
+            .group("event_1")
+            .unwrap()
+            .group("frib_physics")
+            .unwrap();
+        assert_eq!(physics.attr("id").unwrap().read_scalar::<u32>().unwrap(), 1);
+        assert_eq!(
+            physics
+                .attr("body_timestamp")
+                .unwrap()
+                .read_scalar::<u64>()
+                .unwrap(),
+            2000
+        );
+        let fragments = physics
+            .dataset("fragments")
+            .unwrap()
+            .read_raw::<u8>()
+            .unwrap();
+        assert_eq!(fragments.len(), 20 + other(1).len());
+        assert_eq!(fragments[20..], other(1)[..]);
+        drop(file);
+
+        // A source which is not in the data is an error
+        let config = Config {
+            frib_source_id: Some(2),
+            require_evt: true,
+            ..config
+        };
+        let (tx, _rx) = mpsc::channel::<WorkerStatus>();
+        assert!(process_run(&config, 1, &tx, &0).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn make_event_at(event_id: u32, timestamp: u64) -> Event {
+        let mut event = Event::new(
+            &PadMap::default(),
+            &Vec::new(),
+            NUMBER_OF_TIME_BUCKETS as usize,
+            Default::default(),
+        )
+        .unwrap();
+        event.event_id = event_id;
+        event.timestamp = timestamp;
+        event
+    }
+
+    #[test]
+    fn test_timestamp_glitch() {
+        let mut fixer = TimestampFixer::new(100);
+        let mut events: Vec<Event> = [1000, 995, 1010]
+            .iter()
+            .enumerate()
+            .map(|(id, ts)| make_event_at(id as u32, *ts))
+            .collect();
+        for event in events.iter_mut() {
+            fixer.fix(event);
+        }
+        assert_eq!(events[1].timestamp, 1001);
+        assert_eq!(events[1].raw_timestamp, Some(995));
+        assert_eq!(events[2].timestamp, 1010);
+        assert_eq!(events[2].raw_timestamp, None);
+        assert_eq!(fixer.glitches, 1);
+        assert_eq!(fixer.rollovers, 0);
+    }
+
+    #[test]
+    fn test_missing_timestampother() {
+        let mut fixer = TimestampFixer::new(100);
+        let mut event = make_event_at(0, 1000);
+        fixer.check_timestampother(&mut event, false);
+        assert_eq!(event.timestampother, 0);
+        fixer.check_timestampother(&mut event, true);
+        assert_eq!(event.timestampother, 1000);
+        assert_eq!(fixer.missing_timestampother, 2);
+    }
+
+    #[test]
+    fn test_timestamp_rollover() {
+        let mut fixer = TimestampFixer::new(100);
+        let mut events: Vec<Event> = [1_000_000, 10, 20]
+            .iter()
+            .enumerate()
+            .map(|(id, ts)| make_event_at(id as u32, *ts))
+            .collect();
+        for event in events.iter_mut() {
+            fixer.fix(event);
+        }
+        // The clock restarted, so the timestamps are kept and counting goes on from the new value
+        assert_eq!(events[1].timestamp, 10);
+        assert_eq!(events[1].raw_timestamp, None);
+        assert_eq!(events[2].timestamp, 20);
+        assert_eq!(fixer.glitches, 0);
+        assert_eq!(fixer.rollovers, 1);
+    }
+
+    #[test]
+    fn test_time_window() {
+        let dir =
+            std::env::temp_dir().join(format!("attpc_merger_time_window_{}", std::process::id()));
+        let config = Config {
+            min_timestamp: Some(1500),
+            ..write_synthetic_get_run(&dir)
+        };
+        // Give the physics items body header timestamps matching the GET events
+        std::fs::create_dir_all(config.evt_path.join("run1")).unwrap();
+        write_evt_file(
+            &config.evt_path.join("run1"),
+            1,
+            &[
+                make_begin_run_ring(1, 10, "synthetic"),
+                add_body_header(&make_physics_ring(0, 12, &[(1, 2)], 0x1), 1000, 0),
+                add_body_header(&make_physics_ring(1, 13, &[(5, 6)], 0x2), 2000, 0),
+                make_end_run_ring(20, 10),
+            ],
+        )
+        .unwrap();
+        let (tx, _rx) = mpsc::channel::<WorkerStatus>();
+        process_run(&config, 1, &tx, &0).unwrap();
+
+        // Only the second event is written, renumbered as the first
+        let file = hdf5::File::open(config.get_hdf_file_name(1).unwrap()).unwrap();
+        let events = file.group("events").unwrap();
+        let read_u64 = |name: &str| events.attr(name).unwrap().read_scalar::<u64>().unwrap();
+        assert_eq!(read_u64("max_event"), 0);
+        assert_eq!(read_u64("min_get_ts"), 2000);
+        let event = events.group("event_0").unwrap();
+        let read_id = |dset: &str| {
+            event
+                .dataset(dset)
+                .unwrap()
+                .attr("id")
+                .unwrap()
+                .read_scalar::<u32>()
+                .unwrap()
+        };
+        assert_eq!(read_id("get_traces"), 1);
+        let physics = event.group("frib_physics").unwrap();
+        assert_eq!(physics.attr("id").unwrap().read_scalar::<u32>().unwrap(), 1);
+        assert!(events.group("event_1").is_err());
+
+        drop(file);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_stop_requested() {
+        let dir = std::env::temp_dir().join(format!("attpc_merger_stop_{}", std::process::id()));
+        let config = write_synthetic_get_run(&dir);
+        write_synthetic_evt_run(&config);
+        let (tx, _rx) = mpsc::channel::<WorkerStatus>();
+
+        // The worker's clone of the config shares the flag, so the stop reaches it
+        let worker_config = config.clone();
+        config.stop_flag.request_stop();
+        let result = process_run(&worker_config, 1, &tx, &0);
+        assert!(matches!(
+            result,
+            Err(ProcessorError::RunError(1, ref e)) if matches!(**e, ProcessorError::Stopped)
+        ));
+        // The output is still closed, so it can be opened, but it is not complete
+        let hdf_path = config.get_hdf_file_name(1).unwrap();
+        let layout = HdfLayout::default();
+        assert!(hdf5::File::open(&hdf_path).is_ok());
+        assert!(!is_output_complete(&config, &hdf_path, &layout));
+
+        // No more runs are started
+        let (tx, rx) = mpsc::channel::<WorkerStatus>();
+        process_queue(
+            worker_config.clone(),
+            tx,
+            0,
+            Arc::new(RunQueue::new(&config)),
+        )
+        .unwrap();
+        assert_eq!(rx.iter().count(), 0);
+
+        // The next merge of the run replaces the stopped output
+        let mut config = config.clone();
+        config.stop_flag = StopFlag::default();
+        config.clean_partial_outputs = true;
+        let (tx, _rx) = mpsc::channel::<WorkerStatus>();
+        process_run(&config, 1, &tx, &0).unwrap();
+        assert!(is_output_complete(&config, &hdf_path, &layout));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_max_events_per_run() {
+        let dir = std::env::temp_dir().join(format!(
+            "attpc_merger_max_events_per_run_{}",
+            std::process::id()
+        ));
+        let config = Config {
+            max_events_per_run: Some(2),
+            ..write_synthetic_get_run_events(&dir, 3)
+        };
+        std::fs::create_dir_all(config.evt_path.join("run1")).unwrap();
+        write_evt_file(
+            &config.evt_path.join("run1"),
+            1,
+            &[
+                make_begin_run_ring(1, 10, "synthetic"),
+                make_physics_ring(0, 12, &[(1, 2)], 0x1),
+                make_physics_ring(1, 13, &[(3, 4)], 0x1),
+                make_physics_ring(2, 14, &[(5, 6)], 0x1),
+                make_scalers_ring(0, 2, 15, &[5, 6, 7]),
+                make_end_run_ring(20, 10),
+            ],
+        )
+        .unwrap();
+        let (tx, _rx) = mpsc::channel::<WorkerStatus>();
+        process_run(&config, 1, &tx, &0).unwrap();
+
+        // Only the first two events of each source are written, but the scalers and run info after them still are
+        let file = hdf5::File::open(config.get_hdf_file_name(1).unwrap()).unwrap();
+        let events = file.group("events").unwrap();
+        let read_u64 = |name: &str| events.attr(name).unwrap().read_scalar::<u64>().unwrap();
+        assert_eq!(read_u64("max_event"), 1);
+        assert_eq!(read_u64("max_get_ts"), 2000);
+        assert!(events
+            .group("event_1")
+            .unwrap()
+            .group("frib_physics")
+            .is_ok());
+        assert!(events.group("event_2").is_err());
+        assert!(file.group("scalers").unwrap().dataset("event_0").is_ok());
+        assert_eq!(
+            events
+                .attr("frib_run")
+                .unwrap()
+                .read_scalar::<u32>()
+                .unwrap(),
+            1
+        );
+
+        drop(file);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_event_naming() {
+        let dir =
+            std::env::temp_dir().join(format!("attpc_merger_event_naming_{}", std::process::id()));
+        let config = Config {
+            min_timestamp: Some(1500),
+            event_naming: EventNaming::GetEventId,
+            ..write_synthetic_get_run(&dir)
+        };
+        std::fs::create_dir_all(config.evt_path.join("run1")).unwrap();
+        write_evt_file(
+            &config.evt_path.join("run1"),
+            1,
+            &[
+                make_begin_run_ring(1, 10, "synthetic"),
+                add_body_header(&make_physics_ring(0, 12, &[(1, 2)], 0x1), 1000, 0),
+                add_body_header(&make_physics_ring(1, 13, &[(5, 6)], 0x2), 2000, 0),
+                make_end_run_ring(20, 10),
+            ],
+        )
+        .unwrap();
+        let (tx, _rx) = mpsc::channel::<WorkerStatus>();
+        process_run(&config, 1, &tx, &0).unwrap();
+
+        // Only the second event is written, keeping its GET event_id, along with its physics event
+        let file = hdf5::File::open(config.get_hdf_file_name(1).unwrap()).unwrap();
+        let events = file.group("events").unwrap();
+        let read_u64 = |name: &str| events.attr(name).unwrap().read_scalar::<u64>().unwrap();
+        assert_eq!(read_u64("min_event"), 1);
+        assert_eq!(read_u64("max_event"), 1);
+        assert!(events.group("event_0").is_err());
+        let event = events.group("event_1").unwrap();
+        assert!(event.dataset("get_traces").is_ok());
+        let physics = event.group("frib_physics").unwrap();
+        assert_eq!(physics.attr("id").unwrap().read_scalar::<u32>().unwrap(), 1);
+
+        assert_eq!(get_physics_event_number(&config, &[4, 9], 1), 9);
+        assert_eq!(get_physics_event_number(&config, &[4, 9], 3), 11);
+        assert_eq!(get_physics_event_number(&Config::default(), &[4, 9], 1), 1);
+
+        drop(file);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_si_merge_by_timestamp() {
+        let dir =
+            std::env::temp_dir().join(format!("attpc_merger_si_merge_{}", std::process::id()));
+        let config = Config {
+            si_merge_by_timestamp: true,
+            si_coincidence_window: 5,
+            ..write_synthetic_get_run_events(&dir, 4)
+        };
+        // The silicon CoBo missed the trigger of TPC event 1, so its ids lag by one from there on, and has an extra
+        // event at the end matching no TPC event
+        let frames: Vec<Vec<u8>> = [(0, 1001), (1, 3002), (2, 4000), (3, 9000)]
+            .iter()
+            .map(|(event_id, time)| make_graw_frame(*event_id, *time, COBO_WITH_TIMESTAMP, 0, &[]))
+            .collect();
+        write_asad_file(
+            &config
+                .graw_path
+                .join("run_0001")
+                .join(format!("mm{COBO_WITH_TIMESTAMP}")),
+            COBO_WITH_TIMESTAMP,
+            0,
+            &frames,
+        )
+        .unwrap();
+        let (tx, _rx) = mpsc::channel::<WorkerStatus>();
+        process_run(&config, 1, &tx, &0).unwrap();
+
+        let file = hdf5::File::open(config.get_hdf_file_name(1).unwrap()).unwrap();
+        let events = file.group("events").unwrap();
+        let read_timestampother = |group: &hdf5::Group, name: &str| {
+            group
+                .dataset(name)
+                .unwrap()
+                .attr("timestamp_other")
+                .unwrap()
+                .read_scalar::<u64>()
+                .unwrap()
+        };
+        let timestampothers: Vec<u64> = (0..4)
+            .map(|event| {
+                read_timestampother(
+                    &events.group(&format!("event_{event}")).unwrap(),
+                    "get_traces",
+                )
+            })
+            .collect();
+        // Matched by time, not by id
+        assert_eq!(timestampothers, vec![1001, 0, 3002, 4000]);
+        let orphans = file.group("orphan_silicon").unwrap();
+        assert_eq!(orphans.len(), 1);
+        assert_eq!(read_timestampother(&orphans, "event_0"), 9000);
+
+        drop(file);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_debug_frame_headers() {
+        let dir =
+            std::env::temp_dir().join(format!("attpc_merger_frame_headers_{}", std::process::id()));
+        let config = Config {
+            debug_frame_headers: true,
+            write_readout_info: true,
+            ..write_synthetic_get_run(&dir)
+        };
+        // A second AsAd, so that each event is built from two frames
+        let data = [GrawData {
+            aget_id: 0,
+            channel: 0,
+            time_bucket_id: 0,
+            sample: 1,
+        }];
+        let frames: Vec<Vec<u8>> = (0..2)
+            .map(|event_id| make_graw_frame(event_id, 1000 * (event_id as u64 + 1), 0, 1, &data))
+            .collect();
+        write_asad_file(
+            &config.graw_path.join("run_0001").join("mm0"),
+            0,
+            1,
+            &frames,
+        )
+        .unwrap();
+        let (tx, _rx) = mpsc::channel::<WorkerStatus>();
+        process_run(&config, 1, &tx, &0).unwrap();
+
+        let file = hdf5::File::open(config.get_hdf_file_name(1).unwrap()).unwrap();
+        let event = file.group("events").unwrap().group("event_1").unwrap();
+        let headers = event
+            .dataset("get_frame_headers")
+            .unwrap()
+            .read_2d::<u64>()
+            .unwrap();
+        assert_eq!(headers.shape(), [2, 12]);
+        let mut rows: Vec<Vec<u64>> = headers.rows().into_iter().map(|row| row.to_vec()).collect();
+        rows.sort_by_key(|row| row[9]); // by AsAd
+                                        // frame_size, frame_type, revision, header_size, item_size, n_items, event_time, event_id, cobo, asad, read_offset, status
+        assert_eq!(rows[0], vec![2, 1, 0, 1, 4, 2, 2000, 1, 0, 0, 0, 0]);
+        assert_eq!(rows[1], vec![2, 1, 0, 1, 4, 1, 2000, 1, 0, 1, 0, 0]);
+
+        // One row per AsAd: cobo, asad, frames, frame_type, revision, read_offset, status, aget_mask
+        let readout = event
+            .dataset("get_readout")
+            .unwrap()
+            .read_2d::<u64>()
+            .unwrap();
+        assert_eq!(readout.shape(), [2, 8]);
+        assert_eq!(readout.row(0).to_vec()[..7], [0, 0, 1, 1, 0, 0, 0]);
+        assert_eq!(readout.row(1).to_vec()[..7], [0, 1, 1, 1, 0, 0, 0]);
+
+        drop(file);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compression() {
+        let dir =
+            std::env::temp_dir().join(format!("attpc_merger_compression_{}", std::process::id()));
+        let config = Config {
+            compression_level: Some(4),
+            ..write_synthetic_get_run(&dir)
+        };
+        let (tx, _rx) = mpsc::channel::<WorkerStatus>();
+        process_run(&config, 1, &tx, &0).unwrap();
+
+        let file = hdf5::File::open(config.get_hdf_file_name(1).unwrap()).unwrap();
+        let events = file.group("events").unwrap();
+        for event_id in 0..2 {
+            let traces_dset = events
+                .group(&format!("event_{event_id}"))
+                .unwrap()
+                .dataset("get_traces")
+                .unwrap();
+            assert_eq!(
+                traces_dset.filters(),
+                vec![hdf5::filters::Filter::Deflate(4)]
+            );
+            let traces = traces_dset.read_2d::<i16>().unwrap();
+            assert_eq!(
+                traces[[0, 5 + 10 + event_id]],
+                100 * (event_id as i16 + 1) + traces[[0, 3]]
+            );
+        }
+
+        drop(file);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_partial_output() {
+        let dir = std::env::temp_dir().join(format!("attpc_merger_partial_{}", std::process::id()));
+        let config = write_synthetic_get_run(&dir);
+        let hdf_path = config.get_hdf_file_name(1).unwrap();
+        let partial_path = config.hdf_path.join("run_0001.h5.partial");
+        let (tx, _rx) = mpsc::channel::<WorkerStatus>();
+
+        // Left over from a crash; the run proceeds and the garbage is kept aside
+        std::fs::write(&hdf_path, b"not an hdf5 file").unwrap();
+        process_run(&config, 1, &tx, &0).unwrap();
+        assert_eq!(std::fs::read(&partial_path).unwrap(), b"not an hdf5 file");
+        assert!(HDFWriter::verify(&hdf_path, &HdfLayout::default(), &1).unwrap());
+
+        // A complete file is not touched before being overwritten
+        std::fs::remove_file(&partial_path).unwrap();
+        process_run(&config, 1, &tx, &0).unwrap();
+        assert!(!partial_path.exists());
+
+        let config = Config {
+            clean_partial_outputs: true,
+            ..config
+        };
+        std::fs::write(&hdf_path, b"").unwrap();
+        process_run(&config, 1, &tx, &0).unwrap();
+        assert!(!partial_path.exists());
+        assert!(HDFWriter::verify(&hdf_path, &HdfLayout::default(), &1).unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_split_output() {
+        let dir = std::env::temp_dir().join(format!("attpc_merger_split_{}", std::process::id()));
+        let config = Config {
+            max_events_per_file: Some(1),
+            ..write_synthetic_get_run(&dir)
+        };
+        write_synthetic_evt_run(&config);
+        let hdf_path = config.get_hdf_file_name(1).unwrap();
+        let (tx, _rx) = mpsc::channel::<WorkerStatus>();
+        process_run(&config, 1, &tx, &0).unwrap();
+        assert!(!hdf_path.exists());
+
+        // Event n is in part n + 1
+        for (part, timestamp) in [(0, 1000), (1, 2000)] {
+            let part_path = get_part_path(&hdf_path, part + 1);
+            assert!(part_path.ends_with(format!("run_0001_part{}.h5", part + 1)));
+            assert!(HDFWriter::verify(&part_path, &HdfLayout::default(), &part).unwrap());
+            let file = hdf5::File::open(&part_path).unwrap();
+            let events = file.group("events").unwrap();
+            let read_u64 = |name: &str| events.attr(name).unwrap().read_scalar::<u64>().unwrap();
+            assert_eq!(read_u64("min_event"), part);
+            assert_eq!(read_u64("max_event"), part);
+            assert_eq!(read_u64("min_get_ts"), timestamp);
+            assert_eq!(read_u64("max_get_ts"), timestamp);
+            assert_eq!(
+                events
+                    .attr("frib_run")
+                    .unwrap()
+                    .read_scalar::<u32>()
+                    .unwrap(),
+                1
+            );
+            assert!(events.group(&format!("event_{part}/frib_physics")).is_ok());
+            assert!(file.dataset("config/pad_map").is_ok());
+        }
+        // The scaler read before the first physics event stays with the first part
+        let first_part = hdf5::File::open(get_part_path(&hdf_path, 1)).unwrap();
+        assert!(first_part.dataset("scalers/event_0").is_ok());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_split_output_size() {
+        let dir =
+            std::env::temp_dir().join(format!("attpc_merger_split_size_{}", std::process::id()));
+        // Any event crosses the limit, so every event starts a new part
+        let config = Config {
+            max_output_gb: Some(1.0e-9),
+            write_checksums: true,
+            ..write_synthetic_get_run_events(&dir, 3)
+        };
+        let hdf_path = config.get_hdf_file_name(1).unwrap();
+        let (tx, _rx) = mpsc::channel::<WorkerStatus>();
+        process_run(&config, 1, &tx, &0).unwrap();
+        assert!(!hdf_path.exists());
+        assert!(!get_part_path(&hdf_path, 0).exists());
+        assert!(!get_part_path(&hdf_path, 4).exists());
+
+        for part in 0..3 {
+            let part_path = get_part_path(&hdf_path, part + 1);
+            assert!(HDFWriter::verify(&part_path, &HdfLayout::default(), &part).unwrap());
+            let file = hdf5::File::open(&part_path).unwrap();
+            let events = file.group("events").unwrap();
+            let read_u64 = |name: &str| events.attr(name).unwrap().read_scalar::<u64>().unwrap();
+            assert_eq!(read_u64("part"), part + 1);
+            assert_eq!(read_u64("min_event"), part);
+            assert_eq!(read_u64("max_event"), part);
+            assert_eq!(read_u64("min_get_ts"), 1000 * (part + 1));
+            let continues_from = events
+                .attr("continues_from")
+                .and_then(|attr| attr.read_scalar::<hdf5::types::VarLenUnicode>())
+                .map(|name| name.as_str().to_string())
+                .ok();
+            let expected = (part > 0).then(|| format!("run_0001_part{part}.h5"));
+            assert_eq!(continues_from, expected);
+        }
+
+        assert!(get_checksum_path(&get_part_path(&hdf_path, 3)).exists());
+
+        // Merging into fewer parts leaves none of the old parts behind, and a part of a merge which did not complete
+        // is set aside
+        std::fs::write(get_part_path(&hdf_path, 5), b"not an hdf5 file").unwrap();
+        let orphan_checksum = get_checksum_path(&get_part_path(&hdf_path, 7));
+        std::fs::write(&orphan_checksum, b"stale").unwrap();
+        let config = Config {
+            max_output_gb: None,
+            max_events_per_file: Some(2),
+            ..config
+        };
+        process_run(&config, 1, &tx, &0).unwrap();
+        let part_path = get_part_path(&hdf_path, 2);
+        assert!(HDFWriter::verify(&part_path, &HdfLayout::default(), &2).unwrap());
+        assert!(!get_part_path(&hdf_path, 3).exists());
+        assert!(!get_checksum_path(&get_part_path(&hdf_path, 3)).exists());
+        assert!(!orphan_checksum.exists());
+        assert!(!get_part_path(&hdf_path, 5).exists());
+        assert!(hdf_path
+            .with_file_name("run_0001_part5.h5.partial")
+            .exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_disk_space() {
+        let dir =
+            std::env::temp_dir().join(format!("attpc_merger_disk_space_{}", std::process::id()));
+        let mut config = write_synthetic_get_run(&dir);
+        assert_eq!(config.get_run_size_bytes(1), 2 * 512); // two frames of two units
+        assert!(check_disk_space(&config, &[1]).is_ok());
+
+        config.output_size_ratio = 1.0e18;
+        assert!(matches!(
+            check_disk_space(&config, &[1]),
+            Err(ProcessorError::InsufficientDiskSpace(_, _, _))
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_require_evt() {
+        let dir =
+            std::env::temp_dir().join(format!("attpc_merger_require_evt_{}", std::process::id()));
+        let mut config = write_synthetic_get_run(&dir);
+        let (tx, _rx) = mpsc::channel::<WorkerStatus>();
+
+        // No evt data, but it is not required
+        assert!(process_run(&config, 1, &tx, &0).is_ok());
+
+        // No evt data, and it is required
+        config.require_evt = true;
+        assert!(matches!(
+            process_run(&config, 1, &tx, &0),
+            Err(ProcessorError::RunError(1, e)) if matches!(*e, ProcessorError::ConfigError(_))
+        ));
+
+        // The evt directory exists, but has no evt files
+        std::fs::create_dir_all(config.evt_path.join("run1")).unwrap();
+        assert!(matches!(
+            process_run(&config, 1, &tx, &0),
+            Err(ProcessorError::RunError(1, e)) if matches!(*e, ProcessorError::EvtError(_))
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_evt_only() {
+        let dir =
+            std::env::temp_dir().join(format!("attpc_merger_evt_only_{}", std::process::id()));
+        // No GRAW data at all
+        let config = Config {
+            graw_path: dir.join("graw"),
+            evt_path: dir.join("evt"),
+            hdf_path: dir.join("hdf"),
+            first_run_number: 1,
+            last_run_number: 1,
+            merge_get: false,
+            ..Default::default()
+        };
+        std::fs::create_dir_all(&config.hdf_path).unwrap();
+        assert!(!config.does_run_exist(1));
+        write_synthetic_evt_run(&config);
+        assert!(config.does_run_exist(1));
+        assert!(config.validate().is_empty());
+
+        let mut summary = SummaryHook::default();
+        let (tx, _rx) = mpsc::channel::<WorkerStatus>();
+        process_run_with_hooks(&config, 1, &tx, &0, &mut [&mut summary]).unwrap();
+        let summary = summary.summary.unwrap();
+        assert_eq!(summary.get_events, 0);
+        assert_eq!(summary.frib_physics, 2);
+
+        let hdf_path = config.get_hdf_file_name(1).unwrap();
+        assert!(HDFWriter::verify(&hdf_path, &HdfLayout::default(), &1).unwrap());
+        let file = hdf5::File::open(&hdf_path).unwrap();
+        let events = file.group("events").unwrap();
+        assert_eq!(
+            events
+                .attr("min_event")
+                .unwrap()
+                .read_scalar::<u64>()
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            events
+                .attr("frib_run")
+                .unwrap()
+                .read_scalar::<u32>()
+                .unwrap(),
+            1
+        );
+        assert_eq!(events.dataset("quicklook").unwrap().shape()[0], 0);
+        for event_id in 0..2 {
+            let event = events.group(&format!("event_{event_id}")).unwrap();
+            assert!(event.group("frib_physics").is_ok());
+            assert!(event.dataset("get_traces").is_err());
+        }
+        assert!(file.dataset("scalers/event_0").is_ok());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_error_context() {
+        let dir =
+            std::env::temp_dir().join(format!("attpc_merger_error_context_{}", std::process::id()));
+        let config = Config {
+            require_evt: true,
+            ..write_synthetic_get_run(&dir)
+        };
+        let (tx, _rx) = mpsc::channel::<WorkerStatus>();
+
+        // The physics item has a bad module stack
+        let begin = make_begin_run_ring(1, 10, "synthetic");
+        let mut physics = make_physics_ring(0, 12, &[(1, 2)], 0x1);
+        let sis3300 = physics
+            .windows(2)
+            .position(|word| word == 0x1903u16.to_le_bytes())
+            .unwrap();
+        physics[sis3300] = 0;
+        let evt_dir = config.evt_path.join("run1");
+        std::fs::create_dir_all(&evt_dir).unwrap();
+        let evt_file = write_evt_file(&evt_dir, 1, &[begin.clone(), physics]).unwrap();
+        let message = process_run(&config, 1, &tx, &0).unwrap_err().to_string();
+        assert!(message.starts_with("Run 1 failed!"));
+        assert!(message.contains(&evt_file.display().to_string()));
+        assert!(message.contains(&format!("byte offset {}", begin.len())));
+
+        // The second frame of the GRAW file has a bad meta type
+        let graw_dir = config.graw_path.join("run_0001").join("mm0");
+        let graw_file = std::fs::read_dir(&graw_dir)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let mut bytes = std::fs::read(&graw_file).unwrap();
+        bytes[512] = 0x42;
+        std::fs::write(&graw_file, bytes).unwrap();
+        let message = process_run(&config, 1, &tx, &0).unwrap_err().to_string();
+        assert!(message.starts_with("Run 1 failed!"));
+        assert!(message.contains(&graw_file.display().to_string()));
+        assert!(message.contains("byte offset 512"));
+
+        // IO errors are given the file they are from
+        let pedestal_path = dir.join("attpc_merger_does_not_exist.csv");
+        let config = Config {
+            pedestal_path: Some(pedestal_path.clone()),
+            ..config
+        };
+        let message = process_run(&config, 1, &tx, &0).unwrap_err().to_string();
+        assert!(message.starts_with(&format!(
+            "Run 1 failed! Error with file {}",
+            pedestal_path.display()
+        )));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_post_merge_action() {
+        let dir =
+            std::env::temp_dir().join(format!("attpc_merger_post_merge_{}", std::process::id()));
+        let archive = dir.join("archive");
+        let graw_file = std::path::PathBuf::from("run_0001/mm0/CoBo0_AsAd0_0000.graw");
+        let evt_file = std::path::PathBuf::from("run1/run-0001-00.evt");
+        let mut config = write_synthetic_get_run(&dir);
+        write_synthetic_evt_run(&config);
+        std::fs::create_dir_all(&archive).unwrap();
+        let (tx, _rx) = mpsc::channel::<WorkerStatus>();
+
+        // Not confirmed, nothing happens
+        config.post_merge_action = Some(PostMergeAction::MoveTo(archive.clone()));
+        process_run(&config, 1, &tx, &0).unwrap();
+        assert!(config.graw_path.join(&graw_file).exists());
+
+        config.confirm_post_merge_action = true;
+        process_run(&config, 1, &tx, &0).unwrap();
+        assert!(!config.graw_path.join(&graw_file).exists());
+        assert!(!config.evt_path.join(&evt_file).exists());
+        assert!(archive.join(&graw_file).exists());
+        assert!(archive.join(&evt_file).exists());
+
+        // Write the run again and delete it
+        let config = Config {
+            post_merge_action: Some(PostMergeAction::Delete),
+            confirm_post_merge_action: true,
+            ..write_synthetic_get_run(&dir)
+        };
+        write_synthetic_evt_run(&config);
+        process_run(&config, 1, &tx, &0).unwrap();
+        assert!(!config.graw_path.join(&graw_file).exists());
+        assert!(!config.evt_path.join(&evt_file).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_copy_ahead() {
+        let dir =
+            std::env::temp_dir().join(format!("attpc_merger_copy_ahead_{}", std::process::id()));
+        let archive = dir.join("archive");
+        let config = Config {
+            post_merge_action: Some(PostMergeAction::MoveTo(archive.clone())),
+            confirm_post_merge_action: true,
+            last_run_number: 2,
+            ..write_synthetic_get_run(&dir)
+        };
+        write_synthetic_evt_run(&config);
+        std::fs::create_dir_all(&archive).unwrap();
+        assert!(config.need_copy_files());
+
+        // Run 2 does not exist, so the copy of run 1 is finished after the loop
+        let (tx, rx) = mpsc::channel::<WorkerStatus>();
+        process_queue(config.clone(), tx, 0, Arc::new(RunQueue::new(&config))).unwrap();
+        assert!(config.get_hdf_file_name(1).unwrap().exists());
+        assert!(archive.join("run_0001/mm0/CoBo0_AsAd0_0000.graw").exists());
+        assert!(archive.join("run1/run-0001-00.evt").exists());
+        assert!(!config
+            .graw_path
+            .join("run_0001/mm0/CoBo0_AsAd0_0000.graw")
+            .exists());
+        let statuses: Vec<WorkerStatus> = rx.iter().collect();
+        assert_eq!(statuses.last().unwrap().run_number, 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_run_queue() {
+        let config = Config {
+            first_run_number: 1,
+            last_run_number: 5,
+            n_threads: 8,
+            ..Default::default()
+        };
+        assert_eq!(get_n_workers(&config), 5);
+        assert_eq!(
+            get_n_workers(&Config {
+                n_threads: 2,
+                ..config.clone()
+            }),
+            2
+        );
+        // No workers for an empty run range, however many threads are asked for
+        assert_eq!(
+            get_n_workers(&Config {
+                first_run_number: 6,
+                last_run_number: 5,
+                ..config.clone()
+            }),
+            0
+        );
+
+        // Every run is taken exactly once, by whichever worker is free
+        let queue = Arc::new(RunQueue::new(&config));
+        assert_eq!(queue.len(), 5);
+        let handles: Vec<_> = (0..3)
+            .map(|_| {
+                let queue = queue.clone();
+                std::thread::spawn(move || {
+                    let mut taken = Vec::new();
+                    while let Some(run) = queue.next_run() {
+                        taken.push(run);
+                    }
+                    taken
+                })
+            })
+            .collect();
+        let mut taken: Vec<i32> = handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect();
+        taken.sort();
+        assert_eq!(taken, vec![1, 2, 3, 4, 5]);
+        assert!(queue.is_empty());
+
+        let queue = RunQueue::from(vec![4, 2]);
+        assert_eq!(queue.next_run(), Some(4));
+        assert_eq!(queue.next_run(), Some(2));
+        assert_eq!(queue.next_run(), None);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_process_subset() {
+        let config = Config {
+            first_run_number: 1,
+            last_run_number: 5,
+            n_threads: 2,
+            ..Default::default()
+        };
+        assert_eq!(create_subsets(&config), vec![vec![1, 3, 5], vec![2, 4]]);
+
+        // The runs of the subset are merged as by process_queue
+        let dir = std::env::temp_dir().join(format!("attpc_merger_subset_{}", std::process::id()));
+        let config = write_synthetic_get_run(&dir);
+        let (tx, _rx) = mpsc::channel::<WorkerStatus>();
+        process_subset(config.clone(), tx, 0, vec![1, 2]).unwrap();
+        assert!(config.get_hdf_file_name(1).unwrap().exists());
+        assert!(!config.get_hdf_file_name(2).unwrap().exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_run_database() {
+        let dir = std::env::temp_dir().join(format!(
+            "attpc_merger_run_db_process_{}",
+            std::process::id()
+        ));
+        let config = Config {
+            run_database_path: Some(dir.join("runs.csv")),
+            ..write_synthetic_get_run(&dir)
+        };
+        let (tx, _rx) = mpsc::channel::<WorkerStatus>();
+
+        // A bad run is skipped without writing anything
+        std::fs::write(dir.join("runs.csv"), "run,good,beam\n1,bad,16O\n").unwrap();
+        process_run(&config, 1, &tx, &0).unwrap();
+        assert!(!config.get_hdf_file_name(1).unwrap().exists());
+
+        std::fs::write(
+            dir.join("runs.csv"),
+            "run,good,beam,note\n1,good,16O,test run\n",
+        )
+        .unwrap();
+        process_run(&config, 1, &tx, &0).unwrap();
+        let file = hdf5::File::open(config.get_hdf_file_name(1).unwrap()).unwrap();
+        let events = file.group("events").unwrap();
+        let read_string = |name: &str| {
+            events
+                .attr(name)
+                .unwrap()
+                .read_scalar::<hdf5::types::VarLenUnicode>()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(read_string("beam"), "16O");
+        assert_eq!(read_string("note"), "test run");
+        assert!(events.attr("good").is_err());
+
+        drop(file);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,133 @@
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::time::Duration;
+
+use super::config::RetryOptions;
+
+/// Returns true if the error is likely transient (i.e. a flaky network mount) and the read is worth retrying.
+///
+/// Errors like a missing file or the end of the file are never retried.
+pub fn is_retryable(kind: ErrorKind) -> bool {
+    matches!(
+        kind,
+        ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut
+    )
+}
+
+/// The time to wait before a retry (counting from 1), which doubles with each retry starting from the initial backoff
+pub fn get_backoff(options: &RetryOptions, attempt: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+    options.initial_backoff.saturating_mul(factor)
+}
+
+/// Fill the buffer from the reader, retrying with exponential backoff on retryable errors.
+///
+/// Before each retry the reader is returned to the position it had at the start, so data from a partial read is
+/// not lost. After the configured number of retries the last error is returned. Non-retryable errors are
+/// returned immediately.
+pub fn read_exact_with_retry<R: Read + Seek>(
+    reader: &mut R,
+    buffer: &mut [u8],
+    options: &RetryOptions,
+) -> std::io::Result<()> {
+    let start_position = reader.stream_position()?;
+    let mut attempt: u32 = 0;
+    loop {
+        match reader.read_exact(buffer) {
+            Ok(()) => return Ok(()),
+            Err(e) if is_retryable(e.kind()) && attempt < options.retries => {
+                attempt += 1;
+                let backoff = get_backoff(options, attempt);
+                spdlog::warn!(
+                    "Read failed with a retryable error: {e}. Retrying in {} ms (attempt {} of {})",
+                    backoff.as_millis(),
+                    attempt,
+                    options.retries
+                );
+                std::thread::sleep(backoff);
+                reader.seek(SeekFrom::Start(start_position))?;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+//Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// A reader which consumes a byte and then fails with the given kind of error a number of times
+    struct FlakyReader {
+        inner: Cursor<Vec<u8>>,
+        failures: u32,
+        kind: ErrorKind,
+    }
+
+    impl Read for FlakyReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                self.inner.set_position(self.inner.position() + 1);
+                return Err(std::io::Error::from(self.kind));
+            }
+            self.inner.read(buf)
+        }
+    }
+
+    impl Seek for FlakyReader {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    fn make_reader(failures: u32, kind: ErrorKind) -> FlakyReader {
+        FlakyReader {
+            inner: Cursor::new(vec![1, 2, 3, 4]),
+            failures,
+            kind,
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let options = RetryOptions {
+            retries: 3,
+            initial_backoff: Duration::from_millis(100),
+        };
+        let backoffs: Vec<u128> = (1..=4)
+            .map(|attempt| get_backoff(&options, attempt).as_millis())
+            .collect();
+        assert_eq!(backoffs, vec![100, 200, 400, 800]);
+        // Saturates rather than overflowing
+        assert!(get_backoff(&options, 200) > get_backoff(&options, 4));
+    }
+
+    #[test]
+    fn test_read_exact_with_retry() {
+        let options = RetryOptions {
+            retries: 3,
+            initial_backoff: Duration::from_millis(1),
+        };
+        let mut buffer = [0u8; 4];
+
+        // Recovers, and starts the read over from the beginning
+        let mut reader = make_reader(3, ErrorKind::TimedOut);
+        read_exact_with_retry(&mut reader, &mut buffer, &options).unwrap();
+        assert_eq!(buffer, [1, 2, 3, 4]);
+
+        // Gives up after the retries
+        let mut reader = make_reader(4, ErrorKind::WouldBlock);
+        let error = read_exact_with_retry(&mut reader, &mut buffer, &options).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::WouldBlock);
+
+        // Non-retryable errors are not retried
+        let mut reader = make_reader(1, ErrorKind::PermissionDenied);
+        let error = read_exact_with_retry(&mut reader, &mut buffer, &options).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+        let mut reader = make_reader(0, ErrorKind::Other);
+        let mut too_big = [0u8; 5];
+        let error = read_exact_with_retry(&mut reader, &mut too_big, &options).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+}
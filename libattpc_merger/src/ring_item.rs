@@ -1,5 +1,7 @@
+use super::constants::{adc_mask, FADC_ADC_BITS};
 use super::error::EvtItemError;
 use byteorder::{LittleEndian, ReadBytesExt};
+use ndarray::Array2;
 use std::io::{Cursor, Read};
 
 //These are the literal values for the different ring item type fields
@@ -11,9 +13,14 @@ const PHYSICS_VAL: u8 = 30;
 const COUNTER_VAL: u8 = 31;
 
 //Some Ring constants
-const RING_HEADER_PRESENT: u8 = 20;
-const HEADER_PRESENT_INDEX: usize = 28;
+const RING_TYPE_INDEX: usize = 4;
+const BODY_HEADER_SIZE_INDEX: usize = 8;
+const MINIMUM_BODY_HEADER_SIZE: usize = 20; // size, timestamp, source id, barrier type
 const NO_HEADER_INDEX: usize = 12;
+const FRAGMENT_HEADER_SIZE: usize = 20; // timestamp, source id, payload size, barrier type
+
+/// The SIS3300 memory holds 128k samples per group
+const SIS3300_MAX_SAMPLES: usize = 0x20000;
 
 /// RingType is an enum representing the type of data stored within a FRIBDAQ ring.
 ///
@@ -44,42 +51,60 @@ impl From<u8> for RingType {
     }
 }
 
+/// Check if the type word of a ring item is one of the known RingTypes (the upper bytes of the word are always 0)
+pub fn is_known_ring_type(type_word: u32) -> bool {
+    type_word <= u8::MAX as u32 && !matches!(RingType::from(type_word as u8), RingType::Invalid)
+}
+
 /// RingItem is the base object of FRIBDAQ data.
 ///
 /// A RingItem contains a buffer of bytes, a size, and a RingType
 /// which can be used to cast the RingItem to its functional type.
+/// If the item had a body header (FRIBDAQ event builder), its 64-bit timestamp is kept in body_timestamp.
 #[derive(Debug, Clone)]
 pub struct RingItem {
     pub size: usize,
     pub bytes: Vec<u8>,
     pub ring_type: RingType,
+    pub body_timestamp: Option<u64>,
 }
 
 /// Convert the raw byte buffer to a RingItem.
 impl TryFrom<Vec<u8>> for RingItem {
     type Error = EvtItemError;
     fn try_from(buffer: Vec<u8>) -> Result<Self, Self::Error> {
-        let rt_data: u8;
-        {
-            let type_data = buffer.get(4);
-            match type_data {
-                Some(data) => rt_data = *data,
-                None => return Err(EvtItemError::ItemSizeError),
-            };
+        // Items can be truncated at the end of a file, so every fixed offset is checked before it is read
+        if buffer.len() <= RING_TYPE_INDEX {
+            return Err(EvtItemError::ItemSizeError);
+        }
+        let rt_data = buffer[RING_TYPE_INDEX];
+        if buffer.len() < NO_HEADER_INDEX {
+            return Err(EvtItemError::ItemSizeError);
         }
-        //RingItems can optionally have a header. We trim this header
+        //RingItems can optionally have a body header. We keep the timestamp and trim the header.
+        //The header size word is 0 (or 4 in newer FRIBDAQ) when there is no body header.
+        let mut cursor = Cursor::new(&buffer[BODY_HEADER_SIZE_INDEX..]);
+        let body_header_size = cursor.read_u32::<LittleEndian>()? as usize;
         let item_data_buffer: Vec<u8>;
-        if buffer[8] == RING_HEADER_PRESENT && buffer.len() >= HEADER_PRESENT_INDEX {
-            item_data_buffer = buffer[HEADER_PRESENT_INDEX..].to_vec();
-        } else if buffer.len() >= NO_HEADER_INDEX {
-            item_data_buffer = buffer[NO_HEADER_INDEX..].to_vec();
+        let mut body_timestamp = None;
+        if body_header_size >= MINIMUM_BODY_HEADER_SIZE {
+            // The header must hold at least the timestamp, source id and barrier type, and the data follows it
+            let data_index = BODY_HEADER_SIZE_INDEX + body_header_size;
+            if buffer.len() < BODY_HEADER_SIZE_INDEX + MINIMUM_BODY_HEADER_SIZE
+                || buffer.len() < data_index
+            {
+                return Err(EvtItemError::ItemSizeError);
+            }
+            body_timestamp = Some(cursor.read_u64::<LittleEndian>()?);
+            item_data_buffer = buffer[data_index..].to_vec();
         } else {
-            return Err(EvtItemError::ItemSizeError);
+            item_data_buffer = buffer[NO_HEADER_INDEX..].to_vec();
         }
         Ok(Self {
             size: buffer.len(),
             bytes: item_data_buffer,
             ring_type: RingType::from(rt_data),
+            body_timestamp,
         })
     }
 }
@@ -90,6 +115,7 @@ impl Default for RingItem {
             size: 0,
             bytes: vec![],
             ring_type: RingType::Invalid,
+            body_timestamp: None,
         }
     }
 }
@@ -117,6 +143,101 @@ impl RingItem {
             ind += usize::from(wlength * 2); // next boundary
         }
     }
+
+    /// Split the data of an event built PhysicsItem into its fragments.
+    ///
+    /// The FRIBDAQ event builder (glom) writes physics items whose data is the size of the data in bytes (including
+    /// the size itself), followed by one fragment per source: a fragment header (timestamp, source id, payload size,
+    /// barrier type) and the ring item of the source as the payload. Returns None if the data does not have this
+    /// layout, i.e. the item was not event built.
+    /// # Note
+    /// Call this before remove_boundaries, the boundaries are in the ring items of the fragments
+    pub fn get_fragments(&self) -> Option<Vec<Fragment>> {
+        let mut cursor = Cursor::new(&self.bytes);
+        let size = cursor.read_u32::<LittleEndian>().ok()? as usize;
+        if size != self.bytes.len() {
+            return None;
+        }
+        let mut fragments = Vec::new();
+        while (cursor.position() as usize) < size {
+            let timestamp = cursor.read_u64::<LittleEndian>().ok()?;
+            let source_id = cursor.read_u32::<LittleEndian>().ok()?;
+            let payload_size = cursor.read_u32::<LittleEndian>().ok()? as usize;
+            let barrier = cursor.read_u32::<LittleEndian>().ok()?;
+            let start = cursor.position() as usize;
+            // The payload must be a whole ring item, which starts with its own size
+            let payload = self.bytes.get(start..start + payload_size)?;
+            if payload_size < NO_HEADER_INDEX
+                || u32::from_le_bytes(payload[0..4].try_into().unwrap()) as usize != payload_size
+            {
+                return None;
+            }
+            fragments.push(Fragment {
+                timestamp,
+                source_id,
+                barrier,
+                payload: payload.to_vec(),
+            });
+            cursor.set_position((start + payload_size) as u64);
+        }
+        if fragments.is_empty() {
+            return None;
+        }
+        Some(fragments)
+    }
+}
+
+/// A fragment of an event built PhysicsItem: the ring item of one source, with the fragment header of the event builder
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Fragment {
+    pub timestamp: u64,
+    pub source_id: u32,
+    pub barrier: u32,
+    pub payload: Vec<u8>, // the ring item of the source, with its header
+}
+
+impl Fragment {
+    /// Convert the payload to a RingItem. If the payload has no body header, the timestamp of the fragment is used
+    pub fn to_ring(&self) -> Result<RingItem, EvtItemError> {
+        let mut ring = RingItem::try_from(self.payload.clone())?;
+        if ring.body_timestamp.is_none() {
+            ring.body_timestamp = Some(self.timestamp);
+        }
+        Ok(ring)
+    }
+
+    /// The bytes of the fragment as written by the event builder, the fragment header followed by the payload
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(FRAGMENT_HEADER_SIZE + self.payload.len());
+        bytes.extend_from_slice(&self.timestamp.to_le_bytes());
+        bytes.extend_from_slice(&self.source_id.to_le_bytes());
+        bytes.extend_from_slice(&(self.payload.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.barrier.to_le_bytes());
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+}
+
+/// Select the fragment of a source from the fragments of an event built PhysicsItem
+///
+/// With no source_id the first fragment is selected. Returns the RingItem of the selected fragment and the other
+/// fragments, or an error if no fragment is from the source.
+pub fn select_fragment(
+    mut fragments: Vec<Fragment>,
+    source_id: Option<u32>,
+) -> Result<(RingItem, Vec<Fragment>), EvtItemError> {
+    let index = match source_id {
+        Some(id) => fragments
+            .iter()
+            .position(|fragment| fragment.source_id == id)
+            .ok_or(EvtItemError::MissingSource(id))?,
+        None => 0,
+    };
+    if index >= fragments.len() {
+        return Err(EvtItemError::ItemSizeError);
+    }
+    let fragment = fragments.remove(index);
+    Ok((fragment.to_ring()?, fragments))
 }
 
 // Below are the various explicit ring item types. RingItems can be cast into these objects using
@@ -208,7 +329,7 @@ impl RunInfo {
 ///
 /// Scalers are composed of a header containing the timing of the scaler data
 /// and a data vector that contains the scalers themselves (32 bits). The order of the scalers
-/// is defined by FRIBDAQ.
+/// is defined by FRIBDAQ. If the ring had a body header, its 64-bit timestamp is in body_timestamp.
 #[derive(Debug, Clone, Default)]
 pub struct ScalersItem {
     pub start_offset: u32,
@@ -216,6 +337,7 @@ pub struct ScalersItem {
     pub timestamp: u32,
     pub incremental: u32,
     pub data: Vec<u32>,
+    pub body_timestamp: Option<u64>,
 }
 
 /// Cast a RingItem to a ScalersItem
@@ -224,6 +346,7 @@ impl TryFrom<RingItem> for ScalersItem {
     fn try_from(ring: RingItem) -> Result<Self, Self::Error> {
         let mut cursor = Cursor::new(ring.bytes);
         let mut info = ScalersItem::new();
+        info.body_timestamp = ring.body_timestamp;
         info.start_offset = cursor.read_u32::<LittleEndian>()?;
         info.stop_offset = cursor.read_u32::<LittleEndian>()?;
         info.timestamp = cursor.read_u32::<LittleEndian>()?;
@@ -243,6 +366,77 @@ impl ScalersItem {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// Returns true if the scalers count from the start of the time window, false if they are cumulative over the run
+    pub fn is_incremental(&self) -> bool {
+        self.incremental != 0
+    }
+
+    /// Length of the time window covered by the scalers in seconds.
+    ///
+    /// The offsets are in units of the FRIBDAQ offset clock, which ticks offset_divisor times per second. Returns None
+    /// if the offset_divisor is 0, as the length of a tick is then unknown.
+    pub fn get_duration_s(&self, offset_divisor: u32) -> Option<f64> {
+        if offset_divisor == 0 {
+            return None;
+        }
+        Some(self.stop_offset.saturating_sub(self.start_offset) as f64 / offset_divisor as f64)
+    }
+
+    /// Rate of each scaler over the time window in Hz.
+    ///
+    /// Non-incremental scalers are cumulative, so the values of the previous non-incremental ScalersItem
+    /// must be given to get the counts within the window (use an empty slice for the first item of a run).
+    /// Counters which rolled over are handled. If the window has no length (or the offset_divisor is 0, see
+    /// get_duration_s), all rates are 0.
+    pub fn get_rates_hz(&self, offset_divisor: u32, previous: &[u32]) -> Vec<f64> {
+        let duration_s = match self.get_duration_s(offset_divisor) {
+            Some(duration_s) if duration_s != 0.0 => duration_s,
+            _ => return vec![0.0; self.data.len()],
+        };
+        self.get_counts(previous)
+            .map(|counts| counts as f64 / duration_s)
+            .collect()
+    }
+
+    /// Rate of each scaler since the previous scaler read in Hz, from the difference of the read timestamps.
+    ///
+    /// As for get_rates_hz, the values of the previous non-incremental ScalersItem must be given for cumulative scalers.
+    /// The first read of a run has no previous timestamp, so its rates are 0. If the timestamp did not advance
+    /// since the previous read, the rates are 0 and a warning is logged.
+    pub fn get_rates_since_hz(
+        &self,
+        previous: &[u32],
+        previous_timestamp: Option<u32>,
+    ) -> Vec<f64> {
+        let previous_timestamp = match previous_timestamp {
+            Some(timestamp) => timestamp,
+            None => return vec![0.0; self.data.len()],
+        };
+        if self.timestamp <= previous_timestamp {
+            spdlog::warn!(
+                "Scaler timestamp {} did not advance from the previous read at {}, setting the rates since the previous read to 0.",
+                self.timestamp,
+                previous_timestamp
+            );
+            return vec![0.0; self.data.len()];
+        }
+        let interval_s = (self.timestamp - previous_timestamp) as f64;
+        self.get_counts(previous)
+            .map(|counts| counts as f64 / interval_s)
+            .collect()
+    }
+
+    /// The counts of each scaler in this read. Cumulative scalers are differenced with the previous values (handling rollover).
+    fn get_counts<'a>(&'a self, previous: &'a [u32]) -> impl Iterator<Item = u32> + 'a {
+        self.data.iter().enumerate().map(move |(idx, value)| {
+            if self.is_incremental() {
+                *value
+            } else {
+                value.wrapping_sub(*previous.get(idx).unwrap_or(&0))
+            }
+        })
+    }
 }
 
 /// A RingItem which contains the count of the number of physics items found by FRIBDAQ.
@@ -282,16 +476,27 @@ impl CounterItem {
 pub struct PhysicsItem {
     pub event: u32,
     pub timestamp: u32,
+    pub body_timestamp: Option<u64>, // from the body header, if present
     pub fadc: SIS3300Item,
     pub coinc: V977Item,
+    pub fragments: Vec<Fragment>, // fragments of the other sources of an event built item, if they are kept
 }
 
-/// Cast a RingItem to a PhysicsItem
+/// Cast a RingItem to a PhysicsItem, with SIS3300 samples of the default bit depth (FADC_ADC_BITS)
 impl TryFrom<RingItem> for PhysicsItem {
     type Error = EvtItemError;
     fn try_from(ring: RingItem) -> Result<Self, Self::Error> {
+        PhysicsItem::from_ring(ring, FADC_ADC_BITS)
+    }
+}
+
+impl PhysicsItem {
+    /// Cast a RingItem to a PhysicsItem, masking the SIS3300 samples to fadc_bits bits
+    pub fn from_ring(ring: RingItem, fadc_bits: u8) -> Result<Self, EvtItemError> {
         let mut cursor = Cursor::new(ring.bytes);
         let mut info = PhysicsItem::new();
+        info.fadc = SIS3300Item::with_bits(fadc_bits);
+        info.body_timestamp = ring.body_timestamp;
         info.event = cursor.read_u32::<LittleEndian>()?;
         info.timestamp = cursor.read_u32::<LittleEndian>()?;
         // Parse the stack. Order matters!
@@ -319,18 +524,21 @@ impl PhysicsItem {
         PhysicsItem {
             event: 0,
             timestamp: 0,
+            body_timestamp: None,
             fadc: SIS3300Item::new(),
             coinc: V977Item::new(),
+            fragments: Vec::new(),
         }
     }
 }
 
-/// Item from Struck module SIS3300: 8 channel flash ADC (12 bits)
+/// Item from Struck module SIS3300: 8 channel flash ADC (12 bits, or 14 bits for the SIS3301)
 #[derive(Debug, Clone)]
 pub struct SIS3300Item {
     pub traces: Vec<Vec<u16>>,
     pub samples: usize,
     pub channels: usize,
+    pub bits: u8, // bit depth of the samples, the rest of each sample word (i.e. the out of range flag) is masked off
 }
 
 impl Default for SIS3300Item {
@@ -341,11 +549,39 @@ impl Default for SIS3300Item {
 
 impl SIS3300Item {
     pub fn new() -> SIS3300Item {
+        Self::with_bits(FADC_ADC_BITS)
+    }
+
+    /// Make an item whose samples are masked to the given bit depth
+    pub fn with_bits(bits: u8) -> SIS3300Item {
         SIS3300Item {
             traces: vec![vec![]; 8],
             samples: 0,
             channels: 0,
+            bits,
+        }
+    }
+
+    /// The samples as a matrix, with one row per sample and one column per channel
+    pub fn to_matrix(&self) -> Array2<u16> {
+        let mut matrix = Array2::<u16>::zeros([self.samples, self.traces.len()]);
+        for (channel, trace) in self.traces.iter().enumerate() {
+            for (sample, value) in trace.iter().take(self.samples).enumerate() {
+                matrix[[sample, channel]] = *value;
+            }
         }
+        matrix
+    }
+
+    /// The samples as a matrix, with one row per channel and one column per sample (the transpose of to_matrix)
+    pub fn to_channel_matrix(&self) -> Array2<u16> {
+        let mut matrix = Array2::<u16>::zeros([self.traces.len(), self.samples]);
+        for (channel, trace) in self.traces.iter().enumerate() {
+            for (sample, value) in trace.iter().take(self.samples).enumerate() {
+                matrix[[channel, sample]] = *value;
+            }
+        }
+        matrix
     }
 
     /// Extract the relevant data from the PhysicsItem buffer.
@@ -356,6 +592,7 @@ impl SIS3300Item {
         &mut self,
         cursor: &mut std::io::Cursor<Vec<u8>>,
     ) -> Result<(), EvtItemError> {
+        let mask = adc_mask(self.bits);
         let group_enable_flags = cursor.read_u16::<LittleEndian>()?;
         let _daq_register = cursor.read_u32::<LittleEndian>()?; //Never used, but must be read
 
@@ -379,33 +616,44 @@ impl SIS3300Item {
             }
             group_trigger = cursor.read_u32::<LittleEndian>()?;
             self.samples = cursor.read_u32::<LittleEndian>()? as usize;
+            pointer = (group_trigger & 0x1ffff) as usize; // write pointer (start location in the buffer)
+                                                          //Validate the buffer before indexing into it, a malformed header must not panic or read garbage
+            let remaining = (cursor.get_ref().len() as u64).saturating_sub(cursor.position());
+            let is_wrapped = (group_trigger & 0x80000) != 0;
+            if self.samples > SIS3300_MAX_SAMPLES
+                || (self.samples * 4) as u64 > remaining
+                || (is_wrapped && pointer >= self.samples)
+            {
+                return Err(EvtItemError::SIS3300BufferError(
+                    group_trigger,
+                    self.samples,
+                ));
+            }
             self.traces[group * 2] = vec![0; self.samples];
             self.traces[group * 2 + 1] = vec![0; self.samples];
-            pointer = (group_trigger & 0x1ffff) as usize; // write pointer (start location in the buffer)
             let starting_position = cursor.position(); // the original position of the cursor
                                                        //Handle a non-normal initial position in the buffer
-            if ((group_trigger & 0x80000) != 0) && (pointer < self.samples - 1) {
+            if is_wrapped && (pointer + 1 < self.samples) {
                 // if wrap around bit == 1
                 let istart: usize = pointer + 1;
                 let inc: usize = self.samples - pointer - 2;
                 cursor.set_position(starting_position + ((istart * 4) as u64));
                 for p in 0..inc + 1 {
-                    self.traces[group * 2 + 1][p] = cursor.read_u16::<LittleEndian>()? & 0xfff;
-                    self.traces[group * 2][p] = cursor.read_u16::<LittleEndian>()? & 0xfff;
+                    self.traces[group * 2 + 1][p] = cursor.read_u16::<LittleEndian>()? & mask;
+                    self.traces[group * 2][p] = cursor.read_u16::<LittleEndian>()? & mask;
                 }
                 //Wrap back around and read the remaining data
                 let istop: usize = self.samples - inc - 1;
                 cursor.set_position(starting_position);
                 for p in 0..istop {
                     self.traces[group * 2 + 1][p + inc + 1] =
-                        cursor.read_u16::<LittleEndian>()? & 0xfff;
-                    self.traces[group * 2][p + inc + 1] =
-                        cursor.read_u16::<LittleEndian>()? & 0xfff;
+                        cursor.read_u16::<LittleEndian>()? & mask;
+                    self.traces[group * 2][p + inc + 1] = cursor.read_u16::<LittleEndian>()? & mask;
                 }
             } else {
                 for p in 0..self.samples {
-                    self.traces[group * 2 + 1][p] = cursor.read_u16::<LittleEndian>()? & 0xfff;
-                    self.traces[group * 2][p] = cursor.read_u16::<LittleEndian>()? & 0xfff;
+                    self.traces[group * 2 + 1][p] = cursor.read_u16::<LittleEndian>()? & mask;
+                    self.traces[group * 2][p] = cursor.read_u16::<LittleEndian>()? & mask;
                 }
             }
             cursor.set_position(starting_position + ((self.samples * 4) as u64));
@@ -439,3 +687,233 @@ impl V977Item {
         Ok(())
     }
 }
+
+//Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_data::*;
+
+    #[test]
+    fn test_scalers_body_header() {
+        let ring = make_scalers_ring(10, 20, 30, &[1, 2, 3]);
+        for (bytes, body_timestamp) in [
+            (ring.clone(), None),
+            (add_body_header(&ring, 123456789012, 1), Some(123456789012)),
+        ] {
+            let scalers = ScalersItem::try_from(RingItem::try_from(bytes).unwrap()).unwrap();
+            assert_eq!(scalers.start_offset, 10);
+            assert_eq!(scalers.stop_offset, 20);
+            assert_eq!(scalers.timestamp, 30);
+            assert!(scalers.is_incremental());
+            assert_eq!(scalers.data, vec![1, 2, 3]);
+            assert_eq!(scalers.body_timestamp, body_timestamp);
+        }
+    }
+
+    #[test]
+    fn test_physics_body_header() {
+        let ring = make_physics_ring(5, 6, &[(1, 2), (3, 4)], 7);
+        for (bytes, body_timestamp) in [
+            (ring.clone(), None),
+            (add_body_header(&ring, u64::MAX, 2), Some(u64::MAX)),
+        ] {
+            let mut ring_item = RingItem::try_from(bytes).unwrap();
+            ring_item.remove_boundaries();
+            let physics = PhysicsItem::try_from(ring_item).unwrap();
+            assert_eq!(physics.event, 5);
+            assert_eq!(physics.timestamp, 6);
+            assert_eq!(physics.fadc.traces[0], vec![2, 4]);
+            assert_eq!(physics.fadc.traces[1], vec![1, 3]);
+            assert_eq!(physics.coinc.coinc, 7);
+            assert_eq!(physics.body_timestamp, body_timestamp);
+        }
+    }
+
+    #[test]
+    fn test_truncated_items() {
+        // Cut off in the type word, the body header size word, and the body header
+        for length in [0, 3, 6, 10] {
+            assert!(matches!(
+                RingItem::try_from(vec![0; length]),
+                Err(EvtItemError::ItemSizeError)
+            ));
+        }
+        let ring = add_body_header(&make_scalers_ring(10, 20, 30, &[1, 2, 3]), 100, 1);
+        for length in [12, 20, 27] {
+            assert!(matches!(
+                RingItem::try_from(ring[..length].to_vec()),
+                Err(EvtItemError::ItemSizeError)
+            ));
+        }
+        // Without a body header, the 12 byte header is enough
+        let mut empty = vec![12, 0, 0, 0, PHYSICS_VAL, 0, 0, 0, 0, 0, 0, 0];
+        assert!(RingItem::try_from(empty.clone()).unwrap().bytes.is_empty());
+        empty[BODY_HEADER_SIZE_INDEX] = 4;
+        assert!(RingItem::try_from(empty).is_ok());
+    }
+
+    #[test]
+    fn test_physics_fragments() {
+        let physics_ring = make_physics_ring(5, 6, &[(1, 2), (3, 4)], 7);
+        let other_ring = add_body_header(&make_scalers_ring(0, 1, 2, &[9]), 100, 3);
+        let bytes =
+            make_built_physics_ring(100, &[(3, other_ring.clone()), (1, physics_ring.clone())]);
+        let ring_item = RingItem::try_from(bytes).unwrap();
+        let fragments = ring_item.get_fragments().unwrap();
+        assert_eq!(fragments.len(), 2);
+        assert_eq!(fragments[0].source_id, 3);
+        assert_eq!(fragments[1].payload, physics_ring);
+
+        // The physics data of the configured source is parsed, and the other source is kept
+        let (mut ring_item, others) = select_fragment(fragments.clone(), Some(1)).unwrap();
+        ring_item.remove_boundaries();
+        let physics = PhysicsItem::try_from(ring_item).unwrap();
+        assert_eq!(physics.event, 5);
+        assert_eq!(physics.fadc.traces[0], vec![2, 4]);
+        assert_eq!(physics.coinc.coinc, 7);
+        assert_eq!(physics.body_timestamp, Some(100));
+        assert_eq!(others.len(), 1);
+        assert_eq!(others[0].payload, other_ring);
+        assert_eq!(others[0].to_bytes().len(), other_ring.len() + 20);
+        assert!(matches!(
+            select_fragment(fragments, Some(2)),
+            Err(EvtItemError::MissingSource(2))
+        ));
+
+        // Items which were not event built have no fragments
+        let ring_item = RingItem::try_from(physics_ring).unwrap();
+        assert!(ring_item.get_fragments().is_none());
+    }
+
+    /// The bytes of a SIS3300 with only its first group enabled, as read by extract_data
+    fn make_sis3300_bytes(group_trigger: u32, samples: u32, data: &[(u16, u16)]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&1u16.to_le_bytes()); // group enable flags
+        bytes.extend_from_slice(&0u32.to_le_bytes()); // daq register
+        bytes.extend_from_slice(&0xfadcu16.to_le_bytes());
+        bytes.extend_from_slice(&group_trigger.to_le_bytes());
+        bytes.extend_from_slice(&samples.to_le_bytes());
+        for (channel_1, channel_0) in data {
+            bytes.extend_from_slice(&channel_1.to_le_bytes());
+            bytes.extend_from_slice(&channel_0.to_le_bytes());
+        }
+        bytes.extend_from_slice(&0xffffu16.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn test_sis3300_wrap_around() {
+        let data = [(10, 0), (11, 1), (12, 2), (13, 3)];
+        // Wrapped with the write pointer at 1: the trace starts at sample 2
+        let mut fadc = SIS3300Item::new();
+        let mut cursor = Cursor::new(make_sis3300_bytes(0x80001, 4, &data));
+        fadc.extract_data(&mut cursor).unwrap();
+        assert_eq!(fadc.traces[0], vec![2, 3, 0, 1]);
+        assert_eq!(fadc.traces[1], vec![12, 13, 10, 11]);
+
+        // Pointers outside of the buffer, an empty wrapped buffer, and more samples than the buffer holds
+        for (group_trigger, samples) in [(0x80004, 4), (0x9ffff, 4), (0x80000, 0), (0, 5)] {
+            let mut fadc = SIS3300Item::new();
+            let mut cursor = Cursor::new(make_sis3300_bytes(group_trigger, samples, &data));
+            assert!(matches!(
+                fadc.extract_data(&mut cursor),
+                Err(EvtItemError::SIS3300BufferError(_, _))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_sis3300_bits() {
+        // Samples with the out of range flag (bit 12 of a 12-bit module, bit 14 of a 14-bit module) and user bits set
+        let data = [(0x1fff, 0x3abc), (0xc123, 0x4fff)];
+        let mut fadc = SIS3300Item::new();
+        assert_eq!(fadc.bits, 12);
+        fadc.extract_data(&mut Cursor::new(make_sis3300_bytes(0, 2, &data)))
+            .unwrap();
+        assert_eq!(fadc.traces[0], vec![0xabc, 0xfff]);
+        assert_eq!(fadc.traces[1], vec![0xfff, 0x123]);
+
+        let mut fadc = SIS3300Item::with_bits(14);
+        fadc.extract_data(&mut Cursor::new(make_sis3300_bytes(0, 2, &data)))
+            .unwrap();
+        assert_eq!(fadc.traces[0], vec![0x3abc, 0xfff]);
+        assert_eq!(fadc.traces[1], vec![0x1fff, 0x0123]);
+    }
+
+    #[test]
+    fn test_adc_masks() {
+        assert_eq!(adc_mask(12), 0x0fff);
+        assert_eq!(adc_mask(14), 0x3fff);
+        assert_eq!(adc_mask(16), 0xffff);
+        assert_eq!(crate::constants::GET_ADC_MASK, 0x0fff);
+        assert_eq!(crate::constants::SATURATION_VALUE, 4095);
+    }
+
+    #[test]
+    fn test_scaler_rates() {
+        let mut scalers = ScalersItem {
+            start_offset: 10,
+            stop_offset: 30,
+            timestamp: 0,
+            incremental: 1,
+            data: vec![20, 50],
+            body_timestamp: None,
+        };
+        assert_eq!(scalers.get_duration_s(2), Some(10.0));
+        assert_eq!(scalers.get_rates_hz(2, &[]), vec![2.0, 5.0]);
+
+        // The length of a tick is unknown
+        assert_eq!(scalers.get_duration_s(0), None);
+        assert_eq!(scalers.get_rates_hz(0, &[]), vec![0.0, 0.0]);
+
+        // Cumulative scalers only count what happened since the previous item
+        scalers.incremental = 0;
+        assert_eq!(scalers.get_rates_hz(2, &[10, 0]), vec![1.0, 5.0]);
+        assert_eq!(scalers.get_rates_hz(2, &[u32::MAX, 0]), vec![2.1, 5.0]);
+
+        scalers.stop_offset = scalers.start_offset;
+        assert_eq!(scalers.get_rates_hz(2, &[]), vec![0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_scaler_rates_since() {
+        let first = ScalersItem {
+            start_offset: 0,
+            stop_offset: 10,
+            timestamp: 100,
+            incremental: 0,
+            data: vec![100, 40],
+            body_timestamp: None,
+        };
+        let second = ScalersItem {
+            start_offset: 10,
+            stop_offset: 20,
+            timestamp: 104,
+            data: vec![300, 60],
+            ..first.clone()
+        };
+        // The first read has nothing to compare with
+        assert_eq!(first.get_rates_since_hz(&[], None), vec![0.0, 0.0]);
+        assert_eq!(
+            second.get_rates_since_hz(&first.data, Some(first.timestamp)),
+            vec![50.0, 5.0]
+        );
+
+        // Incremental scalers are already the counts since the previous read
+        let incremental = ScalersItem {
+            incremental: 1,
+            ..second.clone()
+        };
+        assert_eq!(
+            incremental.get_rates_since_hz(&[], Some(first.timestamp)),
+            vec![75.0, 15.0]
+        );
+
+        // Repeated timestamps give no rate
+        assert_eq!(
+            second.get_rates_since_hz(&first.data, Some(second.timestamp)),
+            vec![0.0, 0.0]
+        );
+    }
+}
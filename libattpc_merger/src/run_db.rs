@@ -0,0 +1,239 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use fxhash::FxHashMap;
+
+use super::config::Config;
+use super::error::RunDatabaseError;
+
+// Columns with a meaning to the merger. Every other column is kept as an attribute of the run
+const RUN_COLUMN: &str = "run";
+const GOOD_COLUMN: &str = "good";
+// Columns which override the Config for the run
+const PAD_MAP_PATH_COLUMN: &str = "pad_map_path";
+const PEDESTAL_PATH_COLUMN: &str = "pedestal_path";
+const TIME_BUCKETS_COLUMN: &str = "time_buckets";
+
+/// Parse the good/bad flag of a run. An empty flag is good
+fn parse_good(value: &str, line_number: usize) -> Result<bool, RunDatabaseError> {
+    match value.to_lowercase().as_str() {
+        "" | "good" | "true" | "yes" | "y" | "1" => Ok(true),
+        "bad" | "false" | "no" | "n" | "0" => Ok(false),
+        _ => Err(RunDatabaseError::BadLine(
+            line_number,
+            format!("could not parse '{}' as a good/bad flag", value),
+        )),
+    }
+}
+
+/// The row of a run in the runs database
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RunRecord {
+    pub run: i32,
+    pub is_good: bool,
+    pub attributes: BTreeMap<String, String>, // every other column (i.e. beam, field, note) with a value, by column name
+    pub pad_map_path: Option<PathBuf>,
+    pub pedestal_path: Option<PathBuf>,
+    pub time_buckets: Option<u16>,
+}
+
+impl RunRecord {
+    /// Make the Config of the run, replacing the fields of the given Config which the record overrides
+    pub fn apply(&self, config: &Config) -> Config {
+        let mut run_config = config.clone();
+        if let Some(path) = &self.pad_map_path {
+            run_config.pad_map_path = Some(path.clone());
+        }
+        if let Some(path) = &self.pedestal_path {
+            run_config.pedestal_path = Some(path.clone());
+        }
+        if let Some(time_buckets) = self.time_buckets {
+            run_config.time_buckets = time_buckets;
+        }
+        run_config
+    }
+}
+
+/// RunDatabase is the per-run information kept by an experiment in a runs database CSV file.
+///
+/// The first row is a header naming the columns, and each following row is a run. The `run` column is required. The
+/// `good` column flags runs which should not be merged (bad, false, no, n or 0; anything else is good, as are runs
+/// with the column empty). The `pad_map_path`, `pedestal_path` and `time_buckets` columns override the Config for the
+/// run where they are not empty. Every other column (i.e. beam, field, note) is kept as a string attribute of the run.
+/// Whitespace around the elements is ignored, as are blank lines and lines starting with `#`. Values can not contain
+/// commas, as quoting is not supported.
+#[derive(Debug, Clone, Default)]
+pub struct RunDatabase {
+    records: FxHashMap<i32, RunRecord>,
+}
+
+impl RunDatabase {
+    /// Read the runs database at path
+    ///
+    /// Fails on the first bad line, i.e. a row with more elements than the header, a run or time_buckets which is not
+    /// an integer, a flag which is not good or bad, or a run which is in the file more than once.
+    pub fn new(path: &Path) -> Result<Self, RunDatabaseError> {
+        let mut contents = String::new();
+        let mut file = File::open(path)?;
+        file.read_to_string(&mut contents)?;
+
+        let mut lines = contents
+            .lines()
+            .enumerate()
+            .map(|(idx, line)| (idx + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+        let (header_line, header) = match lines.next() {
+            Some(header) => header,
+            None => return Ok(Self::default()),
+        };
+        let columns: Vec<String> = header
+            .split(',')
+            .map(|column| column.trim().to_lowercase())
+            .collect();
+        if !columns.iter().any(|column| column == RUN_COLUMN) {
+            return Err(RunDatabaseError::BadLine(
+                header_line,
+                format!("the header has no {} column", RUN_COLUMN),
+            ));
+        }
+
+        let mut database = Self::default();
+        for (line_number, line) in lines {
+            let entries: Vec<&str> = line.split(',').map(|entry| entry.trim()).collect();
+            if entries.len() > columns.len() {
+                return Err(RunDatabaseError::BadLine(
+                    line_number,
+                    format!(
+                        "expected at most {} columns, found {}",
+                        columns.len(),
+                        entries.len()
+                    ),
+                ));
+            }
+            let mut record = RunRecord {
+                is_good: true,
+                ..Default::default()
+            };
+            let mut run = None;
+            for (column, value) in columns.iter().zip(entries) {
+                if value.is_empty() && column != RUN_COLUMN {
+                    continue;
+                }
+                match column.as_str() {
+                    RUN_COLUMN => {
+                        run = Some(value.parse::<i32>().map_err(|e| {
+                            RunDatabaseError::BadLine(
+                                line_number,
+                                format!("could not parse run '{}' as an integer: {}", value, e),
+                            )
+                        })?)
+                    }
+                    GOOD_COLUMN => record.is_good = parse_good(value, line_number)?,
+                    PAD_MAP_PATH_COLUMN => record.pad_map_path = Some(PathBuf::from(value)),
+                    PEDESTAL_PATH_COLUMN => record.pedestal_path = Some(PathBuf::from(value)),
+                    TIME_BUCKETS_COLUMN => {
+                        record.time_buckets = Some(value.parse::<u16>().map_err(|e| {
+                            RunDatabaseError::BadLine(
+                                line_number,
+                                format!(
+                                    "could not parse time_buckets '{}' as an integer: {}",
+                                    value, e
+                                ),
+                            )
+                        })?)
+                    }
+                    _ => {
+                        record.attributes.insert(column.clone(), value.to_string());
+                    }
+                }
+            }
+            // A row shorter than the header may not reach the run column
+            record.run = match run {
+                Some(run) => run,
+                None => {
+                    return Err(RunDatabaseError::BadLine(
+                        line_number,
+                        String::from("the row has no run"),
+                    ))
+                }
+            };
+            if database.records.contains_key(&record.run) {
+                return Err(RunDatabaseError::BadLine(
+                    line_number,
+                    format!("run {} is in the database more than once", record.run),
+                ));
+            }
+            database.records.insert(record.run, record);
+        }
+
+        spdlog::info!(
+            "Loaded {} runs from the runs database {}",
+            database.records.len(),
+            path.display()
+        );
+
+        Ok(database)
+    }
+
+    /// Get the record of a run. Returns None if the run is not in the database
+    pub fn get_record(&self, run_number: &i32) -> Option<&RunRecord> {
+        self.records.get(run_number)
+    }
+}
+
+//Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_database() {
+        let dir =
+            std::env::temp_dir().join(format!("attpc_merger_run_db_parse_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("runs.csv");
+        std::fs::write(
+            &path,
+            "run, beam, field, good, note, pad_map_path, target\n\
+             # cabling change after run 2\n\
+             1, 16O, 3.0, good, first run, ,\n\
+             2, 16O, 3.0, bad, beam lost\n\
+             3, 16O, 2.5, , , /maps/after_cabling.csv, D2\n",
+        )
+        .unwrap();
+        let database = RunDatabase::new(&path).unwrap();
+
+        let first = database.get_record(&1).unwrap();
+        assert!(first.is_good);
+        assert_eq!(first.attributes["beam"], "16O");
+        assert_eq!(first.attributes["note"], "first run");
+        assert!(!first.attributes.contains_key("target"));
+        assert!(!database.get_record(&2).unwrap().is_good);
+
+        // The override only applies to its run, and unknown columns are kept
+        let third = database.get_record(&3).unwrap();
+        assert!(third.is_good);
+        assert_eq!(third.attributes["target"], "D2");
+        let config = Config::default();
+        assert_eq!(
+            third.apply(&config).pad_map_path,
+            Some(PathBuf::from("/maps/after_cabling.csv"))
+        );
+        assert_eq!(first.apply(&config).pad_map_path, None);
+        assert!(database.get_record(&4).is_none());
+
+        std::fs::write(&path, "run,good\n1,maybe\n").unwrap();
+        assert!(matches!(
+            RunDatabase::new(&path),
+            Err(RunDatabaseError::BadLine(2, _))
+        ));
+        std::fs::write(&path, "run,good\n1,good\n1,bad\n").unwrap();
+        assert!(matches!(
+            RunDatabase::new(&path),
+            Err(RunDatabaseError::BadLine(3, _))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
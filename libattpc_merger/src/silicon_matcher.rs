@@ -0,0 +1,291 @@
+use std::collections::VecDeque;
+
+use super::error::EventBuilderError;
+use super::event::Event;
+use super::event_builder::EventBuilder;
+use super::graw_frame::GrawFrame;
+
+/// The most events of either stream held while waiting for a match. Past this, the oldest events are let go unmatched,
+/// so that a stream which stops (i.e. the silicon CoBo dropping out) can't use up the memory
+const MAX_PENDING_EVENTS: usize = 64;
+
+/// An event the SiliconMatcher is done with
+#[derive(Debug)]
+pub enum MatchedEvent {
+    Tpc(Event), // a TPC event, with the traces of its matching silicon event if there was one
+    Orphan(Event), // a silicon event which matched no TPC event
+}
+
+/// Matches the events of the silicon CoBo (Config::si_cobo) to the TPC events by timestamp, for a silicon CoBo on its own
+/// trigger whose event ids drift from those of the TPC CoBos.
+///
+/// The silicon frames are built into events by a separate EventBuilder. The time of a silicon event is the event_time
+/// of the silicon CoBo's frames shifted by the offset onto the clock of the TPC CoBos, as the silicon CoBo can run on
+/// its own clock (i.e. CoBo 10, which is in sync with FRIBDAQ). Each TPC event is given the silicon event whose time
+/// is nearest to its timestamp, if it is within the coincidence window (in clock ticks). The silicon events matching
+/// no TPC event are orphans.
+///
+/// TPC events are only let go once a silicon event past their window was built (or the run ended), so the events come
+/// out of the matcher in order, a little later than they are built.
+#[derive(Debug)]
+pub struct SiliconMatcher {
+    evb: EventBuilder,
+    cobo: u8,
+    window: u64,
+    offset: i64,
+    tpc: VecDeque<Event>,
+    silicon: VecDeque<Event>,
+    latest_silicon: Option<u64>, // latest time of the silicon events built, on the TPC clock
+    ready: Vec<MatchedEvent>,
+    matched: u64,
+    orphans: u64,
+}
+
+impl SiliconMatcher {
+    /// Create a matcher for the frames of the given CoBo, building the silicon events with evb and matching within
+    /// window clock ticks once offset is added to the silicon times
+    pub fn new(evb: EventBuilder, cobo: u8, window: u64, offset: i64) -> Self {
+        Self {
+            evb,
+            cobo,
+            window,
+            offset,
+            tpc: VecDeque::new(),
+            silicon: VecDeque::new(),
+            latest_silicon: None,
+            ready: Vec::new(),
+            matched: 0,
+            orphans: 0,
+        }
+    }
+
+    /// Add a frame of the silicon CoBo
+    pub fn append_silicon_frame(&mut self, frame: GrawFrame) -> Result<(), EventBuilderError> {
+        if let Some(event) = self.evb.append_frame(frame)? {
+            self.push_silicon(event)?;
+        }
+        Ok(())
+    }
+
+    /// Add a built TPC event
+    pub fn push_tpc(&mut self, event: Event) -> Result<(), EventBuilderError> {
+        self.tpc.push_back(event);
+        self.release(false)
+    }
+
+    /// Flush the last silicon event and let go of every event held. Used at the end of the run
+    pub fn finish(&mut self) -> Result<(), EventBuilderError> {
+        if let Some(event) = self.evb.flush_final_event()? {
+            self.push_silicon(event)?;
+        }
+        self.release(true)
+    }
+
+    /// Take the events the matcher is done with, in order
+    pub fn take_ready(&mut self) -> Vec<MatchedEvent> {
+        std::mem::take(&mut self.ready)
+    }
+
+    /// The number of TPC events which were given a silicon event
+    pub fn get_matched(&self) -> &u64 {
+        &self.matched
+    }
+
+    /// The number of silicon events which matched no TPC event
+    pub fn get_orphans(&self) -> &u64 {
+        &self.orphans
+    }
+
+    /// The EventBuilder of the silicon events
+    pub fn get_event_builder(&self) -> &EventBuilder {
+        &self.evb
+    }
+
+    /// The time of a silicon event on the clock of the TPC CoBos
+    fn get_silicon_time(&self, event: &Event) -> u64 {
+        event
+            .get_cobo_timestamp(self.cobo)
+            .unwrap_or_default()
+            .saturating_add_signed(self.offset)
+    }
+
+    fn push_silicon(&mut self, event: Event) -> Result<(), EventBuilderError> {
+        let time = self.get_silicon_time(&event);
+        self.latest_silicon = Some(self.latest_silicon.map_or(time, |latest| latest.max(time)));
+        self.silicon.push_back(event);
+        self.release(false)
+    }
+
+    fn orphan_front(&mut self) {
+        if let Some(event) = self.silicon.pop_front() {
+            self.orphans += 1;
+            self.ready.push(MatchedEvent::Orphan(event));
+        }
+    }
+
+    /// Match the TPC events which can not get a nearer silicon event, oldest first
+    fn release(&mut self, finished: bool) -> Result<(), EventBuilderError> {
+        while let Some(time) = self.tpc.front().map(|event| event.timestamp) {
+            let is_ready = finished
+                || self.tpc.len() > MAX_PENDING_EVENTS
+                || self
+                    .latest_silicon
+                    .is_some_and(|latest| latest > time.saturating_add(self.window));
+            if !is_ready {
+                break;
+            }
+            let mut tpc = self.tpc.pop_front().unwrap();
+            // Too early for this TPC event, so too early for the later ones as well
+            while self
+                .silicon
+                .front()
+                .is_some_and(|si| self.get_silicon_time(si).saturating_add(self.window) < time)
+            {
+                self.orphan_front();
+            }
+            let nearest = self
+                .silicon
+                .iter()
+                .enumerate()
+                .map(|(index, si)| (index, self.get_silicon_time(si).abs_diff(time)))
+                .filter(|(_, distance)| *distance <= self.window)
+                .min_by_key(|(_, distance)| *distance)
+                .map(|(index, _)| index);
+            if let Some(index) = nearest {
+                let mut si = self.silicon.remove(index).unwrap();
+                // The silicon event is part of the TPC event, whatever its own id
+                si.event_id = tpc.event_id;
+                tpc.merge(si)?;
+                self.matched += 1;
+            }
+            self.ready.push(MatchedEvent::Tpc(tpc));
+        }
+        while self.silicon.len() > MAX_PENDING_EVENTS || (finished && !self.silicon.is_empty()) {
+            self.orphan_front();
+        }
+        Ok(())
+    }
+}
+
+//Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DuplicateBucketPolicy;
+    use crate::constants::COBO_WITH_TIMESTAMP;
+    use crate::pad_map::PadMap;
+
+    fn make_frame(cobo: u8, event_id: u32, event_time: u64) -> GrawFrame {
+        let mut frame = GrawFrame::new();
+        frame.header.cobo_id = cobo;
+        frame.header.event_id = event_id;
+        frame.header.event_time = event_time;
+        frame
+    }
+
+    fn make_builder() -> EventBuilder {
+        EventBuilder::new(
+            PadMap::new(None).unwrap(),
+            false,
+            false,
+            512,
+            DuplicateBucketPolicy::Overwrite,
+            0,
+            false,
+        )
+    }
+
+    #[test]
+    fn test_drifting_ids() {
+        let mut tpc_evb = make_builder();
+        let mut matcher = SiliconMatcher::new(make_builder(), COBO_WITH_TIMESTAMP, 5, 0);
+        // The silicon CoBo missed the trigger of TPC event 3, so its ids lag by one from there on. It also has an
+        // extra event (id 6, time 950) with no TPC event
+        let tpc_times = [100, 200, 300, 400, 500, 600];
+        let silicon = [(0, 101), (1, 198), (2, 303), (3, 500), (4, 602), (6, 950)];
+        let mut silicon_frames = silicon.iter().peekable();
+        for (event_id, time) in tpc_times.iter().enumerate() {
+            while let Some((si_id, si_time)) = silicon_frames.next_if(|(_, t)| t <= time) {
+                matcher
+                    .append_silicon_frame(make_frame(COBO_WITH_TIMESTAMP, *si_id, *si_time))
+                    .unwrap();
+            }
+            if let Some(event) = tpc_evb
+                .append_frame(make_frame(0, event_id as u32, *time))
+                .unwrap()
+            {
+                matcher.push_tpc(event).unwrap();
+            }
+        }
+        for (si_id, si_time) in silicon_frames {
+            matcher
+                .append_silicon_frame(make_frame(COBO_WITH_TIMESTAMP, *si_id, *si_time))
+                .unwrap();
+        }
+        matcher
+            .push_tpc(tpc_evb.flush_final_event().unwrap().unwrap())
+            .unwrap();
+        matcher.finish().unwrap();
+
+        let mut tpc = Vec::new();
+        let mut orphans = Vec::new();
+        for event in matcher.take_ready() {
+            match event {
+                MatchedEvent::Tpc(event) => tpc.push(event),
+                MatchedEvent::Orphan(event) => orphans.push(event),
+            }
+        }
+        assert_eq!(tpc.len(), 6);
+        for (event_id, event) in tpc.iter().enumerate() {
+            assert_eq!(event.event_id, event_id as u32);
+        }
+        // Matched by time, not by id: TPC event 4 gets silicon event 3, and TPC event 3 has no silicon
+        assert!(!tpc[3].has_timestampother());
+        assert_eq!(tpc[4].timestampother, 500);
+        assert_eq!(tpc[5].timestampother, 602);
+        assert_eq!(tpc[2].timestampother, 303);
+        assert_eq!(orphans.len(), 1);
+        assert_eq!(orphans[0].event_id, 6);
+        assert_eq!(*matcher.get_matched(), 5);
+        assert_eq!(*matcher.get_orphans(), 1);
+    }
+
+    #[test]
+    fn test_window() {
+        // A silicon event too far from every TPC event is an orphan, even if its id matches
+        let mut matcher = SiliconMatcher::new(make_builder(), COBO_WITH_TIMESTAMP, 5, 0);
+        let mut tpc_evb = make_builder();
+        tpc_evb.append_frame(make_frame(0, 0, 100)).unwrap();
+        matcher
+            .append_silicon_frame(make_frame(COBO_WITH_TIMESTAMP, 0, 120))
+            .unwrap();
+        matcher
+            .push_tpc(tpc_evb.flush_final_event().unwrap().unwrap())
+            .unwrap();
+        matcher.finish().unwrap();
+        let ready = matcher.take_ready();
+        assert_eq!(ready.len(), 2);
+        assert!(matches!(&ready[0], MatchedEvent::Tpc(event) if !event.has_timestampother()));
+        assert!(matches!(&ready[1], MatchedEvent::Orphan(event) if event.timestampother == 120));
+    }
+
+    #[test]
+    fn test_offset() {
+        // The silicon CoBo is on its own clock, 1000 ticks ahead of the TPC CoBos. Without the offset nothing matches
+        let run = |offset: i64| {
+            let mut matcher = SiliconMatcher::new(make_builder(), 3, 5, offset);
+            let mut tpc_evb = make_builder();
+            tpc_evb.append_frame(make_frame(0, 0, 100)).unwrap();
+            matcher
+                .append_silicon_frame(make_frame(3, 7, 1102))
+                .unwrap();
+            matcher
+                .push_tpc(tpc_evb.flush_final_event().unwrap().unwrap())
+                .unwrap();
+            matcher.finish().unwrap();
+            (*matcher.get_matched(), *matcher.get_orphans())
+        };
+        assert_eq!(run(0), (0, 1));
+        assert_eq!(run(-1000), (1, 0));
+    }
+}
@@ -0,0 +1,36 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A request to stop merging early (i.e. on Ctrl-C), shared by every clone of the flag
+///
+/// The workers check the flag between frames and ring items. The run being merged is cut short, but its output is
+/// still closed so that the file is readable (but marked incomplete, so the run is merged again by the next merge),
+/// and no more runs are started.
+#[derive(Debug, Clone, Default)]
+pub struct StopFlag(Arc<AtomicBool>);
+
+impl StopFlag {
+    pub fn request_stop(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stop_requested(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+//Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shared_flag() {
+        let flag = StopFlag::default();
+        let clone = flag.clone();
+        assert!(!clone.is_stop_requested());
+        flag.request_stop();
+        assert!(clone.is_stop_requested());
+        assert!(!StopFlag::default().is_stop_requested());
+    }
+}
@@ -0,0 +1,267 @@
+//! Builders for synthetic GET and FRIBDAQ data, used to write regression tests for the full pipeline.
+//!
+//! Only compiled for tests, or when the `testing` feature is enabled. Downstream crates can enable the feature to
+//! generate their own inputs.
+//!
+//! The bytes produced follow the formats parsed by GrawFrame and the RingItem types. They are not a complete
+//! description of either format; only the fields read by attpc_merger are filled in.
+use std::path::{Path, PathBuf};
+
+use super::constants::*;
+use super::graw_frame::GrawData;
+
+//Ring type values used by FRIBDAQ
+const BEGIN_RUN_TYPE: u32 = 1;
+const END_RUN_TYPE: u32 = 2;
+const SCALERS_TYPE: u32 = 20;
+const PHYSICS_TYPE: u32 = 30;
+const COUNTER_TYPE: u32 = 31;
+
+/// Make the bytes of a GrawFrame with an empty body of n_items of item_size, padded to a whole number of 256 byte units
+/// like the frames written by the GET DAQ.
+fn make_graw_frame_header(
+    event_id: u32,
+    event_time: u64,
+    cobo_id: u8,
+    asad_id: u8,
+    frame_type: u16,
+    item_size: u16,
+    n_items: usize,
+) -> Vec<u8> {
+    let header_bytes = (EXPECTED_HEADER_SIZE as u32 * SIZE_UNIT) as usize;
+    let data_bytes = n_items * item_size as usize;
+    let frame_size = (header_bytes + data_bytes).div_ceil(SIZE_UNIT as usize);
+
+    let mut frame = vec![0u8; frame_size * SIZE_UNIT as usize];
+    frame[0] = EXPECTED_META_TYPE;
+    frame[1..4].copy_from_slice(&(frame_size as u32).to_be_bytes()[1..]); // 24 bit word
+    frame[5..7].copy_from_slice(&frame_type.to_be_bytes());
+    frame[8..10].copy_from_slice(&EXPECTED_HEADER_SIZE.to_be_bytes());
+    frame[10..12].copy_from_slice(&item_size.to_be_bytes());
+    frame[12..16].copy_from_slice(&(n_items as u32).to_be_bytes());
+    frame[16..22].copy_from_slice(&event_time.to_be_bytes()[2..]); // 48 bit word
+    frame[22..26].copy_from_slice(&event_id.to_be_bytes());
+    frame[26] = cobo_id;
+    frame[27] = asad_id;
+    frame
+}
+
+/// Make the bytes of a partial readout GrawFrame for the given event, CoBo, and AsAd containing the given data.
+///
+/// The frame is padded to a whole number of 256 byte units, like the frames written by the GET DAQ.
+pub fn make_graw_frame(
+    event_id: u32,
+    event_time: u64,
+    cobo_id: u8,
+    asad_id: u8,
+    data: &[GrawData],
+) -> Vec<u8> {
+    let header_bytes = (EXPECTED_HEADER_SIZE as u32 * SIZE_UNIT) as usize;
+    let mut frame = make_graw_frame_header(
+        event_id,
+        event_time,
+        cobo_id,
+        asad_id,
+        EXPECTED_FRAME_TYPE_PARTIAL,
+        EXPECTED_ITEM_SIZE_PARTIAL,
+        data.len(),
+    );
+
+    for (idx, datum) in data.iter().enumerate() {
+        let raw: u32 = ((datum.aget_id as u32) << 30)
+            | ((datum.channel as u32) << 23)
+            | ((datum.time_bucket_id as u32) << 14)
+            | (datum.sample as u32 & GET_ADC_MASK as u32);
+        let start = header_bytes + idx * EXPECTED_ITEM_SIZE_PARTIAL as usize;
+        frame[start..(start + 4)].copy_from_slice(&raw.to_be_bytes());
+    }
+
+    frame
+}
+
+/// Make the bytes of a full readout GrawFrame for the given event, CoBo, and AsAd.
+///
+/// The samples are given as (aget, sample) in the order they are read out. In full readout the channel and time bucket
+/// are not written; they are given by the position of the sample among the samples of its AGET (68 channels per time bucket).
+pub fn make_full_graw_frame(
+    event_id: u32,
+    event_time: u64,
+    cobo_id: u8,
+    asad_id: u8,
+    samples: &[(u8, i16)],
+) -> Vec<u8> {
+    let header_bytes = (EXPECTED_HEADER_SIZE as u32 * SIZE_UNIT) as usize;
+    let mut frame = make_graw_frame_header(
+        event_id,
+        event_time,
+        cobo_id,
+        asad_id,
+        EXPECTED_FRAME_TYPE_FULL,
+        EXPECTED_ITEM_SIZE_FULL,
+        samples.len(),
+    );
+
+    for (idx, (aget_id, sample)) in samples.iter().enumerate() {
+        let raw: u16 = ((*aget_id as u16) << 14) | (*sample as u16 & GET_ADC_MASK);
+        let start = header_bytes + idx * EXPECTED_ITEM_SIZE_FULL as usize;
+        frame[start..(start + 2)].copy_from_slice(&raw.to_be_bytes());
+    }
+
+    frame
+}
+
+/// Write the given frames to a .graw file for a CoBo and AsAd in a directory, named like the files of the GET DAQ.
+///
+/// Returns the path to the file.
+pub fn write_asad_file(
+    directory: &Path,
+    cobo_id: u8,
+    asad_id: u8,
+    frames: &[Vec<u8>],
+) -> std::io::Result<PathBuf> {
+    let path = directory.join(format!("CoBo{}_AsAd{}_0000.graw", cobo_id, asad_id));
+    std::fs::write(&path, frames.concat())?;
+    Ok(path)
+}
+
+/// Wrap a ring item body with a ring header (size, type) and an empty body header
+fn make_ring(ring_type: u32, body: &[u8]) -> Vec<u8> {
+    let size = (body.len() + 12) as u32;
+    let mut ring = Vec::with_capacity(size as usize);
+    ring.extend_from_slice(&size.to_le_bytes());
+    ring.extend_from_slice(&ring_type.to_le_bytes());
+    ring.extend_from_slice(&0u32.to_le_bytes()); // no body header
+    ring.extend_from_slice(body);
+    ring
+}
+
+/// Add a body header (as written by the FRIBDAQ event builder) to a ring item made by one of the builders here
+pub fn add_body_header(ring: &[u8], timestamp: u64, source_id: u32) -> Vec<u8> {
+    const BODY_HEADER_SIZE: u32 = 20;
+    let size = ring.len() as u32 + BODY_HEADER_SIZE - 4;
+    let mut with_header = Vec::with_capacity(size as usize);
+    with_header.extend_from_slice(&size.to_le_bytes());
+    with_header.extend_from_slice(&ring[4..8]); // type
+    with_header.extend_from_slice(&BODY_HEADER_SIZE.to_le_bytes());
+    with_header.extend_from_slice(&timestamp.to_le_bytes());
+    with_header.extend_from_slice(&source_id.to_le_bytes());
+    with_header.extend_from_slice(&0u32.to_le_bytes()); // barrier type
+    with_header.extend_from_slice(&ring[12..]);
+    with_header
+}
+
+/// Make the bytes of an event built (glom) physics ring item with a fragment for each (source id, ring item)
+///
+/// The item and its fragments have the given timestamp.
+pub fn make_built_physics_ring(timestamp: u64, fragments: &[(u32, Vec<u8>)]) -> Vec<u8> {
+    let mut fragment_bytes = Vec::new();
+    for (source_id, ring) in fragments {
+        fragment_bytes.extend_from_slice(&timestamp.to_le_bytes());
+        fragment_bytes.extend_from_slice(&source_id.to_le_bytes());
+        fragment_bytes.extend_from_slice(&(ring.len() as u32).to_le_bytes());
+        fragment_bytes.extend_from_slice(&0u32.to_le_bytes()); // barrier type
+        fragment_bytes.extend_from_slice(ring);
+    }
+    let mut body = Vec::with_capacity(fragment_bytes.len() + 4);
+    body.extend_from_slice(&((fragment_bytes.len() + 4) as u32).to_le_bytes());
+    body.extend_from_slice(&fragment_bytes);
+    add_body_header(&make_ring(PHYSICS_TYPE, &body), timestamp, 0)
+}
+
+/// Make the bytes of a BeginRun ring item
+pub fn make_begin_run_ring(run: u32, start: u32, title: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&run.to_le_bytes());
+    body.extend_from_slice(&0u32.to_le_bytes());
+    body.extend_from_slice(&start.to_le_bytes());
+    body.extend_from_slice(&0u32.to_le_bytes());
+    body.extend_from_slice(title.as_bytes());
+    make_ring(BEGIN_RUN_TYPE, &body)
+}
+
+/// Make the bytes of an EndRun ring item
+pub fn make_end_run_ring(stop: u32, time: u32) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&stop.to_le_bytes());
+    body.extend_from_slice(&time.to_le_bytes());
+    make_ring(END_RUN_TYPE, &body)
+}
+
+/// Make the bytes of a Scalers ring item
+pub fn make_scalers_ring(
+    start_offset: u32,
+    stop_offset: u32,
+    timestamp: u32,
+    data: &[u32],
+) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&start_offset.to_le_bytes());
+    body.extend_from_slice(&stop_offset.to_le_bytes());
+    body.extend_from_slice(&timestamp.to_le_bytes());
+    body.extend_from_slice(&0u32.to_le_bytes());
+    body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    body.extend_from_slice(&1u32.to_le_bytes()); // incremental
+    for value in data {
+        body.extend_from_slice(&value.to_le_bytes());
+    }
+    make_ring(SCALERS_TYPE, &body)
+}
+
+/// Make the bytes of a Physics ring item in the standard AT-TPC VMEUSB stack layout.
+///
+/// The SIS3300 has only its first group enabled. The samples are given as pairs of (channel 1, channel 0), the
+/// order in which the module reads them out. The body is written as a single VMUSB buffer, so there is one boundary word at the start.
+pub fn make_physics_ring(
+    event: u32,
+    timestamp: u32,
+    fadc_samples: &[(u16, u16)],
+    coinc: u16,
+) -> Vec<u8> {
+    let mut stack = Vec::new();
+    stack.extend_from_slice(&event.to_le_bytes());
+    stack.extend_from_slice(&timestamp.to_le_bytes());
+    // SIS3300
+    stack.extend_from_slice(&0x1903u16.to_le_bytes());
+    stack.extend_from_slice(&1u16.to_le_bytes()); // group enable flags
+    stack.extend_from_slice(&0u32.to_le_bytes()); // daq register
+    stack.extend_from_slice(&0xfadcu16.to_le_bytes());
+    stack.extend_from_slice(&0u32.to_le_bytes()); // group trigger, no wrap around
+    stack.extend_from_slice(&(fadc_samples.len() as u32).to_le_bytes());
+    for (channel_1, channel_0) in fadc_samples {
+        stack.extend_from_slice(&channel_1.to_le_bytes());
+        stack.extend_from_slice(&channel_0.to_le_bytes());
+    }
+    stack.extend_from_slice(&0xffffu16.to_le_bytes());
+    // V977
+    stack.extend_from_slice(&0x977u16.to_le_bytes());
+    stack.extend_from_slice(&coinc.to_le_bytes());
+
+    let boundary = (stack.len() / 2) as u16;
+    let mut body = Vec::with_capacity(stack.len() + 2);
+    body.extend_from_slice(&boundary.to_le_bytes());
+    body.extend_from_slice(&stack);
+    make_ring(PHYSICS_TYPE, &body)
+}
+
+/// Make the bytes of a physics event count (Counter) ring item, reporting the given number of physics items
+pub fn make_counter_ring(time_offset: u32, timestamp: u32, count: u64) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&time_offset.to_le_bytes());
+    body.extend_from_slice(&1u32.to_le_bytes()); // offset divisor
+    body.extend_from_slice(&timestamp.to_le_bytes());
+    body.extend_from_slice(&count.to_le_bytes());
+    make_ring(COUNTER_TYPE, &body)
+}
+
+/// Write the given ring items to a .evt file for a run in a directory, named like the files of FRIBDAQ.
+///
+/// Returns the path to the file.
+pub fn write_evt_file(
+    directory: &Path,
+    run_number: i32,
+    rings: &[Vec<u8>],
+) -> std::io::Result<PathBuf> {
+    let path = directory.join(format!("run-{:0>4}-00.evt", run_number));
+    std::fs::write(&path, rings.concat())?;
+    Ok(path)
+}
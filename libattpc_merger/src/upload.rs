@@ -0,0 +1,228 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use s3::creds::Credentials;
+use s3::{Bucket, Region};
+
+use super::error::UploadError;
+
+/// The size of the parts of a multipart upload. Every part but the last must be at least 5 MiB
+const UPLOAD_PART_SIZE: usize = 64 * 1024 * 1024;
+/// The region used when AWS_REGION is not set. MinIO and most other stores ignore it
+const DEFAULT_UPLOAD_REGION: &str = "us-east-1";
+const UPLOAD_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// The object store location given by an upload_url
+#[derive(Debug, Clone, PartialEq)]
+pub struct UploadTarget {
+    pub endpoint: String, // scheme, host and port of the store
+    pub bucket: String,
+    pub prefix: String, // the key prefix of the uploaded files, empty or ending in /
+}
+
+impl UploadTarget {
+    /// Parse an upload_url of the form http(s)://host[:port]/bucket[/prefix]
+    pub fn new(url: &str) -> Result<Self, UploadError> {
+        let bad_url = || UploadError::BadUrl(url.to_string());
+        let (scheme, rest) = url.split_once("://").ok_or_else(bad_url)?;
+        if scheme != "http" && scheme != "https" {
+            return Err(bad_url());
+        }
+        let (host, path) = rest.split_once('/').ok_or_else(bad_url)?;
+        let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+        if host.is_empty() || bucket.is_empty() {
+            return Err(bad_url());
+        }
+        let prefix = prefix.trim_matches('/');
+        Ok(Self {
+            endpoint: format!("{scheme}://{host}"),
+            bucket: bucket.to_string(),
+            prefix: match prefix.is_empty() {
+                true => String::new(),
+                false => format!("{prefix}/"),
+            },
+        })
+    }
+
+    /// The key of the object a local file is uploaded to, which is the prefix followed by the file name
+    pub fn key(&self, path: &Path) -> String {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        format!("{}{}", self.prefix, name)
+    }
+
+    /// Connect to the bucket. The credentials are read from the environment (AWS_ACCESS_KEY_ID and
+    /// AWS_SECRET_ACCESS_KEY), as is the region (AWS_REGION)
+    fn open_bucket(&self) -> Result<Box<Bucket>, UploadError> {
+        let region = Region::Custom {
+            region: std::env::var("AWS_REGION").unwrap_or(String::from(DEFAULT_UPLOAD_REGION)),
+            endpoint: self.endpoint.clone(),
+        };
+        let credentials =
+            Credentials::from_env().map_err(|e| UploadError::S3Error(e.to_string()))?;
+        Ok(Bucket::new(&self.bucket, region, credentials)
+            .map_err(|e| UploadError::S3Error(e.to_string()))?
+            .with_path_style())
+    }
+}
+
+/// Upload the file at path to the target as a multipart upload, calling on_progress with the bytes sent after each part
+///
+/// The upload is verified once complete: the object must have the size of the file, and its etag must be that of a
+/// multipart upload with the number of parts sent. A failed upload is aborted so no partial parts are left in the store.
+/// Returns the number of bytes uploaded.
+pub fn upload_file(
+    target: &UploadTarget,
+    path: &Path,
+    on_progress: &mut dyn FnMut(u64),
+) -> Result<u64, UploadError> {
+    let bucket = target.open_bucket()?;
+    let key = target.key(path);
+    let mut file = File::open(path)?;
+    let file_size = file.metadata()?.len();
+
+    let upload = bucket
+        .initiate_multipart_upload(&key, UPLOAD_CONTENT_TYPE)
+        .map_err(|e| UploadError::S3Error(e.to_string()))?;
+    let mut send_parts = || -> Result<Vec<s3::serde_types::Part>, UploadError> {
+        let mut parts = vec![];
+        let mut sent: u64 = 0;
+        loop {
+            let mut chunk = Vec::with_capacity(UPLOAD_PART_SIZE);
+            file.by_ref()
+                .take(UPLOAD_PART_SIZE as u64)
+                .read_to_end(&mut chunk)?;
+            // An empty file is still sent as one (empty) part
+            if chunk.is_empty() && !parts.is_empty() {
+                break;
+            }
+            let chunk_size = chunk.len() as u64;
+            let part = bucket
+                .put_multipart_chunk(
+                    chunk,
+                    &key,
+                    parts.len() as u32 + 1,
+                    &upload.upload_id,
+                    UPLOAD_CONTENT_TYPE,
+                )
+                .map_err(|e| UploadError::S3Error(e.to_string()))?;
+            parts.push(part);
+            sent += chunk_size;
+            on_progress(sent);
+            if chunk_size < UPLOAD_PART_SIZE as u64 {
+                break;
+            }
+        }
+        Ok(parts)
+    };
+    let parts = match send_parts() {
+        Ok(parts) => parts,
+        Err(e) => {
+            if let Err(abort) = bucket.abort_upload(&key, &upload.upload_id) {
+                spdlog::warn!("Could not abort the failed upload of {key}: {abort}");
+            }
+            return Err(e);
+        }
+    };
+    let n_parts = parts.len();
+    bucket
+        .complete_multipart_upload(&key, &upload.upload_id, parts)
+        .map_err(|e| UploadError::S3Error(e.to_string()))?;
+
+    // Verify the object
+    let (head, _) = bucket
+        .head_object(&key)
+        .map_err(|e| UploadError::S3Error(e.to_string()))?;
+    let remote_size = head.content_length.unwrap_or(0).max(0) as u64;
+    verify_upload(
+        path,
+        file_size,
+        n_parts,
+        remote_size,
+        &head.e_tag.unwrap_or_default(),
+    )?;
+
+    Ok(file_size)
+}
+
+/// Check that an uploaded object matches the file at path: it must have the size of the file, and its etag must be
+/// that of a multipart upload of n_parts parts (the etag of the parts, a dash, and the number of parts)
+pub fn verify_upload(
+    path: &Path,
+    file_size: u64,
+    n_parts: usize,
+    remote_size: u64,
+    etag: &str,
+) -> Result<(), UploadError> {
+    if remote_size != file_size {
+        return Err(UploadError::SizeMismatch(
+            path.to_path_buf(),
+            file_size,
+            remote_size,
+        ));
+    }
+    let etag_parts = etag
+        .trim_matches('"')
+        .rsplit_once('-')
+        .and_then(|(_, parts)| parts.parse::<usize>().ok());
+    if etag_parts != Some(n_parts) {
+        return Err(UploadError::PartsMismatch(
+            path.to_path_buf(),
+            etag.to_string(),
+            n_parts,
+        ));
+    }
+    Ok(())
+}
+
+//Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upload_target() {
+        let target = UploadTarget::new("https://minio.example.org:9000/attpc/e20009/").unwrap();
+        assert_eq!(target.endpoint, "https://minio.example.org:9000");
+        assert_eq!(target.bucket, "attpc");
+        assert_eq!(target.prefix, "e20009/");
+        assert_eq!(
+            target.key(Path::new("/data/run_0001.h5")),
+            "e20009/run_0001.h5"
+        );
+
+        let target = UploadTarget::new("http://localhost:9000/attpc").unwrap();
+        assert_eq!(target.prefix, "");
+        assert_eq!(target.key(Path::new("run_0001.h5")), "run_0001.h5");
+
+        assert!(UploadTarget::new("localhost:9000/attpc").is_err());
+        assert!(UploadTarget::new("ftp://localhost/attpc").is_err());
+        assert!(UploadTarget::new("http://localhost:9000").is_err());
+        assert!(UploadTarget::new("http://localhost:9000/").is_err());
+    }
+
+    #[test]
+    fn test_verify_upload() {
+        let path = Path::new("run_0001.h5");
+        let etag = "\"d41d8cd98f00b204e9800998ecf8427e-12\"";
+        assert!(verify_upload(path, 100, 12, 100, etag).is_ok());
+        assert!(verify_upload(path, 100, 12, 100, etag.trim_matches('"')).is_ok());
+        assert!(matches!(
+            verify_upload(path, 100, 12, 99, etag),
+            Err(UploadError::SizeMismatch(_, 100, 99))
+        ));
+        // Only the number of parts counts, not the end of it
+        assert!(matches!(
+            verify_upload(path, 100, 2, 100, etag),
+            Err(UploadError::PartsMismatch(_, _, 2))
+        ));
+        // A single part upload has a plain etag
+        assert!(matches!(
+            verify_upload(path, 100, 1, 100, "\"d41d8cd98f00b204e9800998ecf8427e\""),
+            Err(UploadError::PartsMismatch(_, _, 1))
+        ));
+    }
+}
@@ -1,8 +1,40 @@
-#[derive(Debug, Clone, Default)]
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// The phase of work a worker is currently in
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum WorkerPhase {
+    #[default]
+    Processing,
+    WaitingForData, // Following files which the DAQ has not finished writing
+    Copying,        // Moving the source files after the merge
+    Uploading,      // Sending the output to the object store after the merge
+    Checksumming,   // Hashing the output after the merge
+}
+
+/// Running totals of the work done on a run, for monitoring. Each total only grows over the run.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RunCounts {
+    pub events: u64,         // GET events merged so far
+    pub bytes_read: u64,     // GRAW bytes read so far
+    pub dropped_frames: u64, // late frames dropped by the EventBuilder so far
+}
+
+#[derive(Debug, Clone)]
 pub struct WorkerStatus {
     pub progress: f32,
     pub run_number: i32,
     pub worker_id: usize,
+    pub phase: WorkerPhase,
+    pub heartbeat: Instant,           // when the status was made
+    pub counts: RunCounts,            // totals of the run so far, if the sender tracks them
+    pub background_copy: Option<i32>, // the run whose source files are being copied while this run is merged
+}
+
+impl Default for WorkerStatus {
+    fn default() -> Self {
+        Self::new(0.0, 0, 0)
+    }
 }
 
 impl WorkerStatus {
@@ -11,6 +43,153 @@ impl WorkerStatus {
             progress,
             run_number,
             worker_id,
+            phase: WorkerPhase::Processing,
+            heartbeat: Instant::now(),
+            counts: RunCounts::default(),
+            background_copy: None,
         }
     }
+
+    /// Make a status for a worker that is waiting on the DAQ to write more data
+    pub fn waiting(progress: f32, run_number: i32, worker_id: usize) -> Self {
+        Self {
+            progress,
+            run_number,
+            worker_id,
+            phase: WorkerPhase::WaitingForData,
+            heartbeat: Instant::now(),
+            counts: RunCounts::default(),
+            background_copy: None,
+        }
+    }
+
+    /// Make a status for a worker that is copying files, where progress is the fraction of the bytes copied
+    pub fn copying(progress: f32, run_number: i32, worker_id: usize) -> Self {
+        Self {
+            progress,
+            run_number,
+            worker_id,
+            phase: WorkerPhase::Copying,
+            heartbeat: Instant::now(),
+            counts: RunCounts::default(),
+            background_copy: None,
+        }
+    }
+
+    /// Make a status for a worker that is uploading the output, where progress is the fraction of the bytes uploaded
+    pub fn uploading(progress: f32, run_number: i32, worker_id: usize) -> Self {
+        Self {
+            progress,
+            run_number,
+            worker_id,
+            phase: WorkerPhase::Uploading,
+            heartbeat: Instant::now(),
+            counts: RunCounts::default(),
+            background_copy: None,
+        }
+    }
+
+    /// Make a status for a worker that is hashing the output, where progress is the fraction of the bytes hashed
+    pub fn checksumming(progress: f32, run_number: i32, worker_id: usize) -> Self {
+        Self {
+            progress,
+            run_number,
+            worker_id,
+            phase: WorkerPhase::Checksumming,
+            heartbeat: Instant::now(),
+            counts: RunCounts::default(),
+            background_copy: None,
+        }
+    }
+
+    /// Attach the totals of the run so far to the status
+    pub fn with_counts(mut self, counts: RunCounts) -> Self {
+        self.counts = counts;
+        self
+    }
+
+    /// Tag the status with the run whose source files are being copied in the background, if any
+    pub fn with_background_copy(mut self, run_number: Option<i32>) -> Self {
+        self.background_copy = run_number;
+        self
+    }
+
+    /// Check if the worker has not sent a status for at least the timeout, i.e. it may be hung on a read
+    ///
+    /// Workers send a status at least every few seconds while they are working, even if the progress hasn't changed.
+    pub fn is_stalled(&self, timeout: Duration) -> bool {
+        self.heartbeat.elapsed() >= timeout
+    }
+}
+
+/// The latest status of each worker, keyed by the worker_id of the status
+///
+/// Statuses are looked up by id rather than by position, so a status from a worker which was never added (or whose id
+/// is past the number of workers) is kept instead of indexing out of bounds.
+#[derive(Debug, Clone, Default)]
+pub struct WorkerStatuses {
+    statuses: BTreeMap<usize, WorkerStatus>,
+}
+
+impl WorkerStatuses {
+    /// Make an empty status for each of n_workers workers, with ids 0..n_workers
+    pub fn new(n_workers: usize) -> Self {
+        Self {
+            statuses: (0..n_workers)
+                .map(|id| (id, WorkerStatus::new(0.0, 0, id)))
+                .collect(),
+        }
+    }
+
+    /// Replace the status of the worker which sent it
+    pub fn update(&mut self, status: WorkerStatus) {
+        self.statuses.insert(status.worker_id, status);
+    }
+
+    /// The latest status of a worker, if it has one
+    pub fn get(&self, worker_id: usize) -> Option<&WorkerStatus> {
+        self.statuses.get(&worker_id)
+    }
+
+    /// The statuses, in order of worker_id
+    pub fn iter(&self) -> impl Iterator<Item = &WorkerStatus> {
+        self.statuses.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.statuses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.statuses.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.statuses.clear();
+    }
+}
+
+//Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_worker_statuses() {
+        let mut statuses = WorkerStatuses::new(2);
+        assert_eq!(statuses.len(), 2);
+        statuses.update(WorkerStatus::new(0.5, 3, 1));
+        assert_eq!(statuses.get(1).unwrap().run_number, 3);
+        assert_eq!(statuses.get(0).unwrap().run_number, 0);
+
+        // A status from an id past the workers does not panic, and is kept in order
+        statuses.update(WorkerStatus::new(0.25, 7, 5));
+        assert_eq!(statuses.len(), 3);
+        let ids: Vec<usize> = statuses.iter().map(|status| status.worker_id).collect();
+        assert_eq!(ids, vec![0, 1, 5]);
+
+        statuses.clear();
+        assert!(statuses.is_empty());
+        assert!(statuses.get(1).is_none());
+    }
 }